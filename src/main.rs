@@ -1,21 +1,247 @@
-use std::fs::File;
+use std::env;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use webarc::boot::boot;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+use webarc::adf::AdfImage;
+use webarc::cmos::{CmosDefaults, RiscOsVersion};
+use webarc::debugger::Debugger;
+use webarc::ether3::Ether3;
+use webarc::fdc::Fdc;
+use webarc::floppy::FloppyDisc;
+use webarc::gdb::GdbStub;
+use webarc::harddisc::{Geometry, HardDiscImage};
+use webarc::hdc::Hdc;
+use webarc::hfe::HfeImage;
+use webarc::hostfs::{self, HostFs};
+use webarc::ide::Ide;
+#[cfg(feature = "jit")]
+use webarc::jit::Jit;
+use webarc::machine::{Machine, Model};
+use webarc::net;
+use webarc::podule::Podules;
+use webarc::registers::Mode;
+use webarc::serial;
+use webarc::swis::SwiLogger;
+use webarc::trace::{self, Class, Sink};
 
-mod webarc;
+extern crate webarc;
+
+const CMOS_FILE: &str = "dist/cmos.ram";
+const CAPTURE_FILE: &str = "dist/network.pcap";
+
+// Acorn's OUI, as on the real cards
+const STATION_ADDRESS: [u8; 6] = [0x00, 0x00, 0xa4, 0x00, 0x00, 0x01];
+
+// Options ending in '=' take a value
+const OPTIONS: [&str; 15] = [
+    "--machine=", "--network=", "--serial=", "--printer=", "--jit", "--trace", "--trace=", "--trace-range=",
+    "--trace-mode=", "--trace-class=", "--log-swis", "--log-swis=", "--debug", "--gdb=", "--restore=",
+];
+
+const USAGE: &str = "Usage: webarc [options] [disc image | directory | podule ROM]...
+Options: --machine=<A310|A3000|A440|A5000> --network=<backend> --serial=<backend>
+         --printer=<path> --jit --trace[=<sink>] --trace-range=<start>-<end>
+         --trace-mode=<modes> --trace-class=<classes> --log-swis[=<path>]
+         --debug --gdb=<port> --restore=<path>";
 
 #[cfg(not(target_os = "emscripten"))]
 fn main() {
     println!("WebArc (native)");
-    let mut rom: Vec<u32> = vec![0; 1 * 1024 * 1024];
+
+    // Anything else starting with -- is a mistake, not a disc image
+    let args: Vec<String> = env::args().skip(1).collect();
+    let known = |arg: &str| OPTIONS.iter().any(|&option| if option.ends_with('=') { arg.starts_with(option) } else { arg == option });
+    if let Some(arg) = args.iter().find(|arg| arg.starts_with("--") && !known(arg)) {
+        if arg != "--help" {
+            println!("Unknown option {}", arg);
+        }
+        println!("{}", USAGE);
+        process::exit(1);
+    }
+
+    let mut rom: Vec<u32> = vec![0; 1024 * 1024];
 
     println!("Opening ROM file");
     let mut f = File::open("dist/riscos311.rom").expect("Couldn't open ROM file");
 
     println!("Reading ROM file");
-    f.read(as_u8_slice(&mut rom));
+    let size = f.read(as_u8_slice(&mut rom)).expect("Couldn't read ROM file");
+    println!("Read {} bytes", size);
+
+    let version = RiscOsVersion::detect(&rom).unwrap_or(RiscOsVersion::RiscOs3);
+    println!("ROM is {:?}", version);
+
+    let model = args.iter()
+        .find_map(|arg| arg.strip_prefix("--machine="))
+        .map(|name| Model::from_name(name).expect("Unknown machine"))
+        .unwrap_or_default();
+    println!("Machine is {:?}", model);
+    let network = args.iter()
+        .find_map(|arg| arg.strip_prefix("--network="))
+        .map(str::to_owned)
+        .unwrap_or_else(|| format!("pcap:{}", CAPTURE_FILE));
+
+    let mut machine = Machine::new(model, rom.into_boxed_slice());
+
+    // Start from sensible settings until RISC OS has saved some of its own
+    if !Path::new(CMOS_FILE).exists() {
+        machine.memory().cmos.set_ram(&CmosDefaults::for_version(version).generate());
+    }
+    machine.memory().cmos.set_ram_file(CMOS_FILE).expect("Couldn't load CMOS RAM");
+
+    // Arguments are floppy disc images for drives 0-3, .hdf ST506 or .ide
+    // IDE hard disc images for drives 4 and 5, a directory to share through
+    // HostFS, or an .ether3 network card ROM. --network=<backend> sends its
+    // frames to loopback, pcap:<out>[,<in>] or udp:<local>,<peer> instead of
+    // capturing them. --machine=<model> picks an A310, A3000, A440 or A5000,
+    // --serial=<backend> connects the serial port to stdio, pty, loopback,
+    // file:<out>[,<in>] or tcp:<port>, --printer=<path> captures printer
+    // output, and --jit (when built with the jit feature) translates code as
+    // it runs.
+    // --trace logs every instruction to stdout, or --trace=<sink> to none,
+    // stdout, file:<path> or ring:<records> (which is shown when the emulator
+    // stops, or by the debugger's trace command). --trace-range=<start>-<end>
+    // (hex), --trace-mode=<usr,fiq,irq,svc> and --trace-class=<alu,mul,swp,
+    // ldr,ldm,b,cp,swi> narrow down what's logged. --log-swis logs SWI calls
+    // and their results to stdout, or --log-swis=<path> to a file. --debug
+    // starts in the debugger instead of running, and --gdb=<port> waits for
+    // gdb to connect to the port first. --restore=<path> carries on from a
+    // snapshot saved by the debugger, once everything else is attached.
+    let (mut floppies, mut hard_discs, mut ide_discs) = (0, 0, 0);
+    let mut debug = false;
+    let mut gdb_port = None;
+    let mut snapshot = None;
+    for path in args {
+        if path.starts_with("--machine=") || path.starts_with("--network=") {
+            continue;
+        } else if path == "--debug" {
+            debug = true;
+        } else if let Some(path) = path.strip_prefix("--restore=") {
+            snapshot = Some(path.to_owned());
+        } else if let Some(port) = path.strip_prefix("--gdb=") {
+            gdb_port = Some(port.parse::<u16>().expect("Bad gdb port"));
+        } else if path == "--trace" {
+            machine.cpu.trace.set_sink(Sink::Stdout);
+        } else if let Some(spec) = path.strip_prefix("--trace=") {
+            machine.cpu.trace.set_sink(trace::open(spec).expect("Couldn't open trace sink"));
+        } else if let Some(spec) = path.strip_prefix("--trace-range=") {
+            machine.cpu.trace.filter.addresses = Some(trace::parse_range(spec).expect("Bad trace address range"));
+        } else if let Some(names) = path.strip_prefix("--trace-mode=") {
+            machine.cpu.trace.filter.modes = names.split(',').map(|name| Mode::from_name(name).expect("Unknown processor mode")).collect();
+        } else if let Some(names) = path.strip_prefix("--trace-class=") {
+            machine.cpu.trace.filter.classes = names.split(',').map(|name| Class::from_name(name).expect("Unknown instruction class")).collect();
+        } else if path == "--jit" {
+            #[cfg(feature = "jit")]
+            {
+                machine.cpu.jit = Some(Jit::new().expect("Couldn't start the JIT"));
+            }
+            #[cfg(not(feature = "jit"))]
+            println!("Built without the JIT, interpreting");
+        } else if path == "--log-swis" || path.starts_with("--log-swis=") {
+            let log = SwiLogger::open(path.strip_prefix("--log-swis=")).expect("Couldn't open SWI log");
+            machine.cpu.swi_log = Some(log);
+        } else if let Some(spec) = path.strip_prefix("--serial=") {
+            println!("Connecting serial port to {}", spec);
+            machine.attach_serial(serial::open(spec).expect("Couldn't open serial backend"));
+        } else if let Some(capture) = path.strip_prefix("--printer=") {
+            println!("Capturing printer output to {}", capture);
+            machine.capture_printer(capture).expect("Couldn't open printer output");
+        } else if Path::new(&path).is_dir() && machine.cpu.hostfs.is_none() {
+            println!("Sharing {} through HostFS", path);
+            machine.cpu.hostfs = Some(HostFs::new(&path));
+            if let Some(podules) = machine.memory().device::<Podules>() {
+                podules.insert(0, Box::new(hostfs::podule()));
+            }
+        } else if path.to_lowercase().ends_with(".ether3") {
+            println!("Fitting network card with ROM {}, connected to {}", path, network);
+            let rom = fs::read(&path).expect("Couldn't open network card ROM");
+            let backend = net::open(&network).expect("Couldn't open network backend");
+            if let Some(podules) = machine.memory().device::<Podules>() {
+                podules.insert(1, Box::new(Ether3::new(rom, STATION_ADDRESS, backend)));
+            }
+        } else if path.to_lowercase().ends_with(".ide") {
+            let size = fs::metadata(&path).expect("Couldn't open hard disc image").len();
+            let geometry = Geometry::ide_for_size(size).expect("Hard disc image is too small");
+            let image = HardDiscImage::open(&path, geometry).expect("Couldn't open hard disc image");
+            match machine.memory().device::<Ide>() {
+                Some(ide) => {
+                    if ide.attach(ide_discs, image) {
+                        println!("Attaching {} as IDE drive {}", path, ide_discs + 4);
+                        ide_discs += 1;
+                    } else {
+                        println!("There's no IDE drive {} for {}", ide_discs + 4, path);
+                    }
+                },
+                None => println!("The {:?} has no IDE interface for {}", model, path)
+            }
+        } else if path.to_lowercase().ends_with(".hdf") && hard_discs < 2 {
+            let image = HardDiscImage::open(&path, Geometry::st506_20mb()).expect("Couldn't open hard disc image");
+            match machine.memory().device::<Hdc>() {
+                Some(hdc) => {
+                    println!("Attaching {} as drive {}", path, hard_discs + 4);
+                    hdc.attach(hard_discs, image);
+                    hard_discs += 1;
+                },
+                None => println!("The {:?} has no ST506 controller for {}", model, path)
+            }
+        } else if floppies < 4 {
+            println!("Inserting {} in drive {}", path, floppies);
+            let disc = open_disc_image(&path);
+            if let Some(fdc) = machine.memory().device::<Fdc>() {
+                if !fdc.insert(floppies, disc) {
+                    println!("There's no drive {} for {}", floppies, path);
+                }
+            }
+            floppies += 1;
+        }
+    }
+
+    if let Some(path) = snapshot {
+        println!("Restoring from {}", path);
+        machine.load_snapshot(&path).expect("Couldn't restore snapshot");
+    }
+
+    // Whatever's in the trace ring shows how things went wrong
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        if let Some(port) = gdb_port {
+            let mut stub = GdbStub::listen(port).expect("Couldn't listen for gdb");
+            if let Err(error) = stub.serve(&mut machine) {
+                println!("Lost gdb: {}", error);
+            }
+            machine.run();
+        } else if debug {
+            Debugger::new().run(&mut machine);
+        } else {
+            machine.run();
+        }
+    }));
+
+    show_trace(&machine);
+    if let Err(panic) = result {
+        panic::resume_unwind(panic);
+    }
+}
 
-    boot(rom.into_boxed_slice());
+#[cfg(not(target_os = "emscripten"))]
+fn show_trace(machine: &Machine) {
+    let records: Vec<_> = machine.cpu.trace.records().collect();
+    if !records.is_empty() {
+        println!("Last {} instructions traced:", records.len());
+        for record in records {
+            println!("{}", record);
+        }
+    }
+}
+
+#[cfg(not(target_os = "emscripten"))]
+fn open_disc_image(path: &str) -> Box<dyn FloppyDisc> {
+    if path.to_lowercase().ends_with(".hfe") {
+        Box::new(HfeImage::open(path, false).expect("Couldn't open disc image"))
+    } else {
+        Box::new(AdfImage::open(path, false).expect("Couldn't open disc image"))
+    }
 }
 
 #[cfg(target_os = "emscripten")]
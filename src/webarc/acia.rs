@@ -0,0 +1,291 @@
+use std::io;
+use webarc::device::{Device, Interrupts};
+use webarc::ioc::IRQ_B_SERIAL;
+use webarc::serial::SerialBackend;
+use webarc::snapshot::{StateReader, StateWriter};
+use webarc::sound::CYCLES_PER_MICROSECOND;
+
+// 6551 ACIA driving the RS423 serial port on IOC machines. Its four registers
+// are in IOC bank 3, and its interrupt output drives IOC IRQ B. Characters
+// move at the programmed baud rate, with ten bits on the wire for each one.
+
+const DATA: u32 = 0x00;
+const STATUS_RESET: u32 = 0x04;
+const COMMAND: u32 = 0x08;
+const CONTROL: u32 = 0x0c;
+
+const STATUS_OVERRUN: u8 = 1 << 2;
+const STATUS_RECEIVE_FULL: u8 = 1 << 3;
+const STATUS_TRANSMIT_EMPTY: u8 = 1 << 4;
+const STATUS_IRQ: u8 = 1 << 7;
+// Parity, framing and overrun errors
+const STATUS_ERRORS: u8 = 0x07;
+
+// DTR enables the receiver and interrupts
+const COMMAND_DTR: u8 = 1 << 0;
+const COMMAND_RECEIVE_IRQ_DISABLE: u8 = 1 << 1;
+const COMMAND_TRANSMIT_CONTROL: u8 = 3 << 2;
+const COMMAND_TRANSMIT_IRQ: u8 = 1 << 2;
+const COMMAND_ECHO: u8 = 1 << 4;
+// Bits cleared by a programmed reset
+const COMMAND_RESET_MASK: u8 = 0x1f;
+
+const CONTROL_BAUD_RATE: u8 = 0x0f;
+
+// Rates for each control register baud rate setting. The first, the 16x
+// external clock, runs at 19200 baud on the Archimedes.
+const BAUD_RATES: [u32; 16] = [
+    19200, 50, 75, 110, 135, 150, 300, 600, 1200, 1800, 2400, 3600, 4800, 7200, 9600, 19200
+];
+
+const BITS_PER_CHARACTER: u32 = 10;
+
+pub struct Acia {
+    backend: Option<Box<dyn SerialBackend>>,
+
+    status: u8,
+    command: u8,
+    control: u8,
+    receive: u8,
+    // Character waiting to go out, and how long until the current one has
+    transmit: Option<u8>,
+    transmit_countdown: u32,
+    receive_countdown: u32,
+}
+
+impl Acia {
+    pub fn new() -> Acia {
+        Acia {
+            backend: None,
+            status: STATUS_TRANSMIT_EMPTY,
+            command: COMMAND_RECEIVE_IRQ_DISABLE,
+            control: 0,
+            receive: 0,
+            transmit: None,
+            transmit_countdown: 0,
+            receive_countdown: 0,
+        }
+    }
+
+    pub fn attach(&mut self, backend: Box<dyn SerialBackend>) {
+        self.backend = Some(backend);
+    }
+
+    pub fn detach(&mut self) -> Option<Box<dyn SerialBackend>> {
+        self.backend.take()
+    }
+
+    pub fn irq(&self) -> bool {
+        self.status & STATUS_IRQ != 0
+    }
+
+    fn character_time(&self) -> u32 {
+        let baud = BAUD_RATES[(self.control & CONTROL_BAUD_RATE) as usize];
+        1_000_000 * CYCLES_PER_MICROSECOND * BITS_PER_CHARACTER / baud
+    }
+
+    fn transmit_irq_enabled(&self) -> bool {
+        self.command & COMMAND_TRANSMIT_CONTROL == COMMAND_TRANSMIT_IRQ
+    }
+}
+
+impl Device for Acia {
+    fn load(&mut self, offset: u32) -> u32 {
+        let value = match offset & 0x0c {
+            DATA => {
+                self.status &= !(STATUS_RECEIVE_FULL | STATUS_ERRORS);
+                self.receive
+            },
+            STATUS_RESET => {
+                let status = self.status;
+                self.status &= !STATUS_IRQ;
+                status
+            },
+            COMMAND => self.command,
+            CONTROL => self.control,
+            _ => unreachable!()
+        };
+        value as u32
+    }
+
+    fn store(&mut self, offset: u32, data: u32) {
+        let value = (data >> 16) as u8;
+
+        match offset & 0x0c {
+            DATA => {
+                self.transmit = Some(value);
+                self.status &= !STATUS_TRANSMIT_EMPTY;
+            },
+            STATUS_RESET => {
+                self.command &= !COMMAND_RESET_MASK;
+                self.status &= !STATUS_OVERRUN;
+            },
+            COMMAND => {
+                self.command = value;
+                if self.transmit_irq_enabled() && self.status & STATUS_TRANSMIT_EMPTY != 0 {
+                    self.status |= STATUS_IRQ;
+                }
+            },
+            CONTROL => self.control = value,
+            _ => unreachable!()
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.transmit_countdown > cycles {
+            self.transmit_countdown -= cycles;
+        } else if let Some(byte) = self.transmit.take() {
+            if let Some(ref mut backend) = self.backend {
+                backend.write(byte);
+            }
+            self.transmit_countdown = self.character_time();
+            self.status |= STATUS_TRANSMIT_EMPTY;
+            if self.transmit_irq_enabled() {
+                self.status |= STATUS_IRQ;
+            }
+        } else {
+            self.transmit_countdown = 0;
+        }
+
+        if self.receive_countdown > cycles {
+            self.receive_countdown -= cycles;
+            return;
+        }
+        self.receive_countdown = self.character_time();
+
+        // Leave characters with the host until there's room for them, rather
+        // than overrunning
+        if self.command & COMMAND_DTR == 0 || self.status & STATUS_RECEIVE_FULL != 0 {
+            return;
+        }
+        if let Some(byte) = self.backend.as_mut().and_then(|backend| backend.read()) {
+            self.receive = byte;
+            self.status |= STATUS_RECEIVE_FULL;
+            if self.command & COMMAND_RECEIVE_IRQ_DISABLE == 0 {
+                self.status |= STATUS_IRQ;
+            }
+
+            if self.command & COMMAND_ECHO != 0 && self.transmit.is_none() {
+                self.transmit = Some(byte);
+            }
+        }
+    }
+
+    // Finishing the character going out, and checking the host for the
+    // next one coming in
+    fn next_event(&self) -> Option<u32> {
+        let transmit = if self.transmit.is_some() { Some(self.transmit_countdown) } else { None };
+        let receive = if self.backend.is_some() { Some(self.receive_countdown) } else { None };
+        transmit.into_iter().chain(receive).min()
+    }
+
+    fn interrupts(&mut self) -> Interrupts {
+        Interrupts::irq_b(IRQ_B_SERIAL, self.irq())
+    }
+
+    // Whatever the port is connected to stays connected
+    fn save(&self, state: &mut StateWriter) {
+        for &register in &[self.status, self.command, self.control, self.receive] {
+            state.u8(register);
+        }
+        state.option_u8(self.transmit);
+        state.u32(self.transmit_countdown);
+        state.u32(self.receive_countdown);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for register in [&mut self.status, &mut self.command, &mut self.control, &mut self.receive] {
+            *register = state.u8()?;
+        }
+        self.transmit = state.option_u8()?;
+        self.transmit_countdown = state.u32()?;
+        self.receive_countdown = state.u32()?;
+        Ok(())
+    }
+}
+
+impl Default for Acia {
+    fn default() -> Acia {
+        Acia::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webarc::serial::Loopback;
+
+    // 19200 baud
+    const CHARACTER_TIME: u32 = 8_000_000 * BITS_PER_CHARACTER / 19200;
+
+    // The ACIA takes bytes from data bus bits 16-23
+    fn store(acia: &mut Acia, register: u32, value: u8) {
+        acia.store(register, (value as u32) << 16);
+    }
+
+    fn load(acia: &mut Acia, register: u32) -> u8 {
+        acia.load(register) as u8
+    }
+
+    fn run(acia: &mut Acia, cycles: u32) {
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let step = acia.next_event().unwrap_or(remaining).clamp(1, remaining);
+            acia.tick(step);
+            remaining -= step;
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        let mut acia = Acia::new();
+        acia.attach(Box::new(Loopback::new()));
+        store(&mut acia, CONTROL, 0x1f);
+        assert_eq!(acia.character_time(), CHARACTER_TIME);
+        assert_eq!(load(&mut acia, STATUS_RESET), STATUS_TRANSMIT_EMPTY);
+
+        // Asking for transmit interrupts with nothing to send raises one, and
+        // reading the status clears it
+        store(&mut acia, COMMAND, COMMAND_DTR | COMMAND_TRANSMIT_IRQ);
+        assert_eq!(acia.interrupts(), Interrupts::irq_b(IRQ_B_SERIAL, true));
+        assert_eq!(load(&mut acia, STATUS_RESET), STATUS_IRQ | STATUS_TRANSMIT_EMPTY);
+        assert_eq!(acia.interrupts(), Interrupts::NONE);
+
+        // Out and straight back in
+        store(&mut acia, DATA, b'A');
+        assert_eq!(load(&mut acia, STATUS_RESET), 0);
+        run(&mut acia, 1);
+        assert!(acia.irq());
+        assert_eq!(load(&mut acia, STATUS_RESET), STATUS_IRQ | STATUS_TRANSMIT_EMPTY | STATUS_RECEIVE_FULL);
+        assert_eq!(load(&mut acia, DATA), b'A');
+        assert_eq!(load(&mut acia, STATUS_RESET), STATUS_TRANSMIT_EMPTY);
+
+        // A character the CPU hasn't read holds up the next, rather than
+        // being overrun
+        store(&mut acia, DATA, b'B');
+        run(&mut acia, CHARACTER_TIME);
+        store(&mut acia, DATA, b'C');
+        run(&mut acia, 3 * CHARACTER_TIME);
+        assert_eq!(load(&mut acia, STATUS_RESET) & (STATUS_RECEIVE_FULL | STATUS_ERRORS), STATUS_RECEIVE_FULL);
+        assert_eq!(load(&mut acia, DATA), b'B');
+        run(&mut acia, CHARACTER_TIME);
+        assert_eq!(load(&mut acia, DATA), b'C');
+
+        // Without DTR nothing is received, and without receive interrupts
+        // what is received doesn't interrupt
+        store(&mut acia, COMMAND, COMMAND_RECEIVE_IRQ_DISABLE);
+        load(&mut acia, STATUS_RESET);
+        store(&mut acia, DATA, b'D');
+        run(&mut acia, 3 * CHARACTER_TIME);
+        assert_eq!(load(&mut acia, STATUS_RESET), STATUS_TRANSMIT_EMPTY);
+        store(&mut acia, COMMAND, COMMAND_DTR | COMMAND_RECEIVE_IRQ_DISABLE);
+        run(&mut acia, CHARACTER_TIME);
+        assert_eq!(load(&mut acia, STATUS_RESET), STATUS_TRANSMIT_EMPTY | STATUS_RECEIVE_FULL);
+        assert_eq!(load(&mut acia, DATA), b'D');
+
+        // A programmed reset clears the bottom of the command register
+        store(&mut acia, COMMAND, 0xe0 | COMMAND_DTR);
+        store(&mut acia, STATUS_RESET, 0);
+        assert_eq!(load(&mut acia, COMMAND), 0xe0);
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+use webarc::floppy::{FloppyDisc, SectorData, SectorId};
+
+// Raw ADFS floppy images (.adf, .adl): every sector in order, with the two
+// sides of each cylinder interleaved. The format is worked out from the size.
+// High density F format images load and save like the rest, but the 1772 only
+// reads single and double density, so it finds no sectors on them.
+
+#[derive(Clone, Copy)]
+pub struct Geometry {
+    pub cylinders: u8,
+    pub sides: u8,
+    pub sectors: u8,
+    // Sector length is 128 << size
+    pub size: u8,
+    pub high_density: bool,
+}
+
+impl Geometry {
+    pub fn from_image_size(bytes: usize) -> Option<Geometry> {
+        let (cylinders, sides, sectors, size, high_density) = match bytes {
+            // ADFS S, 160K
+            163840 => (40, 1, 16, 1, false),
+            // ADFS M, 320K
+            327680 => (80, 1, 16, 1, false),
+            // ADFS L, 640K
+            655360 => (80, 2, 16, 1, false),
+            // ADFS D and E, 800K
+            819200 => (80, 2, 5, 3, false),
+            // ADFS F, 1.6M
+            1638400 => (80, 2, 10, 3, true),
+            _ => return None
+        };
+
+        Some(Geometry { cylinders, sides, sectors, size, high_density })
+    }
+
+    pub fn sector_bytes(&self) -> usize {
+        128 << self.size
+    }
+
+    pub fn image_bytes(&self) -> usize {
+        self.cylinders as usize * self.sides as usize * self.sectors as usize * self.sector_bytes()
+    }
+
+    fn offset(&self, cylinder: u8, side: u8, index: usize) -> usize {
+        let track = cylinder as usize * self.sides as usize + side as usize;
+        (track * self.sectors as usize + index) * self.sector_bytes()
+    }
+}
+
+pub struct AdfImage {
+    pub geometry: Geometry,
+    data: Vec<u8>,
+    file: Option<File>,
+    write_protected: bool,
+}
+
+impl AdfImage {
+    // None if the image isn't a size ADFS uses
+    pub fn from_bytes(data: Vec<u8>) -> Option<AdfImage> {
+        let geometry = Geometry::from_image_size(data.len())?;
+
+        Some(AdfImage {
+            geometry,
+            data,
+            file: None,
+            write_protected: false,
+        })
+    }
+
+    // Open an image file. Sector writes go straight back to the file unless
+    // the disc is write protected, which it is if the file is read-only.
+    pub fn open<P: AsRef<Path>>(path: P, write_protected: bool) -> io::Result<AdfImage> {
+        let (mut file, write_protected) = if write_protected {
+            (File::open(&path)?, true)
+        } else {
+            match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(file) => (file, false),
+                Err(_) => (File::open(&path)?, true)
+            }
+        };
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut image = match AdfImage::from_bytes(data) {
+            Some(image) => image,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unrecognised disc image size"))
+        };
+
+        image.file = Some(file);
+        image.write_protected = write_protected;
+        Ok(image)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        File::create(path)?.write_all(&self.data)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.write_protected = write_protected;
+    }
+
+    // Whether the 1772 can see the track at all, which it can't at high
+    // density or in single density
+    fn readable(&self, cylinder: u8, side: u8, double_density: bool) -> bool {
+        cylinder < self.geometry.cylinders && side < self.geometry.sides && double_density && !self.geometry.high_density
+    }
+
+    fn persist(&mut self, offset: usize, length: usize) -> io::Result<()> {
+        if let Some(ref mut file) = self.file {
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.write_all(&self.data[offset..offset + length])?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl FloppyDisc for AdfImage {
+    fn sector_ids(&self, cylinder: u8, side: u8, double_density: bool) -> Vec<SectorId> {
+        if !self.readable(cylinder, side, double_density) {
+            return Vec::new();
+        }
+
+        (0..self.geometry.sectors).map(|sector| SectorId {
+            track: cylinder,
+            side,
+            sector,
+            size: self.geometry.size,
+            crc_error: false,
+        }).collect()
+    }
+
+    fn read_sector(&self, cylinder: u8, side: u8, double_density: bool, index: usize) -> Option<SectorData> {
+        if !self.readable(cylinder, side, double_density) || index >= self.geometry.sectors as usize {
+            return None;
+        }
+
+        let offset = self.geometry.offset(cylinder, side, index);
+        Some(SectorData {
+            data: self.data[offset..offset + self.geometry.sector_bytes()].to_vec(),
+            deleted: false,
+            crc_error: false,
+        })
+    }
+
+    fn write_sector(&mut self, cylinder: u8, side: u8, double_density: bool, index: usize, data: &[u8], _deleted: bool) {
+        if !self.readable(cylinder, side, double_density) || index >= self.geometry.sectors as usize || self.write_protected {
+            return;
+        }
+
+        let offset = self.geometry.offset(cylinder, side, index);
+        let length = self.geometry.sector_bytes().min(data.len());
+        self.data[offset..offset + length].copy_from_slice(&data[..length]);
+
+        if let Err(error) = self.persist(offset, length) {
+            println!("Couldn't write disc image: {}", error);
+        }
+    }
+
+    fn write_protected(&self) -> bool {
+        self.write_protected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        for &(bytes, sectors) in &[(163840, 16), (327680, 16), (655360, 16), (819200, 5)] {
+            let image = AdfImage::from_bytes(vec![0; bytes]).unwrap();
+            assert_eq!(image.geometry.image_bytes(), bytes);
+            assert_eq!(image.sector_ids(0, 0, true).len(), sectors);
+        }
+
+        assert!(AdfImage::from_bytes(vec![0; 1000]).is_none());
+    }
+
+    // ADFS F loads and saves, but the 1772 can't read high density
+    #[test]
+    fn high_density() {
+        let data: Vec<u8> = (0..1638400).map(|i| (i * 7) as u8).collect();
+        let mut image = AdfImage::from_bytes(data.clone()).unwrap();
+        assert_eq!(image.geometry.sectors, 10);
+        assert!(image.sector_ids(0, 0, true).is_empty());
+        assert!(image.sector_ids(0, 0, false).is_empty());
+        assert!(image.read_sector(0, 0, true, 0).is_none());
+        image.write_sector(0, 0, true, 0, &[0; 1024], false);
+
+        let path = std::env::temp_dir().join(format!("webarc-adf-{}", std::process::id()));
+        image.save(&path).unwrap();
+        let reopened = AdfImage::open(&path, true);
+        std::fs::remove_file(&path).unwrap();
+        let reopened = reopened.unwrap();
+        assert!(reopened.geometry.high_density);
+        assert!(reopened.write_protected());
+        assert!(reopened.data() == &data[..]);
+    }
+}
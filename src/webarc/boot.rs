@@ -1,7 +1,7 @@
-use webarc::cpu::Cpu;
+use webarc::machine::{Machine, Model};
 
 #[no_mangle]
 pub fn boot(rom: Box<[u32]>) {
-    let mut cpu = Cpu::new(rom);
-    cpu.run();
+    let mut machine = Machine::new(Model::default(), rom);
+    machine.run();
 }
@@ -0,0 +1,211 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use webarc::pcf8583::{civil_from_days, RAM_START};
+
+// Default CMOS RAM contents for a fresh machine. RISC OS refers to CMOS bytes
+// by logical address (as used by OS_Byte 161 and 162), which map onto the
+// PCF8583's RAM starting at 0x40 and wrapping round to 0x10 at logical 0xC0.
+
+// Filing system in bits 0-3, language in bits 4-7
+const FILE_LANG: u8 = 0x05;
+// Screen mode in bits 0-3, TV interlace in bit 4, TV vertical adjust in bits 5-7
+const MODE_TV: u8 = 0x0a;
+// Default drive in bits 0-2, caps lock state in bits 3-5
+const START: u8 = 0x0b;
+const KEY_DELAY: u8 = 0x0c;
+const KEY_REPEAT: u8 = 0x0d;
+// Serial baud rate in bits 2-4, printer type in bits 5-7
+const PRINT_SERIAL: u8 = 0x0f;
+// Loud beep in bit 1, scroll in bit 2, boot in bit 4, serial format in bits 5-7
+const DBTB: u8 = 0x10;
+const YEAR: u8 = 0x80;
+// Floppy drives in bits 0-1, ST506 hard discs in bits 3-5
+const ADFS_DRIVES: u8 = 0x85;
+const ADFS_BUFFERS: u8 = 0x87;
+const WIMP_MODE: u8 = 0xc4;
+const CHECKSUM: u8 = 0xef;
+
+const FILING_SYSTEM_ADFS: u8 = 8;
+
+const CAPS_LOCK_OFF: u8 = 0x10;
+const BAUD_9600: u8 = 7;
+const SERIAL_8N1: u8 = 5;
+const PRINTER_PARALLEL: u8 = 1;
+
+// Added to the sum of every other byte to make the checksum
+const CHECKSUM_SEED: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RiscOsVersion {
+    RiscOs2,
+    RiscOs3,
+}
+
+impl RiscOsVersion {
+    // Find the version from the UtilityModule's help string in a ROM image
+    pub fn detect(rom: &[u32]) -> Option<RiscOsVersion> {
+        let bytes: Vec<u8> = rom.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect();
+        let name = b"RISC OS";
+
+        bytes.windows(name.len()).enumerate()
+            .filter(|&(_, window)| window == name)
+            .filter_map(|(position, _)| {
+                bytes[position + name.len()..].iter().take(4).find(|byte| **byte != b' ')
+            })
+            .filter_map(|version| match *version {
+                b'2' => Some(RiscOsVersion::RiscOs2),
+                b'3' => Some(RiscOsVersion::RiscOs3),
+                _ => None
+            })
+            .next()
+    }
+}
+
+pub struct CmosDefaults {
+    pub mode: u8,
+    pub wimp_mode: u8,
+    pub floppies: u8,
+    pub hard_discs: u8,
+    pub adfs_buffers: u8,
+    pub language: u8,
+    pub filing_system: u8,
+    pub drive: u8,
+    pub boot: bool,
+    pub year: u16,
+}
+
+impl CmosDefaults {
+    pub fn for_version(version: RiscOsVersion) -> CmosDefaults {
+        let days = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400;
+        let (year, _, _) = civil_from_days(days as i64);
+
+        match version {
+            // RISC OS 2 has no separate desktop mode and starts at the command line
+            RiscOsVersion::RiscOs2 => CmosDefaults {
+                mode: 12,
+                wimp_mode: 12,
+                floppies: 1,
+                hard_discs: 0,
+                adfs_buffers: 1,
+                language: 0,
+                filing_system: FILING_SYSTEM_ADFS,
+                drive: 0,
+                boot: true,
+                year: year as u16,
+            },
+            // RISC OS 3 starts the desktop, whose position in the ROM module
+            // chain is the language number
+            RiscOsVersion::RiscOs3 => CmosDefaults {
+                mode: 12,
+                wimp_mode: 12,
+                floppies: 1,
+                hard_discs: 0,
+                adfs_buffers: 1,
+                language: 10,
+                filing_system: FILING_SYSTEM_ADFS,
+                drive: 0,
+                boot: true,
+                year: year as u16,
+            },
+        }
+    }
+
+    // RAM image in the chip's physical order, ready for Pcf8583::set_ram
+    pub fn generate(&self) -> [u8; 240] {
+        let mut ram = [0; 240];
+
+        {
+            let mut set = |logical: u8, value: u8| ram[physical_address(logical) - RAM_START] = value;
+
+            set(FILE_LANG, (self.language << 4) | (self.filing_system & 0xf));
+            set(MODE_TV, self.mode & 0xf);
+            set(START, (self.drive & 7) | CAPS_LOCK_OFF);
+            set(KEY_DELAY, 32);
+            set(KEY_REPEAT, 8);
+            set(PRINT_SERIAL, (BAUD_9600 << 2) | (PRINTER_PARALLEL << 5));
+            set(DBTB, (1 << 1) | if self.boot { 1 << 4 } else { 0 } | (SERIAL_8N1 << 5));
+            set(YEAR, (self.year % 100) as u8);
+            set(YEAR + 1, (self.year / 100) as u8);
+            set(ADFS_DRIVES, (self.floppies & 3) | ((self.hard_discs & 7) << 3));
+            set(ADFS_BUFFERS, self.adfs_buffers);
+            set(WIMP_MODE, self.wimp_mode);
+        }
+
+        ram[physical_address(CHECKSUM) - RAM_START] = checksum(&ram);
+        ram
+    }
+}
+
+pub fn physical_address(logical: u8) -> usize {
+    if logical < 0xc0 {
+        logical as usize + 0x40
+    } else {
+        logical as usize - 0xb0
+    }
+}
+
+// Checksum over a RAM image in physical order, excluding the checksum byte
+pub fn checksum(ram: &[u8]) -> u8 {
+    let checksum_index = physical_address(CHECKSUM) - RAM_START;
+
+    ram.iter().enumerate()
+        .filter(|&(index, _)| index != checksum_index)
+        .fold(CHECKSUM_SEED, |sum, (_, byte)| sum.wrapping_add(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The RISC OS 3.11 kernel's MangleCMOSAddress: add 0x40 and wrap round
+    // past the clock registers
+    #[test]
+    fn logical_addresses() {
+        for &(logical, physical) in &[
+            (0x00, 0x40), (FILE_LANG, 0x45), (DBTB, 0x50), (YEAR, 0xc0), (ADFS_DRIVES, 0xc5),
+            (0xbf, 0xff), (0xc0, 0x10), (WIMP_MODE, 0x14), (CHECKSUM, 0x3f)
+        ] {
+            assert_eq!(physical_address(logical), physical, "logical {:#x}", logical);
+        }
+
+        // Every byte of RAM has exactly one logical address
+        let mut physical: Vec<usize> = (0..0xf0).map(physical_address).collect();
+        physical.sort();
+        assert_eq!(physical, (RAM_START..0x100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn checksums() {
+        let mut ram = [0; 240];
+        assert_eq!(checksum(&ram), CHECKSUM_SEED);
+
+        // The checksum byte itself doesn't count
+        ram[0x3f - RAM_START] = 0x99;
+        assert_eq!(checksum(&ram), 1);
+
+        ram[physical_address(0x00) - RAM_START] = 0x12;
+        ram[physical_address(0xc0) - RAM_START] = 0x34;
+        assert_eq!(checksum(&ram), 0x47);
+
+        // Wraps round at 8 bits: 239 bytes of 0xFF and the seed
+        let ram = [0xff; 240];
+        assert_eq!(checksum(&ram), 0x12);
+    }
+
+    // Read back the way RISC OS validates it, by logical address
+    #[test]
+    fn defaults() {
+        for &version in &[RiscOsVersion::RiscOs2, RiscOsVersion::RiscOs3] {
+            let defaults = CmosDefaults::for_version(version);
+            let ram = defaults.generate();
+            let read = |logical: u8| ram[physical_address(logical) - RAM_START];
+
+            let sum = (0..CHECKSUM).fold(CHECKSUM_SEED, |sum, logical| sum.wrapping_add(read(logical)));
+            assert_eq!(read(CHECKSUM), sum);
+
+            assert_eq!(read(FILE_LANG), defaults.language << 4 | FILING_SYSTEM_ADFS);
+            assert_eq!(read(WIMP_MODE), 12);
+            assert_eq!(read(ADFS_DRIVES), 1);
+            assert_eq!(read(DBTB), 0xb2);
+        }
+    }
+}
@@ -1,6 +1,13 @@
-use std::ops::Shr;
+use std::io;
+use webarc::disassembler;
+use webarc::hostfs::HostFs;
+#[cfg(feature = "jit")]
+use webarc::jit::{self, Jit};
 use webarc::memory::Memory;
-use webarc::registers::RegisterFile;
+use webarc::registers::{Mode, RegisterFile};
+use webarc::snapshot::{StateReader, StateWriter};
+use webarc::swis::SwiLogger;
+use webarc::trace::{Record, Tracer};
 use webarc::instructions::*;
 
 const RESET_VECTOR: u32 = 0x00;
@@ -8,6 +15,7 @@ const UNDEFINED_INSTRUCTION_VECTOR: u32 = 0x04;
 const SWI_VECTOR: u32 = 0x08;
 const PREFETCH_ABORT_VECTOR: u32 = 0x0c;
 const DATA_ABORT_VECTOR: u32 = 0x10;
+#[allow(dead_code)]
 const ADDRESS_EXCEPTION_VECTOR: u32 = 0x14;
 const IRQ_VECTOR: u32 = 0x18;
 const FIRQ_VECTOR: u32 = 0x1c;
@@ -16,42 +24,196 @@ const N_BIT: u32 = 0x80000000;
 const Z_BIT: u32 = 0x40000000;
 const C_BIT: u32 = 0x20000000;
 const V_BIT: u32 = 0x10000000;
+const I_BIT: u32 = 0x08000000;
+const F_BIT: u32 = 0x04000000;
 
 pub struct Cpu {
     pub registers: RegisterFile,
     pub memory: Memory,
+    pub hostfs: Option<HostFs>,
+    // Records of the instructions executed
+    pub trace: Tracer,
+    // Log SWIs as they're called and return
+    pub swi_log: Option<SwiLogger>,
+    // Translate hot code, when running rather than stepping
+    #[cfg(feature = "jit")]
+    pub jit: Option<Jit>,
 }
 
 impl Cpu {
-    pub fn new(rom: Box<[u32]>) -> Cpu {
+    pub fn new(memory: Memory) -> Cpu {
         let mut cpu = Cpu {
             registers: RegisterFile::new(),
-            memory: Memory::new(rom),
+            memory,
+            hostfs: None,
+            trace: Tracer::new(),
+            swi_log: None,
+            #[cfg(feature = "jit")]
+            jit: None,
         };
 
         // Start executing from the reset vector (accounting for pipeline offset)
-        cpu.registers.set_reg(15, RESET_VECTOR + 8);
+        // in SVC mode with interrupts off
+        cpu.registers.set_reg(15, I_BIT | F_BIT | Mode::Svc as u32 | (RESET_VECTOR + 8));
         cpu
     }
 
     pub fn run(&mut self) {
         loop {
-            self.step();
+            self.run_for(u64::MAX - self.memory.clock());
+        }
+    }
+
+    // Run until the master clock has advanced by at least a number of cycles.
+    // Nothing outside the CPU changes by itself between events, so the
+    // instructions up to each one run as a batch.
+    pub fn run_for(&mut self, cycles: u64) {
+        let target = self.memory.clock() + cycles;
+        while self.memory.clock() < target {
+            let mut batch_end = self.memory.next_event().min(target);
+            while self.memory.clock() < batch_end {
+                #[cfg(feature = "jit")]
+                let executed = self.execute_block();
+                #[cfg(not(feature = "jit"))]
+                let executed = false;
+                if !executed {
+                    self.execute();
+                }
+
+                // Writing to a device can bring its next event forward
+                if self.memory.take_rescheduled() {
+                    batch_end = self.memory.next_event().min(target);
+                }
+            }
+            self.memory.run_events();
+        }
+        self.trace.flush();
+        if let Some(ref mut swi_log) = self.swi_log {
+            swi_log.flush();
         }
     }
 
     pub fn step(&mut self) {
+        self.execute();
+        self.memory.run_events();
+    }
+
+    pub fn save(&self, state: &mut StateWriter) {
+        self.registers.save(state);
+        self.memory.save(state);
+    }
+
+    // Carry on from a saved state. Code translated before is thrown away
+    // along with the instructions it was made from.
+    pub fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.registers.restore(state)?;
+        self.memory.restore(state)?;
+        #[cfg(feature = "jit")]
+        {
+            if let Some(ref mut jit) = self.jit {
+                jit.flush();
+            }
+        }
+        Ok(())
+    }
+
+    // Execute one instruction, or take an interrupt
+    fn execute(&mut self) {
+        self.check_interrupts();
+        self.memory.set_user_mode(self.registers.mode() == Mode::User);
+
         let fetch_address = self.registers.reg_no_flags(15) - 8;
-        let fetched_word = self.memory.load(fetch_address);
-        let cond = fetched_word.shr(28) as u8;
-        self.log_instruction(fetch_address, fetched_word, cond, fetched_word);
+        if let Some(ref mut swi_log) = self.swi_log {
+            swi_log.check_return(fetch_address, &self.registers, &self.memory);
+        }
+        // An instruction that couldn't be fetched aborts instead of running,
+        // whatever its condition
+        let decoded = match self.memory.fetch(fetch_address) {
+            Some(decoded) => decoded,
+            None => return self.exception(PREFETCH_ABORT_VECTOR, Mode::Svc, I_BIT)
+        };
+        if self.trace.wants(fetch_address, self.registers.mode(), decoded.word) {
+            self.execute_traced(fetch_address, decoded);
+            return;
+        }
 
-        let action = if self.condition_met(cond, self.registers.reg(15)) {
-            exec(&mut self.registers, &mut self.memory, fetched_word)
+        let action = if self.condition_met(decoded.cond, self.registers.reg(15)) {
+            decoded.exec(&mut self.registers, &mut self.memory)
         } else {
             Action::Continue
         };
+        self.complete(action);
+    }
+
+    // Execute an instruction the same way, making a record of it
+    fn execute_traced(&mut self, fetch_address: u32, decoded: Decoded) {
+        let mode = self.registers.mode();
+        let before: Vec<u32> = (0..16).map(|reg| self.registers.slots()[RegisterFile::slot(mode, reg)]).collect();
+        self.memory.accesses = Some(Vec::new());
+
+        let executed = self.condition_met(decoded.cond, self.registers.reg(15));
+        let action = if executed {
+            decoded.exec(&mut self.registers, &mut self.memory)
+        } else {
+            Action::Continue
+        };
+        self.complete(action);
+
+        let registers = (0..16)
+            .map(|reg| (reg, self.registers.slots()[RegisterFile::slot(mode, reg)]))
+            .filter(|&(reg, value)| value != before[reg as usize] && !(reg == 15 && value == before[15].wrapping_add(4)))
+            .collect();
+        let record = Record {
+            pc: fetch_address,
+            word: decoded.word,
+            mode,
+            executed,
+            disassembly: disassembler::disassemble(fetch_address, decoded.word),
+            registers,
+            accesses: self.memory.accesses.take().unwrap_or_default(),
+        };
+        self.trace.record(record);
+    }
+
+    // Run a translated block from PC, if there is one. A block can go past the
+    // end of the batch, which only delays the next event a little.
+    #[cfg(feature = "jit")]
+    fn execute_block(&mut self) -> bool {
+        if self.trace.enabled() || self.swi_log.is_some() {
+            return false;
+        }
+        self.check_interrupts();
+        let mode = self.registers.mode();
+        self.memory.set_user_mode(mode == Mode::User);
+
+        let fetch_address = self.registers.reg_no_flags(15) - 8;
+        let (code, length) = match self.jit {
+            Some(ref mut jit) => match jit.block(&self.memory, fetch_address, mode) {
+                Some(block) => block,
+                None => return false
+            },
+            None => return false
+        };
+
+        for i in 0..length {
+            self.memory.fetch_cycle(fetch_address + i * 4);
+        }
+        // Both pointers come from the same one to the CPU, so neither is
+        // invalidated by the other being made. The block uses the registers
+        // until it calls back into the interpreter through the CPU, and not
+        // after.
+        let cpu = self as *mut Cpu;
+        let exit = unsafe {
+            let registers = (*cpu).registers.slots().as_mut_ptr();
+            code(registers, cpu)
+        };
+        self.complete(jit::exit_action(exit));
+        true
+    }
 
+    // Move on from an instruction that's been executed, with PC still
+    // pointing 8 bytes past it
+    fn complete(&mut self, action: Action) {
         let pc_increment = match action {
             // Increment PC to next instruction
             Action::Continue => 4,
@@ -59,10 +221,69 @@ impl Cpu {
             // Pipeline would usually be flushed here, but we don't have one
             // PC has the address of the next instruction, so add 8 to simulate pipeline's effect
             Action::Flush => 8,
+
+            // Exception entry leaves PC ready for the handler's first instruction
+            Action::Swi(comment) => {
+                if let Some(ref mut swi_log) = self.swi_log {
+                    swi_log.call(self.registers.reg_no_flags(15) - 8, comment, &self.registers);
+                }
+                if self.host_swi(comment) {
+                    4
+                } else {
+                    self.exception(SWI_VECTOR, Mode::Svc, I_BIT);
+                    0
+                }
+            },
+            Action::Undefined => {
+                self.exception(UNDEFINED_INSTRUCTION_VECTOR, Mode::Svc, I_BIT);
+                0
+            },
+            // R14 is left 8 bytes past the aborted instruction, so the
+            // handler can go back and run it again
+            Action::DataAbort => {
+                self.registers.set_reg_no_flags(15, self.registers.reg_no_flags(15) + 4);
+                self.exception(DATA_ABORT_VECTOR, Mode::Svc, I_BIT);
+                0
+            },
         };
 
         let new_pc = self.registers.reg_no_flags(15) + pc_increment;
         self.registers.set_reg_no_flags(15, new_pc);
+
+        // Refilling the pipeline takes a sequential fetch on top of the
+        // non-sequential one the next step makes
+        if let Action::Flush = action {
+            self.memory.sequential_cycle(new_pc - 4);
+        }
+    }
+
+    fn check_interrupts(&mut self) {
+        let r15 = self.registers.reg(15);
+
+        if r15 & F_BIT == 0 && self.memory.ioc.fiq() {
+            self.exception(FIRQ_VECTOR, Mode::Firq, I_BIT | F_BIT);
+        } else if r15 & I_BIT == 0 && self.memory.ioc.irq() {
+            self.exception(IRQ_VECTOR, Mode::Irq, I_BIT);
+        }
+    }
+
+    // SWIs the emulator implements itself rather than RISC OS
+    fn host_swi(&mut self, comment: u32) -> bool {
+        match self.hostfs {
+            Some(ref mut hostfs) => hostfs.swi(comment, &mut self.registers, &mut self.memory),
+            None => false
+        }
+    }
+
+    // Enter an exception handler, leaving R14 in the new mode pointing 4 bytes
+    // past the instruction that would have been executed next
+    fn exception(&mut self, vector: u32, mode: Mode, disable: u32) {
+        let return_address = self.registers.reg(15).wrapping_sub(4);
+        let r15 = (self.registers.reg(15) & !0x03ffffff) | disable | mode as u32;
+
+        self.registers.set_reg(15, r15 | (vector + 8));
+        self.registers.set_reg(14, return_address);
+        self.memory.sequential_cycle(vector + 4);
     }
 
     fn condition_met(&self, cond: u8, r15: u32) -> bool {
@@ -73,10 +294,10 @@ impl Cpu {
             0x3 /* CC */ => (r15 & C_BIT) == 0,
             0x4 /* MI */ => (r15 & N_BIT) != 0,
             0x5 /* PL */ => (r15 & N_BIT) == 0,
-            0x6 /* VS */ => (r15 & Z_BIT) != 0,
-            0x7 /* VC */ => (r15 & Z_BIT) == 0,
+            0x6 /* VS */ => (r15 & V_BIT) != 0,
+            0x7 /* VC */ => (r15 & V_BIT) == 0,
             0x8 /* HI */ => (r15 & (C_BIT | Z_BIT)) == C_BIT,
-            0x9 /* LS */ => (r15 & (C_BIT | Z_BIT)) != 0,
+            0x9 /* LS */ => (r15 & (C_BIT | Z_BIT)) != C_BIT,
             0xA /* GE */ => {
                 let masked = r15 & (N_BIT | V_BIT);
                 masked == (N_BIT | V_BIT) || masked == 0
@@ -87,7 +308,7 @@ impl Cpu {
             },
             0xC /* GT */ => {
                 let masked = r15 & (Z_BIT | N_BIT | V_BIT);
-                masked == N_BIT || masked == V_BIT || masked == Z_BIT || masked == 0
+                masked == (N_BIT | V_BIT) || masked == 0
             },
             0xD /* LE */ => {
                 let masked = r15 & (N_BIT | V_BIT);
@@ -98,15 +319,92 @@ impl Cpu {
             _ => unreachable!()
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: u32 = 0x02001000;
+
+    fn cpu() -> Cpu {
+        Cpu::new(Memory::new(vec![0; 0x1000].into_boxed_slice(), 0x80000))
+    }
+
+    #[test]
+    fn reset() {
+        let cpu = cpu();
+        assert_eq!(cpu.registers.reg(15), I_BIT | F_BIT | Mode::Svc as u32 | (RESET_VECTOR + 8));
+    }
+
+    // Every combination of the flags against what each condition means
+    #[test]
+    fn conditions() {
+        let cpu = cpu();
+        for flags in 0..16 {
+            let r15 = flags << 28;
+            let (n, z, c, v) = (r15 & N_BIT != 0, r15 & Z_BIT != 0, r15 & C_BIT != 0, r15 & V_BIT != 0);
+            let expected = [
+                z, !z, c, !c, n, !n, v, !v,
+                c && !z, !c || z, n == v, n != v, !z && n == v, z || n != v,
+                true, false,
+            ];
+            for (cond, &expected) in expected.iter().enumerate() {
+                assert_eq!(cpu.condition_met(cond as u8, r15), expected, "Condition {:x} with flags {:x}", cond, flags);
+            }
+        }
+    }
 
-    fn log(&self, s: String) {
-        println!("{}", s);
+    // A coprocessor instruction, with no coprocessor to take it
+    #[test]
+    fn undefined_instruction() {
+        let mut cpu = cpu();
+        cpu.memory.store(CODE, 0xee000010);
+        cpu.registers.set_reg(15, C_BIT | Mode::Svc as u32 | (CODE + 8));
+        cpu.step();
+
+        assert_eq!(cpu.registers.reg(15), C_BIT | I_BIT | Mode::Svc as u32 | (UNDEFINED_INSTRUCTION_VECTOR + 8));
+        assert_eq!(cpu.registers.reg(14), C_BIT | Mode::Svc as u32 | (CODE + 4));
     }
 
-    fn log_instruction(&self, fetch_address: u32, fetched_word: u32, cond: u8, instruction: u32) {
-        let stringified = format(fetch_address, cond, instruction);
+    // Loads and stores MEMC refuses, and fetches from pages user mode can't
+    // read
+    #[test]
+    fn aborts() {
+        let mut cpu = cpu();
+        // Physical page 5 at 0x8000, which user mode can only read. Writing
+        // the CAM also takes the ROM away from the bottom of memory.
+        cpu.memory.store(0x03800000 | 0x8000 | 1 << 8 | 5, 0);
+        cpu.memory.store(0x02005000, 0xabcd);
+
+        // LDR R0, [R1] from a page with nothing mapped, and then from page 5
+        cpu.memory.store(CODE, 0xe5910000);
+        cpu.registers.set_reg(0, 0x1234);
+        cpu.registers.set_reg(1, 0xa000);
+        cpu.registers.set_reg(15, Mode::Svc as u32 | (CODE + 8));
+        cpu.step();
+        assert_eq!(cpu.registers.reg(15), I_BIT | Mode::Svc as u32 | (DATA_ABORT_VECTOR + 8));
+        assert_eq!(cpu.registers.reg(14), Mode::Svc as u32 | (CODE + 8));
+        assert_eq!(cpu.registers.reg(0), 0x1234);
+
+        cpu.registers.set_reg(1, 0x8000);
+        cpu.registers.set_reg(15, Mode::Svc as u32 | (CODE + 8));
+        cpu.step();
+        assert_eq!(cpu.registers.reg(0), 0xabcd);
+
+        // STR R0, [R1] in user mode, from the page itself
+        cpu.memory.store(0x02005004, 0xe5810000);
+        cpu.registers.set_reg(15, 0x8004 + 8);
+        cpu.step();
+        assert_eq!(cpu.registers.reg(15), I_BIT | Mode::Svc as u32 | (DATA_ABORT_VECTOR + 8));
+        assert_eq!(cpu.registers.reg(14), 0x8004 + 8);
+        assert_eq!(cpu.memory.peek(0x8000), Some(0xabcd));
 
-        let log_line = format!("{:08X}  {:08X}  {}", fetch_address, fetched_word, stringified);
-        self.log(log_line);
+        // Physical page 6 at 0x9000, which user mode can't read at all
+        cpu.memory.store(0x03800000 | 0x9000 | 2 << 8 | 6, 0);
+        cpu.registers.set_reg(15, 0x9000 + 8);
+        cpu.step();
+        assert_eq!(cpu.registers.reg(15), I_BIT | Mode::Svc as u32 | (PREFETCH_ABORT_VECTOR + 8));
+        assert_eq!(cpu.registers.reg(14), 0x9000 + 4);
     }
 }
@@ -0,0 +1,689 @@
+use std::io::{self, BufRead, Write};
+use webarc::disassembler;
+use webarc::machine::Machine;
+use webarc::registers::{Mode, RegisterFile};
+use webarc::trace::{Access, AccessKind};
+
+// An interactive monitor for the native binary. It steps the machine one
+// instruction at a time, stopping at breakpoints, which can have a condition,
+// and at watchpoints on ranges of memory. Addresses and values are in hex and
+// counts are in decimal. Memory is looked at without bus cycles, so only RAM
+// and ROM can be shown; I/O space comes out as ??. An empty line repeats the
+// last command.
+
+const HELP: &str = "\
+step [count]                  Execute instructions (s)
+continue                      Run until a breakpoint, watchpoint or Ctrl-C (c)
+break [address [if condition]]
+                              Set a breakpoint, or list them (b). Conditions
+                              compare a register or [address] with a value:
+                              r0 == 4, [2000000] != 0, ...
+watch start[-end] [r|w|rw]    Stop on accesses to memory, or list watchpoints
+delete id                     Remove a breakpoint or watchpoint
+regs [all]                    Show registers, or every mode's banks (r)
+set register value            Change a register: r0-r15, sp, lr, pc (the
+                              address of the next instruction) or psr, with
+                              _usr, _fiq, _irq or _svc for another mode's bank
+mem address [bytes]           Dump memory (m)
+poke address value            Store a word in RAM
+pokeb address value           Store a byte in RAM
+dis [address] [count]         Disassemble, around PC by default (d)
+bt                            Backtrace through R14 and APCS frame pointers
+trace [count]                 Show the last instructions kept by
+                              --trace=ring:<records>
+save file                     Save the machine's state to a snapshot
+load file                     Carry on from a snapshot
+help                          This
+quit                          Leave (q)";
+
+const DISASSEMBLY_BEFORE: u32 = 4;
+const DISASSEMBLY_AFTER: u32 = 8;
+const DEFAULT_DUMP_BYTES: u32 = 128;
+const MAX_BACKTRACE_FRAMES: usize = 32;
+const DEFAULT_TRACE_RECORDS: usize = 20;
+
+const MODE_NAMES: [&str; 4] = ["USR", "FIQ", "IRQ", "SVC"];
+const FLAG_NAMES: [(u32, char); 6] = [(31, 'N'), (30, 'Z'), (29, 'C'), (28, 'V'), (27, 'I'), (26, 'F')];
+
+#[cfg(unix)]
+mod interrupt {
+    use std::os::raw::c_int;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const SIGINT: c_int = 2;
+
+    pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+
+    extern "C" fn handle(_signum: c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+
+    // Ctrl-C stops a continue rather than the emulator
+    pub fn install() {
+        unsafe {
+            signal(SIGINT, handle);
+        }
+    }
+
+    pub fn take() -> bool {
+        INTERRUPTED.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[cfg(not(unix))]
+mod interrupt {
+    pub fn install() {}
+
+    pub fn take() -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(Option<Mode>, u32),
+    Memory(u32),
+}
+
+#[derive(Clone, Copy)]
+struct Condition {
+    operand: Operand,
+    comparison: Comparison,
+    value: u32,
+}
+
+struct Breakpoint {
+    id: u32,
+    address: u32,
+    condition: Option<Condition>,
+    text: String,
+}
+
+struct Watchpoint {
+    id: u32,
+    start: u32,
+    end: u32,
+    reads: bool,
+    writes: bool,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &Access) -> bool {
+        let write = match access.kind {
+            AccessKind::Load | AccessKind::LoadByte => false,
+            AccessKind::Store | AccessKind::StoreByte => true,
+        };
+        (self.start..=self.end).contains(&access.address) && if write { self.writes } else { self.reads }
+    }
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: u32,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 1,
+            last_command: String::new(),
+        }
+    }
+
+    // Read commands from stdin until quit or the end of the input
+    pub fn run(&mut self, machine: &mut Machine) {
+        interrupt::install();
+        println!("Debugger ready, type help for commands");
+        self.show_next(machine);
+
+        let stdin = io::stdin();
+        loop {
+            print!("> ");
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            if !self.command(machine, line.trim()) {
+                break;
+            }
+        }
+    }
+
+    // Carry out a command, returning false when it's time to stop
+    pub fn command(&mut self, machine: &mut Machine, line: &str) -> bool {
+        let line = if line.is_empty() { self.last_command.clone() } else { line.to_owned() };
+        self.last_command = line.clone();
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, arguments) = match words.split_first() {
+            Some((command, arguments)) => (command.to_lowercase(), arguments),
+            None => return true
+        };
+
+        let result = match command.as_str() {
+            "s" | "step" => self.step(machine, arguments),
+            "c" | "continue" => {
+                self.continue_running(machine);
+                Ok(())
+            },
+            "b" | "break" => self.set_breakpoint(arguments, &line),
+            "watch" => self.set_watchpoint(arguments),
+            "delete" => self.delete(arguments),
+            "r" | "regs" => {
+                show_registers(machine, arguments.first() == Some(&"all"));
+                Ok(())
+            },
+            "set" => set_register(machine, arguments),
+            "m" | "mem" => dump(machine, arguments),
+            "poke" | "pokeb" => poke(machine, arguments, command == "pokeb"),
+            "d" | "dis" => disassemble(machine, arguments),
+            "bt" => {
+                backtrace(machine);
+                Ok(())
+            },
+            "trace" => show_trace(machine, arguments),
+            "save" | "load" => snapshot(machine, arguments, command == "load"),
+            "h" | "help" => {
+                println!("{}", HELP);
+                Ok(())
+            },
+            "q" | "quit" => return false,
+            _ => Err(format!("Unknown command {}", command))
+        };
+
+        if let Err(error) = result {
+            println!("{}", error);
+        }
+        true
+    }
+
+    fn step(&mut self, machine: &mut Machine, arguments: &[&str]) -> Result<(), String> {
+        let count = match arguments.first() {
+            Some(count) => count.parse().map_err(|_| format!("Bad count {}", count))?,
+            None => 1
+        };
+
+        for _ in 0..count {
+            if self.execute(machine) {
+                break;
+            }
+        }
+        self.show_next(machine);
+        Ok(())
+    }
+
+    fn continue_running(&mut self, machine: &mut Machine) {
+        interrupt::take();
+        loop {
+            if self.execute(machine) || self.at_breakpoint(machine) {
+                break;
+            }
+            if interrupt::take() {
+                println!("Interrupted");
+                break;
+            }
+        }
+        self.show_next(machine);
+    }
+
+    // Execute an instruction, returning whether it hit a watchpoint
+    fn execute(&mut self, machine: &mut Machine) -> bool {
+        if self.watchpoints.is_empty() {
+            machine.step();
+            return false;
+        }
+
+        let address = pc(machine);
+        machine.memory().accesses = Some(Vec::new());
+        machine.step();
+        let accesses = machine.memory().accesses.take().unwrap_or_default();
+
+        let mut hit = false;
+        for access in &accesses {
+            for watchpoint in self.watchpoints.iter().filter(|watchpoint| watchpoint.matches(access)) {
+                println!("Watchpoint {}: {} by {:08X}", watchpoint.id, access, address);
+                hit = true;
+            }
+        }
+        hit
+    }
+
+    fn at_breakpoint(&self, machine: &mut Machine) -> bool {
+        let address = pc(machine);
+        let hit = self.breakpoints.iter()
+            .find(|breakpoint| breakpoint.address == address && breakpoint.condition.is_none_or(|condition| evaluate(machine, condition)));
+        match hit {
+            Some(breakpoint) => {
+                println!("Breakpoint {}: {}", breakpoint.id, breakpoint.text);
+                true
+            },
+            None => false
+        }
+    }
+
+    fn set_breakpoint(&mut self, arguments: &[&str], line: &str) -> Result<(), String> {
+        let address = match arguments.first() {
+            Some(address) => parse_value(address)? & 0x03fffffc,
+            None => {
+                for breakpoint in &self.breakpoints {
+                    println!("{:>3}  {}", breakpoint.id, breakpoint.text);
+                }
+                return Ok(());
+            }
+        };
+        let condition = match arguments.get(1) {
+            Some(&"if") => Some(parse_condition(&arguments[2..])?),
+            Some(word) => return Err(format!("Expected if, not {}", word)),
+            None => None
+        };
+
+        let text = line.split_once(char::is_whitespace).map_or("", |(_, text)| text).trim().to_owned();
+        println!("Breakpoint {} at {:08X}", self.next_id, address);
+        self.breakpoints.push(Breakpoint { id: self.next_id, address, condition, text });
+        self.next_id += 1;
+        Ok(())
+    }
+
+    fn set_watchpoint(&mut self, arguments: &[&str]) -> Result<(), String> {
+        let range = match arguments.first() {
+            Some(range) => range,
+            None => {
+                for watchpoint in &self.watchpoints {
+                    let access = match (watchpoint.reads, watchpoint.writes) {
+                        (true, true) => "rw",
+                        (true, false) => "r",
+                        _ => "w"
+                    };
+                    println!("{:>3}  {:08X}-{:08X} {}", watchpoint.id, watchpoint.start, watchpoint.end, access);
+                }
+                return Ok(());
+            }
+        };
+
+        let mut ends = range.splitn(2, '-');
+        let start = parse_value(ends.next().unwrap_or(""))?;
+        let end = match ends.next() {
+            Some(end) => parse_value(end)?,
+            None => start + 3
+        };
+        let (reads, writes) = match arguments.get(1).copied() {
+            None | Some("rw") => (true, true),
+            Some("r") => (true, false),
+            Some("w") => (false, true),
+            Some(access) => return Err(format!("Expected r, w or rw, not {}", access))
+        };
+
+        println!("Watchpoint {} on {:08X}-{:08X}", self.next_id, start, end);
+        self.watchpoints.push(Watchpoint { id: self.next_id, start, end, reads, writes });
+        self.next_id += 1;
+        Ok(())
+    }
+
+    fn delete(&mut self, arguments: &[&str]) -> Result<(), String> {
+        let id = arguments.first().ok_or("Delete which?")?;
+        let id: u32 = id.parse().map_err(|_| format!("Bad id {}", id))?;
+
+        let before = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.watchpoints.retain(|watchpoint| watchpoint.id != id);
+        if self.breakpoints.len() + self.watchpoints.len() == before {
+            return Err(format!("No breakpoint or watchpoint {}", id));
+        }
+        Ok(())
+    }
+
+    fn show_next(&self, machine: &mut Machine) {
+        let address = pc(machine);
+        println!("{}", disassembly_line(machine, address, true));
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+// The address of the instruction to be executed next
+fn pc(machine: &mut Machine) -> u32 {
+    machine.cpu.registers.reg_no_flags(15).wrapping_sub(8)
+}
+
+// Hex, with or without & or 0x
+fn parse_value(text: &str) -> Result<u32, String> {
+    let digits = text.trim_start_matches('&').trim_start_matches("0x");
+    u32::from_str_radix(digits, 16).map_err(|_| format!("Bad value {}", text))
+}
+
+// r0-r15, sp, lr, pc or psr, with an optional _mode for a bank
+fn parse_register(text: &str) -> Result<(Option<Mode>, u32), String> {
+    let text = text.to_lowercase();
+    let (name, mode) = match text.rfind('_') {
+        Some(underscore) => {
+            let mode = Mode::from_name(&text[underscore + 1..]).ok_or_else(|| format!("Unknown mode in {}", text))?;
+            (&text[..underscore], Some(mode))
+        },
+        None => (text.as_str(), None)
+    };
+
+    let reg = match name {
+        "sp" => 13,
+        "lr" => 14,
+        // Not registers of their own, but views of R15 handled by the callers
+        "pc" => 16,
+        "psr" => 17,
+        _ => name.strip_prefix('r').and_then(|number| number.parse().ok()).filter(|&reg| reg < 16)
+            .ok_or_else(|| format!("Unknown register {}", name))?
+    };
+    Ok((mode, reg))
+}
+
+fn read_register(machine: &mut Machine, mode: Option<Mode>, reg: u32) -> u32 {
+    let registers = &mut machine.cpu.registers;
+    match reg {
+        16 => registers.reg_no_flags(15).wrapping_sub(8),
+        17 => registers.reg(15) & !0x03fffffc,
+        _ => {
+            let mode = mode.unwrap_or_else(|| registers.mode());
+            registers.slots()[RegisterFile::slot(mode, reg)]
+        }
+    }
+}
+
+fn parse_condition(words: &[&str]) -> Result<Condition, String> {
+    let text: String = words.concat();
+    let operators = [
+        ("==", Comparison::Equal), ("!=", Comparison::NotEqual), ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual), ("<", Comparison::Less), (">", Comparison::Greater), ("=", Comparison::Equal),
+    ];
+    let (index, operator, comparison) = operators.iter()
+        .find_map(|&(operator, comparison)| text.find(operator).map(|index| (index, operator, comparison)))
+        .ok_or_else(|| format!("No comparison in {}", text))?;
+
+    let left = &text[..index];
+    let operand = if left.starts_with('[') && left.ends_with(']') {
+        Operand::Memory(parse_value(&left[1..left.len() - 1])?)
+    } else {
+        let (mode, reg) = parse_register(left)?;
+        Operand::Register(mode, reg)
+    };
+    Ok(Condition { operand, comparison, value: parse_value(&text[index + operator.len()..])? })
+}
+
+fn evaluate(machine: &mut Machine, condition: Condition) -> bool {
+    let value = match condition.operand {
+        Operand::Register(mode, reg) => read_register(machine, mode, reg),
+        Operand::Memory(address) => match machine.memory().peek(address) {
+            Some(value) => value,
+            None => return false
+        }
+    };
+
+    match condition.comparison {
+        Comparison::Equal => value == condition.value,
+        Comparison::NotEqual => value != condition.value,
+        Comparison::Less => value < condition.value,
+        Comparison::LessOrEqual => value <= condition.value,
+        Comparison::Greater => value > condition.value,
+        Comparison::GreaterOrEqual => value >= condition.value,
+    }
+}
+
+fn show_registers(machine: &mut Machine, all: bool) {
+    let registers = &mut machine.cpu.registers;
+    let mode = registers.mode();
+    let r15 = registers.reg(15);
+
+    for row in 0..4 {
+        let line: Vec<String> = (row * 4..row * 4 + 4).map(|reg| format!("R{:<2}={:08X}", reg, registers.reg(reg))).collect();
+        println!("{}", line.join("  "));
+    }
+    let flags: String = FLAG_NAMES.iter()
+        .map(|&(bit, name)| if r15 & (1 << bit) != 0 { name } else { name.to_ascii_lowercase() })
+        .collect();
+    println!("PC ={:08X}  {}  {}", registers.reg_no_flags(15).wrapping_sub(8), flags, MODE_NAMES[mode as usize]);
+
+    if all {
+        let banks = [(Mode::User, 8), (Mode::Firq, 8), (Mode::Irq, 13), (Mode::Svc, 13)];
+        for &(mode, first) in banks.iter() {
+            let line: Vec<String> = (first..15)
+                .map(|reg| format!("R{:<2}={:08X}", reg, registers.slots()[RegisterFile::slot(mode, reg)]))
+                .collect();
+            println!("{}  {}", MODE_NAMES[mode as usize], line.join("  "));
+        }
+    }
+}
+
+fn set_register(machine: &mut Machine, arguments: &[&str]) -> Result<(), String> {
+    if arguments.len() != 2 {
+        return Err("set register value".to_owned());
+    }
+    let (mode, reg) = parse_register(arguments[0])?;
+    let value = parse_value(arguments[1])?;
+
+    let registers = &mut machine.cpu.registers;
+    match reg {
+        16 => registers.set_reg_no_flags(15, value.wrapping_add(8)),
+        17 => {
+            let r15 = registers.reg(15);
+            registers.set_reg(15, (r15 & 0x03fffffc) | (value & !0x03fffffc));
+        },
+        _ => {
+            let mode = mode.unwrap_or_else(|| registers.mode());
+            registers.slots()[RegisterFile::slot(mode, reg)] = value;
+        }
+    }
+    Ok(())
+}
+
+fn byte_at(machine: &mut Machine, address: u32) -> Option<u8> {
+    machine.memory().peek(address).map(|word| (word >> ((address & 3) * 8)) as u8)
+}
+
+fn dump(machine: &mut Machine, arguments: &[&str]) -> Result<(), String> {
+    let start = parse_value(arguments.first().ok_or("Dump where?")?)?;
+    let length = match arguments.get(1) {
+        Some(length) => parse_value(length)?,
+        None => DEFAULT_DUMP_BYTES
+    };
+
+    for line in (start & !0xf..start.saturating_add(length)).step_by(16) {
+        let bytes: Vec<Option<u8>> = (0..16).map(|offset| byte_at(machine, line.wrapping_add(offset))).collect();
+        let hex: Vec<String> = bytes.iter()
+            .map(|byte| byte.map_or("??".to_owned(), |byte| format!("{:02X}", byte)))
+            .collect();
+        let text: String = bytes.iter()
+            .map(|byte| match *byte {
+                Some(byte) if (0x20..0x7f).contains(&byte) => byte as char,
+                _ => '.'
+            })
+            .collect();
+        println!("{:08X}  {}  {}", line, hex.join(" "), text);
+    }
+    Ok(())
+}
+
+fn poke(machine: &mut Machine, arguments: &[&str], byte: bool) -> Result<(), String> {
+    if arguments.len() != 2 {
+        return Err("poke address value".to_owned());
+    }
+    let address = parse_value(arguments[0])?;
+    let value = parse_value(arguments[1])?;
+
+    // Straight into RAM without a bus cycle. A word goes to the word the
+    // address is in, as ARM2 would store it.
+    let bytes: Vec<(u32, u8)> = if byte {
+        vec![(address, value as u8)]
+    } else {
+        (0..4).map(|i| ((address & !3) + i, (value >> (i * 8)) as u8)).collect()
+    };
+    for (address, byte) in bytes {
+        if !machine.memory().poke_byte(address, byte) {
+            return Err(format!("{:08X} isn't RAM", address));
+        }
+    }
+    Ok(())
+}
+
+fn show_trace(machine: &mut Machine, arguments: &[&str]) -> Result<(), String> {
+    let count = match arguments.first() {
+        Some(count) => count.parse().map_err(|_| format!("Bad count {}", count))?,
+        None => DEFAULT_TRACE_RECORDS
+    };
+
+    let records: Vec<_> = machine.cpu.trace.records().collect();
+    if records.is_empty() {
+        return Err("Nothing in the trace ring, which --trace=ring:<records> keeps".to_owned());
+    }
+    for record in &records[records.len().saturating_sub(count)..] {
+        println!("{}", record);
+    }
+    Ok(())
+}
+
+fn snapshot(machine: &mut Machine, arguments: &[&str], load: bool) -> Result<(), String> {
+    let path = arguments.first().ok_or("Which file?")?;
+    if load {
+        machine.load_snapshot(path).map_err(|error| format!("Couldn't load snapshot: {}", error))?;
+        println!("Restored from {}", path);
+    } else {
+        machine.save_snapshot(path).map_err(|error| format!("Couldn't save snapshot: {}", error))?;
+        println!("Saved to {}", path);
+    }
+    Ok(())
+}
+
+fn disassembly_line(machine: &mut Machine, address: u32, current: bool) -> String {
+    let marker = if current { ">" } else { " " };
+    match machine.memory().peek(address) {
+        Some(word) => format!("{} {:08X}  {:08X}  {}", marker, address, word, disassembler::disassemble(address, word)),
+        None => format!("{} {:08X}  ????????", marker, address)
+    }
+}
+
+fn disassemble(machine: &mut Machine, arguments: &[&str]) -> Result<(), String> {
+    let pc = pc(machine);
+    let (start, count) = match arguments.first() {
+        Some(address) => {
+            let count = match arguments.get(1) {
+                Some(count) => count.parse().map_err(|_| format!("Bad count {}", count))?,
+                None => DISASSEMBLY_BEFORE + DISASSEMBLY_AFTER
+            };
+            (parse_value(address)? & 0x03fffffc, count)
+        },
+        None => (pc.wrapping_sub(DISASSEMBLY_BEFORE * 4), DISASSEMBLY_BEFORE + DISASSEMBLY_AFTER)
+    };
+
+    for i in 0..count {
+        let address = start.wrapping_add(i * 4);
+        println!("{}", disassembly_line(machine, address, address == pc));
+    }
+    Ok(())
+}
+
+// The name APCS compilers leave in the word before a function's entry: the
+// name, padded to a word, before a word of &FF000000 plus its length
+fn function_name(machine: &mut Machine, entry: u32) -> Option<String> {
+    let marker = machine.memory().peek(entry.wrapping_sub(4))?;
+    if marker & 0xffffff00 != 0xff000000 {
+        return None;
+    }
+    let length = marker & 0xff;
+    let start = entry.wrapping_sub(4).wrapping_sub(length);
+    let name: String = (start..start + length)
+        .map_while(|address| byte_at(machine, address))
+        .take_while(|&byte| byte != 0)
+        .map(|byte| byte as char)
+        .collect();
+    Some(name)
+}
+
+// PC, then R14, then the return addresses saved in APCS stack frames, where
+// the frame pointer (R11) points at the saved PC with the saved R14 below it
+// and the caller's frame pointer below that
+fn backtrace(machine: &mut Machine) {
+    let pc = pc(machine);
+    println!("#0  {:08X}", pc);
+    println!("#1  {:08X}  (R14)", machine.cpu.registers.reg(14) & 0x03fffffc);
+
+    let mut fp = machine.cpu.registers.reg(11);
+    for frame in 2..MAX_BACKTRACE_FRAMES {
+        if fp == 0 {
+            break;
+        }
+        let memory = machine.memory();
+        let (saved_pc, saved_lr, saved_fp) = match (memory.peek(fp), memory.peek(fp.wrapping_sub(4)), memory.peek(fp.wrapping_sub(12))) {
+            (Some(saved_pc), Some(saved_lr), Some(saved_fp)) => (saved_pc, saved_lr, saved_fp),
+            _ => break
+        };
+
+        // STMFD stores PC 12 bytes past itself, and it's the first
+        // instruction of the function
+        let entry = (saved_pc & 0x03fffffc).wrapping_sub(12);
+        match function_name(machine, entry) {
+            Some(name) => println!("#{:<2} {:08X}  in {}", frame, saved_lr & 0x03fffffc, name),
+            None => println!("#{:<2} {:08X}  in function at {:08X}", frame, saved_lr & 0x03fffffc, entry)
+        }
+
+        if saved_fp == fp {
+            break;
+        }
+        fp = saved_fp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webarc::machine::Model;
+
+    fn machine() -> Machine {
+        Machine::new(Model::A3000, vec![0; 0x80000].into_boxed_slice())
+    }
+
+    #[test]
+    fn pokes() {
+        let mut machine = machine();
+        poke(&mut machine, &["2000102", "12345678"], false).unwrap();
+        assert_eq!(machine.memory().peek(0x02000100), Some(0x12345678));
+        poke(&mut machine, &["2000101", "ab"], true).unwrap();
+        assert_eq!(machine.memory().peek(0x02000100), Some(0x1234ab78));
+
+        // ROM and I/O are left alone
+        assert!(poke(&mut machine, &["3800000", "1"], false).is_err());
+        assert!(poke(&mut machine, &["3800003", "1"], true).is_err());
+        assert!(poke(&mut machine, &["3200000", "1"], true).is_err());
+        assert_eq!(machine.memory().peek(0x03800000), Some(0));
+        assert!(poke(&mut machine, &["2000000"], false).is_err());
+    }
+
+    #[test]
+    fn dumps_to_the_top_of_memory() {
+        let mut machine = machine();
+        dump(&mut machine, &["fffffff8", "100"]).unwrap();
+        dump(&mut machine, &["ffffffff"]).unwrap();
+        assert_eq!(byte_at(&mut machine, 0xffffffff), Some(0));
+        assert!(dump(&mut machine, &[]).is_err());
+    }
+}
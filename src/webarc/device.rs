@@ -0,0 +1,88 @@
+use std::any::Any;
+use std::io;
+use std::ops::BitOrAssign;
+use webarc::snapshot::{StateReader, StateWriter};
+
+// A chip or card on the bus. The memory system decodes addresses and hands
+// each device the accesses meant for it: the whole I/O address for devices in
+// an IOC bank (which mask out the register bits they decode themselves), or
+// the port number for PC-style devices. Devices are advanced by CPU cycles and
+// report the IOC interrupt inputs they're driving, since every peripheral on
+// the Archimedes is wired to a fixed IOC input.
+//
+// Devices are only advanced when something happens to them: when they're
+// accessed, or when the event they asked for falls due. Between times they
+// sit idle, so the cycles passed to tick can be many at once.
+
+pub trait Device: AsAny {
+    fn load(&mut self, _address: u32) -> u32 {
+        0
+    }
+
+    fn store(&mut self, _address: u32, _data: u32) {}
+
+    fn tick(&mut self, _cycles: u32) {}
+
+    // Cycles until the device next has something to do of its own accord,
+    // such as finishing a command or raising an interrupt. Devices that only
+    // change when they're accessed have nothing to wait for.
+    fn next_event(&self) -> Option<u32> {
+        None
+    }
+
+    // IRQ A inputs in IOC's latched set are edges, so a device pulsing one
+    // reports it once. Everything else is a level.
+    fn interrupts(&mut self) -> Interrupts {
+        Interrupts::NONE
+    }
+
+    // Write the device's state to a snapshot, and read it back. Devices with
+    // nothing of their own to keep save nothing.
+    fn save(&self, _state: &mut StateWriter) {}
+
+    fn restore(&mut self, _state: &mut StateReader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Interrupts {
+    pub irq_a: u8,
+    pub irq_b: u8,
+    pub fiq: u8,
+}
+
+impl Interrupts {
+    pub const NONE: Interrupts = Interrupts { irq_a: 0, irq_b: 0, fiq: 0 };
+
+    pub fn irq_a(bits: u8, level: bool) -> Interrupts {
+        Interrupts { irq_a: if level { bits } else { 0 }, ..Interrupts::NONE }
+    }
+
+    pub fn irq_b(bits: u8, level: bool) -> Interrupts {
+        Interrupts { irq_b: if level { bits } else { 0 }, ..Interrupts::NONE }
+    }
+
+    pub fn fiq(bits: u8, level: bool) -> Interrupts {
+        Interrupts { fiq: if level { bits } else { 0 }, ..Interrupts::NONE }
+    }
+}
+
+impl BitOrAssign for Interrupts {
+    fn bitor_assign(&mut self, other: Interrupts) {
+        self.irq_a |= other.irq_a;
+        self.irq_b |= other.irq_b;
+        self.fiq |= other.fiq;
+    }
+}
+
+// Lets the machine reach a particular device on the bus by its type
+pub trait AsAny {
+    fn as_any(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,292 @@
+use webarc::swis;
+
+// Turns instruction words back into assembly language, in the syntax of
+// Acorn's assemblers: condition codes before the other suffixes, & for hex,
+// SP, LR and PC for the registers that have special uses, and stack names for
+// LDM and STM on SP. Covers everything ARM2 and ARM3 can execute, including
+// ARM3's SWP and the coprocessor instructions. Words that aren't instructions
+// come out as DCD.
+
+const CONDITIONS: [&str; 16] = ["EQ", "NE", "CS", "CC", "MI", "PL", "VS", "VC", "HI", "LS", "GE", "LT", "GT", "LE", "", "NV"];
+
+const REGISTERS: [&str; 16] = ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7", "R8", "R9", "R10", "R11", "R12", "SP", "LR", "PC"];
+
+const ALU_OPS: [&str; 16] = [
+    "AND", "EOR", "SUB", "RSB", "ADD", "ADC", "SBC", "RSC",
+    "TST", "TEQ", "CMP", "CMN", "ORR", "MOV", "BIC", "MVN"
+];
+
+const SHIFTS: [&str; 4] = ["LSL", "LSR", "ASR", "ROR"];
+
+const OP_SUB: u32 = 0x2;
+const OP_ADD: u32 = 0x4;
+const OP_TST: u32 = 0x8;
+const OP_CMN: u32 = 0xb;
+const OP_MOV: u32 = 0xd;
+const OP_MVN: u32 = 0xf;
+
+// An instruction at an address, which PC-relative operands are shown from
+pub fn disassemble(address: u32, word: u32) -> String {
+    let cond = CONDITIONS[(word >> 28) as usize];
+
+    match (word >> 25) & 7 {
+        0b000 if word & 0x90 == 0x90 => multiply_or_swap(cond, word),
+        0b000 | 0b001 => data_processing(address, cond, word),
+        0b011 if word & 0x10 != 0 => undefined(word),
+        0b010 | 0b011 => single_transfer(address, cond, word),
+        0b100 => block_transfer(cond, word),
+        0b101 => branch(address, cond, word),
+        0b110 => coprocessor_transfer(address, cond, word),
+        _ if word & (1 << 24) != 0 => swi(cond, word),
+        _ if word & 0x10 != 0 => coprocessor_register(cond, word),
+        _ => coprocessor_data(cond, word)
+    }
+}
+
+// The mnemonic in a column of its own
+fn instruction(mnemonic: String, operands: String) -> String {
+    format!("{:<7} {}", mnemonic, operands)
+}
+
+fn reg(word: u32, shift: u32) -> &'static str {
+    REGISTERS[((word >> shift) & 0xf) as usize]
+}
+
+fn bit(word: u32, bit: u32) -> bool {
+    word & (1 << bit) != 0
+}
+
+fn suffix(set: bool, suffix: &'static str) -> &'static str {
+    if set { suffix } else { "" }
+}
+
+fn number(value: u32) -> String {
+    if value < 10 { format!("{}", value) } else { format!("&{:X}", value) }
+}
+
+fn immediate(value: u32) -> String {
+    format!("#{}", number(value))
+}
+
+fn offset(up: bool, value: u32) -> String {
+    format!("#{}{}", suffix(!up, "-"), number(value))
+}
+
+fn address(value: u32) -> String {
+    format!("&{:08X}", value)
+}
+
+fn undefined(word: u32) -> String {
+    instruction("DCD".to_owned(), address(word))
+}
+
+// Rm, with the shift in bits 4-11
+fn shifted_register(word: u32) -> String {
+    let rm = reg(word, 0);
+    let shift = SHIFTS[((word >> 5) & 3) as usize];
+
+    if bit(word, 4) {
+        return format!("{},{} {}", rm, shift, reg(word, 8));
+    }
+    match ((word >> 5) & 3, (word >> 7) & 0x1f) {
+        (0, 0) => rm.to_owned(),
+        // Shifts of 32 are encoded as 0, which ROR uses for RRX
+        (1, 0) | (2, 0) => format!("{},{} #32", rm, shift),
+        (3, 0) => format!("{},RRX", rm),
+        (_, amount) => format!("{},{} #{}", rm, shift, amount)
+    }
+}
+
+fn data_processing(address: u32, cond: &str, word: u32) -> String {
+    let opcode = (word >> 21) & 0xf;
+    let set_flags = bit(word, 20);
+    let operand = if bit(word, 25) {
+        immediate((word & 0xff).rotate_right(((word >> 8) & 0xf) * 2))
+    } else {
+        shifted_register(word)
+    };
+    let mnemonic = format!("{}{}", ALU_OPS[opcode as usize], cond);
+
+    match opcode {
+        // Comparisons always set the flags. Without S, they're the PSR
+        // transfers of later ARMs.
+        OP_TST..=OP_CMN => {
+            if !set_flags {
+                return undefined(word);
+            }
+            // With Rd as PC, the result goes to the PSR bits of R15
+            let p = suffix((word >> 12) & 0xf == 15, "P");
+            instruction(format!("{}{}", mnemonic, p), format!("{},{}", reg(word, 16), operand))
+        },
+        OP_MOV | OP_MVN => instruction(format!("{}{}", mnemonic, suffix(set_flags, "S")), format!("{},{}", reg(word, 12), operand)),
+        // Adding to or subtracting from PC makes an address
+        OP_ADD | OP_SUB if bit(word, 25) && !set_flags && (word >> 16) & 0xf == 15 => {
+            let value = (word & 0xff).rotate_right(((word >> 8) & 0xf) * 2);
+            let pc = address.wrapping_add(8);
+            let target = if opcode == OP_ADD { pc.wrapping_add(value) } else { pc.wrapping_sub(value) };
+            instruction(format!("ADR{}", cond), format!("{},{}", reg(word, 12), self::address(target)))
+        },
+        _ => instruction(format!("{}{}", mnemonic, suffix(set_flags, "S")), format!("{},{},{}", reg(word, 12), reg(word, 16), operand))
+    }
+}
+
+fn multiply_or_swap(cond: &str, word: u32) -> String {
+    if word & 0x0fc000f0 == 0x00000090 {
+        let mnemonic = format!("{}{}{}", if bit(word, 21) { "MLA" } else { "MUL" }, cond, suffix(bit(word, 20), "S"));
+        let operands = format!("{},{},{}", reg(word, 16), reg(word, 0), reg(word, 8));
+        if bit(word, 21) {
+            instruction(mnemonic, format!("{},{}", operands, reg(word, 12)))
+        } else {
+            instruction(mnemonic, operands)
+        }
+    } else if word & 0x0fb00ff0 == 0x01000090 {
+        let mnemonic = format!("SWP{}{}", cond, suffix(bit(word, 22), "B"));
+        instruction(mnemonic, format!("{},{},[{}]", reg(word, 12), reg(word, 0), reg(word, 16)))
+    } else {
+        undefined(word)
+    }
+}
+
+fn single_transfer(address: u32, cond: &str, word: u32) -> String {
+    let pre_indexed = bit(word, 24);
+    let up = bit(word, 23);
+    let write_back = bit(word, 21);
+    let rn = (word >> 16) & 0xf;
+
+    // Post-indexing always writes back, so W asks for a user mode access
+    let mnemonic = format!(
+        "{}{}{}{}",
+        if bit(word, 20) { "LDR" } else { "STR" },
+        cond,
+        suffix(bit(word, 22), "B"),
+        suffix(!pre_indexed && write_back, "T")
+    );
+
+    let location = if bit(word, 25) {
+        let offset = format!("{}{}", suffix(!up, "-"), shifted_register(word));
+        if pre_indexed {
+            format!("[{},{}]{}", reg(word, 16), offset, suffix(write_back, "!"))
+        } else {
+            format!("[{}],{}", reg(word, 16), offset)
+        }
+    } else {
+        indexed(address, word, rn, word & 0xfff)
+    };
+    instruction(mnemonic, format!("{},{}", reg(word, 12), location))
+}
+
+// An immediate offset from a base register, which is shown as the address
+// itself when the base is PC
+fn indexed(address: u32, word: u32, rn: u32, offset: u32) -> String {
+    let pre_indexed = bit(word, 24);
+    let up = bit(word, 23);
+    let write_back = bit(word, 21);
+
+    if pre_indexed && !write_back && rn == 15 && (up || offset != 0) {
+        let pc = address.wrapping_add(8);
+        self::address(if up { pc.wrapping_add(offset) } else { pc.wrapping_sub(offset) })
+    } else if !pre_indexed {
+        format!("[{}],{}", REGISTERS[rn as usize], self::offset(up, offset))
+    } else if offset == 0 && up {
+        format!("[{}]{}", REGISTERS[rn as usize], suffix(write_back, "!"))
+    } else {
+        format!("[{},{}]{}", REGISTERS[rn as usize], self::offset(up, offset), suffix(write_back, "!"))
+    }
+}
+
+fn block_transfer(cond: &str, word: u32) -> String {
+    let load = bit(word, 20);
+    let rn = (word >> 16) & 0xf;
+
+    // Full or empty, ascending or descending, for stacks on SP
+    let mode = match (bit(word, 24), bit(word, 23)) {
+        (false, true) => if rn != 13 { "IA" } else if load { "FD" } else { "EA" },
+        (true, true) => if rn != 13 { "IB" } else if load { "ED" } else { "FA" },
+        (false, false) => if rn != 13 { "DA" } else if load { "FA" } else { "ED" },
+        (true, false) => if rn != 13 { "DB" } else if load { "EA" } else { "FD" },
+    };
+    let mnemonic = format!("{}{}{}", if load { "LDM" } else { "STM" }, cond, mode);
+
+    instruction(mnemonic, format!(
+        "{}{},{{{}}}{}",
+        reg(word, 16),
+        suffix(bit(word, 21), "!"),
+        register_list(word & 0xffff),
+        suffix(bit(word, 22), "^")
+    ))
+}
+
+// Runs of three or more registers are shown as ranges
+fn register_list(list: u32) -> String {
+    let mut registers = Vec::new();
+    let mut reg = 0;
+    while reg < 16 {
+        if list & (1 << reg) == 0 {
+            reg += 1;
+            continue;
+        }
+        let mut last = reg;
+        while last < 15 && list & (1 << (last + 1)) != 0 {
+            last += 1;
+        }
+        if last - reg >= 2 {
+            registers.push(format!("{}-{}", REGISTERS[reg], REGISTERS[last]));
+        } else {
+            registers.extend(REGISTERS[reg..=last].iter().map(|name| name.to_string()));
+        }
+        reg = last + 1;
+    }
+    registers.join(",")
+}
+
+fn branch(address: u32, cond: &str, word: u32) -> String {
+    let offset = ((word << 8) as i32 >> 6) as u32;
+    let target = address.wrapping_add(8).wrapping_add(offset) & 0x03ffffff;
+    instruction(format!("{}{}", if bit(word, 24) { "BL" } else { "B" }, cond), self::address(target))
+}
+
+fn swi(cond: &str, word: u32) -> String {
+    let comment = word & 0x00ffffff;
+    let operand = swis::name(comment).unwrap_or_else(|| format!("&{:X}", comment));
+    instruction(format!("SWI{}", cond), operand)
+}
+
+fn coprocessor_transfer(address: u32, cond: &str, word: u32) -> String {
+    let mnemonic = format!("{}{}{}", if bit(word, 20) { "LDC" } else { "STC" }, cond, suffix(bit(word, 22), "L"));
+    let location = indexed(address, word, (word >> 16) & 0xf, (word & 0xff) * 4);
+    instruction(mnemonic, format!("CP{},CR{},{}", (word >> 8) & 0xf, (word >> 12) & 0xf, location))
+}
+
+fn coprocessor_data(cond: &str, word: u32) -> String {
+    let operands = format!(
+        "CP{},{},CR{},CR{},CR{}{}",
+        (word >> 8) & 0xf,
+        (word >> 20) & 0xf,
+        (word >> 12) & 0xf,
+        (word >> 16) & 0xf,
+        word & 0xf,
+        coprocessor_info(word)
+    );
+    instruction(format!("CDP{}", cond), operands)
+}
+
+fn coprocessor_register(cond: &str, word: u32) -> String {
+    let operands = format!(
+        "CP{},{},{},CR{},CR{}{}",
+        (word >> 8) & 0xf,
+        (word >> 21) & 7,
+        reg(word, 12),
+        (word >> 16) & 0xf,
+        word & 0xf,
+        coprocessor_info(word)
+    );
+    instruction(format!("{}{}", if bit(word, 20) { "MRC" } else { "MCR" }, cond), operands)
+}
+
+// The coprocessor's own operation bits, left off when they're 0
+fn coprocessor_info(word: u32) -> String {
+    match (word >> 5) & 7 {
+        0 => String::new(),
+        info => format!(",{}", info)
+    }
+}
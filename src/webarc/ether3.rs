@@ -0,0 +1,471 @@
+use std::io;
+use webarc::net::NetworkBackend;
+use webarc::podule::{Access, Podule, RomPodule};
+use webarc::snapshot::{StateReader, StateWriter};
+use webarc::sound::CYCLES_PER_MICROSECOND;
+
+// Ether3-style network card: a SEEQ 8005 Ethernet controller with 64KB of
+// packet buffer, and the card's ROM (which holds the driver) paged into the
+// slow space like a ROM podule. The 8005's eight 16-bit registers are in the
+// fast space at offsets 0x00-0x1C. The host moves data in and out of the
+// packet buffer through the buffer window, and the chip transmits and receives
+// chains of packets laid out in it. Frames go to and from a NetworkBackend.
+
+const COMMAND_STATUS: u32 = 0;
+const CONFIG_1: u32 = 1;
+const CONFIG_2: u32 = 2;
+const RECEIVE_END: u32 = 3;
+const BUFFER_WINDOW: u32 = 4;
+const RECEIVE_POINTER: u32 = 5;
+const TRANSMIT_POINTER: u32 = 6;
+const DMA_ADDRESS: u32 = 7;
+
+// Command register, with the interrupt enables reading back in the status
+const INTERRUPT_ENABLES: u16 = 0x000f;
+const INTERRUPT_ACKNOWLEDGES: u16 = 0x00f0;
+const SET_RECEIVE_ON: u16 = 1 << 9;
+const SET_TRANSMIT_ON: u16 = 1 << 10;
+const SET_RECEIVE_OFF: u16 = 1 << 12;
+const SET_TRANSMIT_OFF: u16 = 1 << 13;
+const FIFO_READ: u16 = 1 << 14;
+const FIFO_WRITE: u16 = 1 << 15;
+
+// Status register
+const RECEIVE_INTERRUPT: u16 = 1 << 5;
+const TRANSMIT_INTERRUPT: u16 = 1 << 6;
+const RECEIVE_ON: u16 = 1 << 9;
+const TRANSMIT_ON: u16 = 1 << 10;
+const FIFO_FULL: u16 = 1 << 13;
+const FIFO_EMPTY: u16 = 1 << 14;
+const FIFO_DIRECTION: u16 = 1 << 15;
+
+// Configuration register 1: the buffer window select in bits 0-3, and the
+// address match mode in bits 14-15
+const WINDOW_TRANSMIT_END: u16 = 7;
+const WINDOW_LOCAL_BUFFER: u16 = 8;
+const MATCH_BROADCAST: u16 = 1;
+const MATCH_MULTICAST: u16 = 2;
+const MATCH_PROMISCUOUS: u16 = 3;
+
+const CONFIG_2_BYTE_SWAP: u16 = 1 << 0;
+const CONFIG_2_RESET: u16 = 1 << 15;
+
+// Packet headers: next header pointer (most significant byte first), then a
+// command byte and a status byte
+const HEADER_SIZE: usize = 4;
+const HEADER_TRANSMIT: u8 = 1 << 7;
+const HEADER_CHAIN_CONTINUE: u8 = 1 << 6;
+const HEADER_DATA_FOLLOWS: u8 = 1 << 5;
+const HEADER_TRANSMIT_SUCCESS_INTERRUPT: u8 = 1 << 3;
+const PACKET_DONE: u8 = 1 << 7;
+
+const BUFFER_SIZE: usize = 0x10000;
+const MINIMUM_FRAME: usize = 60;
+const MAXIMUM_FRAME: usize = 1514;
+
+const RECEIVE_POLL: u32 = 1000 * CYCLES_PER_MICROSECOND;
+
+pub struct Ether3 {
+    rom: RomPodule,
+    backend: Box<dyn NetworkBackend>,
+    buffer: Box<[u8]>,
+
+    status: u16,
+    config_1: u16,
+    config_2: u16,
+    station_address: [u8; 6],
+    transmit_end: u8,
+    receive_end: u8,
+    receive_pointer: u16,
+    transmit_pointer: u16,
+    dma_address: u16,
+
+    poll_countdown: u32,
+}
+
+impl Ether3 {
+    pub fn new(rom: Vec<u8>, station_address: [u8; 6], backend: Box<dyn NetworkBackend>) -> Ether3 {
+        let mut card = Ether3 {
+            rom: RomPodule::new(rom),
+            backend,
+            buffer: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            status: 0,
+            config_1: 0,
+            config_2: 0,
+            station_address,
+            transmit_end: 0,
+            receive_end: 0,
+            receive_pointer: 0,
+            transmit_pointer: 0,
+            dma_address: 0,
+            poll_countdown: RECEIVE_POLL,
+        };
+        card.reset();
+        card
+    }
+
+    fn reset(&mut self) {
+        self.status = FIFO_EMPTY;
+        self.config_1 = 0;
+        self.config_2 = 0;
+        self.transmit_end = 0;
+        self.receive_end = 0;
+        self.receive_pointer = 0;
+        self.transmit_pointer = 0;
+        self.dma_address = 0;
+    }
+
+    fn load_register(&mut self, register: u32) -> u16 {
+        match register {
+            COMMAND_STATUS => self.status,
+            CONFIG_1 => self.config_1,
+            CONFIG_2 => self.config_2,
+            BUFFER_WINDOW => self.read_window(),
+            RECEIVE_POINTER => self.receive_pointer,
+            TRANSMIT_POINTER => self.transmit_pointer,
+            DMA_ADDRESS => self.dma_address,
+            _ => 0
+        }
+    }
+
+    fn store_register(&mut self, register: u32, value: u16) {
+        match register {
+            COMMAND_STATUS => self.command(value),
+            CONFIG_1 => self.config_1 = value,
+            CONFIG_2 => {
+                if value & CONFIG_2_RESET != 0 {
+                    self.reset();
+                } else {
+                    self.config_2 = value;
+                }
+            },
+            RECEIVE_END => self.receive_end = (value >> 8) as u8,
+            BUFFER_WINDOW => self.write_window(value),
+            RECEIVE_POINTER => self.receive_pointer = value,
+            TRANSMIT_POINTER => self.transmit_pointer = value,
+            DMA_ADDRESS => self.dma_address = value,
+            _ => {}
+        }
+    }
+
+    fn command(&mut self, value: u16) {
+        self.status = (self.status & !INTERRUPT_ENABLES) | (value & INTERRUPT_ENABLES);
+        self.status &= !(value & INTERRUPT_ACKNOWLEDGES);
+
+        // Buffer window transfers happen instantly, so the FIFO is always
+        // ready in whichever direction was asked for
+        if value & FIFO_READ != 0 {
+            self.status = (self.status | FIFO_DIRECTION | FIFO_FULL) & !FIFO_EMPTY;
+        } else if value & FIFO_WRITE != 0 {
+            self.status = (self.status | FIFO_EMPTY) & !(FIFO_DIRECTION | FIFO_FULL);
+        }
+
+        if value & SET_RECEIVE_OFF != 0 {
+            self.status &= !RECEIVE_ON;
+        } else if value & SET_RECEIVE_ON != 0 {
+            self.status |= RECEIVE_ON;
+        }
+
+        if value & SET_TRANSMIT_OFF != 0 {
+            self.status &= !TRANSMIT_ON;
+        } else if value & SET_TRANSMIT_ON != 0 {
+            self.status |= TRANSMIT_ON;
+            self.transmit();
+        }
+    }
+
+    fn window(&self) -> u16 {
+        self.config_1 & 0xf
+    }
+
+    fn read_window(&mut self) -> u16 {
+        match self.window() {
+            window @ 0..=5 => self.station_address[window as usize] as u16,
+            WINDOW_TRANSMIT_END => self.transmit_end as u16,
+            WINDOW_LOCAL_BUFFER => {
+                let address = self.dma_address as usize;
+                let bytes = [self.buffer[address], self.buffer[(address + 1) % BUFFER_SIZE]];
+                self.dma_address = self.dma_address.wrapping_add(2);
+                self.halfword(bytes)
+            },
+            _ => 0
+        }
+    }
+
+    fn write_window(&mut self, value: u16) {
+        match self.window() {
+            window @ 0..=5 => self.station_address[window as usize] = value as u8,
+            WINDOW_TRANSMIT_END => self.transmit_end = value as u8,
+            WINDOW_LOCAL_BUFFER => {
+                let bytes = self.bytes(value);
+                let address = self.dma_address as usize;
+                self.buffer[address] = bytes[0];
+                self.buffer[(address + 1) % BUFFER_SIZE] = bytes[1];
+                self.dma_address = self.dma_address.wrapping_add(2);
+            },
+            _ => {}
+        }
+    }
+
+    fn halfword(&self, bytes: [u8; 2]) -> u16 {
+        if self.config_2 & CONFIG_2_BYTE_SWAP != 0 { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }
+    }
+
+    fn bytes(&self, value: u16) -> [u8; 2] {
+        if self.config_2 & CONFIG_2_BYTE_SWAP != 0 { value.to_be_bytes() } else { value.to_le_bytes() }
+    }
+
+    // Send the chain of packets starting at the transmit pointer
+    fn transmit(&mut self) {
+        let mut header = self.transmit_pointer as usize;
+
+        loop {
+            let next = ((self.buffer[header] as usize) << 8) | self.buffer[(header + 1) % BUFFER_SIZE] as usize;
+            let command = self.buffer[(header + 2) % BUFFER_SIZE];
+
+            if command & HEADER_TRANSMIT == 0 || command & HEADER_DATA_FOLLOWS == 0 {
+                break;
+            }
+
+            let start = header + HEADER_SIZE;
+            if next > start && next - start <= MAXIMUM_FRAME {
+                let frame = self.buffer[start..next].to_vec();
+                self.backend.send(&frame);
+            }
+            self.buffer[(header + 3) % BUFFER_SIZE] = PACKET_DONE;
+
+            if command & HEADER_TRANSMIT_SUCCESS_INTERRUPT != 0 {
+                self.status |= TRANSMIT_INTERRUPT;
+            }
+            if command & HEADER_CHAIN_CONTINUE == 0 || next == header {
+                break;
+            }
+            header = next;
+        }
+
+        self.transmit_pointer = header as u16;
+        self.status &= !TRANSMIT_ON;
+    }
+
+    fn accepts(&self, frame: &[u8]) -> bool {
+        let destination = &frame[0..6];
+        let broadcast = destination.iter().all(|byte| *byte == 0xff);
+        let multicast = destination[0] & 1 != 0;
+
+        match self.config_1 >> 14 {
+            MATCH_PROMISCUOUS => true,
+            MATCH_MULTICAST if multicast => true,
+            MATCH_BROADCAST if broadcast => true,
+            _ => destination == self.station_address
+        }
+    }
+
+    // The receive area runs from the end of the transmit area to the top of
+    // the buffer and wraps round. The host moves the receive end on as it
+    // takes packets out, and the chip won't write past it.
+    fn receive(&mut self, frame: &[u8]) {
+        if frame.len() < 14 || !self.accepts(frame) {
+            return;
+        }
+
+        let area_start = (self.transmit_end as usize + 1) << 8;
+        if area_start >= BUFFER_SIZE {
+            return;
+        }
+        let area_size = BUFFER_SIZE - area_start;
+        let wrap = |address: usize| if address >= BUFFER_SIZE { address - area_size } else { address };
+
+        let mut data = frame[..frame.len().min(MAXIMUM_FRAME)].to_vec();
+        data.resize(data.len().max(MINIMUM_FRAME), 0);
+
+        let header = (self.receive_pointer as usize).max(area_start);
+        let limit = (self.receive_end as usize) << 8;
+        let free = match (limit + area_size - header) % area_size {
+            0 => area_size,
+            free => free
+        };
+        // Room for this packet's header and data, and the next header
+        if HEADER_SIZE * 2 + data.len() > free {
+            return;
+        }
+
+        let next = wrap(header + HEADER_SIZE + data.len());
+        for (offset, byte) in data.iter().enumerate() {
+            self.buffer[wrap(header + HEADER_SIZE + offset)] = *byte;
+        }
+
+        // Terminate the chain at the next header before completing this one
+        for offset in 0..HEADER_SIZE {
+            self.buffer[wrap(next + offset)] = 0;
+        }
+
+        self.buffer[header] = (next >> 8) as u8;
+        self.buffer[wrap(header + 1)] = next as u8;
+        self.buffer[wrap(header + 2)] = HEADER_DATA_FOLLOWS | HEADER_CHAIN_CONTINUE;
+        self.buffer[wrap(header + 3)] = PACKET_DONE;
+
+        self.receive_pointer = next as u16;
+        self.status |= RECEIVE_INTERRUPT;
+    }
+}
+
+impl Podule for Ether3 {
+    fn load(&mut self, access: Access, offset: u32) -> u32 {
+        match access {
+            Access::Fast if offset < 0x20 => self.load_register(offset >> 2) as u32,
+            _ => self.rom.load(access, offset)
+        }
+    }
+
+    fn store(&mut self, access: Access, offset: u32, data: u32) {
+        match access {
+            Access::Fast if offset < 0x20 => self.store_register(offset >> 2, data as u16),
+            Access::Slow => self.rom.store(access, offset, data),
+            _ => {}
+        }
+    }
+
+    fn irq(&self) -> bool {
+        (self.status >> 4) & self.status & INTERRUPT_ENABLES != 0
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if cycles < self.poll_countdown {
+            self.poll_countdown -= cycles;
+            return;
+        }
+        self.poll_countdown = RECEIVE_POLL;
+
+        if self.status & RECEIVE_ON != 0 {
+            while let Some(frame) = self.backend.receive() {
+                self.receive(&frame);
+            }
+        }
+    }
+
+    // Checking for received frames
+    fn next_event(&self) -> Option<u32> {
+        Some(self.poll_countdown)
+    }
+
+    // Frames still on their way in from the backend aren't included
+    fn save(&self, state: &mut StateWriter) {
+        self.rom.save(state);
+        state.bytes(&self.buffer);
+        for &register in &[self.status, self.config_1, self.config_2, self.receive_pointer, self.transmit_pointer, self.dma_address] {
+            state.u16(register);
+        }
+        state.bytes(&self.station_address);
+        state.u8(self.transmit_end);
+        state.u8(self.receive_end);
+        state.u32(self.poll_countdown);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.rom.restore(state)?;
+        state.bytes_into(&mut self.buffer)?;
+        for register in [
+            &mut self.status, &mut self.config_1, &mut self.config_2,
+            &mut self.receive_pointer, &mut self.transmit_pointer, &mut self.dma_address
+        ] {
+            *register = state.u16()?;
+        }
+        state.bytes_into(&mut self.station_address)?;
+        self.transmit_end = state.u8()?;
+        self.receive_end = state.u8()?;
+        self.poll_countdown = state.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webarc::net::Loopback;
+
+    const STATION: [u8; 6] = [0x00, 0x00, 0xa4, 0x00, 0x00, 0x01];
+
+    fn write(card: &mut Ether3, register: u32, value: u16) {
+        card.store(Access::Fast, register << 2, value as u32);
+    }
+
+    fn read(card: &mut Ether3, register: u32) -> u16 {
+        card.load(Access::Fast, register << 2) as u16
+    }
+
+    // Copy bytes into the packet buffer through the window, two at a time
+    fn fill(card: &mut Ether3, address: u16, bytes: &[u8]) {
+        write(card, CONFIG_1, WINDOW_LOCAL_BUFFER);
+        write(card, COMMAND_STATUS, FIFO_WRITE);
+        write(card, DMA_ADDRESS, address);
+        for pair in bytes.chunks(2) {
+            write(card, BUFFER_WINDOW, u16::from_le_bytes([pair[0], *pair.get(1).unwrap_or(&0)]));
+        }
+    }
+
+    fn dump(card: &mut Ether3, address: u16, length: usize) -> Vec<u8> {
+        write(card, CONFIG_1, WINDOW_LOCAL_BUFFER);
+        write(card, COMMAND_STATUS, FIFO_READ);
+        write(card, DMA_ADDRESS, address);
+        (0..length.div_ceil(2)).flat_map(|_| read(card, BUFFER_WINDOW).to_le_bytes().to_vec()).take(length).collect()
+    }
+
+    fn frame(destination: [u8; 6]) -> Vec<u8> {
+        let mut frame = destination.to_vec();
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02, 0x08, 0x00]);
+        frame.extend((0..50).map(|index| index as u8));
+        frame
+    }
+
+    #[test]
+    fn loopback() {
+        let mut card = Ether3::new(Vec::new(), STATION, Box::new(Loopback::new()));
+
+        // Transmit area below 0x1000, receiving up to the top of the buffer
+        write(&mut card, CONFIG_1, WINDOW_TRANSMIT_END);
+        write(&mut card, BUFFER_WINDOW, 0x0f);
+        write(&mut card, RECEIVE_END, 0xff00);
+        write(&mut card, COMMAND_STATUS, 0x0006 | SET_RECEIVE_ON);
+        assert_eq!(read(&mut card, COMMAND_STATUS) & (RECEIVE_ON | INTERRUPT_ENABLES), RECEIVE_ON | 0x0006);
+
+        // Two packets, one for us and one for someone else
+        let ours = frame(STATION);
+        let theirs = frame([0x02, 0, 0, 0, 0, 0x03]);
+        let second = (HEADER_SIZE + ours.len()) as u16;
+        let end = second + (HEADER_SIZE + theirs.len()) as u16;
+        let command = HEADER_TRANSMIT | HEADER_DATA_FOLLOWS | HEADER_CHAIN_CONTINUE | HEADER_TRANSMIT_SUCCESS_INTERRUPT;
+        let mut packets = vec![(second >> 8) as u8, second as u8, command, 0];
+        packets.extend_from_slice(&ours);
+        packets.extend_from_slice(&[(end >> 8) as u8, end as u8, command & !HEADER_CHAIN_CONTINUE, 0]);
+        packets.extend_from_slice(&theirs);
+        fill(&mut card, 0, &packets);
+
+        write(&mut card, TRANSMIT_POINTER, 0);
+        write(&mut card, COMMAND_STATUS, 0x0006 | SET_TRANSMIT_ON);
+        let status = read(&mut card, COMMAND_STATUS);
+        assert_ne!(status & TRANSMIT_INTERRUPT, 0);
+        assert_eq!(status & TRANSMIT_ON, 0);
+        assert!(card.irq());
+        assert_eq!(read(&mut card, TRANSMIT_POINTER), second);
+        assert_eq!(dump(&mut card, 0, 4)[3], PACKET_DONE);
+        assert_eq!(dump(&mut card, second, 4)[3], PACKET_DONE);
+
+        // Acknowledging the interrupt clears it, and nothing arrives until the
+        // card next polls its backend
+        write(&mut card, COMMAND_STATUS, 0x0006 | TRANSMIT_INTERRUPT);
+        assert!(!card.irq());
+        card.tick(RECEIVE_POLL - 1);
+        assert!(!card.irq());
+        card.tick(1);
+        assert!(card.irq());
+        assert_ne!(read(&mut card, COMMAND_STATUS) & RECEIVE_INTERRUPT, 0);
+
+        // Only the frame addressed to this card is taken in
+        let next = 0x1000 + (HEADER_SIZE + ours.len()) as u16;
+        assert_eq!(read(&mut card, RECEIVE_POINTER), next);
+        let received = dump(&mut card, 0x1000, HEADER_SIZE + ours.len() + HEADER_SIZE);
+        assert_eq!(&received[..HEADER_SIZE], &[(next >> 8) as u8, next as u8, HEADER_DATA_FOLLOWS | HEADER_CHAIN_CONTINUE, PACKET_DONE]);
+        assert_eq!(&received[HEADER_SIZE..HEADER_SIZE + ours.len()], &ours[..]);
+        assert_eq!(&received[HEADER_SIZE + ours.len()..], &[0; HEADER_SIZE]);
+    }
+}
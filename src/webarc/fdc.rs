@@ -0,0 +1,899 @@
+use std::io;
+use webarc::device::{Device, Interrupts};
+use webarc::floppy::{Drive, FloppyDisc, SectorData, SectorId};
+use webarc::ioc::{FIQ_FLOPPY_DATA, FIQ_FLOPPY_INTERRUPT};
+use webarc::snapshot::{self, StateReader, StateWriter};
+use webarc::sound::CYCLES_PER_MICROSECOND;
+
+// WD1772 floppy disc controller, in IOC bank 1 at 0x3310000. Address bits 2-3
+// select the register. DRQ and INTRQ are wired to IOC FIQ inputs, and the
+// drive, side and density are chosen through latches A and B.
+
+const STATUS_COMMAND: u32 = 0x0;
+const TRACK: u32 = 0x4;
+const SECTOR: u32 = 0x8;
+const DATA: u32 = 0xc;
+
+const STATUS_BUSY: u8 = 1 << 0;
+const STATUS_INDEX: u8 = 1 << 1;
+const STATUS_DRQ: u8 = 1 << 1;
+const STATUS_TRACK0: u8 = 1 << 2;
+const STATUS_LOST_DATA: u8 = 1 << 2;
+const STATUS_CRC_ERROR: u8 = 1 << 3;
+const STATUS_SEEK_ERROR: u8 = 1 << 4;
+const STATUS_RECORD_NOT_FOUND: u8 = 1 << 4;
+const STATUS_SPUN_UP: u8 = 1 << 5;
+const STATUS_DELETED: u8 = 1 << 5;
+const STATUS_WRITE_PROTECT: u8 = 1 << 6;
+const STATUS_MOTOR_ON: u8 = 1 << 7;
+
+// Command flags
+const FLAG_UPDATE_TRACK: u8 = 1 << 4;
+const FLAG_MULTIPLE: u8 = 1 << 4;
+const FLAG_SPIN_UP_DISABLE: u8 = 1 << 3;
+const FLAG_VERIFY: u8 = 1 << 2;
+const FLAG_SETTLE: u8 = 1 << 2;
+const FLAG_DELETED_MARK: u8 = 1 << 0;
+const FLAG_IMMEDIATE_INTERRUPT: u8 = 1 << 3;
+
+const MS: u32 = 1000 * CYCLES_PER_MICROSECOND;
+
+// 300rpm, with a 4ms index pulse at the start of each revolution
+const REVOLUTION: u32 = 200 * MS;
+const INDEX_PULSE: u32 = 4 * MS;
+
+const STEP_RATES: [u32; 4] = [6 * MS, 12 * MS, 2 * MS, 3 * MS];
+const SETTLE_TIME: u32 = 30 * MS;
+const SPIN_UP_REVOLUTIONS: u32 = 6;
+const MOTOR_OFF_REVOLUTIONS: u32 = 10;
+const SEARCH_REVOLUTIONS: u32 = 5;
+
+// 250kbit/s MFM, 125kbit/s FM
+const BYTE_TIME_DOUBLE: u32 = 32 * CYCLES_PER_MICROSECOND;
+const BYTE_TIME_SINGLE: u32 = 64 * CYCLES_PER_MICROSECOND;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    SpinUp,
+    Stepping,
+    Verifying,
+    Settling,
+    Searching,
+    Retrying,
+    Reading,
+    Writing,
+    ReadingAddress,
+}
+
+const STATES: [State; 10] = [
+    State::Idle, State::SpinUp, State::Stepping, State::Verifying, State::Settling,
+    State::Searching, State::Retrying, State::Reading, State::Writing, State::ReadingAddress
+];
+
+pub struct Fdc {
+    command: u8,
+    status: u8,
+    track: u8,
+    sector: u8,
+    data: u8,
+
+    pub drives: [Drive; 4],
+    selected: Option<usize>,
+    side: u8,
+    double_density: bool,
+
+    state: State,
+    countdown: u32,
+    rotation: u32,
+    motor_on: bool,
+    idle_revolutions: u32,
+    search_revolutions: u32,
+    direction: i8,
+
+    buffer: Vec<u8>,
+    position: usize,
+    sector_index: usize,
+    sector_crc_error: bool,
+
+    drq: bool,
+    intrq: bool,
+}
+
+impl Fdc {
+    pub fn new() -> Fdc {
+        Fdc {
+            command: 0,
+            status: 0,
+            track: 0,
+            sector: 1,
+            data: 0,
+            drives: [Drive::new(), Drive::new(), Drive::new(), Drive::new()],
+            selected: None,
+            side: 0,
+            double_density: true,
+            state: State::Idle,
+            countdown: 0,
+            rotation: 0,
+            motor_on: false,
+            idle_revolutions: 0,
+            search_revolutions: 0,
+            direction: 1,
+            buffer: Vec::new(),
+            position: 0,
+            sector_index: 0,
+            sector_crc_error: false,
+            drq: false,
+            intrq: false,
+        }
+    }
+
+    // Whether there's a drive to put the disc in
+    pub fn insert(&mut self, drive: usize, disc: Box<dyn FloppyDisc>) -> bool {
+        match self.drives.get_mut(drive) {
+            Some(drive) => {
+                drive.disc = Some(disc);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn eject(&mut self, drive: usize) -> Option<Box<dyn FloppyDisc>> {
+        self.drives.get_mut(drive)?.disc.take()
+    }
+
+    // Drive select from latch A (active low, one bit per drive)
+    pub fn select(&mut self, drives: u8, side: u8) {
+        self.selected = (0..4).find(|drive| drives & (1 << drive) == 0);
+        self.side = side;
+    }
+
+    pub fn set_double_density(&mut self, double_density: bool) {
+        self.double_density = double_density;
+    }
+
+    pub fn reset(&mut self) {
+        self.state = State::Idle;
+        self.status = 0;
+        self.command = 0;
+        self.sector = 1;
+        self.drq = false;
+        self.intrq = false;
+    }
+
+    pub fn drq(&self) -> bool {
+        self.drq
+    }
+
+    pub fn intrq(&self) -> bool {
+        self.intrq
+    }
+
+    fn revolution(&mut self) {
+        if self.state == State::Idle {
+            self.idle_revolutions += 1;
+            if self.idle_revolutions >= MOTOR_OFF_REVOLUTIONS {
+                self.motor_on = false;
+                self.status &= !STATUS_MOTOR_ON;
+            }
+        } else {
+            self.search_revolutions += 1;
+        }
+    }
+
+    fn status_register(&self) -> u8 {
+        let mut status = self.status;
+
+        if self.motor_on {
+            status |= STATUS_MOTOR_ON;
+        }
+
+        if self.is_type_i() {
+            if self.drive().is_some_and(|drive| drive.track0()) {
+                status |= STATUS_TRACK0;
+            }
+            if self.drive().is_none_or(|drive| drive.write_protected()) {
+                status |= STATUS_WRITE_PROTECT;
+            }
+            if self.motor_on && self.rotation < INDEX_PULSE && self.disc_present() {
+                status |= STATUS_INDEX;
+            }
+        } else if self.drq {
+            status |= STATUS_DRQ;
+        }
+
+        status
+    }
+
+    fn is_type_i(&self) -> bool {
+        self.command & 0x80 == 0 || self.command & 0xf0 == 0xd0
+    }
+
+    fn drive(&self) -> Option<&Drive> {
+        self.selected.map(|drive| &self.drives[drive])
+    }
+
+    fn disc_present(&self) -> bool {
+        self.drive().is_some_and(|drive| drive.disc.is_some())
+    }
+
+    fn byte_time(&self) -> u32 {
+        if self.double_density { BYTE_TIME_DOUBLE } else { BYTE_TIME_SINGLE }
+    }
+
+    fn write_command(&mut self, command: u8) {
+        if command & 0xf0 == 0xd0 {
+            self.force_interrupt(command);
+            return;
+        }
+
+        if self.state != State::Idle {
+            return;
+        }
+
+        self.command = command;
+        self.intrq = false;
+        self.drq = false;
+        self.status = STATUS_BUSY;
+        self.idle_revolutions = 0;
+
+        if !self.motor_on && command & FLAG_SPIN_UP_DISABLE == 0 {
+            self.motor_on = true;
+            self.rotation = 0;
+            self.state = State::SpinUp;
+            self.countdown = SPIN_UP_REVOLUTIONS * REVOLUTION;
+        } else {
+            self.motor_on = true;
+            self.spun_up();
+            self.begin();
+        }
+    }
+
+    // Type II and III commands use the same status bit for the record type
+    fn spun_up(&mut self) {
+        if self.is_type_i() {
+            self.status |= STATUS_SPUN_UP;
+        }
+    }
+
+    fn force_interrupt(&mut self, command: u8) {
+        if self.state == State::Idle {
+            self.status = 0;
+        }
+
+        self.command = command;
+        self.state = State::Idle;
+        self.status &= !STATUS_BUSY;
+        self.drq = false;
+        self.intrq = command & FLAG_IMMEDIATE_INTERRUPT != 0;
+    }
+
+    fn begin(&mut self) {
+        let command = self.command;
+
+        match command >> 4 {
+            // Restore
+            0x0 => {
+                self.track = 0xff;
+                self.data = 0;
+                self.start_stepping();
+            },
+
+            // Seek
+            0x1 => self.start_stepping(),
+
+            // Step, step in, step out
+            0x2..=0x7 => {
+                if command & 0x40 != 0 {
+                    self.direction = if command & 0x20 != 0 { -1 } else { 1 };
+                }
+                self.step();
+                self.state = State::Stepping;
+                self.countdown = STEP_RATES[(command & 3) as usize];
+            },
+
+            // Read sector, write sector
+            0x8..=0xb => {
+                if command & 0x20 != 0 && self.drive().is_none_or(|drive| drive.write_protected()) {
+                    self.finish(STATUS_WRITE_PROTECT);
+                } else if command & FLAG_SETTLE != 0 {
+                    self.state = State::Settling;
+                    self.countdown = SETTLE_TIME;
+                } else {
+                    self.start_search();
+                }
+            },
+
+            // Read address
+            0xc => {
+                if command & FLAG_SETTLE != 0 {
+                    self.state = State::Settling;
+                    self.countdown = SETTLE_TIME;
+                } else {
+                    self.start_search();
+                }
+            },
+
+            // Read track and write track aren't emulated
+            _ => self.finish(0)
+        }
+    }
+
+    fn advance(&mut self) {
+        match self.state {
+            State::Idle => {},
+            State::SpinUp => {
+                self.spun_up();
+                self.begin();
+            },
+            State::Stepping => self.continue_stepping(),
+            State::Verifying => self.verify(),
+            State::Settling => self.start_search(),
+            State::Searching => self.found_sector(),
+            State::Retrying => self.search(),
+            State::Reading => self.read_byte(),
+            State::Writing => self.write_byte(),
+            State::ReadingAddress => self.read_address_byte(),
+        }
+    }
+
+    // Type I commands
+
+    fn step(&mut self) {
+        let direction = self.direction;
+        if let Some(drive) = self.selected {
+            self.drives[drive].step(direction);
+        }
+
+        let update_track = self.command & 0xe0 == 0 || self.command & FLAG_UPDATE_TRACK != 0;
+        if update_track {
+            self.track = (self.track as i16 + direction as i16) as u8;
+        }
+    }
+
+    fn start_stepping(&mut self) {
+        self.state = State::Stepping;
+        self.countdown = 0;
+        self.search_revolutions = 0;
+        self.continue_stepping();
+    }
+
+    fn continue_stepping(&mut self) {
+        let seeking = self.command & 0xe0 == 0;
+        let restoring = self.command & 0xf0 == 0;
+
+        if seeking {
+            let at_track0 = self.drive().is_some_and(|drive| drive.track0());
+
+            if restoring && at_track0 {
+                self.track = 0;
+            } else if restoring && self.track == self.data {
+                // 255 steps without seeing track 0, so there's no drive there
+                // or it's broken
+                return self.finish(STATUS_SEEK_ERROR);
+            } else if self.track != self.data {
+                self.direction = if self.data > self.track { 1 } else { -1 };
+                self.step();
+                self.countdown = STEP_RATES[(self.command & 3) as usize];
+                return;
+            }
+        }
+
+        if self.command & FLAG_VERIFY != 0 {
+            self.state = State::Verifying;
+            self.countdown = SETTLE_TIME;
+        } else {
+            self.finish(0);
+        }
+    }
+
+    fn verify(&mut self) {
+        let track = self.track;
+        let found = self.current_ids().iter().any(|id| id.track == track && !id.crc_error);
+
+        if found {
+            self.finish(0);
+        } else if self.search_revolutions >= SEARCH_REVOLUTIONS {
+            self.finish(STATUS_SEEK_ERROR);
+        } else {
+            self.countdown = REVOLUTION;
+        }
+    }
+
+    // Type II and III commands
+
+    fn current_ids(&self) -> Vec<SectorId> {
+        match self.selected {
+            Some(drive) => {
+                let drive = &self.drives[drive];
+                match drive.disc {
+                    Some(ref disc) => disc.sector_ids(drive.cylinder, self.side, self.double_density),
+                    None => Vec::new()
+                }
+            },
+            None => Vec::new()
+        }
+    }
+
+    fn start_search(&mut self) {
+        self.search_revolutions = 0;
+        self.search();
+    }
+
+    // Wait for the next ID that the current command wants to pass under the head
+    fn search(&mut self) {
+        let ids = self.current_ids();
+        let reading_address = self.command & 0xf0 == 0xc0;
+        let (track, sector) = (self.track, self.sector);
+
+        let mut best: Option<(usize, u32)> = None;
+        for (index, id) in ids.iter().enumerate() {
+            if reading_address || (id.track == track && id.sector == sector) {
+                let position = (index as u64 * REVOLUTION as u64 / ids.len() as u64) as u32;
+                let wait = (position + REVOLUTION - self.rotation) % REVOLUTION + 1;
+
+                if best.is_none_or(|(_, best_wait)| wait < best_wait) {
+                    best = Some((index, wait));
+                }
+            }
+        }
+
+        match best {
+            Some((index, wait)) if self.search_revolutions < SEARCH_REVOLUTIONS => {
+                self.sector_index = index;
+                self.state = State::Searching;
+                self.countdown = wait;
+            },
+            _ => {
+                // Give up once the disc has been round enough times
+                let remaining = SEARCH_REVOLUTIONS.saturating_sub(self.search_revolutions);
+                if remaining == 0 {
+                    self.finish(STATUS_RECORD_NOT_FOUND);
+                } else {
+                    self.state = State::Retrying;
+                    self.countdown = remaining * REVOLUTION;
+                }
+            }
+        }
+    }
+
+    fn found_sector(&mut self) {
+        let ids = self.current_ids();
+        let id = match ids.get(self.sector_index) {
+            Some(id) => *id,
+            None => return self.retry()
+        };
+
+        if self.command & 0xf0 == 0xc0 {
+            let crc = id_crc(&id);
+            self.buffer = vec![id.track, id.side, id.sector, id.size, (crc >> 8) as u8, crc as u8];
+            self.sector_crc_error = id.crc_error;
+            self.position = 0;
+            self.state = State::ReadingAddress;
+            self.countdown = self.byte_time();
+            return;
+        }
+
+        // A bad ID CRC means this isn't the sector after all; keep looking
+        if id.crc_error {
+            self.status |= STATUS_CRC_ERROR;
+            return self.retry();
+        }
+        self.status &= !STATUS_CRC_ERROR;
+
+        if self.command & 0x20 == 0 {
+            match self.read_data() {
+                Some(sector) => {
+                    if sector.deleted {
+                        self.status |= STATUS_DELETED;
+                    }
+                    self.sector_crc_error = sector.crc_error;
+                    self.buffer = sector.data;
+                    self.position = 0;
+                    self.state = State::Reading;
+                    self.countdown = self.byte_time();
+                },
+                None => self.retry()
+            }
+        } else {
+            self.buffer = Vec::with_capacity(128 << (id.size & 3));
+            self.position = 128 << (id.size & 3);
+            self.drq = true;
+            self.state = State::Writing;
+
+            // The CPU has two byte times to supply the first byte
+            self.countdown = 2 * self.byte_time();
+        }
+    }
+
+    // Carry on searching from just past the current ID
+    fn retry(&mut self) {
+        self.state = State::Retrying;
+        self.countdown = self.byte_time();
+    }
+
+    fn read_data(&self) -> Option<SectorData> {
+        let drive = &self.drives[self.selected?];
+        let disc = drive.disc.as_ref()?;
+        disc.read_sector(drive.cylinder, self.side, self.double_density, self.sector_index)
+    }
+
+    fn read_byte(&mut self) {
+        if self.position == self.buffer.len() {
+            return self.end_of_sector();
+        }
+
+        if self.drq {
+            self.status |= STATUS_LOST_DATA;
+        }
+
+        self.data = self.buffer[self.position];
+        self.position += 1;
+        self.drq = true;
+        self.countdown = self.byte_time();
+    }
+
+    fn write_byte(&mut self) {
+        if self.drq {
+            self.status |= STATUS_LOST_DATA;
+
+            // Nothing at all arrived for the first byte, so nothing is written
+            if self.buffer.is_empty() {
+                self.drq = false;
+                return self.finish(0);
+            }
+            self.data = 0;
+        }
+
+        self.buffer.push(self.data);
+
+        if self.buffer.len() == self.position {
+            self.drq = false;
+            let deleted = self.command & FLAG_DELETED_MARK != 0;
+            if let Some(drive) = self.selected {
+                let drive = &mut self.drives[drive];
+                if let Some(ref mut disc) = drive.disc {
+                    disc.write_sector(drive.cylinder, self.side, self.double_density, self.sector_index, &self.buffer, deleted);
+                }
+            }
+            // Let the CRC go by, then finish as if the sector had been read
+            self.countdown = self.byte_time();
+            self.state = State::Reading;
+            self.sector_crc_error = false;
+            self.position = self.buffer.len();
+        } else {
+            self.drq = true;
+            self.countdown = self.byte_time();
+        }
+    }
+
+    fn read_address_byte(&mut self) {
+        if self.position == self.buffer.len() {
+            // The 1772 copies the track address into the sector register
+            self.sector = self.buffer[0];
+            let status = if self.sector_crc_error { STATUS_CRC_ERROR } else { 0 };
+            return self.finish(status);
+        }
+
+        if self.drq {
+            self.status |= STATUS_LOST_DATA;
+        }
+
+        self.data = self.buffer[self.position];
+        self.position += 1;
+        self.drq = true;
+        self.countdown = self.byte_time();
+    }
+
+    fn end_of_sector(&mut self) {
+        if self.sector_crc_error {
+            return self.finish(STATUS_CRC_ERROR);
+        }
+
+        if self.command & FLAG_MULTIPLE != 0 {
+            self.sector = self.sector.wrapping_add(1);
+            self.start_search();
+        } else {
+            self.finish(0);
+        }
+    }
+
+    fn finish(&mut self, status: u8) {
+        self.status = (self.status | status) & !STATUS_BUSY;
+        self.state = State::Idle;
+        self.idle_revolutions = 0;
+        self.intrq = true;
+    }
+}
+
+impl Device for Fdc {
+    fn load(&mut self, offset: u32) -> u32 {
+        let value = match offset & 0xc {
+            STATUS_COMMAND => {
+                self.intrq = false;
+                self.status_register()
+            },
+            TRACK => self.track,
+            SECTOR => self.sector,
+            DATA => {
+                self.drq = false;
+                self.data
+            },
+            _ => unreachable!()
+        };
+        value as u32
+    }
+
+    fn store(&mut self, offset: u32, data: u32) {
+        let value = (data >> 16) as u8;
+
+        match offset & 0xc {
+            STATUS_COMMAND => self.write_command(value),
+            TRACK => if self.state == State::Idle { self.track = value },
+            SECTOR => if self.state == State::Idle { self.sector = value },
+            DATA => {
+                self.drq = false;
+                self.data = value;
+            },
+            _ => unreachable!()
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.motor_on {
+            self.rotation += cycles;
+            while self.rotation >= REVOLUTION {
+                self.rotation -= REVOLUTION;
+                self.revolution();
+            }
+        }
+
+        if self.state == State::Idle {
+            return;
+        }
+
+        let mut remaining = cycles;
+        while remaining > 0 && self.state != State::Idle {
+            if remaining < self.countdown {
+                self.countdown -= remaining;
+                return;
+            }
+
+            remaining -= self.countdown;
+            self.countdown = 0;
+            self.advance();
+        }
+    }
+
+    // The next revolution while the motor's on, and the next step of a
+    // command
+    fn next_event(&self) -> Option<u32> {
+        let revolution = if self.motor_on { Some(REVOLUTION - self.rotation) } else { None };
+        let command = if self.state != State::Idle { Some(self.countdown) } else { None };
+        revolution.into_iter().chain(command).min()
+    }
+
+    fn interrupts(&mut self) -> Interrupts {
+        let mut interrupts = Interrupts::fiq(FIQ_FLOPPY_DATA, self.drq);
+        interrupts |= Interrupts::fiq(FIQ_FLOPPY_INTERRUPT, self.intrq);
+        interrupts
+    }
+
+    // The discs themselves stay in the drives, but the heads go back to
+    // where they were
+    fn save(&self, state: &mut StateWriter) {
+        for &register in &[self.command, self.status, self.track, self.sector, self.data] {
+            state.u8(register);
+        }
+        for drive in &self.drives {
+            state.u8(drive.cylinder);
+        }
+        state.option_u8(self.selected.map(|drive| drive as u8));
+        state.u8(self.side);
+        state.bool(self.double_density);
+
+        state.u8(self.state as u8);
+        state.u32(self.countdown);
+        state.u32(self.rotation);
+        state.bool(self.motor_on);
+        state.u32(self.idle_revolutions);
+        state.u32(self.search_revolutions);
+        state.u8(self.direction as u8);
+
+        state.bytes(&self.buffer);
+        state.usize(self.position);
+        state.usize(self.sector_index);
+        state.bool(self.sector_crc_error);
+
+        state.bool(self.drq);
+        state.bool(self.intrq);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for register in [&mut self.command, &mut self.status, &mut self.track, &mut self.sector, &mut self.data] {
+            *register = state.u8()?;
+        }
+        for drive in self.drives.iter_mut() {
+            drive.cylinder = state.u8()?;
+        }
+        self.selected = match state.option_u8()? {
+            Some(drive) if drive as usize >= self.drives.len() => return Err(snapshot::invalid("Bad floppy drive in snapshot")),
+            selected => selected.map(|drive| drive as usize)
+        };
+        self.side = state.u8()?;
+        self.double_density = state.bool()?;
+
+        self.state = *STATES.get(state.u8()? as usize).ok_or_else(|| snapshot::invalid("Bad FDC state in snapshot"))?;
+        self.countdown = state.u32()?;
+        self.rotation = state.u32()?;
+        self.motor_on = state.bool()?;
+        self.idle_revolutions = state.u32()?;
+        self.search_revolutions = state.u32()?;
+        self.direction = state.u8()? as i8;
+
+        self.buffer = state.bytes()?;
+        self.position = state.usize()?;
+        self.sector_index = state.usize()?;
+        self.sector_crc_error = state.bool()?;
+
+        self.drq = state.bool()?;
+        self.intrq = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Fdc {
+    fn default() -> Fdc {
+        Fdc::new()
+    }
+}
+
+// CRC-CCITT over the ID address mark and ID field, as stored on the disc
+fn id_crc(id: &SectorId) -> u16 {
+    let bytes = [0xa1, 0xa1, 0xa1, 0xfe, id.track, id.side, id.sector, id.size];
+    let crc = crc_ccitt(0xffff, &bytes);
+    if id.crc_error { !crc } else { crc }
+}
+
+pub fn crc_ccitt(initial: u16, bytes: &[u8]) -> u16 {
+    let mut crc = initial;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webarc::adf::AdfImage;
+
+    // Track and sector in the first two bytes of every sector of an ADFS E
+    // disc
+    fn disc() -> Box<dyn FloppyDisc> {
+        let mut data = vec![0; 819200];
+        for (index, sector) in data.chunks_mut(1024).enumerate() {
+            sector[0] = (index / 10) as u8;
+            sector[1] = (index % 5) as u8;
+        }
+        Box::new(AdfImage::from_bytes(data).unwrap())
+    }
+
+    fn fdc() -> Fdc {
+        let mut fdc = Fdc::new();
+        assert!(fdc.insert(0, disc()));
+        fdc.select(!1, 0);
+        fdc
+    }
+
+    fn write(fdc: &mut Fdc, register: u32, value: u8) {
+        fdc.store(register, (value as u32) << 16);
+    }
+
+    // Run a command to the end, taking every byte it offers if `reading`
+    fn run(fdc: &mut Fdc, command: u8, reading: bool) -> Vec<u8> {
+        write(fdc, STATUS_COMMAND, command);
+
+        let mut bytes = Vec::new();
+        while fdc.state != State::Idle {
+            fdc.tick(4 * CYCLES_PER_MICROSECOND);
+            if reading && fdc.drq() {
+                bytes.push(fdc.load(DATA) as u8);
+            }
+        }
+        assert!(fdc.intrq());
+        bytes
+    }
+
+    fn read_status(fdc: &mut Fdc) -> u8 {
+        fdc.load(STATUS_COMMAND) as u8
+    }
+
+    #[test]
+    fn restore() {
+        let mut fdc = fdc();
+        fdc.drives[0].cylinder = 5;
+        write(&mut fdc, TRACK, 5);
+
+        run(&mut fdc, 0x00, false);
+        assert_eq!(fdc.track, 0);
+        assert_eq!(fdc.drives[0].cylinder, 0);
+        let status = read_status(&mut fdc);
+        assert_eq!(status & (STATUS_BUSY | STATUS_SEEK_ERROR), 0);
+        assert_ne!(status & STATUS_TRACK0, 0);
+        assert_ne!(status & STATUS_SPUN_UP, 0);
+    }
+
+    #[test]
+    fn restore_without_drive() {
+        let mut fdc = fdc();
+        fdc.select(0xf, 0);
+
+        run(&mut fdc, 0x00, false);
+        assert_ne!(read_status(&mut fdc) & STATUS_SEEK_ERROR, 0);
+    }
+
+    #[test]
+    fn drives() {
+        let mut fdc = fdc();
+        assert!(!fdc.insert(4, disc()));
+        assert!(fdc.eject(4).is_none());
+        assert!(fdc.eject(0).is_some());
+        assert!(fdc.eject(0).is_none());
+    }
+
+    #[test]
+    fn seek() {
+        let mut fdc = fdc();
+        write(&mut fdc, DATA, 10);
+        run(&mut fdc, 0x14, false);
+        assert_eq!(fdc.track, 10);
+        assert_eq!(fdc.drives[0].cylinder, 10);
+        let status = read_status(&mut fdc);
+        assert_eq!(status & (STATUS_SEEK_ERROR | STATUS_TRACK0), 0);
+
+        // Back out again, then past the last cylinder on the disc, where
+        // there are no IDs to verify against
+        write(&mut fdc, DATA, 0);
+        run(&mut fdc, 0x10, false);
+        assert_eq!(fdc.drives[0].cylinder, 0);
+        assert_ne!(read_status(&mut fdc) & STATUS_TRACK0, 0);
+
+        write(&mut fdc, DATA, 82);
+        run(&mut fdc, 0x14, false);
+        assert_eq!(fdc.drives[0].cylinder, 82);
+        assert_ne!(read_status(&mut fdc) & STATUS_SEEK_ERROR, 0);
+    }
+
+    #[test]
+    fn read_sector() {
+        let mut fdc = fdc();
+        write(&mut fdc, DATA, 3);
+        run(&mut fdc, 0x10, false);
+
+        write(&mut fdc, SECTOR, 2);
+        let bytes = run(&mut fdc, 0x80, true);
+        assert_eq!(bytes.len(), 1024);
+        assert_eq!(&bytes[..2], &[3, 2]);
+        assert_eq!(read_status(&mut fdc) & !STATUS_MOTOR_ON, 0);
+
+        // There's no sector 7 on an ADFS E disc
+        write(&mut fdc, SECTOR, 7);
+        assert!(run(&mut fdc, 0x80, true).is_empty());
+        assert_ne!(read_status(&mut fdc) & STATUS_RECORD_NOT_FOUND, 0);
+    }
+
+    #[test]
+    fn lost_data() {
+        let mut fdc = fdc();
+        write(&mut fdc, SECTOR, 0);
+        run(&mut fdc, 0x80, false);
+        let status = read_status(&mut fdc);
+        assert_ne!(status & STATUS_LOST_DATA, 0);
+        assert_eq!(status & (STATUS_BUSY | STATUS_RECORD_NOT_FOUND | STATUS_CRC_ERROR), 0);
+    }
+}
@@ -0,0 +1,72 @@
+// What the 1772 sees of a floppy disc: the sector IDs that pass under the
+// head on each track and the data fields that follow them.
+
+#[derive(Clone, Copy)]
+pub struct SectorId {
+    pub track: u8,
+    pub side: u8,
+    pub sector: u8,
+    // Sector length is 128 << size
+    pub size: u8,
+    pub crc_error: bool,
+}
+
+pub struct SectorData {
+    pub data: Vec<u8>,
+    pub deleted: bool,
+    pub crc_error: bool,
+}
+
+pub trait FloppyDisc {
+    // IDs on a physical track in rotational order, as read at the given density
+    fn sector_ids(&self, cylinder: u8, side: u8, double_density: bool) -> Vec<SectorId>;
+
+    // Data field following the ID at `index` in `sector_ids`, if it has one
+    fn read_sector(&self, cylinder: u8, side: u8, double_density: bool, index: usize) -> Option<SectorData>;
+
+    fn write_sector(&mut self, cylinder: u8, side: u8, double_density: bool, index: usize, data: &[u8], deleted: bool);
+
+    fn write_protected(&self) -> bool;
+}
+
+// Highest cylinder the drive mechanism can step to
+const MAX_CYLINDER: u8 = 83;
+
+pub struct Drive {
+    pub disc: Option<Box<dyn FloppyDisc>>,
+    pub cylinder: u8,
+}
+
+impl Drive {
+    pub fn new() -> Drive {
+        Drive {
+            disc: None,
+            cylinder: 0,
+        }
+    }
+
+    pub fn step(&mut self, direction: i8) {
+        if direction < 0 {
+            self.cylinder = self.cylinder.saturating_sub(1);
+        } else if self.cylinder < MAX_CYLINDER {
+            self.cylinder += 1;
+        }
+    }
+
+    pub fn track0(&self) -> bool {
+        self.cylinder == 0
+    }
+
+    pub fn write_protected(&self) -> bool {
+        match self.disc {
+            Some(ref disc) => disc.write_protected(),
+            None => true
+        }
+    }
+}
+
+impl Default for Drive {
+    fn default() -> Drive {
+        Drive::new()
+    }
+}
@@ -0,0 +1,664 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use webarc::machine::Machine;
+use webarc::trace::{Access, AccessKind};
+
+// A stub for gdb's remote serial protocol, so gdb-multiarch can debug
+// whatever's running on the machine:
+//
+//   gdb-multiarch -ex 'set architecture arm' -ex 'target remote :1234'
+//
+// The machine stays stopped while gdb is connected, except while it's
+// continuing or stepping. gdb sees a 32-bit ARM with a CPSR: the PC is the
+// address of the next instruction, and the CPSR is made up from the flags and
+// mode in R15, so the 26-bit modes come out as 0-3. Memory is read without
+// bus cycles, so only RAM and ROM can be looked at, and only RAM changed.
+// Breakpoints of either kind are checked before each instruction rather than
+// written into memory, which lets them go in ROM too.
+
+// Registers as gdb is told about them: R0-R15, then the CPSR
+const REGISTERS: usize = 17;
+const REG_PC: usize = 15;
+const REG_CPSR: usize = 16;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target>
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.core">
+    <reg name="r0" bitsize="32"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="cpsr" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
+const INTERRUPT: u8 = 0x03;
+
+// How many instructions run between looks for an interrupt from gdb
+const POLL_INSTRUCTIONS: u32 = 10_000;
+
+// The same as the signals gdb uses on Linux
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// R15's flags, and where they go in a CPSR
+const R15_FLAGS: u32 = 0xf0000000;
+const R15_I: u32 = 1 << 27;
+const R15_F: u32 = 1 << 26;
+const CPSR_I: u32 = 1 << 7;
+const CPSR_F: u32 = 1 << 6;
+
+#[derive(Clone, Copy, PartialEq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+struct Watchpoint {
+    kind: WatchKind,
+    start: u32,
+    length: u32,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &Access) -> bool {
+        let write = match access.kind {
+            AccessKind::Load | AccessKind::LoadByte => false,
+            AccessKind::Store | AccessKind::StoreByte => true,
+        };
+        let kind_matches = match self.kind {
+            WatchKind::Write => write,
+            WatchKind::Read => !write,
+            WatchKind::Access => true,
+        };
+        kind_matches && access.address.wrapping_sub(self.start) < self.length
+    }
+
+    fn stop_reason(&self) -> &'static str {
+        match self.kind {
+            WatchKind::Write => "watch",
+            WatchKind::Read => "rwatch",
+            WatchKind::Access => "awatch",
+        }
+    }
+}
+
+// Why the machine stopped, for the stop reply
+enum Stop {
+    Signal(u8),
+    Watchpoint(&'static str, u32),
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    breakpoints: Vec<u32>,
+    watchpoints: Vec<Watchpoint>,
+    acknowledge: bool,
+}
+
+impl GdbStub {
+    // Wait for gdb to connect to a port on this machine
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for gdb on port {}", port);
+        let (stream, peer) = listener.accept()?;
+        println!("gdb connected from {}", peer);
+        stream.set_nodelay(true)?;
+
+        Ok(GdbStub {
+            stream,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            acknowledge: true,
+        })
+    }
+
+    // Answer gdb until it detaches or goes away. Killing the program ends
+    // the emulator.
+    pub fn serve(&mut self, machine: &mut Machine) -> io::Result<()> {
+        loop {
+            let packet = match self.receive()? {
+                Some(packet) => packet,
+                None => return Ok(())
+            };
+
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => stop_reply(&Stop::Signal(SIGTRAP)),
+                Some(b'g') => read_registers(machine),
+                Some(b'G') => write_registers(machine, &packet[1..]),
+                Some(b'p') => read_register(machine, &packet[1..]),
+                Some(b'P') => write_register(machine, &packet[1..]),
+                Some(b'm') => read_memory(machine, &packet[1..]),
+                Some(b'M') => write_memory(machine, &packet[1..]),
+                Some(b'c') => {
+                    resume_at(machine, &packet[1..]);
+                    let stop = self.continue_running(machine)?;
+                    stop_reply(&stop)
+                },
+                Some(b's') => {
+                    resume_at(machine, &packet[1..]);
+                    let stop = self.execute(machine).unwrap_or(Stop::Signal(SIGTRAP));
+                    stop_reply(&stop)
+                },
+                Some(b'Z') => self.insert_point(&packet[1..]),
+                Some(b'z') => self.remove_point(&packet[1..]),
+                Some(b'H') | Some(b'T') => "OK".to_owned(),
+                Some(b'q') => self.query(&packet[1..]),
+                Some(b'Q') if packet == "QStartNoAckMode" => {
+                    self.send("OK")?;
+                    self.acknowledge = false;
+                    continue;
+                },
+                Some(b'D') => {
+                    self.send("OK")?;
+                    println!("gdb detached");
+                    return Ok(());
+                },
+                Some(b'k') => {
+                    println!("Killed by gdb");
+                    std::process::exit(0);
+                },
+                // Anything else isn't supported, which gdb is told with an
+                // empty reply
+                _ => String::new()
+            };
+            self.send(&reply)?;
+        }
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_owned()
+        } else if let Some(annex) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_address_length(annex) {
+                Some((offset, length)) => transfer(TARGET_XML, offset as usize, length as usize),
+                None => "E01".to_owned()
+            }
+        } else {
+            match query {
+                "Attached" => "1".to_owned(),
+                "C" => "QC1".to_owned(),
+                "fThreadInfo" => "m1".to_owned(),
+                "sThreadInfo" => "l".to_owned(),
+                "Symbol::" => "OK".to_owned(),
+                _ => String::new()
+            }
+        }
+    }
+
+    // Z and z packets: type,address,kind
+    fn parse_point(arguments: &str) -> Option<(u8, u32, u32)> {
+        let mut fields = arguments.split(',');
+        let kind = fields.next()?.parse().ok()?;
+        let address = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let length = u32::from_str_radix(fields.next()?.split(';').next()?, 16).ok()?;
+        Some((kind, address, length))
+    }
+
+    fn insert_point(&mut self, arguments: &str) -> String {
+        let (kind, address, length) = match GdbStub::parse_point(arguments) {
+            Some(point) => point,
+            None => return "E01".to_owned()
+        };
+        let kind = match kind {
+            0 | 1 => {
+                if !self.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                }
+                return "OK".to_owned();
+            },
+            2 => WatchKind::Write,
+            3 => WatchKind::Read,
+            4 => WatchKind::Access,
+            _ => return String::new()
+        };
+        self.watchpoints.push(Watchpoint { kind, start: address, length });
+        "OK".to_owned()
+    }
+
+    fn remove_point(&mut self, arguments: &str) -> String {
+        let (kind, address, length) = match GdbStub::parse_point(arguments) {
+            Some(point) => point,
+            None => return "E01".to_owned()
+        };
+        let kind = match kind {
+            0 | 1 => {
+                self.breakpoints.retain(|&breakpoint| breakpoint != address);
+                return "OK".to_owned();
+            },
+            2 => WatchKind::Write,
+            3 => WatchKind::Read,
+            4 => WatchKind::Access,
+            _ => return String::new()
+        };
+        self.watchpoints.retain(|watchpoint| !(watchpoint.kind == kind && watchpoint.start == address && watchpoint.length == length));
+        "OK".to_owned()
+    }
+
+    // Run until a breakpoint, a watchpoint or an interrupt from gdb. The
+    // instruction at the PC runs even if there's a breakpoint on it, as
+    // that's where the last stop was.
+    fn continue_running(&mut self, machine: &mut Machine) -> io::Result<Stop> {
+        let mut instructions = 0;
+        loop {
+            if let Some(stop) = self.execute(machine) {
+                return Ok(stop);
+            }
+            if self.breakpoints.contains(&pc(machine)) {
+                return Ok(Stop::Signal(SIGTRAP));
+            }
+
+            instructions += 1;
+            if instructions == POLL_INSTRUCTIONS {
+                instructions = 0;
+                if self.interrupted()? {
+                    return Ok(Stop::Signal(SIGINT));
+                }
+            }
+        }
+    }
+
+    // Execute an instruction, returning the watchpoint it hit if any
+    fn execute(&mut self, machine: &mut Machine) -> Option<Stop> {
+        if self.watchpoints.is_empty() {
+            machine.step();
+            return None;
+        }
+
+        machine.memory().accesses = Some(Vec::new());
+        machine.step();
+        let accesses = machine.memory().accesses.take().unwrap_or_default();
+
+        accesses.iter().find_map(|access| {
+            self.watchpoints.iter()
+                .find(|watchpoint| watchpoint.matches(access))
+                .map(|watchpoint| Stop::Watchpoint(watchpoint.stop_reason(), access.address))
+        })
+    }
+
+    // Whether gdb has sent Ctrl-C, without waiting if it hasn't
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(1) => Ok(byte[0] == INTERRUPT),
+            Ok(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "gdb went away")),
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error)
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0]))
+        }
+    }
+
+    // The next packet from gdb, or None once it's disconnected. Acks and
+    // stray interrupts between packets are skipped.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {},
+                Some(_) => continue
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte)
+                }
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            if expected == Some(GdbStub::checksum(&data)) {
+                if self.acknowledge {
+                    self.stream.write_all(b"+")?;
+                }
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            if self.acknowledge {
+                self.stream.write_all(b"-")?;
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, GdbStub::checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+    }
+}
+
+// The address of the instruction to be executed next
+fn pc(machine: &mut Machine) -> u32 {
+    machine.cpu.registers.reg_no_flags(15).wrapping_sub(8)
+}
+
+fn stop_reply(stop: &Stop) -> String {
+    match *stop {
+        Stop::Signal(signal) => format!("S{:02x}", signal),
+        Stop::Watchpoint(reason, address) => format!("T{:02x}{}:{:x};", SIGTRAP, reason, address),
+    }
+}
+
+// A piece of a document for qXfer, marked as the last with l
+fn transfer(document: &str, offset: usize, length: usize) -> String {
+    let rest = document.get(offset..).unwrap_or("");
+    if rest.len() > length {
+        format!("m{}", &rest[..length])
+    } else {
+        format!("l{}", rest)
+    }
+}
+
+// c and s can give an address to resume from
+fn resume_at(machine: &mut Machine, arguments: &str) {
+    if let Ok(address) = u32::from_str_radix(arguments, 16) {
+        machine.cpu.registers.set_reg_no_flags(15, address.wrapping_add(8));
+    }
+}
+
+fn register(machine: &Machine, reg: usize) -> u32 {
+    let registers = &machine.cpu.registers;
+    let r15 = registers.reg(15);
+    match reg {
+        REG_PC => registers.reg_no_flags(15).wrapping_sub(8),
+        REG_CPSR => {
+            (r15 & R15_FLAGS)
+                | if r15 & R15_I != 0 { CPSR_I } else { 0 }
+                | if r15 & R15_F != 0 { CPSR_F } else { 0 }
+                | (r15 & 3)
+        },
+        _ => registers.reg(reg as u32)
+    }
+}
+
+fn set_register(machine: &mut Machine, reg: usize, value: u32) {
+    let registers = &mut machine.cpu.registers;
+    match reg {
+        REG_PC => registers.set_reg_no_flags(15, value.wrapping_add(8)),
+        REG_CPSR => {
+            let flags = (value & R15_FLAGS)
+                | if value & CPSR_I != 0 { R15_I } else { 0 }
+                | if value & CPSR_F != 0 { R15_F } else { 0 }
+                | (value & 3);
+            let r15 = registers.reg(15);
+            registers.set_reg(15, (r15 & 0x03fffffc) | flags);
+        },
+        _ => registers.set_reg(reg as u32, value)
+    }
+}
+
+// Registers go over the wire as little endian hex
+fn encode_word(value: u32) -> String {
+    value.to_le_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|index| hex.get(index..index + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+fn decode_word(hex: &str) -> Option<u32> {
+    let bytes = decode_bytes(hex)?;
+    if bytes.len() != 4 {
+        return None;
+    }
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_registers(machine: &mut Machine) -> String {
+    (0..REGISTERS).map(|reg| encode_word(register(machine, reg))).collect()
+}
+
+fn write_registers(machine: &mut Machine, hex: &str) -> String {
+    if hex.len() < REGISTERS * 8 {
+        return "E01".to_owned();
+    }
+    let values: Option<Vec<u32>> = (0..REGISTERS).map(|reg| decode_word(&hex[reg * 8..reg * 8 + 8])).collect();
+    match values {
+        Some(values) => {
+            // The CPSR goes first, so the others land in the mode it picks
+            set_register(machine, REG_CPSR, values[REG_CPSR]);
+            for (reg, &value) in values.iter().enumerate().take(REG_CPSR) {
+                set_register(machine, reg, value);
+            }
+            "OK".to_owned()
+        },
+        None => "E01".to_owned()
+    }
+}
+
+fn read_register(machine: &mut Machine, arguments: &str) -> String {
+    match usize::from_str_radix(arguments, 16) {
+        Ok(reg) if reg < REGISTERS => encode_word(register(machine, reg)),
+        _ => "E01".to_owned()
+    }
+}
+
+fn write_register(machine: &mut Machine, arguments: &str) -> String {
+    let (reg, value) = match arguments.split_once('=') {
+        Some(assignment) => assignment,
+        None => return "E01".to_owned()
+    };
+    match (usize::from_str_radix(reg, 16), decode_word(value)) {
+        (Ok(reg), Some(value)) if reg < REGISTERS => {
+            set_register(machine, reg, value);
+            "OK".to_owned()
+        },
+        _ => "E01".to_owned()
+    }
+}
+
+// address,length in hex
+fn parse_address_length(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(length, 16).ok()?))
+}
+
+// As much as can be read from the start, which gdb takes as a short read
+fn read_memory(machine: &mut Machine, arguments: &str) -> String {
+    let (start, length) = match parse_address_length(arguments) {
+        Some(range) => range,
+        None => return "E01".to_owned()
+    };
+
+    let mut hex = String::new();
+    for offset in 0..length {
+        let address = start.wrapping_add(offset);
+        match machine.memory().peek(address) {
+            Some(word) => hex.push_str(&format!("{:02x}", (word >> ((address & 3) * 8)) as u8)),
+            None => break
+        }
+    }
+    if hex.is_empty() && length > 0 { "E14".to_owned() } else { hex }
+}
+
+fn write_memory(machine: &mut Machine, arguments: &str) -> String {
+    let (range, data) = match arguments.split_once(':') {
+        Some(parts) => parts,
+        None => return "E01".to_owned()
+    };
+    let (start, bytes) = match (parse_address_length(range), decode_bytes(data)) {
+        (Some((start, length)), Some(bytes)) if bytes.len() == length as usize => (start, bytes),
+        _ => return "E01".to_owned()
+    };
+
+    for (offset, &byte) in bytes.iter().enumerate() {
+        if !machine.memory().poke_byte(start.wrapping_add(offset as u32), byte) {
+            return "E14".to_owned();
+        }
+    }
+    "OK".to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webarc::machine::Model;
+
+    const CODE: u32 = 0x02001000;
+    const N: u32 = 1 << 31;
+    const Z: u32 = 1 << 30;
+    const C: u32 = 1 << 29;
+
+    fn machine() -> Machine {
+        Machine::new(Model::A3000, vec![0; 0x80000].into_boxed_slice())
+    }
+
+    // A stub connected to a socket of its own
+    fn stub() -> GdbStub {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let _gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub { stream, breakpoints: Vec::new(), watchpoints: Vec::new(), acknowledge: true }
+    }
+
+    #[test]
+    fn pc_and_cpsr() {
+        let mut machine = machine();
+        machine.cpu.registers.set_reg(15, N | C | R15_I | 3 | (CODE + 8));
+
+        // R15 comes apart into the PC of the next instruction and a CPSR
+        assert_eq!(register(&machine, REG_PC), CODE);
+        assert_eq!(register(&machine, REG_CPSR), N | C | CPSR_I | 3);
+
+        // and goes back together without either disturbing the other
+        set_register(&mut machine, REG_CPSR, Z | CPSR_F | 2);
+        assert_eq!(machine.cpu.registers.reg(15), Z | R15_F | 2 | (CODE + 8));
+        set_register(&mut machine, REG_PC, 0x8000);
+        assert_eq!(machine.cpu.registers.reg(15), Z | R15_F | 2 | 0x8008);
+
+        // Other registers are those of the mode the CPSR picks, IRQ here
+        set_register(&mut machine, 13, 0x1234);
+        assert_eq!(machine.cpu.registers.slots()[23], 0x1234);
+
+        // All of them at once, the CPSR first
+        let mut hex: String = (0..REG_PC).map(|reg| encode_word(reg as u32)).collect();
+        hex.push_str(&encode_word(0x9000));
+        hex.push_str(&encode_word(C | 3));
+        assert_eq!(write_registers(&mut machine, &hex), "OK");
+        assert_eq!(machine.cpu.registers.reg(15), C | 3 | 0x9008);
+        assert_eq!(machine.cpu.registers.slots()[25], 13);
+        assert_eq!(read_registers(&mut machine), hex);
+        assert_eq!(write_registers(&mut machine, &hex[8..]), "E01");
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(GdbStub::checksum(b""), 0);
+        assert_eq!(GdbStub::checksum(b"OK"), 0x9a);
+        assert_eq!(GdbStub::checksum(&[0xff, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(decode_bytes(""), Some(Vec::new()));
+        assert_eq!(decode_bytes("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_bytes("abc"), None);
+        assert_eq!(decode_bytes("zz"), None);
+
+        // Words are little endian
+        assert_eq!(decode_word("78563412"), Some(0x12345678));
+        assert_eq!(encode_word(0x12345678), "78563412");
+        assert_eq!(decode_word("785634"), None);
+        assert_eq!(decode_word("7856341200"), None);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let access = |kind, address| Access { kind, address, value: 0 };
+        let accesses = [
+            access(AccessKind::Load, 0x1000),
+            access(AccessKind::LoadByte, 0x1003),
+            access(AccessKind::Store, 0x1000),
+            access(AccessKind::StoreByte, 0x1003),
+        ];
+        let expected = [
+            (WatchKind::Write, [false, false, true, true]),
+            (WatchKind::Read, [true, true, false, false]),
+            (WatchKind::Access, [true, true, true, true]),
+        ];
+        for &(kind, matches) in &expected {
+            let watchpoint = Watchpoint { kind, start: 0x1000, length: 4 };
+            for (access, &matches) in accesses.iter().zip(matches.iter()) {
+                assert_eq!(watchpoint.matches(access), matches);
+            }
+
+            // Only within the range watched
+            assert!(!watchpoint.matches(&access(AccessKind::Load, 0xffc)));
+            assert!(!watchpoint.matches(&access(AccessKind::Store, 0x1004)));
+        }
+    }
+
+    #[test]
+    fn points() {
+        let mut stub = stub();
+
+        // Software and hardware breakpoints are the same thing, once each
+        assert_eq!(stub.insert_point("0,2000,4"), "OK");
+        assert_eq!(stub.insert_point("1,2000,4"), "OK");
+        assert_eq!(stub.insert_point("1,2004,4;X1,0"), "OK");
+        assert_eq!(stub.breakpoints, [0x2000, 0x2004]);
+
+        for (kind, stop_reason) in [(2, "watch"), (3, "rwatch"), (4, "awatch")] {
+            assert_eq!(stub.insert_point(&format!("{},3000,8", kind)), "OK");
+            let watchpoint = stub.watchpoints.last().unwrap();
+            assert_eq!((watchpoint.stop_reason(), watchpoint.start, watchpoint.length), (stop_reason, 0x3000, 8));
+        }
+
+        // Unsupported kinds get an empty reply, and nonsense an error
+        assert_eq!(stub.insert_point("5,3000,8"), "");
+        assert_eq!(stub.insert_point("2,zz,8"), "E01");
+        assert_eq!(stub.remove_point("0,2000"), "E01");
+
+        // A watchpoint only goes if everything about it matches
+        assert_eq!(stub.remove_point("3,3000,4"), "OK");
+        assert_eq!(stub.watchpoints.len(), 3);
+        assert_eq!(stub.remove_point("3,3000,8"), "OK");
+        assert_eq!(stub.watchpoints.iter().map(|watchpoint| watchpoint.stop_reason()).collect::<Vec<_>>(), ["watch", "awatch"]);
+        assert_eq!(stub.remove_point("2,3000,8"), "OK");
+        assert_eq!(stub.remove_point("4,3000,8"), "OK");
+        assert!(stub.watchpoints.is_empty());
+
+        assert_eq!(stub.remove_point("1,2000,4"), "OK");
+        assert_eq!(stub.breakpoints, [0x2004]);
+        assert_eq!(stub.remove_point("0,2004,4"), "OK");
+        assert!(stub.breakpoints.is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+
+// Raw hard disc images: every sector in cylinder, head, sector order with no
+// header. Images are accessed in place rather than loaded, and may be shorter
+// than the geometry, in which case the missing sectors read as zeros. Whole
+// sectors past the end of the geometry can still be reached by LBA.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Geometry {
+    pub cylinders: u32,
+    pub heads: u32,
+    pub sectors: u32,
+    pub sector_size: usize,
+}
+
+impl Geometry {
+    // 20MB drive as fitted to the A440, formatted by ADFS
+    pub fn st506_20mb() -> Geometry {
+        Geometry { cylinders: 615, heads: 4, sectors: 32, sector_size: 256 }
+    }
+
+    // Largest CHS geometry an ATA drive can report, cut down to fit the image.
+    // Images smaller than one of its cylinders get a single head and shorter
+    // tracks instead, and ones without a whole sector can't be used at all.
+    // Anything past the last whole cylinder is only reachable by LBA.
+    pub fn ide_for_size(bytes: u64) -> Option<Geometry> {
+        let total = bytes / 512;
+        let (heads, sectors) = if total >= 16 * 63 { (16, 63) } else { (1, total.min(63)) };
+        if sectors == 0 {
+            return None;
+        }
+
+        let cylinders = (total / (heads * sectors)).min(16383) as u32;
+        Some(Geometry { cylinders, heads: heads as u32, sectors: sectors as u32, sector_size: 512 })
+    }
+
+    pub fn total_sectors(&self) -> u64 {
+        self.cylinders as u64 * self.heads as u64 * self.sectors as u64
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.total_sectors() * self.sector_size as u64
+    }
+
+    // Sectors are numbered from 0 within a track
+    pub fn lba(&self, cylinder: u32, head: u32, sector: u32) -> Option<u64> {
+        if cylinder >= self.cylinders || head >= self.heads || sector >= self.sectors {
+            return None;
+        }
+        Some((cylinder as u64 * self.heads as u64 + head as u64) * self.sectors as u64 + sector as u64)
+    }
+}
+
+pub struct HardDiscImage {
+    pub geometry: Geometry,
+    file: File,
+    write_protected: bool,
+    capacity: u64,
+}
+
+impl HardDiscImage {
+    // Open an existing image, falling back to read-only access if it can't
+    // be written
+    pub fn open<P: AsRef<Path>>(path: P, geometry: Geometry) -> io::Result<HardDiscImage> {
+        let (file, write_protected) = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => (file, false),
+            Err(_) => (File::open(&path)?, true)
+        };
+        let capacity = geometry.total_sectors().max(file.metadata()?.len() / geometry.sector_size as u64);
+
+        Ok(HardDiscImage { geometry, file, write_protected, capacity })
+    }
+
+    // Create a blank image, sized to the geometry
+    pub fn create<P: AsRef<Path>>(path: P, geometry: Geometry) -> io::Result<HardDiscImage> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        file.set_len(geometry.bytes())?;
+
+        Ok(HardDiscImage { geometry, file, write_protected: false, capacity: geometry.total_sectors() })
+    }
+
+    // Sectors that can be addressed: the whole of the geometry, and any more
+    // whole sectors the file has past it
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn write_protected(&self) -> bool {
+        self.write_protected
+    }
+
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.write_protected = write_protected;
+    }
+
+    pub fn read_sector(&mut self, lba: u64, buffer: &mut [u8]) -> io::Result<()> {
+        let size = self.geometry.sector_size;
+        self.file.seek(SeekFrom::Start(lba * size as u64))?;
+
+        let mut filled = 0;
+        while filled < size {
+            match self.file.read(&mut buffer[filled..size])? {
+                0 => break,
+                read => filled += read
+            }
+        }
+
+        for byte in &mut buffer[filled..size] {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    pub fn write_sector(&mut self, lba: u64, data: &[u8]) -> io::Result<()> {
+        if self.write_protected {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Hard disc is write protected"));
+        }
+
+        let size = self.geometry.sector_size;
+        self.file.seek(SeekFrom::Start(lba * size as u64))?;
+        self.file.write_all(&data[..size])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ide_geometry() {
+        let geometry = Geometry::ide_for_size(100 * 1024 * 1024).unwrap();
+        assert_eq!((geometry.cylinders, geometry.heads, geometry.sectors), (203, 16, 63));
+
+        // Never addresses past the end of the image
+        for &sectors in &[1, 62, 63, 100, 1007, 1008, 1009, 20000, 16383 * 1008 + 5000] {
+            let geometry = Geometry::ide_for_size(sectors * 512 + 100).unwrap();
+            assert!(geometry.total_sectors() <= sectors, "{} sectors", sectors);
+            assert!(geometry.total_sectors() > 0);
+        }
+
+        assert!(Geometry::ide_for_size(0).is_none());
+        assert!(Geometry::ide_for_size(511).is_none());
+    }
+
+    #[test]
+    fn capacity() {
+        let path = std::env::temp_dir().join(format!("webarc-harddisc-{}", std::process::id()));
+        let sectors = 3 * 16 * 63 + 10;
+        std::fs::write(&path, vec![0; sectors as usize * 512 + 100]).unwrap();
+        let geometry = Geometry::ide_for_size(sectors * 512 + 100).unwrap();
+        let image = HardDiscImage::open(&path, geometry);
+
+        // A short image still has the sectors of its geometry
+        let short = HardDiscImage::open(&path, Geometry::st506_20mb());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(geometry.total_sectors(), 3 * 16 * 63);
+        assert_eq!(image.unwrap().capacity(), sectors);
+        assert_eq!(short.unwrap().capacity(), Geometry::st506_20mb().total_sectors());
+    }
+}
@@ -0,0 +1,785 @@
+use std::io;
+use webarc::device::{Device, Interrupts};
+use webarc::harddisc::HardDiscImage;
+use webarc::ioc::IRQ_B_WINCHESTER;
+use webarc::snapshot::{self, StateReader, StateWriter};
+use webarc::sound::CYCLES_PER_MICROSECOND;
+
+// HD63463 ST506 hard disc controller on the A4x0 series, sharing IOC bank 5
+// with the latches but using medium speed cycles, at 0x32D0000. Address bits
+// 2-3 select the register. Accesses are 16 bits wide, and the byte wide
+// registers sit in the top half.
+//
+// A command's parameter block is written a byte at a time to the parameter
+// register, and then the command code to the command register. The first
+// byte of every block is the unit select, which is the drive number plus one.
+// The chip keeps the block up to date as the command runs, so it says where a
+// transfer stopped and READ ID leaves the ID it found there, and it can be
+// read back through the same register. The status register has the status
+// bits in its top byte and the error code of the last command in its bottom
+// byte. Sector data moves a halfword at a time through the data register, and
+// both data requests and command end interrupt through IOC IRQ B.
+//
+// Commands work on whichever track is under the head, so the cylinder in the
+// ID of a read or write has to match where the last seek left the drive.
+// Nothing can be done with a drive until the chip has been through SPECIFY,
+// though the geometry it describes is the host's business; the image keeps
+// its own.
+
+const COMMAND_STATUS: u32 = 0x0;
+const PARAMETER: u32 = 0x4;
+const DATA: u32 = 0x8;
+
+const STATUS_ABNORMAL_END: u8 = 1 << 2;
+const STATUS_DRIVE_ERROR: u8 = 1 << 3;
+const STATUS_SEEK_END: u8 = 1 << 4;
+const STATUS_COMMAND_END: u8 = 1 << 5;
+const STATUS_PARAMETER_REJECTED: u8 = 1 << 6;
+const STATUS_BUSY: u8 = 1 << 7;
+
+const ABORT: u8 = 0xf0;
+const SPECIFY: u8 = 0xe8;
+const TEST: u8 = 0xe0;
+const RECALIBRATE: u8 = 0xc8;
+const SEEK: u8 = 0xc0;
+const WRITE_FORMAT: u8 = 0xa3;
+const WRITE_DATA: u8 = 0x87;
+const READ_ID: u8 = 0x60;
+const CHECK_DATA: u8 = 0x48;
+const READ_DATA: u8 = 0x40;
+
+// Error codes, in the bottom byte of the status register
+const ERROR_NONE: u8 = 0x00;
+const ERROR_ABORTED: u8 = 0x08;
+const ERROR_INVALID_COMMAND: u8 = 0x10;
+const ERROR_PARAMETER: u8 = 0x18;
+const ERROR_NOT_INITIALISED: u8 = 0x20;
+const ERROR_WRITE_FAULT: u8 = 0x38;
+const ERROR_NOT_READY: u8 = 0x40;
+const ERROR_SEEK: u8 = 0x68;
+const ERROR_DATA_FIELD: u8 = 0x98;
+const ERROR_NOT_HIT: u8 = 0xa8;
+const ERROR_NOT_WRITABLE: u8 = 0xc8;
+
+// Where things are in a parameter block. Cylinders and counts are 16 bits,
+// high byte first.
+const UNIT: usize = 0;
+const HEAD: usize = 1;
+const CYLINDER: usize = 2;
+const ID_HEAD: usize = 4;
+const ID_SECTOR: usize = 5;
+const COUNT: usize = 6;
+
+// WRITE FORMAT takes an ID for each sector on the track through the data
+// register: cylinder high and low, head and sector
+const FORMAT_ID: usize = 4;
+
+// The longest parameter block, SPECIFY's, fills the chip's parameter
+// registers, and anything written past that is lost
+const PARAMETER_BYTES: usize = 16;
+
+const MS: u32 = 1000 * CYCLES_PER_MICROSECOND;
+
+// 3600rpm, with buffered seeks that step far faster than the head settles
+const REVOLUTION: u32 = 16667 * CYCLES_PER_MICROSECOND;
+const SEEK_SETTLE: u32 = 3 * MS;
+const SEEK_STEP: u32 = 25 * CYCLES_PER_MICROSECOND;
+const COMMAND_OVERHEAD: u32 = 100 * CYCLES_PER_MICROSECOND;
+
+pub const DRIVES: usize = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Seeking,
+    // Waiting for the next sector to come round under the head
+    Waiting,
+    // Waiting for the CPU to move a sector through the data register
+    Transferring,
+}
+
+const STATES: [State; 4] = [State::Idle, State::Seeking, State::Waiting, State::Transferring];
+
+pub struct Hdc {
+    pub drives: [Option<HardDiscImage>; DRIVES],
+    positions: [u32; DRIVES],
+
+    parameters: Vec<u8>,
+    parameter_position: usize,
+    // The block belongs to the last command, and the next parameter written
+    // starts a new one
+    parameters_used: bool,
+    status: u8,
+    error: u8,
+    specified: bool,
+
+    command: u8,
+    drive: usize,
+    state: State,
+    countdown: u32,
+    cylinder: u32,
+    head: u32,
+    sector: u32,
+    count: u32,
+
+    buffer: Vec<u8>,
+    position: usize,
+
+    data_request: bool,
+    interrupt: bool,
+}
+
+impl Hdc {
+    pub fn new() -> Hdc {
+        Hdc {
+            drives: [None, None],
+            positions: [0; DRIVES],
+            parameters: Vec::new(),
+            parameter_position: 0,
+            parameters_used: false,
+            status: 0,
+            error: ERROR_NONE,
+            specified: false,
+            command: 0,
+            drive: 0,
+            state: State::Idle,
+            countdown: 0,
+            cylinder: 0,
+            head: 0,
+            sector: 0,
+            count: 0,
+            buffer: Vec::new(),
+            position: 0,
+            data_request: false,
+            interrupt: false,
+        }
+    }
+
+    pub fn attach(&mut self, drive: usize, image: HardDiscImage) {
+        self.drives[drive] = Some(image);
+    }
+
+    pub fn detach(&mut self, drive: usize) -> Option<HardDiscImage> {
+        self.drives[drive].take()
+    }
+
+    pub fn irq(&self) -> bool {
+        self.interrupt || self.data_request
+    }
+
+    fn status_register(&self) -> u16 {
+        (self.status as u16) << 8 | self.error as u16
+    }
+
+    fn image(&mut self) -> Option<&mut HardDiscImage> {
+        self.drives[self.drive].as_mut()
+    }
+
+    fn write_parameter(&mut self, value: u8) {
+        if self.parameters_used {
+            self.parameters.clear();
+            self.parameters_used = false;
+        }
+        if self.parameters.len() < PARAMETER_BYTES {
+            self.parameters.push(value);
+        }
+    }
+
+    fn read_parameter(&mut self) -> u8 {
+        let value = self.parameters.get(self.parameter_position).cloned().unwrap_or(0);
+        self.parameter_position += 1;
+        value
+    }
+
+    fn word(&self, index: usize) -> u32 {
+        (self.parameters[index] as u32) << 8 | self.parameters[index + 1] as u32
+    }
+
+    fn write_command(&mut self, command: u8) {
+        if command == ABORT {
+            let error = if self.state == State::Idle { ERROR_NONE } else { ERROR_ABORTED };
+            self.status = 0;
+            return self.finish(error);
+        }
+
+        if self.state != State::Idle {
+            return;
+        }
+
+        self.command = command;
+        self.status = STATUS_BUSY;
+        self.error = ERROR_NONE;
+        self.interrupt = false;
+        self.parameters_used = true;
+        self.parameter_position = 0;
+
+        let expected = match command {
+            TEST | RECALIBRATE => 1,
+            READ_ID => 2,
+            SEEK => 4,
+            CHECK_DATA | READ_DATA | WRITE_DATA | WRITE_FORMAT => 8,
+            SPECIFY => 16,
+            _ => return self.reject(ERROR_INVALID_COMMAND)
+        };
+        if self.parameters.len() < expected {
+            return self.reject(ERROR_PARAMETER);
+        }
+        // Bytes the command doesn't take are ignored
+        self.parameters.truncate(expected);
+
+        if command == SPECIFY {
+            self.specified = true;
+            return self.wait(COMMAND_OVERHEAD);
+        }
+        if !self.specified {
+            return self.finish(ERROR_NOT_INITIALISED);
+        }
+
+        let unit = self.parameters[UNIT] as usize;
+        if unit == 0 || unit > DRIVES {
+            return self.reject(ERROR_PARAMETER);
+        }
+        self.drive = unit - 1;
+        if self.image().is_none() {
+            return self.finish(ERROR_NOT_READY);
+        }
+
+        match command {
+            TEST => self.wait(COMMAND_OVERHEAD),
+            RECALIBRATE => self.seek(0),
+            SEEK => {
+                self.head = self.parameters[HEAD] as u32;
+                let cylinder = self.word(CYLINDER);
+                self.seek(cylinder);
+            },
+            READ_ID => {
+                self.head = self.parameters[HEAD] as u32;
+                self.wait(REVOLUTION / 2);
+            },
+            _ => {
+                self.head = self.parameters[HEAD] as u32;
+                self.cylinder = self.word(CYLINDER);
+                self.sector = self.parameters[ID_SECTOR] as u32;
+                self.count = self.word(COUNT);
+
+                if self.count == 0 {
+                    self.finish(ERROR_NONE);
+                } else if self.cylinder != self.positions[self.drive] || self.parameters[ID_HEAD] as u32 != self.head {
+                    // Round and round looking for an ID that isn't on this track
+                    self.wait(2 * REVOLUTION);
+                } else if command == WRITE_FORMAT {
+                    self.wait(REVOLUTION);
+                } else {
+                    let time = self.sector_time();
+                    self.wait(time);
+                }
+            }
+        }
+    }
+
+    fn wait(&mut self, cycles: u32) {
+        self.state = State::Waiting;
+        self.countdown = cycles;
+    }
+
+    fn seek(&mut self, cylinder: u32) {
+        let distance = (cylinder as i64 - self.positions[self.drive] as i64).unsigned_abs() as u32;
+        self.cylinder = cylinder;
+        self.state = State::Seeking;
+        self.countdown = if distance == 0 { COMMAND_OVERHEAD } else { SEEK_SETTLE + distance * SEEK_STEP };
+    }
+
+    fn advance(&mut self) {
+        match self.state {
+            State::Seeking => self.seeked(),
+            State::Waiting => self.sector_reached(),
+            _ => {}
+        }
+    }
+
+    fn seeked(&mut self) {
+        let cylinders = self.image().map_or(0, |image| image.geometry.cylinders);
+        if self.cylinder >= cylinders {
+            return self.finish(ERROR_SEEK);
+        }
+        self.positions[self.drive] = self.cylinder;
+        self.status |= STATUS_SEEK_END;
+        self.finish(ERROR_NONE);
+    }
+
+    fn sector_time(&mut self) -> u32 {
+        let sectors = self.image().map_or(1, |image| image.geometry.sectors);
+        REVOLUTION / sectors.max(1)
+    }
+
+    fn lba(&mut self) -> Option<u64> {
+        let (cylinder, head, sector) = (self.cylinder, self.head, self.sector);
+        self.image().and_then(|image| image.geometry.lba(cylinder, head, sector))
+    }
+
+    fn sector_reached(&mut self) {
+        match self.command {
+            SPECIFY | TEST => return self.finish(ERROR_NONE),
+            READ_ID => return self.read_id(),
+            _ => {}
+        }
+
+        let on_track = self.cylinder == self.positions[self.drive] && self.parameters[ID_HEAD] as u32 == self.head;
+        if !on_track || (self.command != WRITE_FORMAT && self.lba().is_none()) {
+            return self.finish(ERROR_NOT_HIT);
+        }
+
+        let writing = self.command == WRITE_DATA || self.command == WRITE_FORMAT;
+        if writing && self.image().is_some_and(|image| image.write_protected()) {
+            return self.finish(ERROR_NOT_WRITABLE);
+        }
+
+        match self.command {
+            CHECK_DATA => match self.read_sector() {
+                Ok(()) => self.next_sector(),
+                Err(error) => self.finish(error)
+            },
+            READ_DATA => match self.read_sector() {
+                Ok(()) => self.request_data(),
+                Err(error) => self.finish(error)
+            },
+            WRITE_DATA => {
+                let size = self.image().map_or(0, |image| image.geometry.sector_size);
+                self.buffer = vec![0; size];
+                self.request_data();
+            },
+            _ => {
+                self.buffer = vec![0; (self.count as usize * FORMAT_ID + 1) & !1];
+                if self.buffer.is_empty() {
+                    return self.finish(ERROR_NONE);
+                }
+                self.request_data();
+            }
+        }
+    }
+
+    // The ID of whichever sector comes round next, left in the parameter
+    // block
+    fn read_id(&mut self) {
+        self.cylinder = self.positions[self.drive];
+        self.sector = 0;
+        if self.lba().is_none() {
+            return self.finish(ERROR_NOT_HIT);
+        }
+
+        let (cylinder, head) = (self.cylinder, self.head);
+        self.parameters.resize(ID_SECTOR + 1, 0);
+        self.parameters[CYLINDER] = (cylinder >> 8) as u8;
+        self.parameters[CYLINDER + 1] = cylinder as u8;
+        self.parameters[ID_HEAD] = head as u8;
+        self.parameters[ID_SECTOR] = 0;
+        self.finish(ERROR_NONE);
+    }
+
+    fn request_data(&mut self) {
+        self.position = 0;
+        self.state = State::Transferring;
+        self.data_request = true;
+    }
+
+    fn read_sector(&mut self) -> Result<(), u8> {
+        let lba = self.lba().ok_or(ERROR_NOT_HIT)?;
+        let image = self.drives[self.drive].as_mut().ok_or(ERROR_NOT_READY)?;
+
+        self.buffer = vec![0; image.geometry.sector_size];
+        image.read_sector(lba, &mut self.buffer).map_err(|error| {
+            println!("Couldn't read hard disc: {}", error);
+            ERROR_DATA_FIELD
+        })
+    }
+
+    fn write_sector(&mut self) -> Result<(), u8> {
+        let lba = self.lba().ok_or(ERROR_NOT_HIT)?;
+        let image = self.drives[self.drive].as_mut().ok_or(ERROR_NOT_READY)?;
+
+        image.write_sector(lba, &self.buffer).map_err(|error| {
+            println!("Couldn't write hard disc: {}", error);
+            ERROR_WRITE_FAULT
+        })
+    }
+
+    // Blank every sector the host sent an ID for
+    fn format_track(&mut self) {
+        let ids: Vec<(u32, u32, u32)> = self.buffer.chunks(FORMAT_ID).take(self.count as usize).map(|id| {
+            ((id[0] as u32) << 8 | id[1] as u32, id[2] as u32, id[3] as u32)
+        }).collect();
+
+        let image = match self.drives[self.drive].as_mut() {
+            Some(image) => image,
+            None => return self.finish(ERROR_NOT_READY)
+        };
+
+        let blank = vec![0; image.geometry.sector_size];
+        for (cylinder, head, sector) in ids {
+            let lba = match image.geometry.lba(cylinder, head, sector) {
+                Some(lba) => lba,
+                None => return self.finish(ERROR_NOT_HIT)
+            };
+            if let Err(error) = image.write_sector(lba, &blank) {
+                println!("Couldn't format hard disc: {}", error);
+                return self.finish(ERROR_WRITE_FAULT);
+            }
+        }
+
+        self.count = 0;
+        self.finish(ERROR_NONE);
+    }
+
+    fn read_data(&mut self) -> u16 {
+        if self.state != State::Transferring || self.command != READ_DATA {
+            return 0;
+        }
+
+        let value = self.buffer[self.position] as u16 | (self.buffer[self.position + 1] as u16) << 8;
+        self.position += 2;
+
+        if self.position >= self.buffer.len() {
+            self.data_request = false;
+            self.next_sector();
+        }
+        value
+    }
+
+    fn write_data(&mut self, value: u16) {
+        if self.state != State::Transferring || (self.command != WRITE_DATA && self.command != WRITE_FORMAT) {
+            return;
+        }
+
+        self.buffer[self.position] = value as u8;
+        self.buffer[self.position + 1] = (value >> 8) as u8;
+        self.position += 2;
+
+        if self.position < self.buffer.len() {
+            return;
+        }
+        self.data_request = false;
+
+        if self.command == WRITE_FORMAT {
+            return self.format_track();
+        }
+        match self.write_sector() {
+            Ok(()) => self.next_sector(),
+            Err(error) => self.finish(error)
+        }
+    }
+
+    // Move on through the track, then the cylinder, for multiple sector
+    // commands
+    fn next_sector(&mut self) {
+        self.count -= 1;
+        if self.count == 0 {
+            return self.finish(ERROR_NONE);
+        }
+
+        let (heads, sectors) = self.image().map_or((1, 1), |image| (image.geometry.heads, image.geometry.sectors));
+        self.sector += 1;
+        if self.sector >= sectors {
+            self.sector = 0;
+            self.head += 1;
+            if self.head >= heads {
+                self.head = 0;
+                self.cylinder += 1;
+                self.positions[self.drive] = self.cylinder;
+            }
+        }
+        self.update_parameters();
+
+        let time = self.sector_time();
+        self.wait(time);
+    }
+
+    // Where a transfer has got to, so the host can tell where it stopped
+    fn update_parameters(&mut self) {
+        if self.parameters.len() <= COUNT + 1 {
+            return;
+        }
+
+        let (cylinder, head, sector, count) = (self.cylinder, self.head, self.sector, self.count);
+        self.parameters[HEAD] = head as u8;
+        self.parameters[CYLINDER] = (cylinder >> 8) as u8;
+        self.parameters[CYLINDER + 1] = cylinder as u8;
+        self.parameters[ID_HEAD] = head as u8;
+        self.parameters[ID_SECTOR] = sector as u8;
+        self.parameters[COUNT] = (count >> 8) as u8;
+        self.parameters[COUNT + 1] = count as u8;
+    }
+
+    // Refused before it started, because of the command or its parameters
+    fn reject(&mut self, error: u8) {
+        self.finish(error);
+        self.status |= STATUS_PARAMETER_REJECTED;
+    }
+
+    fn finish(&mut self, error: u8) {
+        if self.command != SPECIFY && self.command != READ_ID {
+            self.update_parameters();
+        }
+
+        self.state = State::Idle;
+        self.data_request = false;
+        self.status &= !STATUS_BUSY;
+        self.status |= STATUS_COMMAND_END;
+        self.error = error;
+        if error != ERROR_NONE {
+            self.status |= STATUS_ABNORMAL_END;
+        }
+        if error == ERROR_NOT_READY || error == ERROR_SEEK || error == ERROR_WRITE_FAULT {
+            self.status |= STATUS_DRIVE_ERROR;
+        }
+
+        self.parameters_used = true;
+        self.parameter_position = 0;
+        self.interrupt = true;
+    }
+}
+
+impl Device for Hdc {
+    fn load(&mut self, offset: u32) -> u32 {
+        match offset & 0xc {
+            COMMAND_STATUS => {
+                self.interrupt = false;
+                self.status_register() as u32
+            },
+            PARAMETER => (self.read_parameter() as u32) << 8,
+            DATA => self.read_data() as u32,
+            _ => 0
+        }
+    }
+
+    // Registers take their halfword from bits 16-31, as left by STR of a
+    // halfword shifted up, so byte registers are in bits 24-31
+    fn store(&mut self, offset: u32, data: u32) {
+        match offset & 0xc {
+            COMMAND_STATUS => self.write_command((data >> 24) as u8),
+            PARAMETER if self.state == State::Idle => self.write_parameter((data >> 24) as u8),
+            DATA => self.write_data((data >> 16) as u16),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let mut remaining = cycles;
+        while remaining > 0 && self.state != State::Idle && self.state != State::Transferring {
+            if remaining < self.countdown {
+                self.countdown -= remaining;
+                return;
+            }
+
+            remaining -= self.countdown;
+            self.countdown = 0;
+            self.advance();
+        }
+    }
+
+    fn next_event(&self) -> Option<u32> {
+        match self.state {
+            State::Idle | State::Transferring => None,
+            _ => Some(self.countdown)
+        }
+    }
+
+    fn interrupts(&mut self) -> Interrupts {
+        Interrupts::irq_b(IRQ_B_WINCHESTER, self.irq())
+    }
+
+    // The disc images stay attached, but the heads go back to where they
+    // were
+    fn save(&self, state: &mut StateWriter) {
+        state.words(&self.positions);
+        state.bytes(&self.parameters);
+        state.usize(self.parameter_position);
+        state.bool(self.parameters_used);
+        state.u8(self.status);
+        state.u8(self.error);
+        state.bool(self.specified);
+
+        state.u8(self.command);
+        state.usize(self.drive);
+        state.u8(self.state as u8);
+        for &value in &[self.countdown, self.cylinder, self.head, self.sector, self.count] {
+            state.u32(value);
+        }
+
+        state.bytes(&self.buffer);
+        state.usize(self.position);
+        state.bool(self.data_request);
+        state.bool(self.interrupt);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.words_into(&mut self.positions)?;
+        self.parameters = state.bytes()?;
+        self.parameter_position = state.usize()?;
+        self.parameters_used = state.bool()?;
+        self.status = state.u8()?;
+        self.error = state.u8()?;
+        self.specified = state.bool()?;
+
+        self.command = state.u8()?;
+        self.drive = state.usize()?;
+        if self.drive >= DRIVES {
+            return Err(snapshot::invalid("Bad hard disc drive in snapshot"));
+        }
+        self.state = *STATES.get(state.u8()? as usize).ok_or_else(|| snapshot::invalid("Bad HDC state in snapshot"))?;
+        for value in [&mut self.countdown, &mut self.cylinder, &mut self.head, &mut self.sector, &mut self.count] {
+            *value = state.u32()?;
+        }
+
+        self.buffer = state.bytes()?;
+        self.position = state.usize()?;
+        if self.position > self.buffer.len() {
+            return Err(snapshot::invalid("Bad hard disc transfer in snapshot"));
+        }
+        self.data_request = state.bool()?;
+        self.interrupt = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Hdc {
+    fn default() -> Hdc {
+        Hdc::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use webarc::harddisc::Geometry;
+
+    fn hdc(name: &str) -> Hdc {
+        let path = env::temp_dir().join(format!("webarc-hdc-{}-{}.hdf", name, std::process::id()));
+        let geometry = Geometry { cylinders: 4, heads: 2, sectors: 4, sector_size: 256 };
+        let image = HardDiscImage::create(&path, geometry).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut hdc = Hdc::new();
+        hdc.attach(0, image);
+        hdc
+    }
+
+    // Send a command with its parameter block and run it until it ends or
+    // wants data. Returns the status register.
+    fn command(hdc: &mut Hdc, command: u8, parameters: &[u8]) -> u16 {
+        for &parameter in parameters {
+            hdc.store(PARAMETER, (parameter as u32) << 24);
+        }
+        hdc.store(COMMAND_STATUS, (command as u32) << 24);
+        run(hdc)
+    }
+
+    fn run(hdc: &mut Hdc) -> u16 {
+        while hdc.state != State::Idle && !hdc.data_request {
+            let cycles = hdc.next_event().unwrap();
+            hdc.tick(cycles);
+        }
+        assert!(hdc.irq());
+        hdc.load(COMMAND_STATUS) as u16
+    }
+
+    fn specify(hdc: &mut Hdc) {
+        assert_eq!(command(hdc, SPECIFY, &[0; 16]), 0x2000);
+    }
+
+    const END: u16 = (STATUS_COMMAND_END as u16) << 8;
+    const SEEK_END: u16 = (STATUS_SEEK_END as u16) << 8;
+    const ABNORMAL_END: u16 = (STATUS_ABNORMAL_END as u16) << 8;
+
+    #[test]
+    fn needs_specify() {
+        let mut hdc = hdc("specify");
+        assert_eq!(command(&mut hdc, TEST, &[1]), END | ABNORMAL_END | ERROR_NOT_INITIALISED as u16);
+        specify(&mut hdc);
+        assert_eq!(command(&mut hdc, TEST, &[1]), END);
+        assert_eq!(hdc.drives[0].as_ref().unwrap().geometry.sectors, 4);
+
+        // No drive 2, and no such command
+        let status = command(&mut hdc, TEST, &[2]);
+        assert_eq!(status & 0xff, ERROR_NOT_READY as u16);
+        assert_ne!(status & (STATUS_DRIVE_ERROR as u16) << 8, 0);
+        let status = command(&mut hdc, 0x12, &[1]);
+        assert_eq!(status & 0xff, ERROR_INVALID_COMMAND as u16);
+        assert_ne!(status & (STATUS_PARAMETER_REJECTED as u16) << 8, 0);
+    }
+
+    #[test]
+    fn extra_parameters() {
+        let mut hdc = hdc("parameters");
+        for _ in 0..100 {
+            hdc.store(PARAMETER, 0x55 << 24);
+        }
+        assert_eq!(hdc.parameters.len(), PARAMETER_BYTES);
+        hdc.store(COMMAND_STATUS, (SPECIFY as u32) << 24);
+        assert_eq!(run(&mut hdc), END);
+
+        // Only the unit select of this block is TEST's
+        assert_eq!(command(&mut hdc, TEST, &[1, 0x55, 0x55, 0x55]), END);
+        let block: Vec<u8> = (0..4).map(|_| (hdc.load(PARAMETER) >> 8) as u8).collect();
+        assert_eq!(block, [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn seeks() {
+        let mut hdc = hdc("seek");
+        specify(&mut hdc);
+        assert_eq!(command(&mut hdc, SEEK, &[1, 0, 0, 3]), END | SEEK_END);
+        assert_eq!(hdc.positions[0], 3);
+        assert_eq!(command(&mut hdc, SEEK, &[1, 0, 0, 4]) & 0xff, ERROR_SEEK as u16);
+        assert_eq!(hdc.positions[0], 3);
+        assert_eq!(command(&mut hdc, RECALIBRATE, &[1]), END | SEEK_END);
+        assert_eq!(hdc.positions[0], 0);
+
+        // READ ID leaves the ID under the head in the parameter block
+        command(&mut hdc, SEEK, &[1, 0, 0, 2]);
+        assert_eq!(command(&mut hdc, READ_ID, &[1, 1]), END);
+        let block: Vec<u8> = (0..6).map(|_| (hdc.load(PARAMETER) >> 8) as u8).collect();
+        assert_eq!(block, [1, 1, 0, 2, 1, 0]);
+    }
+
+    #[test]
+    fn transfers() {
+        let mut hdc = hdc("transfer");
+        specify(&mut hdc);
+        command(&mut hdc, SEEK, &[1, 1, 0, 1]);
+
+        // Two sectors, running on from the end of the track onto the next
+        // head
+        let mut status = command(&mut hdc, WRITE_DATA, &[1, 0, 0, 1, 0, 3, 0, 2]);
+        for sector in 0..2u32 {
+            assert_eq!(status & END, 0);
+            for word in 0..128 {
+                hdc.store(DATA, (sector * 0x1000 + word) << 16);
+            }
+            status = run(&mut hdc);
+        }
+        assert_eq!(status, END);
+
+        command(&mut hdc, SEEK, &[1, 1, 0, 1]);
+        assert_eq!(command(&mut hdc, CHECK_DATA, &[1, 1, 0, 1, 1, 0, 0, 1]), END);
+        assert_eq!(command(&mut hdc, READ_DATA, &[1, 1, 0, 1, 1, 0, 0, 1]) & END, 0);
+        let words: Vec<u32> = (0..128).map(|_| hdc.load(DATA)).collect();
+        assert_eq!(words, (0x1000..0x1080).collect::<Vec<_>>());
+        assert_eq!(run(&mut hdc), END);
+
+        // Nothing matches an ID for another cylinder
+        let status = command(&mut hdc, READ_DATA, &[1, 1, 0, 2, 1, 0, 0, 1]);
+        assert_eq!(status, END | ABNORMAL_END | ERROR_NOT_HIT as u16);
+    }
+
+    #[test]
+    fn aborts() {
+        let mut hdc = hdc("abort");
+        specify(&mut hdc);
+        assert_eq!(command(&mut hdc, READ_DATA, &[1, 0, 0, 0, 0, 0, 0, 1]) & END, 0);
+        hdc.store(COMMAND_STATUS, (ABORT as u32) << 24);
+        assert_eq!(run(&mut hdc), END | ABNORMAL_END | ERROR_ABORTED as u16);
+        assert!(!hdc.irq());
+    }
+}
@@ -0,0 +1,645 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+use webarc::fdc::crc_ccitt;
+use webarc::floppy::{FloppyDisc, SectorData, SectorId};
+
+// HFE track-level images, as used by the HxC floppy emulator. Each track
+// holds the raw stream of MFM or FM bit cells for both sides, so sector IDs,
+// data marks and CRCs are decoded from the stream exactly as the 1772 would
+// see them, including odd sector sizes, deleted data and bad CRCs.
+//
+// The file starts with a 512 byte header, followed by a table of track
+// offsets. Track data is stored in 512 byte blocks holding 256 bytes for
+// side 0 and then 256 for side 1, with the first cell of each byte in bit 0.
+
+const SIGNATURE: &[u8] = b"HXCPICFE";
+const BLOCK: usize = 512;
+const HALF_BLOCK: usize = 256;
+
+// MFM sync byte 0xA1 with a missing clock bit
+const MFM_SYNC: u16 = 0x4489;
+
+const ID_MARK: u8 = 0xfe;
+
+// How far past an ID the 1772 looks for a data mark, in bytes
+const MFM_DATA_WINDOW: usize = 43;
+const FM_DATA_WINDOW: usize = 30;
+
+// FM address marks are written with clock pattern 0xC7
+const FM_MARK_CLOCK: u8 = 0xc7;
+
+struct Track {
+    offset: usize,
+    length: usize,
+    sides: [Vec<u8>; 2],
+}
+
+// A sector ID found on a track, with where to look for its data
+#[derive(Clone, Copy)]
+struct IdRecord {
+    id: SectorId,
+    data_search: usize,
+}
+
+// The IDs on the track last looked at, which the 1772 asks for over and over
+// while it searches
+struct DecodedTrack {
+    cylinder: u8,
+    side: u8,
+    double_density: bool,
+    records: Vec<IdRecord>,
+}
+
+struct DataRecord {
+    mark: u8,
+    // Cell position of the data mark byte
+    position: usize,
+}
+
+pub struct HfeImage {
+    cylinders: u8,
+    sides: u8,
+    bit_rate: u16,
+    tracks: Vec<Track>,
+    decoded: RefCell<Option<DecodedTrack>>,
+    file: Option<File>,
+    write_protected: bool,
+}
+
+impl HfeImage {
+    pub fn from_bytes(data: &[u8]) -> io::Result<HfeImage> {
+        if data.len() < BLOCK || &data[0..8] != SIGNATURE {
+            return Err(invalid("Not an HFE image"));
+        }
+
+        let cylinders = data[9];
+        let sides = data[10];
+        let bit_rate = u16::from(data[12]) | u16::from(data[13]) << 8;
+        let track_list = (u16::from(data[18]) | u16::from(data[19]) << 8) as usize * BLOCK;
+        let write_allowed = data[20] != 0;
+
+        let mut tracks = Vec::new();
+        for cylinder in 0..cylinders as usize {
+            let entry = track_list + cylinder * 4;
+            if entry + 4 > data.len() {
+                return Err(invalid("HFE track list is truncated"));
+            }
+
+            let offset = (u16::from(data[entry]) | u16::from(data[entry + 1]) << 8) as usize * BLOCK;
+            let length = (u16::from(data[entry + 2]) | u16::from(data[entry + 3]) << 8) as usize;
+            if offset + length > data.len() {
+                return Err(invalid("HFE track data is truncated"));
+            }
+
+            let mut side_0 = Vec::with_capacity(length / 2);
+            let mut side_1 = Vec::with_capacity(length / 2);
+            for (index, byte) in data[offset..offset + length].iter().enumerate() {
+                if index % BLOCK < HALF_BLOCK {
+                    side_0.push(*byte);
+                } else {
+                    side_1.push(*byte);
+                }
+            }
+
+            tracks.push(Track { offset, length, sides: [side_0, side_1] });
+        }
+
+        Ok(HfeImage {
+            cylinders,
+            sides,
+            bit_rate,
+            tracks,
+            decoded: RefCell::new(None),
+            file: None,
+            write_protected: !write_allowed,
+        })
+    }
+
+    // Open an image file. Sector writes are encoded back into the track and
+    // written to the file unless the image or the file is read-only.
+    pub fn open<P: AsRef<Path>>(path: P, write_protected: bool) -> io::Result<HfeImage> {
+        let (mut file, read_only) = if write_protected {
+            (File::open(&path)?, true)
+        } else {
+            match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(file) => (file, false),
+                Err(_) => (File::open(&path)?, true)
+            }
+        };
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut image = HfeImage::from_bytes(&data)?;
+        image.file = Some(file);
+        image.write_protected |= read_only;
+        Ok(image)
+    }
+
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.write_protected = write_protected;
+    }
+
+    fn cells(&self, cylinder: u8, side: u8) -> Option<&[u8]> {
+        // The 1772 can't read high density tracks
+        if cylinder >= self.cylinders || side >= self.sides || self.bit_rate > 300 {
+            return None;
+        }
+
+        let cells = &self.tracks[cylinder as usize].sides[side as usize];
+        if cells.is_empty() { None } else { Some(cells) }
+    }
+
+    fn ids<F, T>(&self, cylinder: u8, side: u8, double_density: bool, f: F) -> T where F: FnOnce(&[IdRecord]) -> T {
+        let mut decoded = self.decoded.borrow_mut();
+        let cached = decoded.as_ref().is_some_and(|track| {
+            track.cylinder == cylinder && track.side == side && track.double_density == double_density
+        });
+
+        if !cached {
+            let records = match self.cells(cylinder, side) {
+                Some(cells) => {
+                    let stream = CellStream { cells };
+                    if double_density { stream.mfm_ids() } else { stream.fm_ids() }
+                },
+                None => Vec::new()
+            };
+            *decoded = Some(DecodedTrack { cylinder, side, double_density, records });
+        }
+
+        f(&decoded.as_ref().unwrap().records)
+    }
+
+    fn id(&self, cylinder: u8, side: u8, double_density: bool, index: usize) -> Option<IdRecord> {
+        self.ids(cylinder, side, double_density, |records| records.get(index).cloned())
+    }
+
+    fn persist(&mut self, cylinder: usize) -> io::Result<()> {
+        let track = &self.tracks[cylinder];
+        let mut data = vec![0; track.length];
+        let (mut side_0, mut side_1) = (track.sides[0].iter(), track.sides[1].iter());
+
+        for (index, byte) in data.iter_mut().enumerate() {
+            let source = if index % BLOCK < HALF_BLOCK { side_0.next() } else { side_1.next() };
+            *byte = *source.unwrap_or(&0);
+        }
+
+        if let Some(ref mut file) = self.file {
+            file.seek(SeekFrom::Start(track.offset as u64))?;
+            file.write_all(&data)?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl FloppyDisc for HfeImage {
+    fn sector_ids(&self, cylinder: u8, side: u8, double_density: bool) -> Vec<SectorId> {
+        self.ids(cylinder, side, double_density, |records| records.iter().map(|record| record.id).collect())
+    }
+
+    fn read_sector(&self, cylinder: u8, side: u8, double_density: bool, index: usize) -> Option<SectorData> {
+        let record = self.id(cylinder, side, double_density, index)?;
+        let stream = CellStream { cells: self.cells(cylinder, side)? };
+        let data_record = stream.find_data(record.data_search, double_density)?;
+        let length = 128usize << (record.id.size & 3);
+
+        let byte_cells = 16;
+        let mut data = Vec::with_capacity(length);
+        for byte in 0..length {
+            data.push(stream.byte(data_record.position + (byte + 1) * byte_cells));
+        }
+
+        let crc_position = data_record.position + (length + 1) * byte_cells;
+        let stored_crc = u16::from(stream.byte(crc_position)) << 8
+            | u16::from(stream.byte(crc_position + byte_cells));
+
+        Some(SectorData {
+            crc_error: stored_crc != data_crc(data_record.mark, &data, double_density),
+            deleted: data_record.mark & 0x02 == 0,
+            data,
+        })
+    }
+
+    fn write_sector(&mut self, cylinder: u8, side: u8, double_density: bool, index: usize, data: &[u8], deleted: bool) {
+        if self.write_protected {
+            return;
+        }
+
+        let position = match self.id(cylinder, side, double_density, index) {
+            Some(record) => {
+                let stream = CellStream { cells: self.tracks[cylinder as usize].sides[side as usize].as_slice() };
+                match stream.find_data(record.data_search, double_density) {
+                    Some(data_record) => data_record.position,
+                    None => return
+                }
+            },
+            None => return
+        };
+
+        let mark = if deleted { 0xf8 } else { 0xfb };
+        let crc = data_crc(mark, data, double_density);
+
+        let mut bytes = Vec::with_capacity(data.len() + 3);
+        bytes.push(mark);
+        bytes.extend_from_slice(data);
+        bytes.push((crc >> 8) as u8);
+        bytes.push(crc as u8);
+
+        {
+            let cells = &mut self.tracks[cylinder as usize].sides[side as usize];
+            let mut writer = CellWriter { cells, position };
+            for (index, byte) in bytes.iter().enumerate() {
+                if double_density {
+                    writer.write_mfm(*byte);
+                } else if index == 0 {
+                    writer.write_fm(*byte, FM_MARK_CLOCK);
+                } else {
+                    writer.write_fm(*byte, 0xff);
+                }
+            }
+        }
+
+        // The data field could have run on over the next ID
+        *self.decoded.borrow_mut() = None;
+
+        if let Err(error) = self.persist(cylinder as usize) {
+            println!("Couldn't write disc image: {}", error);
+        }
+    }
+
+    fn write_protected(&self) -> bool {
+        self.write_protected
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn data_crc(mark: u8, data: &[u8], double_density: bool) -> u16 {
+    let initial = if double_density { crc_ccitt(0xffff, &[0xa1, 0xa1, 0xa1]) } else { 0xffff };
+    crc_ccitt(crc_ccitt(initial, &[mark]), data)
+}
+
+// Interleave clock and data bits into the 16 cells FM uses for a byte
+fn fm_cells(data: u8, clock: u8) -> u16 {
+    let mut cells = 0;
+    for bit in (0..8).rev() {
+        cells = (cells << 2) | (u16::from(clock >> bit) & 1) << 1 | (u16::from(data >> bit) & 1);
+    }
+    cells
+}
+
+// Read-only view of one side of a track as a circular stream of cells
+struct CellStream<'a> {
+    cells: &'a [u8],
+}
+
+impl<'a> CellStream<'a> {
+    fn len(&self) -> usize {
+        self.cells.len() * 8
+    }
+
+    fn cell(&self, position: usize) -> u16 {
+        let position = position % self.len();
+        u16::from(self.cells[position / 8] >> (position % 8)) & 1
+    }
+
+    fn raw(&self, position: usize) -> u16 {
+        (0..16).fold(0, |cells, offset| (cells << 1) | self.cell(position + offset))
+    }
+
+    // Data bits are every other cell, after each clock cell
+    fn byte(&self, position: usize) -> u8 {
+        (0..8).fold(0, |byte, bit| (byte << 1) | self.cell(position + bit * 2 + 1) as u8)
+    }
+
+    fn mfm_ids(&self) -> Vec<IdRecord> {
+        let mut records = Vec::new();
+        let mut shift: u16 = 0;
+
+        // Run on past the index to catch marks that straddle it, but not as
+        // far as coming round to the first ID again. Sync has a leading zero,
+        // so it can end as early as cell 14.
+        let mut end = self.len() + 15;
+        // Where the last ID taken ends
+        let mut next = 0;
+        for position in 0..self.len() + 15 {
+            if position >= end {
+                break;
+            }
+            shift = (shift << 1) | self.cell(position);
+            if position < next || shift != MFM_SYNC {
+                continue;
+            }
+
+            // The mark follows the last of the sync bytes
+            let mark_position = position + 1;
+            if self.raw(mark_position) == MFM_SYNC || self.byte(mark_position) != ID_MARK {
+                continue;
+            }
+
+            let crc = crc_ccitt(0xffff, &[0xa1, 0xa1, 0xa1, ID_MARK]);
+            records.push(self.id_record(mark_position, crc));
+            if records.len() == 1 {
+                end = end.min(position + self.len());
+            }
+            // The mark byte and the six of the ID field
+            next = mark_position + 7 * 16;
+        }
+
+        records
+    }
+
+    fn fm_ids(&self) -> Vec<IdRecord> {
+        let id_mark = fm_cells(ID_MARK, FM_MARK_CLOCK);
+        let mut records = Vec::new();
+        let mut shift: u16 = 0;
+
+        for position in 0..self.len() + 15 {
+            shift = (shift << 1) | self.cell(position);
+            if shift != id_mark {
+                continue;
+            }
+
+            let mark_position = position + 1 - 16 + self.len();
+            let crc = crc_ccitt(0xffff, &[ID_MARK]);
+            records.push(self.id_record(mark_position, crc));
+        }
+
+        records
+    }
+
+    fn id_record(&self, mark_position: usize, mark_crc: u16) -> IdRecord {
+        let field: Vec<u8> = (1..7).map(|byte| self.byte(mark_position + byte * 16)).collect();
+        let crc = crc_ccitt(mark_crc, &field[0..4]);
+        let stored_crc = u16::from(field[4]) << 8 | u16::from(field[5]);
+
+        IdRecord {
+            id: SectorId {
+                track: field[0],
+                side: field[1],
+                sector: field[2],
+                size: field[3],
+                crc_error: stored_crc != crc,
+            },
+            data_search: (mark_position + 7 * 16) % self.len(),
+        }
+    }
+
+    fn find_data(&self, start: usize, double_density: bool) -> Option<DataRecord> {
+        let window = if double_density { MFM_DATA_WINDOW } else { FM_DATA_WINDOW } * 16;
+        let fm_marks: Vec<u16> = (0xf8..0xfc).map(|mark| fm_cells(mark, FM_MARK_CLOCK)).collect();
+        let mut shift: u16 = 0;
+
+        for position in start..start + window {
+            shift = (shift << 1) | self.cell(position);
+
+            if double_density {
+                if shift == MFM_SYNC && self.raw(position + 1) != MFM_SYNC {
+                    let mark = self.byte(position + 1);
+                    if (0xf8..=0xfb).contains(&mark) {
+                        return Some(DataRecord { mark, position: (position + 1) % self.len() });
+                    }
+                }
+            } else if let Some(index) = fm_marks.iter().position(|cells| *cells == shift) {
+                let position = (position + 1 + self.len() - 16) % self.len();
+                return Some(DataRecord { mark: 0xf8 + index as u8, position });
+            }
+        }
+
+        None
+    }
+}
+
+// Encodes bytes back into a track, wrapping round at the index
+struct CellWriter<'a> {
+    cells: &'a mut Vec<u8>,
+    position: usize,
+}
+
+impl<'a> CellWriter<'a> {
+    fn set(&mut self, value: u16) {
+        let length = self.cells.len() * 8;
+        let position = self.position % length;
+        if value != 0 {
+            self.cells[position / 8] |= 1 << (position % 8);
+        } else {
+            self.cells[position / 8] &= !(1 << (position % 8));
+        }
+        self.position += 1;
+    }
+
+    fn previous_data_bit(&self) -> u16 {
+        let length = self.cells.len() * 8;
+        let position = (self.position + length - 1) % length;
+        u16::from(self.cells[position / 8] >> (position % 8)) & 1
+    }
+
+    // MFM puts a clock bit between two zero data bits
+    fn write_mfm(&mut self, byte: u8) {
+        for bit in (0..8).rev() {
+            let data = u16::from(byte >> bit) & 1;
+            let clock = if data == 0 && self.previous_data_bit() == 0 { 1 } else { 0 };
+            self.set(clock);
+            self.set(data);
+        }
+    }
+
+    fn write_fm(&mut self, byte: u8, clock: u8) {
+        let cells = fm_cells(byte, clock);
+        for bit in (0..16).rev() {
+            self.set((cells >> bit) & 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // Bytes of cells per side, enough for a track at 250kbit/s
+    const SIDE_BYTES: usize = 49 * HALF_BLOCK;
+
+    struct Sector {
+        sector: u8,
+        size: u8,
+        mark: u8,
+        id_crc_error: bool,
+        data_crc_error: bool,
+    }
+
+    fn contents(sector: u8, length: usize) -> Vec<u8> {
+        (0..length).map(|byte| (byte as u8).wrapping_mul(7) ^ sector).collect()
+    }
+
+    fn gap(writer: &mut CellWriter, double_density: bool, byte: u8, count: usize) {
+        for _ in 0..count {
+            if double_density { writer.write_mfm(byte) } else { writer.write_fm(byte, 0xff) }
+        }
+    }
+
+    fn mark(writer: &mut CellWriter, double_density: bool, mark: u8) {
+        if double_density {
+            for _ in 0..3 {
+                for bit in (0..16).rev() {
+                    writer.set((MFM_SYNC >> bit) & 1);
+                }
+            }
+            writer.write_mfm(mark);
+        } else {
+            writer.write_fm(mark, FM_MARK_CLOCK);
+        }
+    }
+
+    fn field(writer: &mut CellWriter, double_density: bool, bytes: &[u8], crc: u16) {
+        for &byte in bytes.iter().chain(&[(crc >> 8) as u8, crc as u8]) {
+            gap(writer, double_density, byte, 1);
+        }
+    }
+
+    // Format one side of a track the way the 1772's Write Track would
+    fn track(double_density: bool, sectors: &[Sector]) -> Vec<u8> {
+        let (filler, sync) = if double_density { (0x4e, 12) } else { (0xff, 6) };
+        let mut cells = vec![0; SIDE_BYTES];
+        {
+            let mut writer = CellWriter { cells: &mut cells, position: 0 };
+            gap(&mut writer, double_density, filler, 40);
+
+            for sector in sectors {
+                let id = [0, 0, sector.sector, sector.size];
+                let mark_crc = if double_density { crc_ccitt(0xffff, &[0xa1, 0xa1, 0xa1, ID_MARK]) } else { crc_ccitt(0xffff, &[ID_MARK]) };
+                let crc = crc_ccitt(mark_crc, &id);
+                gap(&mut writer, double_density, 0, sync);
+                mark(&mut writer, double_density, ID_MARK);
+                field(&mut writer, double_density, &id, if sector.id_crc_error { !crc } else { crc });
+                gap(&mut writer, double_density, filler, 22);
+
+                let data = contents(sector.sector, 128 << sector.size);
+                let crc = data_crc(sector.mark, &data, double_density);
+                gap(&mut writer, double_density, 0, sync);
+                mark(&mut writer, double_density, sector.mark);
+                field(&mut writer, double_density, &data, if sector.data_crc_error { !crc } else { crc });
+                gap(&mut writer, double_density, filler, 24);
+            }
+
+            while writer.position + 16 <= SIDE_BYTES * 8 {
+                gap(&mut writer, double_density, filler, 1);
+            }
+        }
+        cells
+    }
+
+    // A single sided, single cylinder image
+    fn image(side_0: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 2 * BLOCK];
+        data[0..8].copy_from_slice(SIGNATURE);
+        data[9] = 1;
+        data[10] = 1;
+        data[12] = 250;
+        data[18] = 1;
+        data[20] = 0xff;
+
+        let length = SIDE_BYTES * 2;
+        data[BLOCK..BLOCK + 4].copy_from_slice(&[2, 0, length as u8, (length >> 8) as u8]);
+        for half in side_0.chunks(HALF_BLOCK) {
+            data.extend_from_slice(half);
+            data.extend_from_slice(&[0; HALF_BLOCK]);
+        }
+        data
+    }
+
+    fn sectors() -> Vec<Sector> {
+        vec![
+            Sector { sector: 0, size: 1, mark: 0xfb, id_crc_error: false, data_crc_error: false },
+            Sector { sector: 1, size: 1, mark: 0xf8, id_crc_error: false, data_crc_error: false },
+            Sector { sector: 2, size: 1, mark: 0xfb, id_crc_error: false, data_crc_error: true },
+            Sector { sector: 3, size: 0, mark: 0xfb, id_crc_error: true, data_crc_error: false },
+            Sector { sector: 4, size: 2, mark: 0xfb, id_crc_error: false, data_crc_error: false },
+        ]
+    }
+
+    fn check(disc: &dyn FloppyDisc, double_density: bool) {
+        let ids = disc.sector_ids(0, 0, double_density);
+        let found: Vec<(u8, u8, bool)> = ids.iter().map(|id| (id.sector, id.size, id.crc_error)).collect();
+        assert_eq!(found, vec![(0, 1, false), (1, 1, false), (2, 1, false), (3, 0, true), (4, 2, false)]);
+
+        // The other density sees nothing
+        assert!(disc.sector_ids(0, 0, !double_density).is_empty());
+
+        for (index, sector) in sectors().iter().enumerate() {
+            let read = disc.read_sector(0, 0, double_density, index).unwrap();
+            assert_eq!(read.data, contents(sector.sector, 128 << sector.size));
+            assert_eq!(read.deleted, sector.mark == 0xf8);
+            assert_eq!(read.crc_error, sector.data_crc_error);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for &double_density in &[true, false] {
+            let disc = HfeImage::from_bytes(&image(&track(double_density, &sectors()))).unwrap();
+            check(&disc, double_density);
+        }
+    }
+
+    // A track written with the index just after the first ID's sync, so the
+    // scan for IDs starts in the middle of one
+    #[test]
+    fn sync_at_the_index() {
+        let cells = track(true, &sectors());
+        let length = cells.len() * 8;
+        let cell = |position: usize| (cells[position / 8] >> (position % 8)) & 1;
+
+        // 40 bytes of gap and 12 of zeros, then the third sync byte ends
+        let sync_end = (40 + 12 + 3) * 16 - 1;
+        let shift = sync_end - 14;
+        let mut rotated = vec![0; cells.len()];
+        for position in 0..length {
+            rotated[position / 8] |= cell((position + shift) % length) << (position % 8);
+        }
+
+        let disc = HfeImage::from_bytes(&image(&rotated)).unwrap();
+        check(&disc, true);
+    }
+
+    #[test]
+    fn write_back() {
+        let path = env::temp_dir().join(format!("webarc-hfe-{}.hfe", std::process::id()));
+
+        for &double_density in &[true, false] {
+            fs::write(&path, image(&track(double_density, &sectors()))).unwrap();
+
+            // Rewrite the bad sector as deleted data, which fixes its CRC
+            let replacement = contents(9, 256);
+            {
+                let mut disc = HfeImage::open(&path, false).unwrap();
+                assert!(disc.read_sector(0, 0, double_density, 2).unwrap().crc_error);
+                disc.write_sector(0, 0, double_density, 2, &replacement, true);
+            }
+
+            let disc = HfeImage::open(&path, false).unwrap();
+            let read = disc.read_sector(0, 0, double_density, 2).unwrap();
+            assert_eq!(read.data, replacement);
+            assert!(read.deleted);
+            assert!(!read.crc_error);
+
+            // Nothing else has moved
+            assert_eq!(disc.sector_ids(0, 0, double_density).len(), 5);
+            for &index in &[0, 1, 4] {
+                let read = disc.read_sector(0, 0, double_density, index).unwrap();
+                assert_eq!(read.data, contents(index as u8, read.data.len()));
+                assert!(!read.crc_error);
+            }
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1048 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::ops::Shr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use webarc::memory::Memory;
+use webarc::podule::{Chunk, ExtendedId, RomPodule};
+use webarc::registers::RegisterFile;
+
+// HostFS: a filing system that maps a host directory into RISC OS. A podule
+// carries a small relocatable module which registers the filing system with
+// FileSwitch, and whose entry points are each a SWI in a chunk that the
+// emulator traps instead of passing to RISC OS. RISC OS filetypes are kept in
+// `,xxx` suffixes on host filenames, and untyped load and exec addresses in
+// `,llllllll-eeeeeeee` suffixes.
+//
+// Memory is read and written directly rather than through the bus, so a
+// buffer anywhere but RAM is an error rather than an access to I/O.
+
+pub const SWI_BASE: u32 = 0x55900;
+const SWI_X_BIT: u32 = 0x20000;
+
+const OPEN: u32 = 0;
+const GET_BYTES: u32 = 1;
+const PUT_BYTES: u32 = 2;
+const ARGS: u32 = 3;
+const CLOSE: u32 = 4;
+const FILE: u32 = 5;
+const FUNC: u32 = 6;
+const ENTRY_POINTS: u32 = 7;
+
+const FS_NUMBER: u32 = 0x99;
+const FS_NAME: &str = "HostFS";
+const HELP: &str = "HostFS\t\t1.00 (18 Oct 2026)";
+
+// Module layout: header, filing system information block, the entry points
+// (a SWI and a return each), initialisation and finalisation code, strings,
+// and a buffer for error blocks, which works because RISC OS copies podule
+// modules into RAM
+const FS_INFORMATION: usize = 0x2c;
+const ENTRIES: usize = 0x5c;
+const INIT: usize = 0x94;
+const FINAL: usize = 0xb0;
+const TITLE: usize = 0xc8;
+const HELP_STRING: usize = 0xd0;
+const ERROR_BUFFER: usize = 0x100;
+const MODULE_SIZE: usize = 0x200;
+
+const OS_FSCONTROL: u32 = 0x29;
+const FSCONTROL_ADD_FS: u32 = 12;
+const FSCONTROL_REMOVE_FS: u32 = 16;
+
+// Chunk directory entry type for a relocatable module
+const OS_IDENTITY_MODULE: u8 = 0x81;
+
+const V_BIT: u32 = 0x10000000;
+
+// Files are buffered by FileSwitch in blocks of this size
+const BUFFER_SIZE: u32 = 512;
+
+const OBJECT_NONE: u32 = 0;
+const OBJECT_FILE: u32 = 1;
+const OBJECT_DIRECTORY: u32 = 2;
+
+const ATTRIBUTE_OWNER_READ: u32 = 1 << 0;
+const ATTRIBUTE_OWNER_WRITE: u32 = 1 << 1;
+const ATTRIBUTE_PUBLIC_READ: u32 = 1 << 4;
+const ATTRIBUTE_PUBLIC_WRITE: u32 = 1 << 5;
+
+const OPEN_WRITE: u32 = 1 << 31;
+const OPEN_READ: u32 = 1 << 30;
+
+const FILETYPE_DATA: u32 = 0xffd;
+
+// Centiseconds between 1st January 1900 and the Unix epoch
+const EPOCH_1970: u64 = 2208988800 * 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FsError {
+    NotFound,
+    Exists,
+    DirectoryNotEmpty,
+    BadName,
+    // The host won't let us
+    AccessViolation,
+    // Anything else the host reported, which is worth telling the user about
+    Disc(io::ErrorKind),
+    NotSupported,
+    BadParameters,
+}
+
+impl FsError {
+    fn number(&self) -> u32 {
+        let code = match *self {
+            FsError::NotFound => 0xd6,
+            FsError::Exists => 0xc4,
+            FsError::DirectoryNotEmpty => 0xb4,
+            FsError::BadName => 0xcc,
+            FsError::AccessViolation => 0xbd,
+            FsError::Disc(_) => 0xc7,
+            FsError::NotSupported => 0xa5,
+            FsError::BadParameters => 0xbf,
+        };
+        0x10000 | (FS_NUMBER << 8) | code
+    }
+
+    fn message(&self) -> &'static str {
+        match *self {
+            FsError::NotFound => "Not found",
+            FsError::Exists => "Already exists",
+            FsError::DirectoryNotEmpty => "Directory not empty",
+            FsError::BadName => "Bad name",
+            FsError::AccessViolation => "Access violation",
+            FsError::Disc(_) => "Host file error",
+            FsError::NotSupported => "Bad FS function",
+            FsError::BadParameters => "Bad parameters",
+        }
+    }
+}
+
+impl From<io::Error> for FsError {
+    fn from(error: io::Error) -> FsError {
+        match error.kind() {
+            io::ErrorKind::NotFound => FsError::NotFound,
+            io::ErrorKind::AlreadyExists => FsError::Exists,
+            io::ErrorKind::PermissionDenied => FsError::AccessViolation,
+            kind => FsError::Disc(kind)
+        }
+    }
+}
+
+type FsResult = Result<(), FsError>;
+
+struct CatalogueInfo {
+    object_type: u32,
+    load: u32,
+    exec: u32,
+    length: u32,
+    attributes: u32,
+}
+
+struct OpenFile {
+    file: File,
+    path: PathBuf,
+}
+
+pub struct HostFs {
+    root: PathBuf,
+    files: HashMap<u32, OpenFile>,
+    next_handle: u32,
+}
+
+impl HostFs {
+    pub fn new<P: AsRef<Path>>(root: P) -> HostFs {
+        HostFs {
+            root: root.as_ref().to_path_buf(),
+            files: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    // Handle a SWI if it is one of the module's entry points, returning
+    // whether it was
+    pub fn swi(&mut self, comment: u32, registers: &mut RegisterFile, memory: &mut Memory) -> bool {
+        let operation = (comment & !SWI_X_BIT).wrapping_sub(SWI_BASE);
+        if operation >= ENTRY_POINTS {
+            return false;
+        }
+
+        // Find the module from the entry point the SWI was called from. The
+        // SWI numbers are ours, but anything could call them.
+        let pc = registers.reg_no_flags(15).wrapping_sub(8);
+        let module = match entry_module(memory, pc, operation) {
+            Some(module) => module,
+            None => return false
+        };
+
+        let result = match operation {
+            OPEN => self.open(registers, memory),
+            GET_BYTES => self.get_bytes(registers, memory),
+            PUT_BYTES => self.put_bytes(registers, memory),
+            ARGS => self.args(registers, memory),
+            CLOSE => self.close(registers),
+            FILE => self.file(registers, memory),
+            _ => self.func(registers, memory),
+        };
+
+        let r15 = registers.reg(15);
+        match result {
+            Ok(()) => registers.set_reg(15, r15 & !V_BIT),
+            Err(error) => {
+                if let FsError::Disc(kind) = error {
+                    println!("HostFS error: {}", io::Error::from(kind));
+                }
+                let block = module + ERROR_BUFFER as u32;
+                let mut bytes = error.number().to_le_bytes().to_vec();
+                bytes.extend_from_slice(error.message().as_bytes());
+                bytes.push(0);
+                // RISC OS always copies the module into RAM, so this works
+                let _ = store_bytes(memory, block, &bytes);
+                registers.set_reg(0, block);
+                registers.set_reg(15, r15 | V_BIT);
+            }
+        }
+        true
+    }
+
+    // FSEntry_Open
+
+    fn open(&mut self, registers: &mut RegisterFile, memory: &mut Memory) -> FsResult {
+        let reason = registers.reg(0);
+        let name = load_string(memory, registers.reg(1))?;
+
+        let (file, path) = if reason == 1 {
+            self.remove_file(&name)?;
+            let path = self.new_path(&name, FILETYPE_DATA)?;
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+            (file, path)
+        } else {
+            match self.find(&name)? {
+                Some(ref path) if path.is_file() => {
+                    let file = match OpenOptions::new().read(true).write(reason == 2).open(path) {
+                        Ok(file) => file,
+                        Err(ref error) if error.kind() == io::ErrorKind::PermissionDenied => File::open(path)?,
+                        Err(error) => return Err(error.into())
+                    };
+                    (file, path.clone())
+                },
+                _ => {
+                    // Not found is a handle of 0, not an error
+                    registers.set_reg(1, 0);
+                    return Ok(());
+                }
+            }
+        };
+
+        let writable = reason != 0 && !file.metadata()?.permissions().readonly();
+        let extent = file.metadata()?.len() as u32;
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.files.insert(handle, OpenFile { file, path });
+
+        registers.set_reg(0, OPEN_READ | if writable { OPEN_WRITE } else { 0 });
+        registers.set_reg(1, handle);
+        registers.set_reg(2, BUFFER_SIZE);
+        registers.set_reg(3, extent);
+        registers.set_reg(4, allocated(extent));
+        Ok(())
+    }
+
+    fn open_file(&mut self, handle: u32) -> Result<&mut OpenFile, FsError> {
+        self.files.get_mut(&handle).ok_or(FsError::NotFound)
+    }
+
+    // FSEntry_GetBytes and FSEntry_PutBytes, for buffered files
+
+    fn get_bytes(&mut self, registers: &mut RegisterFile, memory: &mut Memory) -> FsResult {
+        let (address, count, offset) = (registers.reg(2), registers.reg(3), registers.reg(4));
+        check_length(memory, count)?;
+        let file = &mut self.open_file(registers.reg(1))?.file;
+
+        let mut data = vec![0; count as usize];
+        file.seek(SeekFrom::Start(offset as u64))?;
+        let mut filled = 0;
+        while filled < data.len() {
+            match file.read(&mut data[filled..])? {
+                0 => break,
+                read => filled += read
+            }
+        }
+
+        store_bytes(memory, address, &data)
+    }
+
+    fn put_bytes(&mut self, registers: &mut RegisterFile, memory: &mut Memory) -> FsResult {
+        let (address, count, offset) = (registers.reg(2), registers.reg(3), registers.reg(4));
+        let data = load_bytes(memory, address, count)?;
+        let file = &mut self.open_file(registers.reg(1))?.file;
+
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.write_all(&data)?;
+        Ok(())
+    }
+
+    // FSEntry_Args
+
+    fn args(&mut self, registers: &mut RegisterFile, memory: &mut Memory) -> FsResult {
+        let reason = registers.reg(0);
+        let (r2, r3) = (registers.reg(2), registers.reg(3));
+        let open_file = self.open_file(registers.reg(1))?;
+        let length = open_file.file.metadata()?.len() as u32;
+
+        match reason {
+            // Write extent
+            3 => open_file.file.set_len(r2 as u64)?,
+            // Read allocated size
+            4 => registers.set_reg(2, allocated(length)),
+            // Flush, and read the date stamp
+            6 | 9 => {
+                open_file.file.flush()?;
+                let info = catalogue_info(&open_file.path)?;
+                registers.set_reg(2, info.load);
+                registers.set_reg(3, info.exec);
+            },
+            // Ensure size
+            7 => {
+                if r2 > length {
+                    open_file.file.set_len(r2 as u64)?;
+                }
+                registers.set_reg(2, allocated(r2.max(length)));
+            },
+            // Write zeros
+            8 => {
+                check_length(memory, r3)?;
+                open_file.file.seek(SeekFrom::Start(r2 as u64))?;
+                open_file.file.write_all(&vec![0; r3 as usize])?;
+            },
+            // Image stamp
+            10 => {},
+            _ => return Err(FsError::NotSupported)
+        }
+        Ok(())
+    }
+
+    // FSEntry_Close
+
+    fn close(&mut self, registers: &mut RegisterFile) -> FsResult {
+        let (load, exec) = (registers.reg(2), registers.reg(3));
+        let open_file = self.files.remove(&registers.reg(1)).ok_or(FsError::NotFound)?;
+        drop(open_file.file);
+
+        if load != 0 || exec != 0 {
+            set_load_exec(&open_file.path, load, exec)?;
+        }
+        Ok(())
+    }
+
+    // FSEntry_File
+
+    fn file(&mut self, registers: &mut RegisterFile, memory: &mut Memory) -> FsResult {
+        let reason = registers.reg(0);
+        let name = load_string(memory, registers.reg(1))?;
+        let (r2, r3, r4, r5) = (registers.reg(2), registers.reg(3), registers.reg(4), registers.reg(5));
+
+        match reason {
+            // Save
+            0 => {
+                let data = load_bytes(memory, r4, block_length(memory, r4, r5)?)?;
+                self.save(&name, r2, r3, &data)
+            },
+            // Write catalogue information, load address, exec address
+            1..=3 => {
+                let path = self.find(&name)?.ok_or(FsError::NotFound)?;
+                let info = catalogue_info(&path)?;
+                let load = if reason == 3 { info.load } else { r2 };
+                let exec = if reason == 2 { info.exec } else { r3 };
+                let path = set_load_exec(&path, load, exec)?;
+                if reason == 1 {
+                    set_attributes(&path, r5)?;
+                }
+                Ok(())
+            },
+            // Write attributes
+            4 => {
+                let path = self.find(&name)?.ok_or(FsError::NotFound)?;
+                set_attributes(&path, r5)
+            },
+            // Read catalogue information
+            5 => {
+                let info = match self.find(&name)? {
+                    Some(path) => catalogue_info(&path)?,
+                    None => CatalogueInfo { object_type: OBJECT_NONE, load: 0, exec: 0, length: 0, attributes: 0 }
+                };
+                set_catalogue_info(registers, &info);
+                Ok(())
+            },
+            // Delete
+            6 => {
+                let info = match self.find(&name)? {
+                    Some(path) => {
+                        let info = catalogue_info(&path)?;
+                        if path.is_dir() {
+                            fs::remove_dir(&path).map_err(|_| FsError::DirectoryNotEmpty)?;
+                        } else {
+                            fs::remove_file(&path)?;
+                        }
+                        info
+                    },
+                    None => CatalogueInfo { object_type: OBJECT_NONE, load: 0, exec: 0, length: 0, attributes: 0 }
+                };
+                set_catalogue_info(registers, &info);
+                Ok(())
+            },
+            // Create an empty file
+            7 => {
+                let length = block_length(memory, r4, r5)?;
+                self.save(&name, r2, r3, &vec![0; length as usize])
+            },
+            // Create directory
+            8 => {
+                if let Some(path) = self.find(&name)? {
+                    return if path.is_dir() { Ok(()) } else { Err(FsError::Exists) };
+                }
+                let path = self.new_path(&name, FILETYPE_DATA)?;
+                fs::create_dir(path)?;
+                Ok(())
+            },
+            // Load
+            255 => {
+                let path = match self.find(&name)? {
+                    Some(ref path) if path.is_file() => path.clone(),
+                    _ => return Err(FsError::NotFound)
+                };
+                let mut data = Vec::new();
+                File::open(&path)?.read_to_end(&mut data)?;
+                store_bytes(memory, r2, &data)?;
+
+                let info = catalogue_info(&path)?;
+                let name_pointer = registers.reg(1);
+                set_catalogue_info(registers, &info);
+                registers.set_reg(6, name_pointer);
+                Ok(())
+            },
+            _ => Err(FsError::NotSupported)
+        }
+    }
+
+    fn save(&mut self, name: &str, load: u32, exec: u32, data: &[u8]) -> FsResult {
+        self.remove_file(name)?;
+        let path = self.new_path(name, FILETYPE_DATA)?;
+        File::create(&path)?.write_all(data)?;
+        set_load_exec(&path, load, exec)?;
+        Ok(())
+    }
+
+    // Make way for a new file by deleting any existing one, whatever its type
+    fn remove_file(&self, name: &str) -> FsResult {
+        if let Some(path) = self.find(name)? {
+            if path.is_dir() {
+                return Err(FsError::Exists);
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    // FSEntry_Func
+
+    fn func(&mut self, registers: &mut RegisterFile, memory: &mut Memory) -> FsResult {
+        let reason = registers.reg(0);
+
+        match reason {
+            // Rename
+            8 => {
+                let from = load_string(memory, registers.reg(1))?;
+                let to = load_string(memory, registers.reg(2))?;
+                let source = self.find(&from)?.ok_or(FsError::NotFound)?;
+                if self.find(&to)?.is_some_and(|path| path != source) {
+                    return Err(FsError::Exists);
+                }
+
+                // Keep the type suffix with the file
+                let suffix = split_suffix(&host_name(&source)).1.to_owned();
+                let destination = self.new_path(&to, FILETYPE_DATA)?;
+                let destination = destination.with_file_name(format!("{}{}", host_name(&destination), suffix));
+                fs::rename(source, destination)?;
+                registers.set_reg(1, 0);
+                Ok(())
+            },
+            // Access, boot and shutdown need nothing doing
+            9 | 10 => Ok(()),
+            16 => {
+                self.files.clear();
+                Ok(())
+            },
+            // Read the name and boot option of the disc
+            11 => {
+                let mut name = vec![FS_NAME.len() as u8];
+                name.extend_from_slice(FS_NAME.as_bytes());
+                name.push(0);
+                store_bytes(memory, registers.reg(2), &name)
+            },
+            // Read current and library directory names
+            12 | 13 => store_bytes(memory, registers.reg(2), &[0, 1, b'$']),
+            // Read directory entries, with or without their information
+            14 | 15 => self.read_directory(reason == 15, registers, memory),
+            _ => Err(FsError::NotSupported)
+        }
+    }
+
+    fn read_directory(&mut self, with_info: bool, registers: &mut RegisterFile, memory: &mut Memory) -> FsResult {
+        let name = load_string(memory, registers.reg(1))?;
+        let (buffer, wanted, start, buffer_size) = (registers.reg(2), registers.reg(3), registers.reg(4), registers.reg(5));
+
+        let directory = self.find(&name)?.ok_or(FsError::NotFound)?;
+        let mut entries: Vec<PathBuf> = fs::read_dir(&directory)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect();
+        entries.sort();
+
+        let mut output = Vec::new();
+        let mut read = 0;
+        let mut next = start as usize;
+        while next < entries.len() && read < wanted {
+            let path = &entries[next];
+            let mut record = Vec::new();
+            if with_info {
+                let info = catalogue_info(path)?;
+                for word in &[info.load, info.exec, info.length, info.attributes, info.object_type] {
+                    record.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+            record.extend_from_slice(riscos_name(&host_name(path)).as_bytes());
+            record.push(0);
+            if with_info {
+                while record.len() % 4 != 0 {
+                    record.push(0);
+                }
+            }
+
+            if output.len() + record.len() > buffer_size as usize {
+                break;
+            }
+            output.extend_from_slice(&record);
+            read += 1;
+            next += 1;
+        }
+
+        store_bytes(memory, buffer, &output)?;
+        registers.set_reg(3, read);
+        registers.set_reg(4, if next >= entries.len() { 0xffffffff } else { next as u32 });
+        Ok(())
+    }
+
+    // Names
+
+    // Host path of an existing object, matching each component without regard
+    // to case or type suffix
+    fn find(&self, name: &str) -> Result<Option<PathBuf>, FsError> {
+        let mut path = self.root.clone();
+        for component in components(name)? {
+            match find_entry(&path, &component)? {
+                Some(entry) => path = entry,
+                None => return Ok(None)
+            }
+        }
+        Ok(Some(path))
+    }
+
+    // Host path for a new object, in an existing directory
+    fn new_path(&self, name: &str, filetype: u32) -> Result<PathBuf, FsError> {
+        let mut components = components(name)?;
+        let leaf = components.pop().ok_or(FsError::BadName)?;
+        let parent = self.find(&components.join("."))?.ok_or(FsError::NotFound)?;
+        if !parent.is_dir() {
+            return Err(FsError::NotFound);
+        }
+
+        Ok(parent.join(format!("{}{}", host_leaf(&leaf), type_suffix(filetype))))
+    }
+}
+
+// The module in the podule's ROM, as a card with an extended ID
+pub fn podule() -> RomPodule {
+    let id = ExtendedId {
+        product: 0,
+        manufacturer: 0,
+        country: 0,
+        chunks: vec![Chunk { os_identity: OS_IDENTITY_MODULE, data: module() }],
+    };
+    RomPodule::new(id.bytes())
+}
+
+fn module() -> Vec<u8> {
+    let mut module = vec![0; MODULE_SIZE];
+
+    let mut put = |offset: usize, word: u32| module[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+
+    // Header
+    put(0x04, INIT as u32);
+    put(0x08, FINAL as u32);
+    put(0x10, TITLE as u32);
+    put(0x14, HELP_STRING as u32);
+
+    // Filing system information block, with no OS_GBPB entry so FileSwitch
+    // uses GetBytes and PutBytes
+    let entry = |operation: u32| (ENTRIES as u32) + operation * 8;
+    put(FS_INFORMATION, TITLE as u32);
+    put(FS_INFORMATION + 0x04, TITLE as u32);
+    put(FS_INFORMATION + 0x08, entry(OPEN));
+    put(FS_INFORMATION + 0x0c, entry(GET_BYTES));
+    put(FS_INFORMATION + 0x10, entry(PUT_BYTES));
+    put(FS_INFORMATION + 0x14, entry(ARGS));
+    put(FS_INFORMATION + 0x18, entry(CLOSE));
+    put(FS_INFORMATION + 0x1c, entry(FILE));
+    put(FS_INFORMATION + 0x20, FS_NUMBER);
+    put(FS_INFORMATION + 0x24, entry(FUNC));
+
+    // SWI HostFS_Op; MOV PC, R14, keeping the V flag the trap sets
+    for operation in 0..ENTRY_POINTS {
+        put(entry(operation) as usize, 0xef000000 | (SWI_BASE + operation));
+        put(entry(operation) as usize + 4, 0xe1a0f00e);
+    }
+
+    // STMFD R13!, {R14}; MOV R0, #AddFS; SUB R1, PC, #(to module start);
+    // MOV R2, #(information block); MOV R3, #0; SWI XOS_FSControl;
+    // LDMFD R13!, {PC}
+    let init = [
+        0xe92d4000,
+        0xe3a00000 | FSCONTROL_ADD_FS,
+        0xe24f1000 | (INIT as u32 + 8 + 8),
+        0xe3a02000 | FS_INFORMATION as u32,
+        0xe3a03000,
+        0xef000000 | SWI_X_BIT | OS_FSCONTROL,
+        0xe8bd8000,
+    ];
+
+    // STMFD R13!, {R14}; MOV R0, #RemoveFS; ADD R1, PC, #(to title);
+    // SWI XOS_FSControl; CMP R0, R0 to clear V; LDMFD R13!, {PC}
+    let finalise = [
+        0xe92d4000,
+        0xe3a00000 | FSCONTROL_REMOVE_FS,
+        0xe28f1000 | (TITLE - (FINAL + 8 + 8)) as u32,
+        0xef000000 | SWI_X_BIT | OS_FSCONTROL,
+        0xe1500000,
+        0xe8bd8000,
+    ];
+
+    for (index, word) in init.iter().enumerate() {
+        put(INIT + index * 4, *word);
+    }
+    for (index, word) in finalise.iter().enumerate() {
+        put(FINAL + index * 4, *word);
+    }
+
+    module[TITLE..TITLE + FS_NAME.len()].copy_from_slice(FS_NAME.as_bytes());
+    module[HELP_STRING..HELP_STRING + HELP.len()].copy_from_slice(HELP.as_bytes());
+    module
+}
+
+fn components(name: &str) -> Result<Vec<String>, FsError> {
+    let name = name.trim_start_matches('$').trim_start_matches('.');
+    if name.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let components: Vec<String> = name.split('.').map(|component| component.to_owned()).collect();
+    if components.iter().any(|component| component.is_empty() || component == "^" || component == "@") {
+        return Err(FsError::BadName);
+    }
+    Ok(components)
+}
+
+fn find_entry(directory: &Path, leaf: &str) -> Result<Option<PathBuf>, FsError> {
+    if !directory.is_dir() {
+        return Ok(None);
+    }
+
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if riscos_name(&host_name(&path)).eq_ignore_ascii_case(leaf) {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+fn host_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+// RISC OS uses '/' where hosts use '.', and the other way round
+fn riscos_name(host_name: &str) -> String {
+    split_suffix(host_name).0.replace('.', "/")
+}
+
+fn host_leaf(riscos_name: &str) -> String {
+    riscos_name.replace('/', ".")
+}
+
+// Split a host name into the name and its suffix, if it has a valid one
+fn split_suffix(name: &str) -> (&str, &str) {
+    if let Some(comma) = name.rfind(',') {
+        let suffix = &name[comma + 1..];
+        let hex = |text: &str, length: usize| text.len() == length && text.chars().all(|c| c.is_ascii_hexdigit());
+
+        let typed = hex(suffix, 3);
+        let load_exec = suffix.len() == 17 && hex(&suffix[..8], 8) && &suffix[8..9] == "-" && hex(&suffix[9..], 8);
+        if typed || load_exec {
+            return (&name[..comma], &name[comma..]);
+        }
+    }
+    (name, "")
+}
+
+fn type_suffix(filetype: u32) -> String {
+    if filetype == FILETYPE_DATA {
+        String::new()
+    } else {
+        format!(",{:03x}", filetype)
+    }
+}
+
+fn catalogue_info(path: &Path) -> Result<CatalogueInfo, FsError> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let stamp = modified.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis() as u64 / 10) + EPOCH_1970;
+
+    let name = host_name(path);
+    let suffix = split_suffix(&name).1;
+    let (load, exec) = if suffix.len() == 18 {
+        (u32::from_str_radix(&suffix[1..9], 16).unwrap_or(0), u32::from_str_radix(&suffix[10..], 16).unwrap_or(0))
+    } else {
+        let filetype = if suffix.len() == 4 { u32::from_str_radix(&suffix[1..], 16).unwrap_or(FILETYPE_DATA) } else { FILETYPE_DATA };
+        (0xfff00000 | (filetype << 8) | ((stamp >> 32) as u32 & 0xff), stamp as u32)
+    };
+
+    let readonly = metadata.permissions().readonly();
+    let attributes = ATTRIBUTE_OWNER_READ | ATTRIBUTE_PUBLIC_READ
+        | if readonly { 0 } else { ATTRIBUTE_OWNER_WRITE | ATTRIBUTE_PUBLIC_WRITE };
+
+    Ok(CatalogueInfo {
+        object_type: if metadata.is_dir() { OBJECT_DIRECTORY } else { OBJECT_FILE },
+        load,
+        exec,
+        length: metadata.len() as u32,
+        attributes,
+    })
+}
+
+fn set_catalogue_info(registers: &mut RegisterFile, info: &CatalogueInfo) {
+    registers.set_reg(0, info.object_type);
+    registers.set_reg(2, info.load);
+    registers.set_reg(3, info.exec);
+    registers.set_reg(4, info.length);
+    registers.set_reg(5, info.attributes);
+}
+
+// Give a file a new type or load and exec address by renaming it, and carry
+// a typed file's date stamp over to its modification time
+fn set_load_exec(path: &Path, load: u32, exec: u32) -> Result<PathBuf, FsError> {
+    if path.is_dir() {
+        return Ok(path.to_path_buf());
+    }
+
+    let name = host_name(path);
+    let base = split_suffix(&name).0;
+
+    let typed = load & 0xfff00000 == 0xfff00000;
+    let suffix = if typed {
+        type_suffix((load >> 8) & 0xfff)
+    } else {
+        format!(",{:08x}-{:08x}", load, exec)
+    };
+
+    let new_path = path.with_file_name(format!("{}{}", base, suffix));
+    if new_path != path {
+        fs::rename(path, &new_path)?;
+    }
+
+    if typed {
+        let stamp = ((load as u64 & 0xff) << 32) | exec as u64;
+        if stamp >= EPOCH_1970 {
+            let time = UNIX_EPOCH + Duration::from_millis((stamp - EPOCH_1970) * 10);
+            set_modified(&new_path, time)?;
+        }
+    }
+    Ok(new_path)
+}
+
+fn set_modified(path: &Path, time: SystemTime) -> FsResult {
+    OpenOptions::new().write(true).open(path)
+        .and_then(|file| file.set_modified(time))
+        .or_else(|error| if error.kind() == io::ErrorKind::PermissionDenied { Ok(()) } else { Err(error) })?;
+    Ok(())
+}
+
+fn set_attributes(path: &Path, attributes: u32) -> FsResult {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(attributes & ATTRIBUTE_OWNER_WRITE == 0);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+fn allocated(length: u32) -> u32 {
+    (length + BUFFER_SIZE - 1) & !(BUFFER_SIZE - 1)
+}
+
+// Access to emulated memory
+
+// The module an entry point belongs to, if the SWI at the PC is one in a
+// HostFS module's table of entry points
+fn entry_module(memory: &Memory, pc: u32, operation: u32) -> Option<u32> {
+    let module = pc.wrapping_sub(ENTRIES as u32 + operation * 8);
+    let entry = |operation: u32| ENTRIES as u32 + operation * 8;
+
+    let instruction = memory.peek(pc)?;
+    let information = memory.peek(module.wrapping_add(FS_INFORMATION as u32 + 0x08))?;
+    let number = memory.peek(module.wrapping_add(FS_INFORMATION as u32 + 0x20))?;
+    let valid = pc & 3 == 0
+        && instruction & 0x0fffffff == 0x0f000000 | (SWI_BASE + operation)
+        && information == entry(OPEN)
+        && number == FS_NUMBER;
+    if valid { Some(module) } else { None }
+}
+
+fn peek_byte(memory: &Memory, address: u32) -> Result<u8, FsError> {
+    let word = memory.peek(address).ok_or(FsError::BadParameters)?;
+    Ok(word.shr((address & 3) * 8) as u8)
+}
+
+// Lengths of data passed in memory can't be more than there is RAM
+fn check_length(memory: &Memory, length: u32) -> FsResult {
+    if length as usize > memory.ram_size() { Err(FsError::BadParameters) } else { Ok(()) }
+}
+
+// The length of a block of memory given by its start and end
+fn block_length(memory: &Memory, start: u32, end: u32) -> Result<u32, FsError> {
+    let length = end.checked_sub(start).ok_or(FsError::BadParameters)?;
+    check_length(memory, length)?;
+    Ok(length)
+}
+
+// Names longer than this are taken to be garbage
+const MAX_STRING: u32 = 1024;
+
+fn load_string(memory: &Memory, address: u32) -> Result<String, FsError> {
+    let mut bytes = Vec::new();
+    for offset in 0..MAX_STRING {
+        let byte = peek_byte(memory, address.wrapping_add(offset))?;
+        if byte < 0x20 {
+            return Ok(String::from_utf8_lossy(&bytes).into_owned());
+        }
+        bytes.push(byte);
+    }
+    Err(FsError::BadName)
+}
+
+fn load_bytes(memory: &Memory, address: u32, count: u32) -> Result<Vec<u8>, FsError> {
+    check_length(memory, count)?;
+    (0..count).map(|offset| peek_byte(memory, address.wrapping_add(offset))).collect()
+}
+
+fn store_bytes(memory: &mut Memory, address: u32, data: &[u8]) -> FsResult {
+    for (offset, byte) in data.iter().enumerate() {
+        if !memory.poke_byte(address.wrapping_add(offset as u32), *byte) {
+            return Err(FsError::BadParameters);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // An empty directory of its own for each test
+    fn directory(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("webarc-hostfs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        path
+    }
+
+    fn names(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory).unwrap().map(|entry| host_name(&entry.unwrap().path())).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(split_suffix("Prog,ff8"), ("Prog", ",ff8"));
+        assert_eq!(split_suffix("Prog,FF8"), ("Prog", ",FF8"));
+        assert_eq!(split_suffix("Code,00008000-0000801c"), ("Code", ",00008000-0000801c"));
+        assert_eq!(split_suffix("a,b,fff"), ("a,b", ",fff"));
+
+        // Not quite suffixes
+        for &name in &["Plain", "Text,ff", "Text,ffff", "Text,xyz", "Code,00008000_0000801c", "Code,0008000-0000801c", "Trailing,"] {
+            assert_eq!(split_suffix(name), (name, ""));
+        }
+        assert_eq!(riscos_name("read.me,fff"), "read/me");
+    }
+
+    #[test]
+    fn host_errors() {
+        let error = |kind| FsError::from(io::Error::from(kind));
+        assert_eq!(error(io::ErrorKind::NotFound), FsError::NotFound);
+        assert_eq!(error(io::ErrorKind::AlreadyExists), FsError::Exists);
+        assert_eq!(error(io::ErrorKind::PermissionDenied), FsError::AccessViolation);
+        assert_eq!(error(io::ErrorKind::PermissionDenied).message(), "Access violation");
+        assert_eq!(error(io::ErrorKind::PermissionDenied).number() & 0xff, 0xbd);
+        assert_eq!(error(io::ErrorKind::UnexpectedEof), FsError::Disc(io::ErrorKind::UnexpectedEof));
+        assert_eq!(error(io::ErrorKind::UnexpectedEof).message(), "Host file error");
+    }
+
+    #[test]
+    fn catalogue() {
+        let directory = directory("catalogue");
+        fs::write(directory.join("Prog,ff8"), [0; 100]).unwrap();
+        fs::write(directory.join("Code,00008000-0000801c"), [0; 28]).unwrap();
+        fs::write(directory.join("Plain"), []).unwrap();
+        fs::create_dir(directory.join("Dir")).unwrap();
+
+        let info = catalogue_info(&directory.join("Prog,ff8")).unwrap();
+        assert_eq!(info.object_type, OBJECT_FILE);
+        assert_eq!(info.load & 0xffffff00, 0xfffff800);
+        assert_eq!(info.length, 100);
+        assert_eq!(info.attributes, ATTRIBUTE_OWNER_READ | ATTRIBUTE_OWNER_WRITE | ATTRIBUTE_PUBLIC_READ | ATTRIBUTE_PUBLIC_WRITE);
+
+        let info = catalogue_info(&directory.join("Code,00008000-0000801c")).unwrap();
+        assert_eq!((info.load, info.exec, info.length), (0x8000, 0x801c, 28));
+
+        let info = catalogue_info(&directory.join("Plain")).unwrap();
+        assert_eq!(info.load & 0xffffff00, 0xfffffd00);
+
+        assert_eq!(catalogue_info(&directory.join("Dir")).unwrap().object_type, OBJECT_DIRECTORY);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn retyping() {
+        let directory = directory("retype");
+        let path = directory.join("File,ffb");
+        fs::write(&path, b"data").unwrap();
+
+        // A new type renames the file, and the date stamp becomes its
+        // modification time: 1st January 2000
+        let stamp = EPOCH_1970 + 946684800 * 100;
+        let path = set_load_exec(&path, 0xfff00000 | 0xff8 << 8 | (stamp >> 32) as u32, stamp as u32).unwrap();
+        assert_eq!(names(&directory), ["File,ff8"]);
+        let info = catalogue_info(&path).unwrap();
+        assert_eq!(info.load, 0xfffff800 | (stamp >> 32) as u32);
+        assert_eq!(info.exec, stamp as u32);
+
+        // Untyped, then back to plain data, which has no suffix
+        let path = set_load_exec(&path, 0x8000, 0x801c).unwrap();
+        assert_eq!(names(&directory), ["File,00008000-0000801c"]);
+        let path = set_load_exec(&path, 0xfffffd00, 0).unwrap();
+        assert_eq!(names(&directory), ["File"]);
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+
+        // Directories keep their names
+        fs::create_dir(directory.join("Dir")).unwrap();
+        set_load_exec(&directory.join("Dir"), 0xfffff800, 0).unwrap();
+        assert_eq!(names(&directory), ["Dir", "File"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // Where the module and the data passed to it go in RAM
+    const MODULE: u32 = 0x02010000;
+    const NAME: u32 = 0x02000100;
+    const BUFFER: u32 = 0x02000200;
+
+    fn machine() -> (Memory, RegisterFile) {
+        let mut memory = Memory::new(vec![0; 1024].into_boxed_slice(), 512 * 1024);
+        for (offset, byte) in module().iter().enumerate() {
+            memory.poke_byte(MODULE + offset as u32, *byte);
+        }
+        (memory, RegisterFile::new())
+    }
+
+    // Call an entry point the way FileSwitch would, returning whether it was
+    // taken as a HostFS SWI and the error number if there was one
+    fn call(hostfs: &mut HostFs, memory: &mut Memory, registers: &mut RegisterFile, operation: u32, name: &str) -> (bool, Option<u32>) {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.push(0);
+        store_bytes(memory, NAME, &bytes).unwrap();
+        registers.set_reg(1, NAME);
+
+        registers.set_reg(15, 0x0c000003 | (MODULE + ENTRIES as u32 + operation * 8 + 8));
+        let handled = hostfs.swi(SWI_BASE + operation, registers, memory);
+        if registers.reg(15) & V_BIT == 0 {
+            return (handled, None);
+        }
+        let error = registers.reg(0);
+        (handled, memory.peek(error))
+    }
+
+    #[test]
+    fn entry_points() {
+        let directory = directory("entries");
+        let mut hostfs = HostFs::new(&directory);
+        let (mut memory, mut registers) = machine();
+
+        // Reading catalogue information for something that isn't there
+        registers.set_reg(0, 5);
+        assert_eq!(call(&mut hostfs, &mut memory, &mut registers, FILE, "Missing"), (true, None));
+        assert_eq!(registers.reg(0), OBJECT_NONE);
+
+        // The same SWI from anywhere else is left to RISC OS
+        registers.set_reg(15, 0x0c000003 | (BUFFER + 8));
+        assert!(!hostfs.swi(SWI_BASE + FILE, &mut registers, &mut memory));
+        registers.set_reg(15, 0x0c000003 | (MODULE + ENTRIES as u32 + 8));
+        assert!(!hostfs.swi(SWI_BASE + FILE, &mut registers, &mut memory));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn create_replaces_typed_files() {
+        let directory = directory("create");
+        fs::write(directory.join("Text,fff"), b"old").unwrap();
+        let mut hostfs = HostFs::new(&directory);
+        let (mut memory, mut registers) = machine();
+
+        registers.set_reg(0, 1);
+        assert_eq!(call(&mut hostfs, &mut memory, &mut registers, OPEN, "text"), (true, None));
+        assert_eq!(names(&directory), ["text"]);
+        assert_eq!(registers.reg(3), 0);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn bad_blocks() {
+        let directory = directory("blocks");
+        fs::write(directory.join("Data"), [1; 64]).unwrap();
+        let mut hostfs = HostFs::new(&directory);
+        let (mut memory, mut registers) = machine();
+        let bad_parameters = Some(FsError::BadParameters.number());
+
+        // Save and create with the end before the start, or more than RAM
+        for &(reason, start, end) in &[(0, BUFFER + 4, BUFFER), (7, BUFFER + 4, BUFFER), (0, BUFFER, BUFFER + 0x100000)] {
+            registers.set_reg(0, reason);
+            registers.set_reg(4, start);
+            registers.set_reg(5, end);
+            assert_eq!(call(&mut hostfs, &mut memory, &mut registers, FILE, "New"), (true, bad_parameters));
+        }
+        assert_eq!(names(&directory), ["Data"]);
+
+        // Loading into ROM, or into I/O space
+        for &address in &[0x03800000, 0x03200000] {
+            registers.set_reg(0, 255);
+            registers.set_reg(2, address);
+            assert_eq!(call(&mut hostfs, &mut memory, &mut registers, FILE, "Data"), (true, bad_parameters));
+        }
+
+        registers.set_reg(0, 255);
+        registers.set_reg(2, BUFFER);
+        assert_eq!(call(&mut hostfs, &mut memory, &mut registers, FILE, "Data"), (true, None));
+        assert_eq!(memory.peek(BUFFER + 60), Some(0x01010101));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+use webarc::instructions::Decoded;
+
+// Instructions already decoded, by physical address, so running code doesn't
+// go through the decode tables every time. Pages are filled in as they're
+// executed, and a write only throws away the word it changed.
+
+pub const PAGE_SHIFT: u32 = 12;
+const PAGE_WORDS: usize = 1 << (PAGE_SHIFT - 2);
+// Physical RAM and ROM both sit within the 64MB address space
+const PAGES: usize = 0x04000000 >> PAGE_SHIFT;
+
+pub struct InstructionCache {
+    pages: Vec<Option<Box<[Option<Decoded>]>>>,
+    // Writes to each page, so anything else made from the code in it can
+    // tell when it's out of date
+    generations: Vec<u32>,
+}
+
+impl InstructionCache {
+    pub fn new() -> InstructionCache {
+        InstructionCache {
+            pages: vec![None; PAGES],
+            generations: vec![0; PAGES],
+        }
+    }
+
+    fn locate(address: u32) -> (usize, usize) {
+        let page = ((address >> PAGE_SHIFT) as usize) % PAGES;
+        let word = ((address >> 2) as usize) % PAGE_WORDS;
+        (page, word)
+    }
+
+    pub fn get(&self, address: u32) -> Option<Decoded> {
+        let (page, word) = InstructionCache::locate(address);
+        self.pages[page].as_ref().and_then(|page| page[word])
+    }
+
+    pub fn insert(&mut self, address: u32, decoded: Decoded) {
+        let (page, word) = InstructionCache::locate(address);
+        self.pages[page].get_or_insert_with(|| vec![None; PAGE_WORDS].into_boxed_slice())[word] = Some(decoded);
+    }
+
+    // Forget the instruction decoded from a word that's been written to. The
+    // page's generation moves on even if nothing was decoded from it, because
+    // translated code is made straight from memory.
+    pub fn invalidate(&mut self, address: u32) {
+        let (page, word) = InstructionCache::locate(address);
+        if let Some(ref mut decoded) = self.pages[page] {
+            decoded[word] = None;
+        }
+        self.generations[page] = self.generations[page].wrapping_add(1);
+    }
+
+    // Forget everything decoded from the page an address is in
+    pub fn invalidate_page(&mut self, address: u32) {
+        let (page, _) = InstructionCache::locate(address);
+        self.pages[page] = None;
+        self.generations[page] = self.generations[page].wrapping_add(1);
+    }
+
+    pub fn generation(&self, address: u32) -> u32 {
+        let (page, _) = InstructionCache::locate(address);
+        self.generations[page]
+    }
+}
+
+impl Default for InstructionCache {
+    fn default() -> InstructionCache {
+        InstructionCache::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webarc::instructions;
+
+    #[test]
+    fn invalidation() {
+        let mut icache = InstructionCache::new();
+        let page = 0x02001000;
+        icache.insert(page, instructions::decode(0xe2800001));
+        icache.insert(page + 4, instructions::decode(0xe2811001));
+        let generation = icache.generation(page);
+
+        // A write only loses the word it hit, and the rest of the page stays
+        icache.invalidate(page + 4);
+        assert_eq!(icache.get(page).map(|decoded| decoded.word), Some(0xe2800001));
+        assert!(icache.get(page + 4).is_none());
+        assert_ne!(icache.generation(page), generation);
+
+        // Pages with nothing decoded in them still count writes
+        let empty = 0x02002000;
+        let generation = icache.generation(empty);
+        icache.invalidate(empty);
+        assert!(icache.pages[(empty >> PAGE_SHIFT) as usize].is_none());
+        assert_ne!(icache.generation(empty), generation);
+
+        icache.invalidate_page(page);
+        assert!(icache.get(page).is_none());
+        assert!(icache.pages[(page >> PAGE_SHIFT) as usize].is_none());
+    }
+}
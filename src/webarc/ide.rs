@@ -0,0 +1,789 @@
+use std::io;
+use webarc::device::{Device, Interrupts};
+use webarc::harddisc::HardDiscImage;
+use webarc::ioc::IRQ_B_WINCHESTER;
+use webarc::snapshot::{self, StateReader, StateWriter};
+use webarc::sound::CYCLES_PER_MICROSECOND;
+
+// IDE interface on the A5000 and A3020, which is the 82C711's ATA task file at
+// the PC I/O ports 0x1F0-0x1F7 and 0x3F6. Up to two drives share it, with
+// sectors addressed either by LBA or by CHS through the translation set up with
+// INITIALISE DEVICE PARAMETERS. Data moves 16 bits at a time in PIO mode, and
+// INTRQ drives IOC IRQ B.
+
+pub const PORT_DATA: u16 = 0x1f0;
+const PORT_ERROR_FEATURES: u16 = 0x1f1;
+const PORT_SECTOR_COUNT: u16 = 0x1f2;
+const PORT_SECTOR_NUMBER: u16 = 0x1f3;
+const PORT_CYLINDER_LOW: u16 = 0x1f4;
+const PORT_CYLINDER_HIGH: u16 = 0x1f5;
+const PORT_DRIVE_HEAD: u16 = 0x1f6;
+pub const PORT_STATUS_COMMAND: u16 = 0x1f7;
+pub const PORT_ALTERNATE_STATUS: u16 = 0x3f6;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DSC: u8 = 1 << 4;
+const STATUS_DRDY: u8 = 1 << 6;
+const STATUS_BSY: u8 = 1 << 7;
+
+const ERROR_ABRT: u8 = 1 << 2;
+const ERROR_IDNF: u8 = 1 << 4;
+const ERROR_UNC: u8 = 1 << 6;
+
+const DRIVE_HEAD_DRIVE: u8 = 1 << 4;
+const DRIVE_HEAD_LBA: u8 = 1 << 6;
+
+const CONTROL_NIEN: u8 = 1 << 1;
+const CONTROL_SRST: u8 = 1 << 2;
+
+const RECALIBRATE: u8 = 0x10;
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_NO_RETRY: u8 = 0x21;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_NO_RETRY: u8 = 0x31;
+const READ_VERIFY: u8 = 0x40;
+const READ_VERIFY_NO_RETRY: u8 = 0x41;
+const SEEK: u8 = 0x70;
+const INITIALISE_DEVICE_PARAMETERS: u8 = 0x91;
+const IDLE_IMMEDIATE: u8 = 0xe1;
+const CHECK_POWER_MODE: u8 = 0xe5;
+const IDENTIFY_DEVICE: u8 = 0xec;
+const SET_FEATURES: u8 = 0xef;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const COMMAND_TIME: u32 = 50 * CYCLES_PER_MICROSECOND;
+const SECTOR_TIME: u32 = 200 * CYCLES_PER_MICROSECOND;
+
+const MODEL: &str = "WEBARC IDE DISC";
+const SERIAL: &str = "0000000001";
+const FIRMWARE: &str = "1.0";
+
+pub const DRIVES: usize = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    // Busy before the next sector is ready, or after the last one
+    Busy,
+    // DRQ is set and the host is moving a sector through the data port
+    Transferring,
+}
+
+const STATES: [State; 3] = [State::Idle, State::Busy, State::Transferring];
+
+pub struct Ide {
+    pub drives: [Option<HardDiscImage>; DRIVES],
+    // CHS translation in heads and sectors per track, for each drive
+    translations: [(u32, u32); DRIVES],
+
+    error: u8,
+    sector_count: u8,
+    sector_number: u8,
+    cylinder: u16,
+    drive_head: u8,
+    status: u8,
+    control: u8,
+
+    command: u8,
+    state: State,
+    countdown: u32,
+    remaining: u32,
+    buffer: Vec<u8>,
+    position: usize,
+
+    intrq: bool,
+}
+
+impl Ide {
+    pub fn new() -> Ide {
+        Ide {
+            drives: [None, None],
+            translations: [(0, 0); DRIVES],
+            error: 0,
+            sector_count: 1,
+            sector_number: 1,
+            cylinder: 0,
+            drive_head: 0,
+            status: STATUS_DRDY | STATUS_DSC,
+            control: 0,
+            command: 0,
+            state: State::Idle,
+            countdown: 0,
+            remaining: 0,
+            buffer: Vec::new(),
+            position: 0,
+            intrq: false,
+        }
+    }
+
+    // Whether there's a drive to attach the image as
+    pub fn attach(&mut self, drive: usize, image: HardDiscImage) -> bool {
+        if drive >= DRIVES {
+            return false;
+        }
+        let geometry = image.geometry;
+        self.translations[drive] = (geometry.heads, geometry.sectors);
+        self.drives[drive] = Some(image);
+        true
+    }
+
+    pub fn detach(&mut self, drive: usize) -> Option<HardDiscImage> {
+        self.drives.get_mut(drive)?.take()
+    }
+
+    pub fn intrq(&self) -> bool {
+        self.intrq && self.control & CONTROL_NIEN == 0
+    }
+
+    fn drive(&self) -> usize {
+        if self.drive_head & DRIVE_HEAD_DRIVE != 0 { 1 } else { 0 }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        if value & CONTROL_SRST != 0 && self.control & CONTROL_SRST == 0 {
+            self.reset();
+        }
+        self.control = value;
+    }
+
+    // Software reset puts the task file back to its signature values
+    fn reset(&mut self) {
+        self.state = State::Idle;
+        self.error = 1;
+        self.sector_count = 1;
+        self.sector_number = 1;
+        self.cylinder = 0;
+        self.drive_head = 0;
+        self.status = STATUS_DRDY | STATUS_DSC;
+        self.intrq = false;
+    }
+
+    fn write_command(&mut self, command: u8) {
+        let drive = self.drive();
+        if self.drives[drive].is_none() {
+            return;
+        }
+
+        self.command = command;
+        self.error = 0;
+        self.intrq = false;
+        self.remaining = if self.sector_count == 0 { 256 } else { self.sector_count as u32 };
+
+        match command {
+            READ_SECTORS | READ_SECTORS_NO_RETRY | READ_VERIFY | READ_VERIFY_NO_RETRY | IDENTIFY_DEVICE => {
+                self.busy(SECTOR_TIME);
+            },
+            WRITE_SECTORS | WRITE_SECTORS_NO_RETRY => {
+                if self.drives[drive].as_ref().is_some_and(|image| image.write_protected()) {
+                    return self.abort();
+                }
+                self.buffer = vec![0; SECTOR_SIZE];
+                self.position = 0;
+                self.state = State::Transferring;
+                self.status = STATUS_DRDY | STATUS_DSC | STATUS_DRQ;
+            },
+            INITIALISE_DEVICE_PARAMETERS => {
+                let heads = (self.drive_head & 0xf) as u32 + 1;
+                let sectors = self.sector_count as u32;
+                if sectors == 0 {
+                    return self.abort();
+                }
+                self.translations[drive] = (heads, sectors);
+                self.busy(COMMAND_TIME);
+            },
+            SEEK | SET_FEATURES | IDLE_IMMEDIATE => self.busy(COMMAND_TIME),
+            CHECK_POWER_MODE => {
+                // Always spinning
+                self.sector_count = 0xff;
+                self.busy(COMMAND_TIME);
+            },
+            _ if command & 0xf0 == RECALIBRATE => {
+                self.cylinder = 0;
+                self.busy(COMMAND_TIME);
+            },
+            _ => self.abort()
+        }
+    }
+
+    fn busy(&mut self, cycles: u32) {
+        self.state = State::Busy;
+        self.countdown = cycles;
+        self.status = (self.status & !(STATUS_DRQ | STATUS_ERR)) | STATUS_BSY;
+    }
+
+    // The drive has finished working and either has data for the host or is done
+    fn ready(&mut self) {
+        match self.command {
+            READ_SECTORS | READ_SECTORS_NO_RETRY => {
+                match self.read_sector() {
+                    Ok(()) => {
+                        self.position = 0;
+                        self.state = State::Transferring;
+                        self.status = STATUS_DRDY | STATUS_DSC | STATUS_DRQ;
+                        self.intrq = true;
+                    },
+                    Err(error) => self.fail(error)
+                }
+            },
+            READ_VERIFY | READ_VERIFY_NO_RETRY => {
+                match self.read_sector() {
+                    Ok(()) => {
+                        self.remaining -= 1;
+                        if self.remaining == 0 {
+                            self.complete();
+                        } else {
+                            self.next_sector();
+                            self.busy(SECTOR_TIME);
+                        }
+                    },
+                    Err(error) => self.fail(error)
+                }
+            },
+            IDENTIFY_DEVICE => {
+                self.buffer = self.identify();
+                self.position = 0;
+                self.remaining = 1;
+                self.state = State::Transferring;
+                self.status = STATUS_DRDY | STATUS_DSC | STATUS_DRQ;
+                self.intrq = true;
+            },
+            SEEK => {
+                if self.lba().is_some() {
+                    self.complete();
+                } else {
+                    self.fail(ERROR_IDNF);
+                }
+            },
+            _ => self.complete()
+        }
+    }
+
+    fn complete(&mut self) {
+        self.state = State::Idle;
+        self.status = STATUS_DRDY | STATUS_DSC;
+        self.intrq = true;
+    }
+
+    fn fail(&mut self, error: u8) {
+        self.state = State::Idle;
+        self.error = error;
+        self.status = STATUS_DRDY | STATUS_DSC | STATUS_ERR;
+        self.intrq = true;
+    }
+
+    fn abort(&mut self) {
+        self.fail(ERROR_ABRT);
+    }
+
+    // Address in the task file, in whichever mode the host chose. Either can
+    // reach any sector in the image, including those past the last cylinder
+    // of the geometry.
+    fn lba(&self) -> Option<u64> {
+        let drive = self.drive();
+        let image = self.drives[drive].as_ref()?;
+        let total = image.capacity();
+
+        let lba = if self.drive_head & DRIVE_HEAD_LBA != 0 {
+            ((self.drive_head & 0xf) as u64) << 24 | (self.cylinder as u64) << 8 | self.sector_number as u64
+        } else {
+            let (heads, sectors) = self.translations[drive];
+            let head = (self.drive_head & 0xf) as u64;
+            let sector = self.sector_number as u64;
+            if head >= heads as u64 || sector == 0 || sector > sectors as u64 {
+                return None;
+            }
+            (self.cylinder as u64 * heads as u64 + head) * sectors as u64 + sector - 1
+        };
+
+        if lba < total { Some(lba) } else { None }
+    }
+
+    // Leave the task file pointing at the next sector, as real drives do
+    fn next_sector(&mut self) {
+        if self.drive_head & DRIVE_HEAD_LBA != 0 {
+            let lba = ((self.drive_head & 0xf) as u32) << 24 | (self.cylinder as u32) << 8 | self.sector_number as u32;
+            let next = lba.wrapping_add(1);
+            self.sector_number = next as u8;
+            self.cylinder = (next >> 8) as u16;
+            self.drive_head = (self.drive_head & 0xf0) | ((next >> 24) & 0xf) as u8;
+        } else {
+            let (heads, sectors) = self.translations[self.drive()];
+            if self.sector_number as u32 >= sectors {
+                self.sector_number = 1;
+                let head = (self.drive_head & 0xf) as u32 + 1;
+                if head >= heads {
+                    self.drive_head &= 0xf0;
+                    self.cylinder = self.cylinder.wrapping_add(1);
+                } else {
+                    self.drive_head = (self.drive_head & 0xf0) | head as u8;
+                }
+            } else {
+                self.sector_number += 1;
+            }
+        }
+        self.sector_count = self.sector_count.wrapping_sub(1);
+    }
+
+    fn read_sector(&mut self) -> Result<(), u8> {
+        let lba = self.lba().ok_or(ERROR_IDNF)?;
+        let drive = self.drive();
+        let image = self.drives[drive].as_mut().ok_or(ERROR_ABRT)?;
+
+        self.buffer = vec![0; SECTOR_SIZE];
+        image.read_sector(lba, &mut self.buffer).map_err(|error| {
+            println!("Couldn't read IDE disc: {}", error);
+            ERROR_UNC
+        })
+    }
+
+    fn read_data(&mut self) -> u16 {
+        if self.state != State::Transferring || self.command & 0xf0 == WRITE_SECTORS {
+            return 0;
+        }
+
+        let value = self.buffer[self.position] as u16 | (self.buffer[self.position + 1] as u16) << 8;
+        self.position += 2;
+
+        if self.position >= self.buffer.len() {
+            self.status &= !STATUS_DRQ;
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                if self.command != IDENTIFY_DEVICE {
+                    self.next_sector();
+                }
+                self.state = State::Idle;
+            } else {
+                self.next_sector();
+                self.busy(SECTOR_TIME);
+            }
+        }
+        value
+    }
+
+    fn write_data(&mut self, value: u16) {
+        if self.state != State::Transferring || self.command & 0xf0 != WRITE_SECTORS {
+            return;
+        }
+
+        self.buffer[self.position] = value as u8;
+        self.buffer[self.position + 1] = (value >> 8) as u8;
+        self.position += 2;
+
+        if self.position < self.buffer.len() {
+            return;
+        }
+
+        let drive = self.drive();
+        let lba = match self.lba() {
+            Some(lba) => lba,
+            None => return self.fail(ERROR_IDNF)
+        };
+        let result = match self.drives[drive].as_mut() {
+            Some(image) => image.write_sector(lba, &self.buffer),
+            None => return self.abort()
+        };
+        if let Err(error) = result {
+            println!("Couldn't write IDE disc: {}", error);
+            return self.fail(ERROR_UNC);
+        }
+
+        self.next_sector();
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            // Report completion once the last sector has gone to the disc
+            self.busy(SECTOR_TIME);
+        } else {
+            // Interrupt for each further sector the drive wants
+            self.position = 0;
+            self.status = STATUS_DRDY | STATUS_DSC | STATUS_DRQ;
+            self.intrq = true;
+        }
+    }
+
+    fn identify(&self) -> Vec<u8> {
+        let drive = self.drive();
+        let mut words = [0u16; 256];
+        let (heads, sectors) = self.translations[drive];
+
+        if let Some(ref image) = self.drives[drive] {
+            let geometry = image.geometry;
+            // What 28 bit LBA can address
+            let total = image.capacity().min(0x0fff_ffff) as u32;
+            let current_cylinders = (total / (heads * sectors).max(1)).min(0xffff);
+
+            // Fixed disc
+            words[0] = 0x0040;
+            words[1] = geometry.cylinders as u16;
+            words[3] = geometry.heads as u16;
+            words[4] = (SECTOR_SIZE * geometry.sectors as usize) as u16;
+            words[5] = SECTOR_SIZE as u16;
+            words[6] = geometry.sectors as u16;
+            identify_string(&mut words[10..20], SERIAL);
+            identify_string(&mut words[23..27], FIRMWARE);
+            identify_string(&mut words[27..47], MODEL);
+            // LBA supported, and the current translation fields are valid
+            words[49] = 0x0200;
+            words[53] = 0x0001;
+            words[54] = current_cylinders as u16;
+            words[55] = heads as u16;
+            words[56] = sectors as u16;
+            let capacity = current_cylinders * heads * sectors;
+            words[57] = capacity as u16;
+            words[58] = (capacity >> 16) as u16;
+            words[60] = total as u16;
+            words[61] = (total >> 16) as u16;
+        }
+
+        words.iter().flat_map(|word| vec![*word as u8, (word >> 8) as u8]).collect()
+    }
+}
+
+impl Device for Ide {
+    // 8-bit registers use the bottom byte of the data bus, and the data port
+    // the bottom halfword
+    fn load(&mut self, port: u32) -> u32 {
+        let port = port as u16;
+        if port == PORT_DATA {
+            return self.read_data() as u32;
+        }
+
+        if self.drives[self.drive()].is_none() {
+            return 0;
+        }
+
+        let value = match port {
+            PORT_ERROR_FEATURES => self.error,
+            PORT_SECTOR_COUNT => self.sector_count,
+            PORT_SECTOR_NUMBER => self.sector_number,
+            PORT_CYLINDER_LOW => self.cylinder as u8,
+            PORT_CYLINDER_HIGH => (self.cylinder >> 8) as u8,
+            PORT_DRIVE_HEAD => self.drive_head | 0xa0,
+            PORT_STATUS_COMMAND => {
+                self.intrq = false;
+                self.status
+            },
+            PORT_ALTERNATE_STATUS => self.status,
+            _ => 0
+        };
+        value as u32
+    }
+
+    fn store(&mut self, port: u32, data: u32) {
+        let port = port as u16;
+        let value = data as u8;
+
+        if port == PORT_ALTERNATE_STATUS {
+            return self.write_control(value);
+        }
+
+        // The task file is locked while the drive is busy
+        if self.status & STATUS_BSY != 0 {
+            return;
+        }
+
+        match port {
+            PORT_DATA => self.write_data(data as u16),
+            // Features only matter to SET FEATURES, which accepts anything
+            PORT_ERROR_FEATURES => {},
+            PORT_SECTOR_COUNT => self.sector_count = value,
+            PORT_SECTOR_NUMBER => self.sector_number = value,
+            PORT_CYLINDER_LOW => self.cylinder = (self.cylinder & 0xff00) | value as u16,
+            PORT_CYLINDER_HIGH => self.cylinder = (self.cylinder & 0x00ff) | (value as u16) << 8,
+            PORT_DRIVE_HEAD => self.drive_head = value & 0x5f,
+            PORT_STATUS_COMMAND => self.write_command(value),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.state != State::Busy {
+            return;
+        }
+
+        if cycles < self.countdown {
+            self.countdown -= cycles;
+        } else {
+            self.countdown = 0;
+            self.ready();
+        }
+    }
+
+    fn next_event(&self) -> Option<u32> {
+        if self.state == State::Busy { Some(self.countdown) } else { None }
+    }
+
+    fn interrupts(&mut self) -> Interrupts {
+        Interrupts::irq_b(IRQ_B_WINCHESTER, self.intrq())
+    }
+
+    // The disc images stay attached
+    fn save(&self, state: &mut StateWriter) {
+        for &(heads, sectors) in &self.translations {
+            state.u32(heads);
+            state.u32(sectors);
+        }
+        for &register in &[self.error, self.sector_count, self.sector_number] {
+            state.u8(register);
+        }
+        state.u16(self.cylinder);
+        for &register in &[self.drive_head, self.status, self.control, self.command] {
+            state.u8(register);
+        }
+
+        state.u8(self.state as u8);
+        state.u32(self.countdown);
+        state.u32(self.remaining);
+        state.bytes(&self.buffer);
+        state.usize(self.position);
+        state.bool(self.intrq);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for translation in self.translations.iter_mut() {
+            *translation = (state.u32()?, state.u32()?);
+        }
+        for register in [&mut self.error, &mut self.sector_count, &mut self.sector_number] {
+            *register = state.u8()?;
+        }
+        self.cylinder = state.u16()?;
+        for register in [&mut self.drive_head, &mut self.status, &mut self.control, &mut self.command] {
+            *register = state.u8()?;
+        }
+
+        self.state = *STATES.get(state.u8()? as usize).ok_or_else(|| snapshot::invalid("Bad IDE state in snapshot"))?;
+        self.countdown = state.u32()?;
+        self.remaining = state.u32()?;
+        self.buffer = state.bytes()?;
+        self.position = state.usize()?;
+        self.intrq = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Ide {
+    fn default() -> Ide {
+        Ide::new()
+    }
+}
+
+// ATA strings are space padded with the first character of each pair in the
+// high byte
+fn identify_string(words: &mut [u16], text: &str) {
+    let mut bytes = text.as_bytes().to_vec();
+    bytes.resize(words.len() * 2, b' ');
+
+    for (word, pair) in words.iter_mut().zip(bytes.chunks(2)) {
+        *word = (pair[0] as u16) << 8 | pair[1] as u16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use webarc::harddisc::Geometry;
+
+    // Two whole cylinders of the largest translation, and a few sectors past
+    // them that only LBA reaches
+    const SECTORS: u64 = 2 * 16 * 63 + 10;
+
+    fn image(name: &str, fill: u8) -> HardDiscImage {
+        let path = env::temp_dir().join(format!("webarc-ide-{}-{}.ide", name, std::process::id()));
+        fs::write(&path, vec![fill; SECTORS as usize * SECTOR_SIZE]).unwrap();
+        let geometry = Geometry::ide_for_size(SECTORS * SECTOR_SIZE as u64).unwrap();
+        let image = HardDiscImage::open(&path, geometry).unwrap();
+        fs::remove_file(&path).unwrap();
+        image
+    }
+
+    fn ide(name: &str) -> Ide {
+        let mut ide = Ide::new();
+        assert!(ide.attach(0, image(name, 0)));
+        ide
+    }
+
+    fn outb(ide: &mut Ide, port: u16, value: u8) {
+        ide.store(port as u32, value as u32);
+    }
+
+    fn inb(ide: &mut Ide, port: u16) -> u8 {
+        ide.load(port as u32) as u8
+    }
+
+    // Run a command until the drive stops being busy, returning the status
+    fn command(ide: &mut Ide, command: u8) -> u8 {
+        outb(ide, PORT_STATUS_COMMAND, command);
+        run(ide)
+    }
+
+    fn run(ide: &mut Ide) -> u8 {
+        while let Some(cycles) = ide.next_event() {
+            ide.tick(cycles);
+        }
+        inb(ide, PORT_STATUS_COMMAND)
+    }
+
+    fn chs(ide: &mut Ide, cylinder: u16, head: u8, sector: u8, count: u8) {
+        outb(ide, PORT_SECTOR_COUNT, count);
+        outb(ide, PORT_SECTOR_NUMBER, sector);
+        outb(ide, PORT_CYLINDER_LOW, cylinder as u8);
+        outb(ide, PORT_CYLINDER_HIGH, (cylinder >> 8) as u8);
+        outb(ide, PORT_DRIVE_HEAD, head);
+    }
+
+    fn lba(ide: &mut Ide, drive: u8, lba: u32, count: u8) {
+        chs(ide, (lba >> 8) as u16, DRIVE_HEAD_LBA | drive | (lba >> 24) as u8 & 0xf, lba as u8, count);
+    }
+
+    // The halfwords of a sector, which say which sector it is
+    fn pattern(sector: u32) -> Vec<u16> {
+        (0..SECTOR_SIZE as u32 / 2).map(|i| (sector * 0x100 + i) as u16).collect()
+    }
+
+    fn write_sectors(ide: &mut Ide, first: u32, count: u32) {
+        assert_eq!(command(ide, WRITE_SECTORS), STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+        for sector in first..first + count {
+            for word in pattern(sector) {
+                ide.store(PORT_DATA as u32, word as u32);
+            }
+            let status = run(ide);
+            if sector + 1 < first + count {
+                assert_eq!(status, STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+            } else {
+                assert_eq!(status, STATUS_DRDY | STATUS_DSC);
+            }
+        }
+    }
+
+    fn read_sectors(ide: &mut Ide, count: u32) -> Vec<Vec<u16>> {
+        outb(ide, PORT_STATUS_COMMAND, READ_SECTORS);
+        (0..count).map(|_| {
+            assert_eq!(run(ide), STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+            (0..SECTOR_SIZE / 2).map(|_| ide.load(PORT_DATA as u32) as u16).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn identify() {
+        let mut ide = ide("identify");
+        assert_eq!(command(&mut ide, IDENTIFY_DEVICE), STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+        let words: Vec<u16> = (0..256).map(|_| ide.load(PORT_DATA as u32) as u16).collect();
+        assert_eq!(inb(&mut ide, PORT_STATUS_COMMAND), STATUS_DRDY | STATUS_DSC);
+
+        assert_eq!(words[0], 0x0040);
+        assert_eq!((words[1], words[3], words[6]), (2, 16, 63));
+        assert_eq!(words[5], SECTOR_SIZE as u16);
+        assert_eq!(words[27], (b'W' as u16) << 8 | b'E' as u16);
+        assert_eq!(words[46], 0x2020);
+        assert_eq!(words[10], (b'0' as u16) << 8 | b'0' as u16);
+        assert_ne!(words[49] & 0x0200, 0);
+
+        // The current translation covers the whole cylinders, and LBA the
+        // whole file
+        assert_eq!((words[54], words[55], words[56]), (2, 16, 63));
+        assert_eq!(words[57] as u32 | (words[58] as u32) << 16, 2 * 16 * 63);
+        assert_eq!(words[60] as u64 | (words[61] as u64) << 16, SECTORS);
+    }
+
+    #[test]
+    fn chs_transfers() {
+        let mut ide = ide("chs");
+
+        // The last sector of a track runs on to the next head, and the task
+        // file is left at the sector after the last
+        chs(&mut ide, 0, 0, 63, 2);
+        write_sectors(&mut ide, 62, 2);
+        assert_eq!(inb(&mut ide, PORT_SECTOR_COUNT), 0);
+        assert_eq!(inb(&mut ide, PORT_SECTOR_NUMBER), 2);
+        assert_eq!(inb(&mut ide, PORT_DRIVE_HEAD), 0xa1);
+
+        lba(&mut ide, 0, 62, 2);
+        assert_eq!(read_sectors(&mut ide, 2), [pattern(62), pattern(63)]);
+
+        // After INITIALISE DEVICE PARAMETERS, the same sectors are somewhere
+        // else
+        chs(&mut ide, 0, 3, 4, 10);
+        assert_eq!(command(&mut ide, INITIALISE_DEVICE_PARAMETERS), STATUS_DRDY | STATUS_DSC);
+        chs(&mut ide, 1, 2, 3, 1);
+        assert_eq!(read_sectors(&mut ide, 1), [pattern(62)]);
+
+        // Sector 0 and heads past the translation don't exist
+        chs(&mut ide, 0, 0, 0, 1);
+        assert_eq!(command(&mut ide, READ_SECTORS), STATUS_DRDY | STATUS_DSC | STATUS_ERR);
+        assert_eq!(inb(&mut ide, PORT_ERROR_FEATURES), ERROR_IDNF);
+        chs(&mut ide, 0, 4, 1, 1);
+        assert_eq!(command(&mut ide, READ_SECTORS), STATUS_DRDY | STATUS_DSC | STATUS_ERR);
+    }
+
+    #[test]
+    fn lba_transfers() {
+        let mut ide = ide("lba");
+
+        // Past the last whole cylinder, up to the end of the file
+        let last = SECTORS as u32 - 1;
+        lba(&mut ide, 0, last - 2, 3);
+        write_sectors(&mut ide, last - 2, 3);
+        lba(&mut ide, 0, last - 2, 3);
+        assert_eq!(read_sectors(&mut ide, 3), [pattern(last - 2), pattern(last - 1), pattern(last)]);
+        assert_eq!(inb(&mut ide, PORT_STATUS_COMMAND), STATUS_DRDY | STATUS_DSC);
+        assert_eq!(inb(&mut ide, PORT_SECTOR_NUMBER), (last + 1) as u8);
+
+        lba(&mut ide, 0, last + 1, 1);
+        assert_eq!(command(&mut ide, READ_SECTORS), STATUS_DRDY | STATUS_DSC | STATUS_ERR);
+        assert_eq!(inb(&mut ide, PORT_ERROR_FEATURES), ERROR_IDNF);
+        lba(&mut ide, 0, last + 1, 1);
+        assert_eq!(command(&mut ide, WRITE_SECTORS), STATUS_DRDY | STATUS_DSC | STATUS_DRQ);
+        for _ in 0..SECTOR_SIZE / 2 {
+            ide.store(PORT_DATA as u32, 0);
+        }
+        assert_eq!(inb(&mut ide, PORT_STATUS_COMMAND), STATUS_DRDY | STATUS_DSC | STATUS_ERR);
+    }
+
+    #[test]
+    fn drive_1() {
+        let mut ide = ide("drive-0");
+        assert!(ide.attach(1, image("drive-1", 0x5a)));
+        assert!(!ide.attach(2, image("drive-2", 0)));
+
+        lba(&mut ide, DRIVE_HEAD_DRIVE, 0, 1);
+        assert_eq!(read_sectors(&mut ide, 1), [vec![0x5a5a; SECTOR_SIZE / 2]]);
+        lba(&mut ide, 0, 0, 1);
+        assert_eq!(read_sectors(&mut ide, 1), [vec![0; SECTOR_SIZE / 2]]);
+
+        // Without a drive 1, selecting it leaves nothing answering
+        ide.detach(1).unwrap();
+        assert!(ide.detach(2).is_none());
+        outb(&mut ide, PORT_DRIVE_HEAD, DRIVE_HEAD_DRIVE);
+        assert_eq!(inb(&mut ide, PORT_STATUS_COMMAND), 0);
+        outb(&mut ide, PORT_STATUS_COMMAND, IDENTIFY_DEVICE);
+        assert!(ide.next_event().is_none());
+    }
+
+    #[test]
+    fn software_reset() {
+        let mut ide = ide("reset");
+        lba(&mut ide, 0, 0x123456, 4);
+        outb(&mut ide, PORT_STATUS_COMMAND, READ_SECTORS);
+        assert_ne!(inb(&mut ide, PORT_ALTERNATE_STATUS) & STATUS_BSY, 0);
+
+        // Only the edge of SRST resets, and the task file holds its signature
+        outb(&mut ide, PORT_ALTERNATE_STATUS, CONTROL_SRST | CONTROL_NIEN);
+        outb(&mut ide, PORT_ALTERNATE_STATUS, CONTROL_NIEN);
+        assert!(ide.next_event().is_none());
+        assert!(!ide.intrq());
+        let task_file: Vec<u8> = (PORT_ERROR_FEATURES..=PORT_STATUS_COMMAND).map(|port| inb(&mut ide, port)).collect();
+        assert_eq!(task_file, [1, 1, 1, 0, 0, 0xa0, STATUS_DRDY | STATUS_DSC]);
+
+        // NIEN keeps INTRQ off the bus
+        outb(&mut ide, PORT_STATUS_COMMAND, IDLE_IMMEDIATE);
+        while let Some(cycles) = ide.next_event() {
+            ide.tick(cycles);
+        }
+        assert!(!ide.intrq());
+        outb(&mut ide, PORT_ALTERNATE_STATUS, 0);
+        assert!(ide.intrq());
+    }
+}
@@ -1,295 +1,635 @@
 use std::ops::Shl;
-use std::ops::Shr;
-use webarc::registers::RegisterFile;
+use webarc::registers::{Mode, RegisterFile};
 use webarc::memory::Memory;
 
+const N_BIT: u32 = 0x80000000;
+const Z_BIT: u32 = 0x40000000;
+const C_BIT: u32 = 0x20000000;
+const V_BIT: u32 = 0x10000000;
+const FLAGS: u32 = N_BIT | Z_BIT | C_BIT | V_BIT;
+
+// R15 holds the flags, the interrupt disables and the mode around the PC. Only
+// the flags can be changed in user mode.
+const PSR_BITS: u32 = 0xfc000003;
+const PC_BITS: u32 = 0x03fffffc;
+
 pub enum Action {
     Continue,
-    Flush
+    Flush,
+    // Take the SWI exception, unless the emulator handles the SWI itself
+    Swi(u32),
+    // Take the undefined instruction exception, which is also what becomes of
+    // coprocessor instructions with no coprocessors to take them
+    Undefined,
+    // Take the data abort exception, after MEMC has refused an access
+    DataAbort,
 }
 
-const COND_STRINGS: [&str; 16] = ["EQ", "NE", "CS", "CC", "MI", "PL", "VS", "VC", "HI", "LS", "GE", "LT", "GT", "LE", "", "NV"];
-
 pub fn exec(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> Action {
     let offset = ((instruction >> 24) & 0xf) as usize;
     let handler = INSTRUCTION_HANDLERS[offset];
     handler(registers, memory, instruction)
 }
 
-pub fn format(address: u32, cond: u8, instruction: u32) -> String {
-    let offset = ((instruction >> 24) & 0xf) as usize;
-    let formatter = INSTRUCTION_FORMATTERS[offset];
-    let cond_string = COND_STRINGS[cond as usize];
-    return formatter(address, cond_string, instruction);
+// An instruction decoded ahead of time: the handler for its particular form,
+// with the operands it needs already taken out of the word. Forms without a
+// handler of their own go through exec.
+#[derive(Clone, Copy)]
+pub struct Decoded {
+    pub word: u32,
+    pub cond: u8,
+    handler: DecodedHandler,
+    rd: u32,
+    rn: u32,
+    operand: u32,
+}
+
+type DecodedHandler = fn(registers: &mut RegisterFile, memory: &mut Memory, decoded: &Decoded) -> Action;
+
+impl Decoded {
+    pub fn exec(&self, registers: &mut RegisterFile, memory: &mut Memory) -> Action {
+        (self.handler)(registers, memory, self)
+    }
+}
+
+pub fn decode(word: u32) -> Decoded {
+    let mut decoded = Decoded {
+        word,
+        cond: (word >> 28) as u8,
+        handler: exec_decoded_word,
+        rd: (word >> 12) & 0xf,
+        rn: (word >> 16) & 0xf,
+        operand: 0,
+    };
+
+    match (word >> 24) & 0xf {
+        0x0..=0x3 if word & (1 << 25) != 0 => {
+            let rotate = ((word >> 8) & 0xf) * 2;
+            decoded.operand = (word & 0xff).rotate_right(rotate);
+            // Most don't set the flags or write to R15, and only need the result
+            decoded.handler = if word & (1 << 20) == 0 && decoded.rd != 15 {
+                exec_decoded_alu_immediate_result
+            } else {
+                exec_decoded_alu_immediate
+            };
+        },
+        0x4 | 0x5 => {
+            decoded.operand = word & 0xfff;
+            decoded.handler = exec_decoded_ldr_str_immediate;
+        },
+        0xa | 0xb => {
+            decoded.operand = (word & 0x00ffffff).shl(2);
+            decoded.handler = exec_decoded_branch;
+        },
+        0xf => {
+            decoded.operand = word & 0x00ffffff;
+            decoded.handler = exec_decoded_swi;
+        },
+        _ => {}
+    }
+    decoded
+}
+
+fn exec_decoded_word(registers: &mut RegisterFile, memory: &mut Memory, decoded: &Decoded) -> Action {
+    exec(registers, memory, decoded.word)
 }
 
 // Branch
 
-fn exec_branch(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> Action {
+fn exec_branch(registers: &mut RegisterFile, _memory: &mut Memory, instruction: u32) -> Action {
+    let offset = (instruction & 0x00ffffff).shl(2);
+    // console.debug("Offset = 0x" + offset.toString(16));
+    branch(registers, instruction, offset)
+}
+
+fn exec_decoded_branch(registers: &mut RegisterFile, _memory: &mut Memory, decoded: &Decoded) -> Action {
+    branch(registers, decoded.word, decoded.operand)
+}
+
+fn branch(registers: &mut RegisterFile, instruction: u32, offset: u32) -> Action {
     let pc = registers.reg_no_flags(15);
 
+    // The link is the address of the next instruction, along with the PSR
     if instruction & 0x01000000 != 0 {
-        registers.set_reg(14, pc);
+        registers.set_reg(14, registers.reg(15).wrapping_sub(4));
     }
 
-    let offset = (instruction & 0x00ffffff).shl(2);
-    // console.debug("Offset = 0x" + offset.toString(16));
     registers.set_reg_no_flags(15, (pc + offset) & 0x03ffffffu32);
     Action::Flush
 }
 
-fn format_branch(address: u32, cond: &str, instruction: u32) -> String {
-    let offset = u32::shl(instruction & 0x00ffffff, 2);
-    let dest = (address + offset + 8u32) & 0x03ffffffu32;
-
-    if instruction & 0x01000000 != 0 {
-        format!("BL{} {:0X}", cond, dest)
-    } else {
-        format!("B{} ${:0X}", cond, dest)
+// The barrel shifter: a value shifted by an amount from the instruction or the
+// bottom byte of a register, and the carry out of it. Shifts by an immediate
+// use 0 to mean 32 for LSR and ASR, and RRX for ROR.
+fn shift(value: u32, shift_type: u32, amount: u32, immediate: bool, carry: bool) -> (u32, bool) {
+    let bit = |n: u32| (value >> n) & 1 != 0;
+
+    match shift_type {
+        // LSL
+        0b00 => match amount {
+            0 => (value, carry),
+            1..=31 => (value << amount, bit(32 - amount)),
+            32 => (0, bit(0)),
+            _ => (0, false)
+        },
+        // LSR
+        0b01 => match if immediate && amount == 0 { 32 } else { amount } {
+            0 => (value, carry),
+            amount @ 1..=31 => (value >> amount, bit(amount - 1)),
+            32 => (0, bit(31)),
+            _ => (0, false)
+        },
+        // ASR
+        0b10 => match if immediate && amount == 0 { 32 } else { amount } {
+            0 => (value, carry),
+            amount @ 1..=31 => (((value as i32) >> amount) as u32, bit(amount - 1)),
+            _ => (((value as i32) >> 31) as u32, bit(31))
+        },
+        // ROR
+        _ => {
+            if immediate && amount == 0 {
+                ((carry as u32) << 31 | value >> 1, bit(0))
+            } else if amount == 0 {
+                (value, carry)
+            } else {
+                let rotated = value.rotate_right(amount & 31);
+                (rotated, rotated >> 31 != 0)
+            }
+        }
     }
 }
 
 // ALU operations
-type AluInstructionHandler = fn(registers: &mut RegisterFile, memory: &mut Memory, rd: u32, op1: u32, op2: u32) -> Action;
 
-fn exec_alu(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> Action {
-    const ALU_INSTRUCTION_HANDLERS: [AluInstructionHandler; 16] = [
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_add,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-        exec_alu_unimplemented,
-    ];
+// What an operation makes of its operands and the carry flag. Arithmetic
+// gives a carry and an overflow, and logical operations leave the carry to
+// the shifter and the overflow alone.
+struct AluResult {
+    value: u32,
+    carry: Option<bool>,
+    overflow: Option<bool>,
+}
 
-    let opcode = (instruction >> 21) & 0xf;
-    println!("ALU opcode: {}", opcode);
+type AluInstructionHandler = fn(op1: u32, op2: u32, carry: bool) -> AluResult;
+
+const ALU_INSTRUCTION_HANDLERS: [AluInstructionHandler; 16] = [
+    exec_alu_and,
+    exec_alu_eor,
+    exec_alu_sub,
+    exec_alu_rsb,
+    exec_alu_add,
+    exec_alu_adc,
+    exec_alu_sbc,
+    exec_alu_rsc,
+    exec_alu_and,
+    exec_alu_eor,
+    exec_alu_sub,
+    exec_alu_add,
+    exec_alu_orr,
+    exec_alu_mov,
+    exec_alu_bic,
+    exec_alu_mvn,
+];
 
+fn exec_alu(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> Action {
     let rd = (instruction >> 12) & 0xf;
 
     let rn = (instruction >> 16) & 0xf;
 
     let immediate = (instruction >> 25) & 1 != 0;
 
-    let (op1, op2) = if immediate {
+    // Multiplies and swaps are in the space of register shifts by a register
+    // with bit 7 set, which would otherwise be undefined
+    if !immediate && instruction & 0x90 == 0x90 {
+        return if instruction & 0x0fc000f0 == 0x00000090 {
+            exec_multiply(registers, memory, instruction)
+        } else if instruction & 0x0fb00ff0 == 0x01000090 {
+            exec_swap(registers, memory, instruction)
+        } else {
+            Action::Undefined
+        };
+    }
+
+    let carry = registers.reg(15) & C_BIT != 0;
+    let (op1, op2, shifter_carry) = if immediate {
         let value = instruction & 0xff;
         let rotate = ((instruction >> 8) & 0xf) * 2;
-        (registers.reg_no_flags(rn), value.rotate_right(rotate))
+        let op2 = value.rotate_right(rotate);
+        (registers.reg_no_flags(rn), op2, if rotate == 0 { carry } else { op2 >> 31 != 0 })
     } else {
         let rm = instruction & 0xf;
 
         let shift_type = (instruction >> 5) & 0b11;
 
-        let (op1, shift_amount, unshifted) = if instruction & (1 << 4) != 0 {
+        let (op1, shift_amount, unshifted, register_shift) = if instruction & (1 << 4) != 0 {
+            // Reading the shift register takes an internal cycle
             let rs = (instruction >> 8) & 0xf;
+            memory.internal_cycles(1);
             (
                 registers.reg_no_flags(rn) + if rn == 15 { 4 } else { 0 },
                 registers.reg_no_flags(rs) & 0xff,
-                registers.reg(rm) + if rm == 15 { 4 } else { 0 }
+                registers.reg(rm) + if rm == 15 { 4 } else { 0 },
+                true
             )
         } else {
             (
                 registers.reg_no_flags(rn),
                 (instruction >> 7) & 0b11111,
-                registers.reg(rm)
+                registers.reg(rm),
+                false
             )
         };
 
-        let op2 = match shift_type {
-            0b00 => unshifted << shift_amount,
-            0b01 => (unshifted as i32 >> shift_amount) as u32,
-            0b10 => unshifted >> shift_amount,
-            0b11 => (instruction >> shift_amount) | (instruction << (32 - shift_amount)),
-            _ => unreachable!()
-        };
+        let (op2, shifter_carry) = shift(unshifted, shift_type, shift_amount, !register_shift, carry);
+        (op1, op2, shifter_carry)
+    };
 
-        println!("op1 = {:08X}, op2 = {:08X}", op1, op2);
-        (op1, op2)
+    alu(registers, instruction, rd, op1, op2, shifter_carry)
+}
+
+fn exec_decoded_alu_immediate(registers: &mut RegisterFile, _memory: &mut Memory, decoded: &Decoded) -> Action {
+    let op1 = registers.reg_no_flags(decoded.rn);
+    // A rotated immediate carries out its top bit
+    let shifter_carry = if decoded.word & 0xf00 != 0 {
+        decoded.operand >> 31 != 0
+    } else {
+        registers.reg(15) & C_BIT != 0
     };
+    alu(registers, decoded.word, decoded.rd, op1, decoded.operand, shifter_carry)
+}
 
-    ALU_INSTRUCTION_HANDLERS[opcode as usize](registers, memory, rd, op1, op2)
+fn exec_decoded_alu_immediate_result(registers: &mut RegisterFile, _memory: &mut Memory, decoded: &Decoded) -> Action {
+    let opcode = (decoded.word >> 21) & 0xf;
+    let op1 = registers.reg_no_flags(decoded.rn);
+    let carry = registers.reg(15) & C_BIT != 0;
+    let result = ALU_INSTRUCTION_HANDLERS[opcode as usize](op1, decoded.operand, carry);
+    if opcode & 0b1100 != 0b1000 {
+        registers.set_reg(decoded.rd, result.value);
+    }
+    Action::Continue
 }
 
-fn format_alu(address: u32, cond: &str, instruction: u32) -> String {
-    const ALU_OPS: [&str; 16] = [
-        "AND", "EOR", "SUB", "RSB", "ADD", "ADC", "SBC", "RSC",
-        "TST", "TEQ", "CMP", "CMN", "ORR", "MOV", "BIC", "MVN"
-    ];
+// Carry out an operation and put its result where the instruction says.
+// TST, TEQ, CMP and CMN only set the flags. With S, a result written to R15
+// goes to the PSR as well as the PC, and the tests write their result to the
+// PSR alone.
+fn alu(registers: &mut RegisterFile, instruction: u32, rd: u32, op1: u32, op2: u32, shifter_carry: bool) -> Action {
+    let opcode = (instruction >> 21) & 0xf;
+    let set_flags = instruction & (1 << 20) != 0;
+    let test = opcode & 0b1100 == 0b1000;
 
-    let opcode = ALU_OPS[((instruction >> 21) & 0xf) as usize];
-    let rd = (instruction >> 12) & 0xf; // TODO: Change to PC if it's 15
-    let rn = (instruction >> 16) & 0xf;
+    let r15 = registers.reg(15);
+    let result = ALU_INSTRUCTION_HANDLERS[opcode as usize](op1, op2, r15 & C_BIT != 0);
 
-    let immediate = (instruction >> 25) & 1 != 0;
+    if rd == 15 && set_flags {
+        let psr_bits = if registers.mode() == Mode::User { FLAGS } else { PSR_BITS };
+        let pc = if test { r15 } else { result.value };
+        registers.set_reg(15, (r15 & !psr_bits & !PC_BITS) | (result.value & psr_bits) | (pc & PC_BITS));
+        return if test { Action::Continue } else { Action::Flush };
+    }
 
-    let op2 = if immediate {
-        let value = instruction & 0xff;
-        let rotate = ((instruction >> 8) & 0xf) * 2;
-        let rotate_text = if rotate > 0 { format!("ROR {}", rotate) } else { "".to_string() };
-        format!("#{} {}", value, rotate_text)
-    } else {
-        let rm = instruction & 0xf;
+    if !test {
+        registers.set_reg_no_flags(rd, result.value);
+    }
+    if set_flags {
+        let mut flags = 0;
+        if result.value & N_BIT != 0 {
+            flags |= N_BIT;
+        }
+        if result.value == 0 {
+            flags |= Z_BIT;
+        }
+        if result.carry.unwrap_or(shifter_carry) {
+            flags |= C_BIT;
+        }
+        if result.overflow.unwrap_or(r15 & V_BIT != 0) {
+            flags |= V_BIT;
+        }
+        registers.set_reg(15, (registers.reg(15) & !FLAGS) | flags);
+    }
 
-        let shift_amount = if instruction & (1 << 4) != 0 {
-            let rs = (instruction >> 8) & 0xf;
-            format!("R{}", rs)
-        } else {
-            let imm = (instruction >> 7) & 0b11111;
-            format!("#{}", imm)
-        };
+    if rd == 15 && !test { Action::Flush } else { Action::Continue }
+}
 
-        let shift_type = match (instruction >> 5) & 0b11 {
-            0b00 => "LSL",
-            0b01 => "ASR",
-            0b10 => "LSR",
-            0b11 => "ROR",
-            _ => unreachable!()
-        };
+fn logical(value: u32) -> AluResult {
+    AluResult { value, carry: None, overflow: None }
+}
 
-        format!("R{} {} {}", rm, shift_type, shift_amount)
-    };
+// Subtraction is addition of the inverse, with the carry meaning no borrow
+fn add_with_carry(op1: u32, op2: u32, carry: bool) -> AluResult {
+    let (sum, carry_out) = op1.overflowing_add(op2);
+    let (value, carry_in_out) = sum.overflowing_add(carry as u32);
+    let overflow = (!(op1 ^ op2) & (op1 ^ value)) & N_BIT != 0;
+    AluResult { value, carry: Some(carry_out || carry_in_out), overflow: Some(overflow) }
+}
 
-    format!("{}{} R{}, R{}, {} ; ALU instruction", opcode, cond, rd, rn, op2)
+fn exec_alu_and(op1: u32, op2: u32, _carry: bool) -> AluResult {
+    logical(op1 & op2)
 }
 
-fn exec_alu_add(registers: &mut RegisterFile, memory: &mut Memory, rd: u32, op1: u32, op2: u32) -> Action {
-    registers.set_reg(rd, op1 + op2);
-    if rd == 15 { Action::Flush } else { Action::Continue }
+fn exec_alu_eor(op1: u32, op2: u32, _carry: bool) -> AluResult {
+    logical(op1 ^ op2)
+}
+
+fn exec_alu_sub(op1: u32, op2: u32, _carry: bool) -> AluResult {
+    add_with_carry(op1, !op2, true)
+}
+
+fn exec_alu_rsb(op1: u32, op2: u32, _carry: bool) -> AluResult {
+    add_with_carry(op2, !op1, true)
+}
+
+fn exec_alu_add(op1: u32, op2: u32, _carry: bool) -> AluResult {
+    add_with_carry(op1, op2, false)
+}
+
+fn exec_alu_adc(op1: u32, op2: u32, carry: bool) -> AluResult {
+    add_with_carry(op1, op2, carry)
+}
+
+fn exec_alu_sbc(op1: u32, op2: u32, carry: bool) -> AluResult {
+    add_with_carry(op1, !op2, carry)
+}
+
+fn exec_alu_rsc(op1: u32, op2: u32, carry: bool) -> AluResult {
+    add_with_carry(op2, !op1, carry)
+}
+
+fn exec_alu_orr(op1: u32, op2: u32, _carry: bool) -> AluResult {
+    logical(op1 | op2)
+}
+
+fn exec_alu_mov(_op1: u32, op2: u32, _carry: bool) -> AluResult {
+    logical(op2)
+}
+
+fn exec_alu_bic(op1: u32, op2: u32, _carry: bool) -> AluResult {
+    logical(op1 & !op2)
 }
 
-fn exec_alu_unimplemented(registers: &mut RegisterFile, memory: &mut Memory, rd: u32, op1: u32, op2: u32) -> Action {
-    exec_unimplemented(registers, memory, 0)
+fn exec_alu_mvn(_op1: u32, op2: u32, _carry: bool) -> AluResult {
+    logical(!op2)
+}
+
+// MUL/MLA
+
+// The multiplier goes through Rs two bits a cycle, stopping once the rest are
+// zero. Only N and Z are set, and R15 can't be the destination.
+fn exec_multiply(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> Action {
+    let accumulate = (instruction & 1 << 21) != 0;
+    let set_flags = (instruction & 1 << 20) != 0;
+    let rd = (instruction >> 16) & 0xf;
+    let rn = (instruction >> 12) & 0xf;
+    let rs = (instruction >> 8) & 0xf;
+    let rm = instruction & 0xf;
+
+    let multiplier = registers.reg_no_flags(rs);
+    let mut result = registers.reg_no_flags(rm).wrapping_mul(multiplier);
+    if accumulate {
+        result = result.wrapping_add(registers.reg_no_flags(rn));
+    }
+    memory.internal_cycles(((32 - multiplier.leading_zeros()).div_ceil(2)).max(1));
+
+    if rd != 15 {
+        registers.set_reg(rd, result);
+    }
+    if set_flags {
+        let mut flags = registers.reg(15) & !(N_BIT | Z_BIT);
+        if result & N_BIT != 0 {
+            flags |= N_BIT;
+        }
+        if result == 0 {
+            flags |= Z_BIT;
+        }
+        registers.set_reg(15, flags);
+    }
+    Action::Continue
+}
+
+// SWP
+
+// The ARM3's swap: a load and then a store to the same address, with the bus
+// held in between
+fn exec_swap(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> Action {
+    let byte_transfer = (instruction & 1 << 22) != 0;
+    let rn = (instruction >> 16) & 0xf;
+    let rd = (instruction >> 12) & 0xf;
+    let rm = instruction & 0xf;
+
+    let address = registers.reg_no_flags(rn);
+    let data = registers.reg(rm);
+    let value = if byte_transfer { memory.load_byte(address) as u32 } else { load_word(memory, address) };
+    // An aborted load doesn't go on to the store
+    if memory.take_abort() {
+        return Action::DataAbort;
+    }
+    if byte_transfer {
+        memory.store_byte(address, data as u8);
+    } else {
+        memory.store(address, data);
+    }
+    memory.internal_cycles(1);
+    if memory.take_abort() {
+        return Action::DataAbort;
+    }
+
+    registers.set_reg_no_flags(rd, value);
+    if rd == 15 { Action::Flush } else { Action::Continue }
 }
 
 // LDR/STR
 
 fn exec_ldr_str(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> Action {
     let op2_is_reg = (instruction & 1 << 25) != 0;
-    let pre_indexing = (instruction & 1 << 24) != 0;
-    let positive_offset = (instruction & 1 << 23) != 0;
-    let byte_transfer = (instruction & 1 << 22) != 0;
-    let write_back = (instruction & 1 << 21) != 0 || !pre_indexing;
-    let is_load = (instruction & 1 << 20) != 0;
-
-    let base_reg = (instruction >> 16) & 0xf;
-    let base = registers.reg_no_flags(base_reg);
-    let sd_reg = (instruction >> 12) & 0xf;
 
     let offset= if op2_is_reg {
+        // Register shifts aren't allowed here
+        if instruction & (1 << 4) != 0 {
+            return Action::Undefined;
+        }
+
         let shift_amount = (instruction >> 7) & 0x1f;
         let shift_type = (instruction >> 5) & 0x3;
         let op2_reg = instruction & 0xf;
         let unshifted = registers.reg(op2_reg);
 
-        match shift_type {
-            0b00 => unshifted << shift_amount,
-            0b01 => (unshifted as i32 >> shift_amount) as u32,
-            0b10 => unshifted >> shift_amount,
-            0b11 => (instruction >> shift_amount) | (instruction << (32 - shift_amount)),
-            _ => unreachable!()
-        }
+        let carry = registers.reg(15) & C_BIT != 0;
+        shift(unshifted, shift_type, shift_amount, true, carry).0
     } else {
         instruction & 0xfff
     };
 
-    let signed_offset = if positive_offset { offset as i32 } else { -(offset as i32) };
-    let address = (base as i32 + if pre_indexing { signed_offset } else { 0 }) as u32;
+    transfer(registers, memory, instruction, offset)
+}
 
-    if byte_transfer {
-        if is_load {
-            registers.set_reg_no_flags(sd_reg, memory.load_byte(address) as u32);
-        } else {
-            memory.store_byte(address, registers.reg(sd_reg) as u8);
-        }
+fn exec_decoded_ldr_str_immediate(registers: &mut RegisterFile, memory: &mut Memory, decoded: &Decoded) -> Action {
+    transfer(registers, memory, decoded.word, decoded.operand)
+}
+
+fn transfer(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32, offset: u32) -> Action {
+    let pre_indexing = (instruction & 1 << 24) != 0;
+    let positive_offset = (instruction & 1 << 23) != 0;
+    let byte_transfer = (instruction & 1 << 22) != 0;
+    let write_back = (instruction & 1 << 21) != 0 || !pre_indexing;
+    let is_load = (instruction & 1 << 20) != 0;
+
+    let base_reg = (instruction >> 16) & 0xf;
+    let base = registers.reg_no_flags(base_reg);
+    let sd_reg = (instruction >> 12) & 0xf;
+
+    let offset_address = if positive_offset { base.wrapping_add(offset) } else { base.wrapping_sub(offset) };
+    let address = if pre_indexing { offset_address } else { base };
+
+    // Post-indexed with W set, the access is made as if from user mode
+    let user_mode = memory.user_mode();
+    if !pre_indexing && (instruction & 1 << 21) != 0 {
+        memory.set_user_mode(true);
+    }
+
+    let loaded = if is_load {
+        Some(if byte_transfer { memory.load_byte(address) as u32 } else { load_word(memory, address) })
     } else {
-        if is_load {
-            registers.set_reg_no_flags(sd_reg, memory.load(address));
+        // R15 is stored with the PSR, 12 bytes on
+        let data = registers.reg(sd_reg).wrapping_add(if sd_reg == 15 { 4 } else { 0 });
+        if byte_transfer {
+            memory.store_byte(address, data as u8);
         } else {
-            memory.store(address, registers.reg(sd_reg));
+            memory.store(address, data);
         }
-    }
+        None
+    };
+    memory.set_user_mode(user_mode);
 
+    // The base is written back even if the access is aborted, which the abort
+    // handler has to undo
     if write_back {
-        registers.set_reg(base_reg, address + if pre_indexing { 0 } else { offset });
+        registers.set_reg_no_flags(base_reg, offset_address);
+    }
+    if memory.take_abort() {
+        return Action::DataAbort;
+    }
+
+    // Loads spend an internal cycle writing the data to the register, and
+    // what's loaded wins over a written back base
+    if let Some(value) = loaded {
+        memory.internal_cycles(1);
+        registers.set_reg_no_flags(sd_reg, value);
     }
 
     if is_load && sd_reg == 15 { Action::Flush } else { Action::Continue}
 }
 
-fn format_ldr_str(address: u32, cond: &str, instruction: u32) -> String {
-    const SHIFT_MNEMONICS: [&str; 4] = ["LSL", "LSR", "ASR", "ROR"];
+// A word load from an address that isn't word aligned rotates the byte
+// addressed to the bottom
+fn load_word(memory: &mut Memory, address: u32) -> u32 {
+    memory.load(address).rotate_right((address & 3) * 8)
+}
+
+// LDM/STM
 
-    let op2_is_reg = (instruction & 1 << 25) != 0;
+fn exec_ldm_stm(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> Action {
     let pre_indexing = (instruction & 1 << 24) != 0;
-    let base_reg = (instruction >> 16) & 0xf;
-    let sd_reg = (instruction >> 12) & 0xf;
+    let ascending = (instruction & 1 << 23) != 0;
+    let psr_or_user = (instruction & 1 << 22) != 0;
+    let write_back = (instruction & 1 << 21) != 0;
+    let is_load = (instruction & 1 << 20) != 0;
 
-    let mnemonic = if instruction & 1 << 20 != 0 { "LDR" } else { "STR" };
-    let b = if instruction & 1 << 22 != 0 { "B" } else { "" };
-    let minus = if instruction & 1 << 23 != 0 { "" } else { "-" };
-    let pling = if instruction & 1 << 21 != 0 { "!" } else { "" };
+    let base_reg = (instruction >> 16) & 0xf;
+    let base = registers.reg_no_flags(base_reg);
+    let list = instruction & 0xffff;
+    let size = list.count_ones() * 4;
 
-    let op2 = if op2_is_reg {
-        let shift_amount = (instruction >> 7) & 0x1f;
-        let shift_type = (instruction >> 5) & 0x3;
-        let op2_reg = instruction & 0xf;
+    // The lowest register goes to the lowest address whichever way the base
+    // moves
+    let (mut address, final_base) = if ascending {
+        (if pre_indexing { base.wrapping_add(4) } else { base }, base.wrapping_add(size))
+    } else {
+        let lowest = base.wrapping_sub(size);
+        (if pre_indexing { lowest } else { lowest.wrapping_add(4) }, lowest)
+    };
 
-        let shift = if shift_amount > 0 {
-            format!(" {} {}", SHIFT_MNEMONICS[shift_type as usize], shift_amount)
-        } else {
-            "".to_owned()
-        };
+    // With S, loading R15 loads the PSR too, and otherwise the registers are
+    // the user mode ones
+    let loads_pc = is_load && list & (1 << 15) != 0;
+    let mode = if psr_or_user && !loads_pc { Mode::User } else { registers.mode() };
+
+    // The base is written back after the first transfer, so a stored base is
+    // the original if it's first in the list and the new one otherwise, and a
+    // loaded base wins
+    let mut written_back = !write_back;
+    if is_load && !written_back {
+        registers.set_reg_no_flags(base_reg, final_base);
+        written_back = true;
+    }
 
-        if pre_indexing {
-            format!("[R{}, {}R{}{}]{}", base_reg, minus, op2_reg, shift, pling)
+    // Once a load is aborted, the rest of the transfers still happen but none
+    // of the registers are written
+    let mut aborted = false;
+    let mut pc = None;
+    for reg in (0..16).filter(|reg| list & (1 << reg) != 0) {
+        let slot = RegisterFile::slot(mode, reg);
+        if is_load {
+            let value = memory.load(address);
+            aborted |= memory.take_abort();
+            if !aborted {
+                if reg == 15 {
+                    pc = Some(value);
+                } else {
+                    registers.slots()[slot] = value;
+                }
+            }
         } else {
-            format!("[R{}], {}R{}{}", base_reg, minus, op2_reg, shift)
+            let value = if reg == 15 { registers.reg(15).wrapping_add(4) } else { registers.slots()[slot] };
+            memory.store(address, value);
         }
-    } else {
-        let offset = instruction & 0xfff;
+        address = address.wrapping_add(4);
 
-        if pre_indexing {
-            if offset == 0 {
-                format!("[R{}]{}", base_reg, pling)
-            } else {
-                format!("[R{}, {}{}]{}", base_reg, minus, offset, pling)
-            }
-        } else {
-            if offset == 0 {
-                format!("[R{}]", base_reg)
-            } else {
-                format!("[R{}], {}{}", base_reg, minus, offset)
-            }
+        if !written_back {
+            registers.set_reg_no_flags(base_reg, final_base);
+            written_back = true;
         }
-    };
+    }
+    if !written_back {
+        registers.set_reg_no_flags(base_reg, final_base);
+    }
+
+    if is_load {
+        memory.internal_cycles(1);
+    }
+    if aborted || memory.take_abort() {
+        return Action::DataAbort;
+    }
 
-    format!("{}{}{} R{}, {}", mnemonic, cond, b, sd_reg, op2)
+    match pc {
+        Some(value) => {
+            let r15 = registers.reg(15);
+            let psr_bits = if !psr_or_user { 0 } else if registers.mode() == Mode::User { FLAGS } else { PSR_BITS };
+            registers.set_reg(15, (r15 & !psr_bits & !PC_BITS) | (value & (psr_bits | PC_BITS)));
+            Action::Flush
+        },
+        None => Action::Continue
+    }
 }
 
-//class LoadStoreInstruction implements Instruction {
-//    stringify(address: number, cond: string, instruction: number): string {
-//    }
+// SWI
+
+fn exec_swi(_registers: &mut RegisterFile, _memory: &mut Memory, instruction: u32) -> Action {
+    Action::Swi(instruction & 0x00ffffff)
+}
 
-fn exec_unimplemented(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> Action {
-    unimplemented!();
+fn exec_decoded_swi(_registers: &mut RegisterFile, _memory: &mut Memory, decoded: &Decoded) -> Action {
+    Action::Swi(decoded.operand)
 }
 
-fn format_unimplemented(address: u32, cond: &str, instruction: u32) -> String {
-    format!("...{}", cond)
+// Coprocessor data operations and transfers. There are no coprocessors to
+// answer, so they're undefined, which is where the floating point emulator
+// picks them up.
+
+fn exec_coprocessor(_registers: &mut RegisterFile, _memory: &mut Memory, _instruction: u32) -> Action {
+    Action::Undefined
 }
 
 type InstructionHandler = fn(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> Action;
-type InstructionFormatter = fn(address: u32, cond: &str, instruction: u32) -> String;
 
 const INSTRUCTION_HANDLERS: [InstructionHandler; 16] = [
     exec_alu,
@@ -300,31 +640,376 @@ const INSTRUCTION_HANDLERS: [InstructionHandler; 16] = [
     exec_ldr_str,
     exec_ldr_str,
     exec_ldr_str,
-    exec_unimplemented,
-    exec_unimplemented,
+    exec_ldm_stm,
+    exec_ldm_stm,
     exec_branch,
     exec_branch,
-    exec_unimplemented,
-    exec_unimplemented,
-    exec_unimplemented,
-    exec_unimplemented,
+    exec_coprocessor,
+    exec_coprocessor,
+    exec_coprocessor,
+    exec_swi,
 ];
 
-const INSTRUCTION_FORMATTERS: [InstructionFormatter; 16] = [
-    format_alu,
-    format_alu,
-    format_alu,
-    format_alu,
-    format_ldr_str,
-    format_ldr_str,
-    format_ldr_str,
-    format_ldr_str,
-    format_unimplemented,
-    format_unimplemented,
-    format_branch,
-    format_branch,
-    format_unimplemented,
-    format_unimplemented,
-    format_unimplemented,
-    format_unimplemented,
-];
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AL: u32 = 0xe << 28;
+    const CODE: u32 = 0x02001000;
+    const DATA: u32 = 0x02000100;
+    // SVC mode with interrupts off
+    const R15_FLAGS: u32 = 0x0c000003;
+
+    const AND: u32 = 0;
+    const SUB: u32 = 2;
+    const RSB: u32 = 3;
+    const ADD: u32 = 4;
+    const ADC: u32 = 5;
+    const SBC: u32 = 6;
+    const RSC: u32 = 7;
+    const TST: u32 = 8;
+    const TEQ: u32 = 9;
+    const CMP: u32 = 10;
+    const CMN: u32 = 11;
+    const ORR: u32 = 12;
+    const MOV: u32 = 13;
+    const BIC: u32 = 14;
+    const MVN: u32 = 15;
+
+    const LSL: u32 = 0;
+    const LSR: u32 = 1;
+    const ASR: u32 = 2;
+    const ROR: u32 = 3;
+
+    fn alu_immediate(opcode: u32, s: bool, rd: u32, rn: u32, rotate: u32, immediate: u32) -> u32 {
+        AL | 1 << 25 | opcode << 21 | (s as u32) << 20 | rn << 16 | rd << 12 | rotate << 8 | immediate
+    }
+
+    fn alu_register(opcode: u32, s: bool, rd: u32, rn: u32, rm: u32, shift_type: u32, amount: u32) -> u32 {
+        AL | opcode << 21 | (s as u32) << 20 | rn << 16 | rd << 12 | amount << 7 | shift_type << 5 | rm
+    }
+
+    fn multiply(accumulate: bool, s: bool, rd: u32, rm: u32, rs: u32, rn: u32) -> u32 {
+        AL | (accumulate as u32) << 21 | (s as u32) << 20 | rd << 16 | rn << 12 | rs << 8 | 0x90 | rm
+    }
+
+    // Pre-indexed upwards unless the flags say otherwise
+    fn transfer(flags: u32, rd: u32, rn: u32, offset: u32) -> u32 {
+        AL | 1 << 26 | ((1 << 24 | 1 << 23) ^ flags) | rn << 16 | rd << 12 | offset
+    }
+
+    const REGISTER_OFFSET: u32 = 1 << 25;
+    const POST_INDEX: u32 = 1 << 24;
+    const DOWN: u32 = 1 << 23;
+    const BYTE: u32 = 1 << 22;
+    const WRITE_BACK: u32 = 1 << 21;
+    const LOAD: u32 = 1 << 20;
+
+    // P, U, S, W and L in bits 4-0 of the mode
+    fn block(mode: u32, rn: u32, list: u32) -> u32 {
+        AL | 0b100 << 25 | mode << 20 | rn << 16 | list
+    }
+
+    const STMDB_WRITE_BACK: u32 = 0b10010;
+    const LDMIA_WRITE_BACK: u32 = 0b01011;
+    const STMIA_WRITE_BACK: u32 = 0b01010;
+    const LDMIA_PSR: u32 = 0b01101;
+    const LDMIB: u32 = 0b11001;
+    const LDMDA: u32 = 0b00001;
+
+    fn setup() -> (RegisterFile, Memory) {
+        let mut registers = RegisterFile::new();
+        registers.set_reg(15, R15_FLAGS | (CODE + 8));
+        (registers, Memory::new(vec![0; 0x1000].into_boxed_slice(), 0x80000))
+    }
+
+    fn set_flags(registers: &mut RegisterFile, flags: u32) {
+        let r15 = registers.reg(15);
+        registers.set_reg(15, (r15 & !FLAGS) | flags);
+    }
+
+    fn flags(registers: &RegisterFile) -> u32 {
+        registers.reg(15) & FLAGS
+    }
+
+    // Run an instruction, and say what it set R0 to and the flags
+    fn result(registers: &mut RegisterFile, memory: &mut Memory, instruction: u32) -> (u32, u32) {
+        exec(registers, memory, instruction);
+        (registers.reg(0), flags(registers))
+    }
+
+    #[test]
+    fn arithmetic() {
+        let (mut registers, mut memory) = setup();
+        for (reg, value) in [(1, 1), (2, 2), (3, 0x7fffffff), (4, 0xffffffff)] {
+            registers.set_reg(reg, value);
+        }
+        let (registers, memory) = (&mut registers, &mut memory);
+
+        // The carry is set when there's no borrow
+        assert_eq!(result(registers, memory, alu_register(SUB, true, 0, 1, 2, LSL, 0)), (0xffffffff, N_BIT));
+        assert_eq!(result(registers, memory, alu_register(SUB, true, 0, 2, 1, LSL, 0)), (1, C_BIT));
+        assert_eq!(result(registers, memory, alu_immediate(ADD, true, 0, 3, 0, 1)), (0x80000000, N_BIT | V_BIT));
+        assert_eq!(result(registers, memory, alu_immediate(ADD, true, 0, 4, 0, 1)), (0, Z_BIT | C_BIT));
+
+        // With the carry in, and without S the flags stay as they were
+        assert_eq!(result(registers, memory, alu_register(ADC, false, 0, 1, 2, LSL, 0)), (4, Z_BIT | C_BIT));
+        set_flags(registers, 0);
+        assert_eq!(result(registers, memory, alu_register(SBC, false, 0, 2, 1, LSL, 0)), (0, 0));
+        assert_eq!(result(registers, memory, alu_immediate(RSB, false, 0, 1, 0, 5)), (4, 0));
+        assert_eq!(result(registers, memory, alu_immediate(RSC, false, 0, 1, 0, 5)), (3, 0));
+        assert_eq!(result(registers, memory, alu_immediate(SBC, true, 0, 3, 0, 0)), (0x7ffffffe, C_BIT));
+        assert_eq!(result(registers, memory, alu_immediate(ADC, true, 0, 3, 0, 0)), (0x80000000, N_BIT | V_BIT));
+
+        // The tests only set the flags
+        registers.set_reg(0, 0x1234);
+        assert_eq!(result(registers, memory, alu_immediate(CMP, true, 0, 1, 0, 1)), (0x1234, Z_BIT | C_BIT));
+        assert_eq!(result(registers, memory, alu_immediate(CMP, true, 0, 1, 0, 2)), (0x1234, N_BIT));
+        assert_eq!(result(registers, memory, alu_immediate(CMN, true, 0, 4, 0, 1)), (0x1234, Z_BIT | C_BIT));
+        assert_eq!(result(registers, memory, alu_immediate(CMN, true, 0, 3, 0, 1)), (0x1234, N_BIT | V_BIT));
+    }
+
+    #[test]
+    fn logical() {
+        let (mut registers, mut memory) = setup();
+        for (reg, value) in [(1, 0xf0f0f0f0), (2, 0x0ff00ff0), (3, 0x80000001)] {
+            registers.set_reg(reg, value);
+        }
+        let (registers, memory) = (&mut registers, &mut memory);
+
+        // The overflow is left alone, and the carry is the shifter's
+        set_flags(registers, V_BIT | C_BIT);
+        assert_eq!(result(registers, memory, alu_register(AND, true, 0, 1, 2, LSL, 0)), (0x00f000f0, V_BIT | C_BIT));
+        assert_eq!(result(registers, memory, alu_register(ORR, true, 0, 1, 3, LSR, 1)), (0xf0f0f0f0, N_BIT | V_BIT | C_BIT));
+        assert_eq!(result(registers, memory, alu_register(BIC, true, 0, 1, 1, LSL, 0)), (0, Z_BIT | V_BIT | C_BIT));
+        assert_eq!(result(registers, memory, alu_register(MOV, true, 0, 0, 3, LSL, 1)), (2, V_BIT | C_BIT));
+        assert_eq!(result(registers, memory, alu_register(MOV, true, 0, 0, 3, LSL, 2)), (4, V_BIT));
+
+        // A rotated immediate carries out its top bit, and an unrotated one
+        // leaves the carry
+        assert_eq!(result(registers, memory, alu_immediate(MVN, true, 0, 0, 1, 2)), (0x7fffffff, V_BIT | C_BIT));
+        assert_eq!(result(registers, memory, alu_immediate(MOV, true, 0, 0, 0, 0)), (0, Z_BIT | V_BIT | C_BIT));
+
+        registers.set_reg(0, 0x1234);
+        assert_eq!(result(registers, memory, alu_register(TST, true, 0, 1, 2, LSL, 0)), (0x1234, V_BIT | C_BIT));
+        assert_eq!(result(registers, memory, alu_register(TEQ, true, 0, 1, 1, LSL, 0)), (0x1234, Z_BIT | V_BIT | C_BIT));
+    }
+
+    #[test]
+    fn shifts() {
+        let cases = [
+            // Value, shift type, amount, by an immediate, carry in, result
+            (0x80000001, LSL, 1, true, false, (2, true)),
+            (0x80000001, LSL, 0, true, true, (0x80000001, true)),
+            (1, LSL, 32, false, false, (0, true)),
+            (1, LSL, 33, false, true, (0, false)),
+            (0x80000002, LSR, 1, true, true, (0x40000001, false)),
+            (0x80000000, LSR, 0, true, false, (0, true)),
+            (0x80000000, LSR, 0, false, false, (0x80000000, false)),
+            (0x80000000, LSR, 32, false, false, (0, true)),
+            (0x80000000, LSR, 40, false, true, (0, false)),
+            (0x80000006, ASR, 2, true, false, (0xe0000001, true)),
+            (0x80000000, ASR, 0, true, false, (0xffffffff, true)),
+            (0x40000000, ASR, 40, false, true, (0, false)),
+            (3, ROR, 1, true, false, (0x80000001, true)),
+            (3, ROR, 0, true, true, (0x80000001, true)),
+            (2, ROR, 0, true, false, (1, false)),
+            (0x80000000, ROR, 32, false, false, (0x80000000, true)),
+            (3, ROR, 33, false, false, (0x80000001, true)),
+        ];
+        for &(value, shift_type, amount, immediate, carry, expected) in &cases {
+            assert_eq!(shift(value, shift_type, amount, immediate, carry), expected, "{:x} {} {}", value, shift_type, amount);
+        }
+    }
+
+    #[test]
+    fn psr_writes() {
+        let (mut registers, mut memory) = setup();
+
+        // MOVS PC, R14 takes the mode, interrupt disables and flags along with
+        // the PC
+        registers.set_reg(14, N_BIT | 0x8000);
+        assert!(matches!(exec(&mut registers, &mut memory, alu_register(MOV, true, 15, 0, 14, LSL, 0)), Action::Flush));
+        assert_eq!(registers.reg(15), N_BIT | 0x8000);
+        assert_eq!(registers.mode(), Mode::User);
+
+        // In user mode, only the flags
+        registers.set_reg(14, 0xfc000003 | 0x9000);
+        exec(&mut registers, &mut memory, alu_register(MOV, true, 15, 0, 14, LSL, 0));
+        assert_eq!(registers.reg(15), FLAGS | 0x9000);
+
+        // Without S, only the PC
+        registers.set_reg(0, 0x0c000003 | 0xa000);
+        exec(&mut registers, &mut memory, alu_register(MOV, false, 15, 0, 0, LSL, 0));
+        assert_eq!(registers.reg(15), FLAGS | 0xa000);
+
+        // TEQP sets the PSR and leaves the PC
+        let (mut registers, mut memory) = setup();
+        registers.set_reg(1, 0x08000002 | Z_BIT);
+        assert!(matches!(exec(&mut registers, &mut memory, alu_immediate(TEQ, true, 15, 1, 0, 0)), Action::Continue));
+        assert_eq!(registers.reg(15), 0x08000002 | Z_BIT | (CODE + 8));
+        assert_eq!(registers.mode(), Mode::Irq);
+    }
+
+    #[test]
+    fn multiplies() {
+        let (mut registers, mut memory) = setup();
+        for (reg, value) in [(1, 6), (2, 7), (3, (-42i32) as u32), (4, 0xffffffff)] {
+            registers.set_reg(reg, value);
+        }
+        set_flags(&mut registers, C_BIT | V_BIT);
+
+        let start = memory.clock();
+        assert_eq!(result(&mut registers, &mut memory, multiply(false, false, 0, 1, 2, 0)), (42, C_BIT | V_BIT));
+        assert_eq!(memory.clock() - start, 2);
+
+        // Only N and Z are set
+        assert_eq!(result(&mut registers, &mut memory, multiply(true, true, 0, 1, 2, 3)), (0, Z_BIT | C_BIT | V_BIT));
+        assert_eq!(result(&mut registers, &mut memory, multiply(false, true, 0, 4, 1, 0)), (-6i32 as u32, N_BIT | C_BIT | V_BIT));
+
+        // Two bits of the multiplier a cycle
+        let start = memory.clock();
+        exec(&mut registers, &mut memory, multiply(false, false, 0, 1, 4, 0));
+        assert_eq!(memory.clock() - start, 16);
+        assert_eq!(registers.reg(0), -6i32 as u32);
+    }
+
+    #[test]
+    fn swaps() {
+        let (mut registers, mut memory) = setup();
+        memory.store(DATA, 0x11223344);
+        registers.set_reg(1, DATA);
+        registers.set_reg(2, 0xaabbccdd);
+
+        exec(&mut registers, &mut memory, AL | 1 << 24 | 1 << 16 | 0x90 | 2);
+        assert_eq!(registers.reg(0), 0x11223344);
+        assert_eq!(memory.load(DATA), 0xaabbccdd);
+
+        registers.set_reg(1, DATA + 1);
+        registers.set_reg(2, 0x55);
+        exec(&mut registers, &mut memory, AL | 1 << 24 | 1 << 22 | 1 << 16 | 0x90 | 2);
+        assert_eq!(registers.reg(0), 0xcc);
+        assert_eq!(memory.load(DATA), 0xaabb55dd);
+    }
+
+    #[test]
+    fn transfers() {
+        let (mut registers, mut memory) = setup();
+        memory.store(DATA, 0x11223344);
+        memory.store(DATA + 4, 0x55667788);
+        registers.set_reg(1, DATA);
+        let (registers, memory) = (&mut registers, &mut memory);
+
+        // An unaligned word load rotates
+        exec(registers, memory, transfer(LOAD, 0, 1, 1));
+        assert_eq!(registers.reg(0), 0x44112233);
+
+        // Post-indexed downwards writes back
+        exec(registers, memory, transfer(LOAD | POST_INDEX | DOWN, 0, 1, 4));
+        assert_eq!(registers.reg(0), 0x11223344);
+        assert_eq!(registers.reg(1), DATA - 4);
+
+        // A register offset, shifted
+        registers.set_reg(1, DATA);
+        registers.set_reg(2, 0x10);
+        exec(registers, memory, transfer(LOAD | REGISTER_OFFSET, 0, 1, 2 << 7 | LSR << 5 | 2));
+        assert_eq!(registers.reg(0), 0x55667788);
+        exec(registers, memory, transfer(LOAD | BYTE | REGISTER_OFFSET, 0, 1, 3 << 7 | LSR << 5 | 2));
+        assert_eq!(registers.reg(0), 0x22);
+
+        // What's loaded wins over the written back base
+        exec(registers, memory, transfer(LOAD | WRITE_BACK, 1, 1, 4));
+        assert_eq!(registers.reg(1), 0x55667788);
+
+        // R15 is stored with the PSR, 12 bytes on
+        registers.set_reg(1, DATA);
+        exec(registers, memory, transfer(0, 15, 1, 0));
+        assert_eq!(memory.load(DATA), R15_FLAGS | (CODE + 12));
+
+        // Register shifts aren't allowed
+        assert!(matches!(exec(registers, memory, transfer(LOAD | REGISTER_OFFSET, 0, 1, 1 << 4 | 2)), Action::Undefined));
+    }
+
+    #[test]
+    fn block_transfers() {
+        let (mut registers, mut memory) = setup();
+        for (reg, value) in [(0, 1), (1, 2), (2, 3), (14, 4), (13, DATA + 0x100)] {
+            registers.set_reg(reg, value);
+        }
+        let (registers, memory) = (&mut registers, &mut memory);
+
+        // Pushed and pulled, lowest register at the lowest address
+        exec(registers, memory, block(STMDB_WRITE_BACK, 13, 0x4007));
+        assert_eq!(registers.reg(13), DATA + 0xf0);
+        assert_eq!((0..4).map(|i| memory.load(DATA + 0xf0 + i * 4)).collect::<Vec<_>>(), [1, 2, 3, 4]);
+        exec(registers, memory, block(LDMIA_WRITE_BACK, 13, 0x00f0));
+        assert_eq!((4..8).map(|reg| registers.reg(reg)).collect::<Vec<_>>(), [1, 2, 3, 4]);
+        assert_eq!(registers.reg(13), DATA + 0x100);
+
+        // The other two addressing modes
+        registers.set_reg(0, DATA + 0xf4);
+        exec(registers, memory, block(LDMIB, 0, 0x0002));
+        assert_eq!(registers.reg(1), 3);
+        exec(registers, memory, block(LDMDA, 0, 0x0006));
+        assert_eq!((registers.reg(1), registers.reg(2)), (1, 2));
+
+        // A stored base is the original if it's first, and the written back
+        // one otherwise
+        registers.set_reg(0, DATA);
+        registers.set_reg(1, DATA + 0x20);
+        exec(registers, memory, block(STMIA_WRITE_BACK, 0, 0x0003));
+        assert_eq!(memory.load(DATA), DATA);
+        exec(registers, memory, block(STMIA_WRITE_BACK, 1, 0x0003));
+        assert_eq!(memory.load(DATA + 0x24), DATA + 0x28);
+
+        // With S and no PC, the user mode registers
+        memory.store(DATA + 0x40, 0x1111);
+        memory.store(DATA + 0x44, 0x2222);
+        registers.set_reg(0, DATA + 0x40);
+        registers.set_reg(13, 0x3333);
+        exec(registers, memory, AL | block(LDMIA_PSR, 0, 0x6000) | 1 << 22);
+        assert_eq!(registers.reg(13), 0x3333);
+        assert_eq!(registers.slots()[13..15], [0x1111, 0x2222]);
+
+        // And with the PC, the PSR too
+        memory.store(DATA + 0x40, N_BIT | 0x3000);
+        assert!(matches!(exec(registers, memory, block(LDMIA_PSR, 0, 0x8000)), Action::Flush));
+        assert_eq!(registers.reg(15), N_BIT | 0x3000);
+    }
+
+    #[test]
+    fn coprocessor() {
+        let (mut registers, mut memory) = setup();
+        for &word in &[0xee000010, 0xee000000, 0xec800100, 0xed900100] {
+            assert!(matches!(exec(&mut registers, &mut memory, word), Action::Undefined));
+        }
+    }
+
+    // Decoded forms do just the same as the instructions run from scratch
+    #[test]
+    fn decoded() {
+        let words = [
+            alu_immediate(MOV, true, 0, 0, 1, 2),
+            alu_immediate(SUB, true, 1, 2, 0, 3),
+            alu_immediate(ADC, false, 2, 1, 4, 0xff),
+            alu_immediate(TEQ, true, 15, 2, 0, 0),
+            transfer(LOAD, 3, 4, 4),
+            transfer(WRITE_BACK, 2, 4, 8),
+        ];
+        for &word in &words {
+            let (mut registers, mut memory) = setup();
+            let (mut expected, mut expected_memory) = setup();
+            for reg in 0..4 {
+                registers.set_reg(reg, reg * 0x40000001);
+                expected.set_reg(reg, reg * 0x40000001);
+            }
+            registers.set_reg(4, DATA);
+            expected.set_reg(4, DATA);
+
+            decode(word).exec(&mut registers, &mut memory);
+            exec(&mut expected, &mut expected_memory, word);
+            assert_eq!(registers.slots(), expected.slots(), "{:08x}", word);
+            assert_eq!(memory.clock(), expected_memory.clock());
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::io;
+use webarc::device::{Device, Interrupts};
+use webarc::snapshot::{StateReader, StateWriter};
+
+// IOC internal registers live in bank 0 of the I/O space at 0x3200000.
+// IOC's data lines are wired to bits 16-23 of the data bus, which is where
+// both STR and STRB (which replicates the byte) leave the value.
+
+const CONTROL: u32 = 0x00;
+
+// Control register lines
+pub const CONTROL_SDA: u8 = 1 << 0;
+pub const CONTROL_SCL: u8 = 1 << 1;
+const IRQ_A_STATUS: u32 = 0x10;
+const IRQ_A_REQUEST: u32 = 0x14;
+const IRQ_A_MASK: u32 = 0x18;
+const IRQ_B_STATUS: u32 = 0x20;
+const IRQ_B_REQUEST: u32 = 0x24;
+const IRQ_B_MASK: u32 = 0x28;
+const FIQ_STATUS: u32 = 0x30;
+const FIQ_REQUEST: u32 = 0x34;
+const FIQ_MASK: u32 = 0x38;
+
+// Four 16-bit timers from 0x40, 0x10 bytes apart, counting down at 2MHz and
+// reloading from their latch as they pass zero. Timers 0 and 1 raise IRQ A,
+// and timers 2 and 3 set the serial and keyboard baud rates.
+const TIMERS_START: u32 = 0x40;
+const TIMERS_END: u32 = 0x7c;
+const TIMER_LOW: u32 = 0x0;
+const TIMER_HIGH: u32 = 0x4;
+const TIMER_GO: u32 = 0x8;
+const TIMER_LATCH: u32 = 0xc;
+
+const CYCLES_PER_TIMER_TICK: u32 = 4;
+
+// IRQ A sources
+pub const IRQ_A_PRINTER_BUSY: u8 = 1 << 0;
+pub const IRQ_A_SERIAL_RINGING: u8 = 1 << 1;
+pub const IRQ_A_PRINTER_ACK: u8 = 1 << 2;
+pub const IRQ_A_VSYNC: u8 = 1 << 3;
+pub const IRQ_A_POWER_ON_RESET: u8 = 1 << 4;
+pub const IRQ_A_TIMER_0: u8 = 1 << 5;
+pub const IRQ_A_TIMER_1: u8 = 1 << 6;
+pub const IRQ_A_FORCE: u8 = 1 << 7;
+
+// IRQ A events that stay set until cleared through the request register
+pub const IRQ_A_LATCHED: u8 = 0x7c;
+// IRQ A inputs from other devices that are level sensitive
+const IRQ_A_LEVELS: u8 = IRQ_A_PRINTER_BUSY | IRQ_A_SERIAL_RINGING;
+
+// IRQ B sources
+pub const IRQ_B_SOUND: u8 = 1 << 1;
+pub const IRQ_B_SERIAL: u8 = 1 << 2;
+pub const IRQ_B_WINCHESTER: u8 = 1 << 3;
+pub const IRQ_B_PODULE: u8 = 1 << 5;
+
+// FIQ sources
+pub const FIQ_FLOPPY_DATA: u8 = 1 << 0;
+pub const FIQ_FLOPPY_INTERRUPT: u8 = 1 << 1;
+pub const FIQ_PODULE: u8 = 1 << 6;
+pub const FIQ_FORCE: u8 = 1 << 7;
+
+#[derive(Clone, Copy, Default)]
+struct Timer {
+    count: u16,
+    latch: u16,
+    // Count as of the last latch command, which is what reads return
+    output: u16,
+}
+
+impl Timer {
+    // Count down, returning whether the timer reloaded on the way
+    fn advance(&mut self, ticks: u32) -> bool {
+        let count = self.count as u32;
+        if ticks <= count {
+            self.count = (count - ticks) as u16;
+            return false;
+        }
+
+        let period = self.latch as u32 + 1;
+        let overshoot = (ticks - count - 1) % period;
+        self.count = (self.latch as u32 - overshoot) as u16;
+        true
+    }
+}
+
+pub struct Ioc {
+    control: u8,
+    control_inputs: u8,
+    irq_a_status: u8,
+    irq_a_mask: u8,
+    irq_b_status: u8,
+    irq_b_mask: u8,
+    fiq_status: u8,
+    fiq_mask: u8,
+    timers: [Timer; 4],
+    // Cycles towards the next timer tick
+    timer_cycles: u32,
+}
+
+impl Ioc {
+    pub fn new() -> Ioc {
+        Ioc {
+            control: 0xff,
+            control_inputs: 0xff,
+            irq_a_status: IRQ_A_FORCE | IRQ_A_POWER_ON_RESET,
+            irq_a_mask: 0,
+            irq_b_status: 0,
+            irq_b_mask: 0,
+            fiq_status: FIQ_FORCE,
+            fiq_mask: 0,
+            timers: [Timer::default(); 4],
+            timer_cycles: 0,
+        }
+    }
+
+    // Levels the control register pins have been driven to by this chip
+    pub fn control(&self) -> u8 {
+        self.control
+    }
+
+    // The control register pins are open drain, so other devices on them can
+    // pull them low
+    pub fn set_control_inputs(&mut self, inputs: u8) {
+        self.control_inputs = inputs;
+    }
+
+    // Take the interrupt inputs driven by the other devices. Latched IRQ A
+    // inputs are edges, each passed on once, and everything else follows its
+    // input.
+    pub fn set_inputs(&mut self, inputs: Interrupts) {
+        let edges = inputs.irq_a & IRQ_A_LATCHED;
+        self.irq_a_status = (self.irq_a_status & !IRQ_A_LEVELS) | (inputs.irq_a & IRQ_A_LEVELS) | edges;
+
+        self.irq_b_status = inputs.irq_b;
+        self.fiq_status = (self.fiq_status & FIQ_FORCE) | (inputs.fiq & !FIQ_FORCE);
+    }
+
+    pub fn irq(&self) -> bool {
+        (self.irq_a_status & self.irq_a_mask) | (self.irq_b_status & self.irq_b_mask) != 0
+    }
+
+    pub fn fiq(&self) -> bool {
+        self.fiq_status & self.fiq_mask != 0
+    }
+}
+
+impl Device for Ioc {
+    fn load(&mut self, offset: u32) -> u32 {
+        let register = offset & 0x7c;
+        let value = match register {
+            CONTROL => self.control & self.control_inputs,
+            IRQ_A_STATUS => self.irq_a_status,
+            IRQ_A_REQUEST => self.irq_a_status & self.irq_a_mask,
+            IRQ_A_MASK => self.irq_a_mask,
+            IRQ_B_STATUS => self.irq_b_status,
+            IRQ_B_REQUEST => self.irq_b_status & self.irq_b_mask,
+            IRQ_B_MASK => self.irq_b_mask,
+            FIQ_STATUS => self.fiq_status,
+            FIQ_REQUEST => self.fiq_status & self.fiq_mask,
+            FIQ_MASK => self.fiq_mask,
+            TIMERS_START..=TIMERS_END => {
+                let timer = &self.timers[((register - TIMERS_START) >> 4) as usize];
+                match register & 0xc {
+                    TIMER_LOW => timer.output as u8,
+                    TIMER_HIGH => (timer.output >> 8) as u8,
+                    _ => 0
+                }
+            },
+            _ => 0
+        };
+        value as u32
+    }
+
+    fn store(&mut self, offset: u32, data: u32) {
+        let value = (data >> 16) as u8;
+        let register = offset & 0x7c;
+        match register {
+            CONTROL => self.control = value | 0xc0,
+            IRQ_A_REQUEST => self.irq_a_status &= !(value & IRQ_A_LATCHED),
+            IRQ_A_MASK => self.irq_a_mask = value,
+            IRQ_B_MASK => self.irq_b_mask = value,
+            FIQ_MASK => self.fiq_mask = value,
+            TIMERS_START..=TIMERS_END => {
+                let timer = &mut self.timers[((register - TIMERS_START) >> 4) as usize];
+                match register & 0xc {
+                    TIMER_LOW => timer.latch = (timer.latch & 0xff00) | value as u16,
+                    TIMER_HIGH => timer.latch = (timer.latch & 0x00ff) | (value as u16) << 8,
+                    TIMER_GO => timer.count = timer.latch,
+                    TIMER_LATCH => timer.output = timer.count,
+                    _ => unreachable!()
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.timer_cycles += cycles;
+        let ticks = self.timer_cycles / CYCLES_PER_TIMER_TICK;
+        self.timer_cycles %= CYCLES_PER_TIMER_TICK;
+        if ticks == 0 {
+            return;
+        }
+
+        if self.timers[0].advance(ticks) {
+            self.irq_a_status |= IRQ_A_TIMER_0;
+        }
+        if self.timers[1].advance(ticks) {
+            self.irq_a_status |= IRQ_A_TIMER_1;
+        }
+        self.timers[2].advance(ticks);
+        self.timers[3].advance(ticks);
+    }
+
+    // The next reload of a timer whose interrupt isn't already waiting to be
+    // cleared. Clearing it is an access, which looks again.
+    fn next_event(&self) -> Option<u32> {
+        [(0, IRQ_A_TIMER_0), (1, IRQ_A_TIMER_1)].iter()
+            .filter(|&&(_, bit)| self.irq_a_status & bit == 0)
+            .map(|&(timer, _)| (self.timers[timer].count as u32 + 1) * CYCLES_PER_TIMER_TICK - self.timer_cycles)
+            .min()
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        for &register in &[
+            self.control, self.control_inputs, self.irq_a_status, self.irq_a_mask,
+            self.irq_b_status, self.irq_b_mask, self.fiq_status, self.fiq_mask
+        ] {
+            state.u8(register);
+        }
+        for timer in &self.timers {
+            state.u16(timer.count);
+            state.u16(timer.latch);
+            state.u16(timer.output);
+        }
+        state.u32(self.timer_cycles);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for register in [
+            &mut self.control, &mut self.control_inputs, &mut self.irq_a_status, &mut self.irq_a_mask,
+            &mut self.irq_b_status, &mut self.irq_b_mask, &mut self.fiq_status, &mut self.fiq_mask
+        ] {
+            *register = state.u8()?;
+        }
+        for timer in self.timers.iter_mut() {
+            timer.count = state.u16()?;
+            timer.latch = state.u16()?;
+            timer.output = state.u16()?;
+        }
+        self.timer_cycles = state.u32()?;
+        Ok(())
+    }
+}
+
+impl Default for Ioc {
+    fn default() -> Ioc {
+        Ioc::new()
+    }
+}
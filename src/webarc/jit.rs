@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::mem;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, FuncRef, InstBuilder, MemFlags, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use webarc::cpu::Cpu;
+use webarc::icache::PAGE_SHIFT;
+use webarc::instructions::{self, Action};
+use webarc::memory::Memory;
+use webarc::registers::{Mode, RegisterFile};
+
+// Dynamic recompiler. Straight runs of ARM code are translated into host code
+// a block at a time with Cranelift, and the CPU runs a whole block in one go
+// where it can.
+//
+// A block is the data processing instructions the translator handles itself,
+// ending with a branch, with one instruction it doesn't (which the block calls
+// the interpreter for), or at the end of a page. Anything that can change the
+// mode, take an exception or touch memory therefore only ever comes last, so
+// the CPU can charge every fetch in the block before running it and sort out
+// the PC afterwards just as it does for a single instruction. Blocks are only
+// kept while nothing has written to the page they came from, and each one
+// remembers the block that followed it last time to skip the lookup.
+
+const MAX_BLOCK_INSTRUCTIONS: u32 = 64;
+// Blocks thrown away before all the code is freed and translation starts over
+const MAX_DEAD_BLOCKS: usize = 4096;
+
+// What a block returns: how the CPU should carry on from the last instruction
+const EXIT_CONTINUE: u32 = 0;
+const EXIT_FLUSH: u32 = 1;
+const EXIT_UNDEFINED: u32 = 2;
+const EXIT_DATA_ABORT: u32 = 3;
+// With the comment field in the low 24 bits
+const EXIT_SWI: u32 = 1 << 31;
+
+const OPCODE_ADD: u32 = 4;
+
+const N_BIT: u32 = 0x80000000;
+const Z_BIT: u32 = 0x40000000;
+const C_BIT: u32 = 0x20000000;
+const V_BIT: u32 = 0x10000000;
+
+pub type BlockCode = unsafe extern "C" fn(registers: *mut u32, cpu: *mut Cpu) -> u32;
+
+pub fn exit_action(exit: u32) -> Action {
+    match exit {
+        EXIT_CONTINUE => Action::Continue,
+        EXIT_FLUSH => Action::Flush,
+        EXIT_UNDEFINED => Action::Undefined,
+        EXIT_DATA_ABORT => Action::DataAbort,
+        _ => Action::Swi(exit & 0x00ffffff)
+    }
+}
+
+// The interpreter, for the instruction that ends a block
+unsafe extern "C" fn exec_instruction(cpu: *mut Cpu, word: u32) -> u32 {
+    let cpu = &mut *cpu;
+    match instructions::exec(&mut cpu.registers, &mut cpu.memory, word) {
+        Action::Continue => EXIT_CONTINUE,
+        Action::Flush => EXIT_FLUSH,
+        Action::Undefined => EXIT_UNDEFINED,
+        Action::DataAbort => EXIT_DATA_ABORT,
+        Action::Swi(comment) => EXIT_SWI | comment
+    }
+}
+
+enum Kind {
+    // Translated
+    Native,
+    // Translated, and ends the block
+    Branch,
+    // Left to the interpreter, and ends the block
+    Other,
+}
+
+fn classify(word: u32) -> Kind {
+    let rd = (word >> 12) & 0xf;
+    let rn = (word >> 16) & 0xf;
+    let rm = word & 0xf;
+    let immediate = word & (1 << 25) != 0;
+
+    match (word >> 24) & 0xf {
+        0x0..=0x3 => {
+            let set_flags = word & (1 << 20) != 0;
+            let register_shift = !immediate && word & (1 << 4) != 0;
+            let reads_pc = !immediate && rm == 15;
+            // Shifts by 0 other than LSL are LSR #32, ASR #32 and RRX
+            let special_shift = !immediate && (word >> 7) & 0x1f == 0 && (word >> 5) & 0b11 != 0;
+            if (word >> 21) & 0xf == OPCODE_ADD && !set_flags && rd != 15 && rn != 15 && !register_shift && !reads_pc && !special_shift {
+                Kind::Native
+            } else {
+                Kind::Other
+            }
+        },
+        0xa | 0xb => Kind::Branch,
+        _ => Kind::Other
+    }
+}
+
+struct Block {
+    code: BlockCode,
+    length: u32,
+    // Where the code came from, and the page's writes when it was translated
+    code_address: u32,
+    generation: u32,
+    // The block that ran next last time, by key and index
+    link: Option<(u32, usize)>,
+}
+
+// What's at a fetch address: a block, or nothing worth translating, as of a
+// page generation
+struct Entry {
+    generation: u32,
+    block: Option<usize>,
+}
+
+pub struct Jit {
+    module: Option<JITModule>,
+    context: Context,
+    builder_context: FunctionBuilderContext,
+    exec_instruction: FuncId,
+
+    blocks: Vec<Block>,
+    // By fetch address, with the mode in the bottom two bits
+    entries: HashMap<u32, Entry>,
+    dead: usize,
+    previous: Option<usize>,
+}
+
+impl Jit {
+    pub fn new() -> Result<Jit, String> {
+        let (module, exec_instruction) = Jit::module()?;
+        Ok(Jit {
+            context: module.make_context(),
+            module: Some(module),
+            builder_context: FunctionBuilderContext::new(),
+            exec_instruction,
+            blocks: Vec::new(),
+            entries: HashMap::new(),
+            dead: 0,
+            previous: None,
+        })
+    }
+
+    fn module() -> Result<(JITModule, FuncId), String> {
+        let mut flags = settings::builder();
+        flags.set("use_colocated_libcalls", "false").map_err(|error| error.to_string())?;
+        flags.set("is_pic", "false").map_err(|error| error.to_string())?;
+        let isa = cranelift_native::builder()?
+            .finish(settings::Flags::new(flags))
+            .map_err(|error| error.to_string())?;
+
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("webarc_exec_instruction", exec_instruction as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let pointer = module.target_config().pointer_type();
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(types::I32));
+        signature.returns.push(AbiParam::new(types::I32));
+        let exec_instruction = module.declare_function("webarc_exec_instruction", Linkage::Import, &signature)
+            .map_err(|error| error.to_string())?;
+
+        Ok((module, exec_instruction))
+    }
+
+    // Throw away every block and the code memory they're in
+    pub fn flush(&mut self) {
+        if let Some(module) = self.module.take() {
+            // Nothing refers to the blocks any more
+            unsafe { module.free_memory() };
+        }
+        match Jit::module() {
+            Ok((module, exec_instruction)) => {
+                self.module = Some(module);
+                self.exec_instruction = exec_instruction;
+            },
+            Err(error) => println!("Couldn't restart the JIT: {}", error)
+        }
+        self.blocks.clear();
+        self.entries.clear();
+        self.dead = 0;
+        self.previous = None;
+    }
+
+    // The block to run from a fetch address, translating it if need be, with
+    // how many instructions it covers
+    pub fn block(&mut self, memory: &Memory, fetch_address: u32, mode: Mode) -> Option<(BlockCode, u32)> {
+        // Code that can't be fetched is left to abort in the interpreter
+        let masked_address = fetch_address & 0x03fffffc;
+        if !memory.readable(masked_address) {
+            return None;
+        }
+        let code_address = memory.code_address(masked_address)?;
+        let generation = memory.code_generation(code_address);
+        let key = fetch_address | mode as u32;
+        let current = |block: &Block| block.code_address == code_address && block.generation == generation;
+
+        let linked = self.previous
+            .and_then(|previous| self.blocks[previous].link)
+            .filter(|&(link_key, index)| link_key == key && current(&self.blocks[index]))
+            .map(|(_, index)| index);
+
+        let index = match linked {
+            Some(index) => index,
+            None => {
+                let index = match self.entries.get(&key) {
+                    Some(entry) if entry.generation == generation && entry.block.is_none_or(|index| current(&self.blocks[index])) => entry.block,
+                    stale => {
+                        if stale.is_some_and(|entry| entry.block.is_some()) {
+                            self.dead += 1;
+                        }
+                        if self.dead > MAX_DEAD_BLOCKS {
+                            self.flush();
+                        }
+
+                        let block = self.translate(memory, fetch_address, code_address, generation, mode);
+                        self.entries.insert(key, Entry { generation, block });
+                        block
+                    }
+                }?;
+                if let Some(previous) = self.previous {
+                    self.blocks[previous].link = Some((key, index));
+                }
+                index
+            }
+        };
+
+        self.previous = Some(index);
+        let block = &self.blocks[index];
+        Some((block.code, block.length))
+    }
+
+    fn translate(&mut self, memory: &Memory, fetch_address: u32, code_address: u32, generation: u32, mode: Mode) -> Option<usize> {
+        // Up to the end of the page, since that's what writes are tracked by
+        let page_words = (((code_address >> PAGE_SHIFT) + 1) << PAGE_SHIFT).wrapping_sub(code_address) / 4;
+        let mut words = Vec::new();
+        for i in 0..page_words.min(MAX_BLOCK_INSTRUCTIONS) {
+            let word = memory.peek_code(code_address + i * 4);
+            words.push(word);
+            match classify(word) {
+                Kind::Native => continue,
+                Kind::Branch | Kind::Other => break
+            }
+        }
+
+        // A lone instruction the interpreter would run anyway isn't worth it
+        if words.len() == 1 {
+            if let Kind::Other = classify(words[0]) {
+                return None;
+            }
+        }
+
+        match self.compile(&words, fetch_address, mode) {
+            Ok(code) => {
+                self.blocks.push(Block {
+                    code,
+                    length: words.len() as u32,
+                    code_address,
+                    generation,
+                    link: None,
+                });
+                Some(self.blocks.len() - 1)
+            },
+            Err(error) => {
+                println!("Couldn't translate block at {:08X}: {}", fetch_address, error);
+                None
+            }
+        }
+    }
+
+    fn compile(&mut self, words: &[u32], fetch_address: u32, mode: Mode) -> Result<BlockCode, String> {
+        let module = self.module.as_mut().ok_or("JIT not running")?;
+        let pointer = module.target_config().pointer_type();
+
+        self.context.func.signature.params.push(AbiParam::new(pointer));
+        self.context.func.signature.params.push(AbiParam::new(pointer));
+        self.context.func.signature.returns.push(AbiParam::new(types::I32));
+
+        {
+            let mut builder = FunctionBuilder::new(&mut self.context.func, &mut self.builder_context);
+            let exec_instruction = module.declare_func_in_func(self.exec_instruction, builder.func);
+
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let registers = builder.block_params(entry)[0];
+            let cpu = builder.block_params(entry)[1];
+
+            let mut translator = Translator { builder, registers, cpu, mode, exec_instruction };
+            translator.instructions(words, fetch_address);
+            translator.builder.seal_all_blocks();
+            translator.builder.finalize();
+        }
+
+        let context = &mut self.context;
+        let result = match module.declare_anonymous_function(&context.func.signature) {
+            Ok(id) => module.define_function(id, context).map(|_| id),
+            Err(error) => Err(error),
+        }.map_err(|error| error.to_string());
+        module.clear_context(context);
+        let id = result?;
+
+        module.finalize_definitions().map_err(|error| error.to_string())?;
+        let code = module.get_finalized_function(id);
+        Ok(unsafe { mem::transmute::<*const u8, BlockCode>(code) })
+    }
+}
+
+// Builds the host code for one block
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    registers: Value,
+    cpu: Value,
+    mode: Mode,
+    exec_instruction: FuncRef,
+}
+
+impl<'a> Translator<'a> {
+    fn instructions(&mut self, words: &[u32], fetch_address: u32) {
+        for (i, &word) in words.iter().enumerate() {
+            let address = fetch_address + i as u32 * 4;
+            match classify(word) {
+                Kind::Native => self.conditional(word, |translator| translator.add(word)),
+                Kind::Branch => return self.branch(word, address),
+                Kind::Other => return self.interpret(word, address)
+            }
+        }
+
+        let last = fetch_address + (words.len() as u32 - 1) * 4;
+        self.set_pc(last + 8);
+        self.exit(EXIT_CONTINUE);
+    }
+
+    fn offset(&self, reg: u32) -> i32 {
+        (RegisterFile::slot(self.mode, reg) * 4) as i32
+    }
+
+    fn load(&mut self, reg: u32) -> Value {
+        let offset = self.offset(reg);
+        self.builder.ins().load(types::I32, MemFlags::trusted(), self.registers, offset)
+    }
+
+    fn store(&mut self, reg: u32, value: Value) {
+        let offset = self.offset(reg);
+        self.builder.ins().store(MemFlags::trusted(), value, self.registers, offset);
+    }
+
+    fn constant(&mut self, value: u32) -> Value {
+        self.builder.ins().iconst(types::I32, value as i32 as i64)
+    }
+
+    fn exit(&mut self, exit: u32) {
+        let exit = self.constant(exit);
+        self.builder.ins().return_(&[exit]);
+    }
+
+    // Point PC's address bits at an address, keeping the mode and flags
+    fn set_pc(&mut self, address: u32) {
+        let r15 = self.load(15);
+        let flags = self.builder.ins().band_imm(r15, !0x03fffffc_u32 as i32 as i64);
+        let pc = self.builder.ins().bor_imm(flags, (address & 0x03fffffc) as i32 as i64);
+        self.store(15, pc);
+    }
+
+    // Emit code that only runs if the condition passes
+    fn conditional<F: FnOnce(&mut Translator<'a>)>(&mut self, word: u32, emit: F) {
+        match word >> 28 {
+            0xe => emit(self),
+            0xf => {},
+            cond => {
+                let passed = self.condition(cond);
+                let then = self.builder.create_block();
+                let next = self.builder.create_block();
+                self.builder.ins().brif(passed, then, &[], next, &[]);
+
+                self.builder.switch_to_block(then);
+                emit(self);
+                self.builder.ins().jump(next, &[]);
+                self.builder.switch_to_block(next);
+            }
+        }
+    }
+
+    // The same tests as the interpreter makes of the flags
+    fn condition(&mut self, cond: u32) -> Value {
+        let r15 = self.load(15);
+        let ins = |translator: &mut Translator, mask: u32| translator.builder.ins().band_imm(r15, mask as i32 as i64);
+        let equal = |translator: &mut Translator, value: Value, expected: u32| {
+            translator.builder.ins().icmp_imm(IntCC::Equal, value, expected as i32 as i64)
+        };
+        let not_equal = |translator: &mut Translator, value: Value, expected: u32| {
+            translator.builder.ins().icmp_imm(IntCC::NotEqual, value, expected as i32 as i64)
+        };
+
+        match cond {
+            0x0 => { let z = ins(self, Z_BIT); not_equal(self, z, 0) },
+            0x1 => { let z = ins(self, Z_BIT); equal(self, z, 0) },
+            0x2 => { let c = ins(self, C_BIT); not_equal(self, c, 0) },
+            0x3 => { let c = ins(self, C_BIT); equal(self, c, 0) },
+            0x4 => { let n = ins(self, N_BIT); not_equal(self, n, 0) },
+            0x5 => { let n = ins(self, N_BIT); equal(self, n, 0) },
+            0x6 => { let v = ins(self, V_BIT); not_equal(self, v, 0) },
+            0x7 => { let v = ins(self, V_BIT); equal(self, v, 0) },
+            0x8 => { let masked = ins(self, C_BIT | Z_BIT); equal(self, masked, C_BIT) },
+            0x9 => { let masked = ins(self, C_BIT | Z_BIT); not_equal(self, masked, C_BIT) },
+            0xa => {
+                let masked = ins(self, N_BIT | V_BIT);
+                let both = equal(self, masked, N_BIT | V_BIT);
+                let neither = equal(self, masked, 0);
+                self.builder.ins().bor(both, neither)
+            },
+            0xb => {
+                let masked = ins(self, N_BIT | V_BIT);
+                let n = equal(self, masked, N_BIT);
+                let v = equal(self, masked, V_BIT);
+                self.builder.ins().bor(n, v)
+            },
+            0xc => {
+                let masked = ins(self, Z_BIT | N_BIT | V_BIT);
+                let both = equal(self, masked, N_BIT | V_BIT);
+                let neither = equal(self, masked, 0);
+                self.builder.ins().bor(both, neither)
+            },
+            0xd => {
+                let z = ins(self, Z_BIT);
+                let z = not_equal(self, z, 0);
+                let masked = ins(self, N_BIT | V_BIT);
+                let n = equal(self, masked, N_BIT);
+                let v = equal(self, masked, V_BIT);
+                let either = self.builder.ins().bor(n, v);
+                self.builder.ins().bor(z, either)
+            },
+            _ => unreachable!()
+        }
+    }
+
+    // ADD without S, with an immediate or a register shifted by an immediate
+    fn add(&mut self, word: u32) {
+        let rd = (word >> 12) & 0xf;
+        let rn = (word >> 16) & 0xf;
+
+        let op1 = self.load(rn);
+        let op2 = if word & (1 << 25) != 0 {
+            let rotate = ((word >> 8) & 0xf) * 2;
+            self.constant((word & 0xff).rotate_right(rotate))
+        } else {
+            let rm = self.load(word & 0xf);
+            let amount = ((word >> 7) & 0x1f) as i64;
+            // LSL, LSR, ASR and ROR by 1-31, or LSL #0
+            match (word >> 5) & 0b11 {
+                0b00 => self.builder.ins().ishl_imm(rm, amount),
+                0b01 => self.builder.ins().ushr_imm(rm, amount),
+                0b10 => self.builder.ins().sshr_imm(rm, amount),
+                _ => self.builder.ins().rotr_imm(rm, amount)
+            }
+        };
+
+        let sum = self.builder.ins().iadd(op1, op2);
+        self.store(rd, sum);
+    }
+
+    fn branch(&mut self, word: u32, address: u32) {
+        let pc = address + 8;
+        self.set_pc(pc);
+
+        if word >> 28 == 0xf {
+            return self.exit(EXIT_CONTINUE);
+        }
+        self.conditional(word, |translator| {
+            // The link is the address of the next instruction, along with
+            // the PSR
+            if word & 0x01000000 != 0 {
+                let r15 = translator.load(15);
+                let link = translator.builder.ins().iadd_imm(r15, -4);
+                translator.store(14, link);
+            }
+            let offset = (word & 0x00ffffff) << 2;
+            translator.set_pc(pc.wrapping_add(offset) & 0x03ffffff);
+            translator.exit(EXIT_FLUSH);
+
+            // Anything after the return is unreachable, but needs a block
+            let after = translator.builder.create_block();
+            translator.builder.switch_to_block(after);
+        });
+        self.exit(EXIT_CONTINUE);
+    }
+
+    fn interpret(&mut self, word: u32, address: u32) {
+        self.set_pc(address + 8);
+
+        if word >> 28 == 0xf {
+            return self.exit(EXIT_CONTINUE);
+        }
+        self.conditional(word, |translator| {
+            let word = translator.constant(word);
+            let call = translator.builder.ins().call(translator.exec_instruction, &[translator.cpu, word]);
+            let exit = translator.builder.inst_results(call)[0];
+            translator.builder.ins().return_(&[exit]);
+
+            let after = translator.builder.create_block();
+            translator.builder.switch_to_block(after);
+        });
+        self.exit(EXIT_CONTINUE);
+    }
+}
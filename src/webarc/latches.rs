@@ -0,0 +1,120 @@
+use std::io;
+use webarc::device::{Device, Interrupts};
+use webarc::ioc::{IRQ_A_PRINTER_ACK, IRQ_A_PRINTER_BUSY};
+use webarc::printer::Printer;
+use webarc::snapshot::{StateReader, StateWriter};
+
+// The write-only latches in IOC bank 5: the printer data latch at 0x10, latch
+// B at 0x18 and latch A at 0x40. Latch A selects the floppy drive and side,
+// and latch B holds the floppy density and reset lines and the printer strobe.
+// The printer port is nothing more than the data latch and the strobe, so the
+// printer hangs off here. The memory system passes the floppy lines on to the
+// FDC whenever the latches are written.
+
+const PRINTER_DATA: u32 = 0x10;
+const LATCH_B: u32 = 0x18;
+const LATCH_A: u32 = 0x40;
+
+const LATCH_A_DRIVES: u8 = 0x0f;
+const LATCH_A_SIDE: u8 = 1 << 4;
+const LATCH_B_SINGLE_DENSITY: u8 = 1 << 1;
+const LATCH_B_FDC_RESET: u8 = 1 << 3;
+// Inverted on its way to the connector, so setting it starts the pulse
+const LATCH_B_PRINTER_STROBE: u8 = 1 << 4;
+
+pub struct Latches {
+    pub printer: Printer,
+    printer_data: u8,
+    latch_a: u8,
+    latch_b: u8,
+    // The FDC reset line (active low) has been asserted since it was last
+    // passed on
+    fdc_reset: bool,
+}
+
+impl Latches {
+    pub fn new() -> Latches {
+        Latches {
+            printer: Printer::new(),
+            printer_data: 0,
+            latch_a: 0xff,
+            latch_b: 0xff,
+            fdc_reset: false,
+        }
+    }
+
+    // Drive selects (active low, one bit per drive) and the side
+    pub fn floppy_select(&self) -> (u8, u8) {
+        let side = if self.latch_a & LATCH_A_SIDE == 0 { 1 } else { 0 };
+        (self.latch_a & LATCH_A_DRIVES, side)
+    }
+
+    pub fn double_density(&self) -> bool {
+        self.latch_b & LATCH_B_SINGLE_DENSITY == 0
+    }
+
+    pub fn take_fdc_reset(&mut self) -> bool {
+        let reset = self.fdc_reset;
+        self.fdc_reset = false;
+        reset
+    }
+}
+
+impl Device for Latches {
+    fn store(&mut self, address: u32, data: u32) {
+        let value = (data >> 16) as u8;
+
+        match address & 0xfc {
+            PRINTER_DATA => self.printer_data = value,
+            LATCH_A => self.latch_a = value,
+            LATCH_B => {
+                if value & LATCH_B_FDC_RESET == 0 && self.latch_b & LATCH_B_FDC_RESET != 0 {
+                    self.fdc_reset = true;
+                }
+                if value & LATCH_B_PRINTER_STROBE != 0 && self.latch_b & LATCH_B_PRINTER_STROBE == 0 {
+                    self.printer.strobe(self.printer_data);
+                }
+                self.latch_b = value;
+            },
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.printer.tick(cycles);
+    }
+
+    fn next_event(&self) -> Option<u32> {
+        self.printer.next_event()
+    }
+
+    // ACK is a pulse, which IOC latches
+    fn interrupts(&mut self) -> Interrupts {
+        let mut interrupts = Interrupts::irq_a(IRQ_A_PRINTER_BUSY, self.printer.busy());
+        interrupts |= Interrupts::irq_a(IRQ_A_PRINTER_ACK, self.printer.take_acknowledge());
+        interrupts
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        self.printer.save(state);
+        state.u8(self.printer_data);
+        state.u8(self.latch_a);
+        state.u8(self.latch_b);
+        state.bool(self.fdc_reset);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.printer.restore(state)?;
+        self.printer_data = state.u8()?;
+        self.latch_a = state.u8()?;
+        self.latch_b = state.u8()?;
+        self.fdc_reset = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Latches {
+    fn default() -> Latches {
+        Latches::new()
+    }
+}
@@ -0,0 +1,202 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use webarc::acia::Acia;
+use webarc::cpu::Cpu;
+use webarc::fdc::Fdc;
+use webarc::hdc::Hdc;
+use webarc::ide::{self, Ide};
+use webarc::latches::Latches;
+use webarc::memory::{Memory, Select};
+use webarc::podule::Podules;
+use webarc::printer::{self, PcParallel};
+use webarc::serial::SerialBackend;
+use webarc::snapshot::{self, StateReader, StateWriter};
+use webarc::sound::CYCLES_PER_MICROSECOND;
+use webarc::uart::{self, Uart};
+
+// A whole computer: the CPU and its memory system, with the peripherals that
+// the model came with attached to the I/O space.
+
+// External I/O banks behind IOC
+const BANK_FDC: u32 = 1;
+const BANK_SERIAL: u32 = 3;
+const BANK_PODULES: u32 = 4;
+const BANK_LATCHES: u32 = 5;
+
+// The hard disc controller shares bank 5 with the latches, told apart by
+// using medium speed cycles
+const BANK_HDC: u32 = 5;
+const SPEED_MEDIUM: u32 = 1;
+
+const MB: usize = 1024 * 1024;
+
+// How far the emulation runs between checks against real time: 10ms
+const SLICE_CYCLES: u64 = 10_000 * CYCLES_PER_MICROSECOND as u64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Model {
+    // The original IOC machines, with a 6551 serial port and the printer on
+    // IOC's latches
+    A310,
+    #[default]
+    A3000,
+    // Adds the HD63463 ST506 hard disc controller
+    A440,
+    // Serial, parallel and IDE come from the 82C711. Its floppy controller
+    // isn't emulated, so the 1772 stays.
+    A5000,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name.to_lowercase().as_str() {
+            "a310" => Some(Model::A310),
+            "a3000" => Some(Model::A3000),
+            "a440" => Some(Model::A440),
+            "a5000" => Some(Model::A5000),
+            _ => None
+        }
+    }
+
+    pub fn ram_size(&self) -> usize {
+        match *self {
+            Model::A310 | Model::A3000 => MB,
+            Model::A440 => 4 * MB,
+            Model::A5000 => 2 * MB,
+        }
+    }
+
+    // Attach the model's peripherals
+    fn attach_devices(&self, memory: &mut Memory) {
+        memory.attach(Box::new(Fdc::new()), &[Select::Bank(BANK_FDC)]);
+        memory.attach(Box::new(Podules::new()), &[Select::Bank(BANK_PODULES)]);
+
+        match *self {
+            Model::A310 | Model::A3000 | Model::A440 => {
+                memory.attach(Box::new(Acia::new()), &[Select::Bank(BANK_SERIAL)]);
+            },
+            Model::A5000 => {
+                memory.attach(Box::new(Ide::new()), &[
+                    Select::PcPorts(ide::PORT_DATA, ide::PORT_STATUS_COMMAND),
+                    Select::PcPorts(ide::PORT_ALTERNATE_STATUS, ide::PORT_ALTERNATE_STATUS),
+                ]);
+                memory.attach(Box::new(Uart::new()), &[Select::PcPorts(uart::PORT_BASE, uart::PORT_END)]);
+                memory.attach(Box::new(PcParallel::new()), &[Select::PcPorts(printer::PORT_DATA, printer::PORT_CONTROL)]);
+            },
+        }
+
+        if *self == Model::A440 {
+            memory.attach(Box::new(Hdc::new()), &[Select::BankSpeed(BANK_HDC, SPEED_MEDIUM)]);
+        }
+        memory.attach(Box::new(Latches::new()), &[Select::Bank(BANK_LATCHES)]);
+    }
+}
+
+pub struct Machine {
+    pub model: Model,
+    pub cpu: Cpu,
+}
+
+impl Machine {
+    pub fn new(model: Model, rom: Box<[u32]>) -> Machine {
+        let mut memory = Memory::new(rom, model.ram_size());
+        model.attach_devices(&mut memory);
+
+        Machine {
+            model,
+            cpu: Cpu::new(memory),
+        }
+    }
+
+    pub fn memory(&mut self) -> &mut Memory {
+        &mut self.cpu.memory
+    }
+
+    // Run at the speed of the real machine, in slices short enough that
+    // nothing notices the catching up
+    pub fn run(&mut self) {
+        let start = Instant::now();
+        loop {
+            self.cpu.run_for(SLICE_CYCLES);
+
+            let emulated = Duration::from_micros(self.cpu.memory.clock() / CYCLES_PER_MICROSECOND as u64);
+            let elapsed = start.elapsed();
+            if emulated > elapsed {
+                thread::sleep(emulated - elapsed);
+            }
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.cpu.step();
+    }
+
+    // The whole state of the machine, as a snapshot to carry on from later
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.header();
+        state.bytes(format!("{:?}", self.model).as_bytes());
+        self.cpu.save(&mut state);
+        state.into_bytes()
+    }
+
+    // Carry on from a snapshot of the same model, with the same ROM and
+    // devices. If it can't be restored the machine is left as it was.
+    pub fn restore(&mut self, data: &[u8]) -> io::Result<()> {
+        let before = self.snapshot();
+        let result = self.restore_state(data);
+        if result.is_err() {
+            self.restore_state(&before).expect("Couldn't put the machine back after a failed restore");
+        }
+        result
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut state = StateReader::new(data);
+        state.header()?;
+        let model = String::from_utf8_lossy(&state.bytes()?).into_owned();
+        if model != format!("{:?}", self.model) {
+            return Err(snapshot::invalid(&format!("Snapshot is of an {}", model)));
+        }
+
+        self.cpu.restore(&mut state)?;
+        if !state.finished() {
+            return Err(snapshot::invalid("Snapshot has more in it than the machine"));
+        }
+        Ok(())
+    }
+
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.snapshot())
+    }
+
+    pub fn load_snapshot<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let data = fs::read(path)?;
+        self.restore(&data)
+    }
+
+    // Connect whichever serial port the machine has
+    pub fn attach_serial(&mut self, backend: Box<dyn SerialBackend>) {
+        if let Some(acia) = self.memory().device::<Acia>() {
+            acia.attach(backend);
+        } else if let Some(uart) = self.memory().device::<Uart>() {
+            uart.attach(backend);
+        }
+    }
+
+    // Send whatever's printed through the machine's printer port to a file.
+    // The A5000 still has IOC's latches, but its port is the 82C711's.
+    pub fn capture_printer<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let printer = match self.model {
+            Model::A310 | Model::A3000 | Model::A440 => self.memory().device::<Latches>().map(|latches| &mut latches.printer),
+            Model::A5000 => self.memory().device::<PcParallel>().map(|parallel| &mut parallel.printer),
+        };
+        match printer {
+            Some(printer) => printer.capture_to(path),
+            None => Ok(())
+        }
+    }
+}
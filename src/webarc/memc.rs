@@ -0,0 +1,425 @@
+use std::io;
+use webarc::device::{Device, Interrupts};
+use webarc::ioc::IRQ_B_SOUND;
+use webarc::snapshot::{StateReader, StateWriter};
+
+// MEMC registers are written through the address bus: a write anywhere in
+// 0x3600000-0x37FFFFF selects the register with address bits 17-19 and takes
+// its value from address bits 2-16. The data bus is ignored.
+//
+// MEMC also maps the logical 32MB at the bottom of the address space onto
+// physical RAM. Its CAM has an entry for each of the 128 physical pages,
+// written through the address bus above 0x3800000, giving the logical page
+// the physical page appears at and how it's protected. The page size (4KB to
+// 32KB) is set in the control register. Accesses to logical pages with
+// nothing mapped, or that the protection doesn't allow, are aborted.
+
+const VINIT: u32 = 0;
+const VSTART: u32 = 1;
+const VEND: u32 = 2;
+const CINIT: u32 = 3;
+const SSTART: u32 = 4;
+const SENDN: u32 = 5;
+const SPTR: u32 = 6;
+const CONTROL: u32 = 7;
+
+const CONTROL_PAGE_SIZE: u32 = 2;
+const CONTROL_LOW_ROM_SPEED: u32 = 4;
+const CONTROL_HIGH_ROM_SPEED: u32 = 6;
+const CONTROL_VIDEO_DMA: u32 = 1 << 10;
+const CONTROL_SOUND_DMA: u32 = 1 << 11;
+const CONTROL_OS_MODE: u32 = 1 << 12;
+
+// Access times in cycles (non-sequential, sequential) for each ROM speed
+// setting: 450ns, 325ns, 200ns, and 200ns with 60ns nibble mode reads
+const ROM_ACCESS_TIMES: [(u32, u32); 4] = [(4, 4), (3, 3), (2, 2), (2, 1)];
+
+// DRAM cycles take one clock in page mode (sequential) and two otherwise
+const RAM_ACCESS_TIMES: (u32, u32) = (2, 1);
+
+// The DMA address generators count through the bottom 512KB of physical
+// RAM, a quadword at a time, and wrap round at the top of it
+const DMA_ADDRESS_MASK: u32 = 0x7fff0;
+
+// DMA moves a quadword at a time, as one non-sequential and three sequential
+// cycles, and the CPU waits for the bus while it does
+const DMA_QUADWORD_CYCLES: u32 = RAM_ACCESS_TIMES.0 + 3 * RAM_ACCESS_TIMES.1;
+
+const PHYSICAL_PAGES: usize = 128;
+// Logical space is looked up 4KB at a time, whatever the page size
+const LOGICAL_SHIFT: u32 = 12;
+const LOGICAL_PAGES: usize = 0x02000000 >> LOGICAL_SHIFT;
+
+// Protection levels: user mode can read and write level 0 and only read level
+// 1, OS mode can read and write levels 0 and 1 and only read 2 and 3, and
+// supervisor mode can do anything
+const PROTECTION_USER_READ: u32 = 1;
+const PROTECTION_OS_READ: u32 = 2;
+
+// A physical page's CAM entry
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct CamEntry {
+    logical: u32,
+    protection: u32,
+}
+
+pub struct Memc {
+    // DMA address generators (physical byte addresses)
+    pub vinit: u32,
+    pub vstart: u32,
+    pub vend: u32,
+    pub cinit: u32,
+    pub sstart: u32,
+    pub sendn: u32,
+    pub sptr: u32,
+
+    // End of the sound buffer currently being played. SendN only takes
+    // effect when the current buffer runs out.
+    sendc: u32,
+
+    pub control: u32,
+
+    // Level of MEMC's sound interrupt output: set when the sound pointer is
+    // reloaded from Sstart, cleared when the next Sstart is written.
+    pub sound_irq: bool,
+
+    // Cycles DMA has taken from the CPU that it hasn't been charged for yet
+    dma_cycles: u32,
+
+    cam: [Option<CamEntry>; PHYSICAL_PAGES],
+    // The physical address and protection of each 4KB of logical space, made
+    // from the CAM
+    pages: Box<[Option<(u32, u32)>]>,
+}
+
+impl Memc {
+    pub fn new() -> Memc {
+        Memc {
+            vinit: 0,
+            vstart: 0,
+            vend: 0,
+            cinit: 0,
+            sstart: 0,
+            sendn: 0,
+            sptr: 0,
+            sendc: 0,
+            control: 0,
+            sound_irq: false,
+            dma_cycles: 0,
+            cam: [None; PHYSICAL_PAGES],
+            pages: vec![None; LOGICAL_PAGES].into_boxed_slice(),
+        }
+    }
+
+    pub fn write(&mut self, address: u32) {
+        let register = (address >> 17) & 7;
+
+        // Address generators hold bits 4-18 of a physical address
+        let value = ((address >> 2) << 4) & DMA_ADDRESS_MASK;
+
+        match register {
+            VINIT => self.vinit = value,
+            VSTART => self.vstart = value,
+            VEND => self.vend = value,
+            CINIT => self.cinit = value,
+            SSTART => {
+                self.sstart = value;
+                self.sound_irq = false;
+            },
+            SENDN => self.sendn = value,
+            SPTR => {
+                // Writing any value to Sptr restarts sound DMA from Sstart
+                self.sptr = self.sstart;
+                self.sendc = self.sendn;
+            },
+            CONTROL => {
+                let page_size = self.page_shift();
+                self.control = address & 0x3ffc;
+                if self.page_shift() != page_size {
+                    self.map_all();
+                }
+            },
+            _ => unreachable!()
+        }
+    }
+
+    // Write a CAM entry. Which address bits are the physical page number
+    // depends on the page size, and the logical page number is in the bits
+    // above it up to bit 22, with bits 10-11 giving logical address bits
+    // 23-24. The protection level is in bits 8-9.
+    pub fn write_cam(&mut self, address: u32) {
+        let shift = self.page_shift();
+        let physical = match shift {
+            12 => address & 0x7f,
+            13 => (address >> 1 & 0x3f) | (address & 1) << 6,
+            14 => (address >> 2 & 0x1f) | (address & 3) << 5,
+            _ => (address >> 3 & 0xf) | (address & 1) << 4 | (address & 2) << 5 | (address & 4) << 3
+        } as usize;
+        let logical = (address & 0x007fffff & !((1 << shift) - 1)) | (address >> 10 & 3) << 23;
+        let entry = CamEntry { logical, protection: address >> 8 & 3 };
+
+        if let Some(old) = self.cam[physical].replace(entry) {
+            self.map(old.logical);
+        }
+        self.map(logical);
+    }
+
+    fn page_shift(&self) -> u32 {
+        12 + ((self.control >> CONTROL_PAGE_SIZE) & 3)
+    }
+
+    // Point the lookups for a logical page at the physical page mapped there,
+    // if there still is one. Where more than one is, the lowest numbered wins.
+    fn map(&mut self, logical: u32) {
+        let shift = self.page_shift();
+        let mapped = self.cam.iter().enumerate().find_map(|(physical, entry)| {
+            entry.filter(|entry| entry.logical == logical).map(|entry| ((physical as u32) << shift, entry.protection))
+        });
+
+        let first = (logical >> LOGICAL_SHIFT) as usize;
+        for (i, page) in self.pages[first..first + (1 << (shift - LOGICAL_SHIFT))].iter_mut().enumerate() {
+            *page = mapped.map(|(physical, protection)| (physical + ((i as u32) << LOGICAL_SHIFT), protection));
+        }
+    }
+
+    fn map_all(&mut self) {
+        for page in self.pages.iter_mut() {
+            *page = None;
+        }
+        // Entries written with a smaller page size start where a page of the
+        // new size would
+        let mask = !((1 << self.page_shift()) - 1);
+        for entry in self.cam.iter_mut().flatten() {
+            entry.logical &= mask;
+        }
+        for physical in 0..PHYSICAL_PAGES {
+            if let Some(entry) = self.cam[physical] {
+                self.map(entry.logical);
+            }
+        }
+    }
+
+    // The physical address (from the bottom of RAM) of a logical address
+    // below 32MB, and its page's protection level, if a page is mapped there
+    pub fn translate(&self, logical: u32) -> Option<(u32, u32)> {
+        self.pages[(logical >> LOGICAL_SHIFT) as usize]
+            .map(|(physical, protection)| (physical | (logical & ((1 << LOGICAL_SHIFT) - 1)), protection))
+    }
+
+    // Whether an access to a page with a protection level is allowed, in user
+    // mode or not
+    pub fn permits(&self, protection: u32, user: bool, write: bool) -> bool {
+        if !user {
+            true
+        } else if self.os_mode() {
+            !write || protection < PROTECTION_OS_READ
+        } else {
+            protection == 0 || (!write && protection == PROTECTION_USER_READ)
+        }
+    }
+
+    pub fn video_dma_enabled(&self) -> bool {
+        self.control & CONTROL_VIDEO_DMA != 0
+    }
+
+    pub fn sound_dma_enabled(&self) -> bool {
+        self.control & CONTROL_SOUND_DMA != 0
+    }
+
+    pub fn os_mode(&self) -> bool {
+        self.control & CONTROL_OS_MODE != 0
+    }
+
+    pub fn ram_cycles(&self, sequential: bool) -> u32 {
+        if sequential { RAM_ACCESS_TIMES.1 } else { RAM_ACCESS_TIMES.0 }
+    }
+
+    // ROM at 0x3800000 is the high ROM, which is also what's mapped low after
+    // reset. The low ROM space at 0x3400000 has its own speed.
+    pub fn rom_cycles(&self, high: bool, sequential: bool) -> u32 {
+        let shift = if high { CONTROL_HIGH_ROM_SPEED } else { CONTROL_LOW_ROM_SPEED };
+        let (non_sequential, sequential_time) = ROM_ACCESS_TIMES[((self.control >> shift) & 3) as usize];
+        if sequential { sequential_time } else { non_sequential }
+    }
+
+    // Video and cursor DMA, which VIDC requests a line at a time. The data
+    // itself is only needed once there's a display to draw.
+    pub fn video_dma(&mut self, quadwords: u32) {
+        if self.video_dma_enabled() {
+            self.dma_cycles += quadwords * DMA_QUADWORD_CYCLES;
+        }
+    }
+
+    // Cycles taken by DMA since this was last called
+    pub fn take_dma_cycles(&mut self) -> u32 {
+        let cycles = self.dma_cycles;
+        self.dma_cycles = 0;
+        cycles
+    }
+
+    // Fetch the next quadword of sound data from physical RAM, swapping to
+    // the next buffer when the end of the current one is reached. Where no
+    // RAM is fitted the data bus reads as zero.
+    pub fn sound_dma(&mut self, ram: &[u32]) -> [u32; 4] {
+        let base = (self.sptr / 4) as usize;
+        self.dma_cycles += DMA_QUADWORD_CYCLES;
+
+        let mut words = [0; 4];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = ram.get(base + i).copied().unwrap_or(0);
+        }
+
+        if self.sptr >= self.sendc {
+            self.sptr = self.sstart;
+            self.sendc = self.sendn;
+            self.sound_irq = true;
+        } else {
+            self.sptr = (self.sptr + 16) & DMA_ADDRESS_MASK;
+        }
+
+        words
+    }
+}
+
+impl Device for Memc {
+    fn store(&mut self, address: u32, _data: u32) {
+        self.write(address);
+    }
+
+    fn interrupts(&mut self) -> Interrupts {
+        Interrupts::irq_b(IRQ_B_SOUND, self.sound_irq)
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        for &register in &[self.vinit, self.vstart, self.vend, self.cinit, self.sstart, self.sendn, self.sptr, self.sendc, self.control] {
+            state.u32(register);
+        }
+        state.bool(self.sound_irq);
+        state.u32(self.dma_cycles);
+
+        for entry in &self.cam {
+            state.bool(entry.is_some());
+            if let Some(entry) = *entry {
+                state.u32(entry.logical);
+                state.u8(entry.protection as u8);
+            }
+        }
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for register in [
+            &mut self.vinit, &mut self.vstart, &mut self.vend, &mut self.cinit, &mut self.sstart,
+            &mut self.sendn, &mut self.sptr, &mut self.sendc, &mut self.control
+        ] {
+            *register = state.u32()?;
+        }
+        self.sound_irq = state.bool()?;
+        self.dma_cycles = state.u32()?;
+
+        for entry in self.cam.iter_mut() {
+            *entry = if state.bool()? {
+                Some(CamEntry { logical: state.u32()?, protection: state.u8()? as u32 })
+            } else {
+                None
+            };
+        }
+        self.map_all();
+        Ok(())
+    }
+}
+
+impl Default for Memc {
+    fn default() -> Memc {
+        Memc::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The address bus value that writes a physical address to a register
+    fn register(register: u32, value: u32) -> u32 {
+        0x03600000 | register << 17 | (value >> 4) << 2
+    }
+
+    #[test]
+    fn sound_dma_bounds() {
+        let mut memc = Memc::new();
+        memc.write(register(SSTART, 0x7ffe0));
+        memc.write(register(SENDN, 0x7fff0));
+        memc.write(register(SPTR, 0));
+        assert_eq!((memc.sptr, memc.sendc), (0x7ffe0, 0x7fff0));
+
+        // RAM that doesn't reach the buffer reads as zero, even none at all
+        assert_eq!(memc.sound_dma(&[]), [0; 4]);
+        assert_eq!(memc.sptr, 0x7fff0);
+        let ram: Vec<u32> = (0..0x20000).collect();
+        assert_eq!(memc.sound_dma(&ram[..0x1fffe]), [0x1fffc, 0x1fffd, 0, 0]);
+        assert!(memc.sound_irq);
+        assert_eq!(memc.sound_dma(&ram), [0x1fff8, 0x1fff9, 0x1fffa, 0x1fffb]);
+
+        // Past the top of the address generator's 512KB is the bottom again
+        memc.write(register(SSTART, 0x7fff0));
+        memc.write(register(SENDN, 0x10));
+        memc.write(register(SPTR, 0));
+        memc.sendc = 0x80000;
+        memc.sound_dma(&ram);
+        assert_eq!(memc.sptr, 0);
+        assert_eq!(memc.sound_dma(&ram), [0, 1, 2, 3]);
+    }
+
+    fn page_size(memc: &mut Memc, size: u32) {
+        memc.write(0x03600000 | CONTROL << 17 | size << CONTROL_PAGE_SIZE);
+    }
+
+    #[test]
+    fn cam() {
+        let mut memc = Memc::new();
+        assert_eq!(memc.translate(0), None);
+
+        // 4KB pages, with the top two bits of the logical page in bits 10-11
+        memc.write_cam(0x03800000 | 0x8000 | 1 << 8 | 5);
+        assert_eq!(memc.translate(0x8abc), Some((0x5abc, 1)));
+        assert_eq!(memc.translate(0x9000), None);
+        memc.write_cam(0x03800000 | 3 << 10 | 0x1000 | 7);
+        assert_eq!(memc.translate(0x01801ffc), Some((0x7ffc, 0)));
+
+        // Moving a page leaves nothing where it was, unless another page is
+        // mapped there too
+        memc.write_cam(0x03800000 | 0x9000 | 5);
+        assert_eq!(memc.translate(0x8000), None);
+        assert_eq!(memc.translate(0x9000), Some((0x5000, 0)));
+        memc.write_cam(0x03800000 | 0x4000 | 2);
+        memc.write_cam(0x03800000 | 0x4000 | 3);
+        memc.write_cam(0x03800000 | 0x6000 | 2);
+        assert_eq!(memc.translate(0x4000), Some((0x3000, 0)));
+
+        // Bigger pages take their low physical page number bits from the
+        // bottom of the address
+        page_size(&mut memc, 1);
+        assert_eq!(memc.translate(0x6000), Some((2 << 13, 0)));
+        memc.write_cam(0x03800000 | 0xa000 | 0b0000011);
+        assert_eq!(memc.translate(0xbffc), Some((65 << 13 | 0x1ffc, 0)));
+        page_size(&mut memc, 2);
+        memc.write_cam(0x03800000 | 0x1c000 | 0b0001111);
+        assert_eq!(memc.translate(0x1c000), Some((99 << 14, 0)));
+        page_size(&mut memc, 3);
+        memc.write_cam(0x03800000 | 0x20000 | 0b1000111);
+        assert_eq!(memc.translate(0x27ffc), Some((120 << 15 | 0x7ffc, 0)));
+    }
+
+    #[test]
+    fn protection() {
+        let mut memc = Memc::new();
+        let allowed = |memc: &Memc, user: bool, write: bool| (0..4).filter(|&level| memc.permits(level, user, write)).collect::<Vec<_>>();
+        assert_eq!(allowed(&memc, false, true), [0, 1, 2, 3]);
+        assert_eq!(allowed(&memc, true, false), [0, 1]);
+        assert_eq!(allowed(&memc, true, true), [0]);
+
+        memc.write(0x03600000 | CONTROL << 17 | CONTROL_OS_MODE);
+        assert_eq!(allowed(&memc, true, false), [0, 1, 2, 3]);
+        assert_eq!(allowed(&memc, true, true), [0, 1]);
+        assert_eq!(allowed(&memc, false, true), [0, 1, 2, 3]);
+    }
+}
@@ -1,42 +1,541 @@
+use std::io;
+use std::mem;
 use std::ops::Shl;
 use std::ops::Shr;
+use webarc::device::{Device, Interrupts};
+use webarc::fdc::Fdc;
+use webarc::icache::{InstructionCache, PAGE_SHIFT};
+use webarc::instructions::{self, Decoded};
+use webarc::ioc::{Ioc, CONTROL_SCL, CONTROL_SDA, IRQ_A_LATCHED};
+use webarc::latches::Latches;
+use webarc::memc::Memc;
+use webarc::pcf8583::Pcf8583;
+use webarc::scheduler::{Scheduler, Source};
+use webarc::snapshot::{self, StateReader, StateWriter};
+use webarc::trace::{Access, AccessKind};
+use webarc::vidc::Vidc;
+
+// The memory system: RAM and ROM, the chips every machine has (MEMC, VIDC,
+// IOC and the CMOS clock on IOC's I2C lines), and whatever other devices the
+// machine's profile attaches to the I/O space.
+//
+// Devices run lazily. Each is brought up to date with the master clock when
+// it's accessed or when the event it's waiting for falls due, and then says
+// when it next needs to be looked at. Between times the CPU runs on alone.
+
+// IOC internal registers
+const BANK_IOC: u32 = 0;
+
+// PC-style peripherals in the A5000's 82C711, outside IOC's select, with the
+// PC port number in address bits 2-11
+const PC_IO_START: u32 = 0x03010000;
+const PC_IO_END: u32 = 0x03011000;
+
+// I/O cycle times for each IOC cycle speed (slow, medium, fast and
+// synchronous to IOC's 2MHz clock, on average), and for the 82C711's ports
+const IOC_ACCESS_TIMES: [u32; 4] = [8, 6, 4, 8];
+const PC_IO_ACCESS_TIME: u32 = 4;
+// VIDC and MEMC take an ordinary non-sequential cycle
+const CHIP_ACCESS_TIME: u32 = 2;
+
+// Longest a device goes without being brought up to date, which keeps the
+// cycles passed to tick in range: one second
+const MAX_EVENT_INTERVAL: u32 = 8_000_000;
+
+// Where a device sits in the I/O space. IOC selects are by bank (address bits
+// 16-18) and optionally by cycle speed (bits 19-20: 0 slow, 1 medium, 2 fast,
+// 3 sync); PC-style devices are by an inclusive range of ports.
+#[derive(Clone, Copy)]
+pub enum Select {
+    Bank(u32),
+    BankSpeed(u32, u32),
+    PcPorts(u16, u16),
+}
+
+impl Select {
+    // Whether an I/O address is for this select, and if so the address or port
+    // to give the device
+    fn decode(&self, address: u32) -> Option<u32> {
+        let ioc_selected = address & 0x00200000 != 0;
+        let bank = (address >> 16) & 7;
+        let speed = (address >> 19) & 3;
+
+        match *self {
+            Select::Bank(b) if ioc_selected && bank == b => Some(address),
+            Select::BankSpeed(b, s) if ioc_selected && bank == b && speed == s => Some(address),
+            Select::PcPorts(first, last) if !ioc_selected && (PC_IO_START..PC_IO_END).contains(&address) => {
+                let port = ((address >> 2) & 0x3ff) as u16;
+                if (first..=last).contains(&port) { Some(port as u32) } else { None }
+            },
+            _ => None
+        }
+    }
+}
 
 pub struct Memory {
     ram: Box<[u32]>,
     rom: Box<[u32]>,
-    rom_mapped: bool
+    rom_mapped: bool,
+    // The core chips aren't Devices because they're wired to each other and
+    // to RAM rather than just to the bus: VIDC's DMA goes through MEMC's
+    // pointers and reads RAM, the CPU is held for the cycles it steals, MEMC
+    // decides how the low 32MB maps onto RAM and ROM, and the CMOS clock is
+    // on IOC's control lines. Every model has all four, so there's nothing for
+    // a profile to choose.
+    pub memc: Memc,
+    pub vidc: Vidc,
+    pub ioc: Ioc,
+    pub cmos: Pcf8583,
+    devices: Vec<Box<dyn Device>>,
+    // Each select and the index of its device, searched in the order attached
+    selects: Vec<(Select, usize)>,
+
+    // Address a sequential access would be to next
+    next_sequential: u32,
+    // Master clock: every cycle since reset
+    clock: u64,
+    scheduler: Scheduler,
+    // For each event source, by index: the clock when it was last brought up
+    // to date, and the interrupt inputs it was driving then
+    synced: Vec<u64>,
+    inputs: Vec<Interrupts>,
+    // Set by an access that may have brought an event forward, so whoever is
+    // running up to the next one knows to look again
+    rescheduled: bool,
+
+    icache: InstructionCache,
+
+    // Whether accesses are being made in user mode, as the ARM tells MEMC,
+    // and whether one has been aborted since the CPU last asked
+    user_mode: bool,
+    aborted: bool,
+
+    // Data accesses made since tracing asked for them
+    pub accesses: Option<Vec<Access>>,
 }
 
 impl Memory {
-    pub fn new(rom: Box<[u32]>) -> Memory {
-        Memory {
-            ram: vec![0; 1 * 1024 * 1024].into_boxed_slice(),
+    pub fn new(rom: Box<[u32]>, ram_size: usize) -> Memory {
+        let mut memory = Memory {
+            ram: vec![0; ram_size / 4].into_boxed_slice(),
             rom,
-            rom_mapped: true
-        }
+            rom_mapped: true,
+            memc: Memc::new(),
+            vidc: Vidc::new(),
+            ioc: Ioc::new(),
+            cmos: Pcf8583::new(),
+            devices: Vec::new(),
+            selects: Vec::new(),
+            next_sequential: 0,
+            clock: 0,
+            scheduler: Scheduler::new(),
+            rescheduled: false,
+            synced: vec![0; Source::Device(0).index()],
+            inputs: vec![Interrupts::NONE; Source::Device(0).index()],
+            icache: InstructionCache::new(),
+            user_mode: false,
+            aborted: false,
+            accesses: None,
+        };
         // console.debug('ROM size: 0x' + this.rom.byteLength.toString(16));
+
+        memory.reschedule(Source::Vidc);
+        memory.reschedule(Source::Ioc);
+        memory.reschedule(Source::Cmos);
+        memory
+    }
+
+    // Put a device on the bus, answering to any of the selects. Where selects
+    // overlap, the device attached first wins.
+    pub fn attach(&mut self, device: Box<dyn Device>, selects: &[Select]) {
+        let index = self.devices.len();
+        self.devices.push(device);
+        self.selects.extend(selects.iter().map(|select| (*select, index)));
+
+        self.synced.push(self.clock);
+        self.inputs.push(Interrupts::NONE);
+        self.reschedule(Source::Device(index));
+    }
+
+    // The attached device of a particular type, if the machine has one. It's
+    // brought up to date first, and whatever's done to it is taken into
+    // account at the next event check.
+    pub fn device<T: Device + 'static>(&mut self) -> Option<&mut T> {
+        let index = self.devices.iter_mut().position(|device| (**device).as_any().is::<T>())?;
+        self.sync(Source::Device(index));
+        self.scheduler.schedule(Source::Device(index), self.clock);
+        (*self.devices[index]).as_any().downcast_mut::<T>()
+    }
+
+    // Cycles an access takes, from MEMC's timing for RAM and ROM or the I/O
+    // cycle speed
+    fn access_time(&self, address: u32, sequential: bool) -> u32 {
+        let masked_address = address & 0x03fffffc;
+
+        if masked_address < 0x02000000 {
+            if self.rom_mapped { self.memc.rom_cycles(true, sequential) } else { self.memc.ram_cycles(sequential) }
+        } else if masked_address < 0x03000000 {
+            self.memc.ram_cycles(sequential)
+        } else if masked_address < 0x03400000 {
+            if masked_address & 0x00200000 != 0 {
+                IOC_ACCESS_TIMES[((masked_address >> 19) & 3) as usize]
+            } else {
+                PC_IO_ACCESS_TIME
+            }
+        } else if masked_address < 0x03800000 {
+            // Reads here are from the low ROM, and writes go to VIDC or MEMC
+            self.memc.rom_cycles(false, sequential).max(CHIP_ACCESS_TIME)
+        } else {
+            self.memc.rom_cycles(true, sequential)
+        }
+    }
+
+    // Charge a bus cycle to the clock. An access to the word after the last
+    // one is a sequential (S) cycle, and anything else is non-sequential (N).
+    fn bus_cycle(&mut self, address: u32) {
+        let word = address & 0x03fffffc;
+        let sequential = word == self.next_sequential;
+        self.clock += self.access_time(word, sequential) as u64;
+        self.next_sequential = word + 4;
+    }
+
+    // Charge a sequential cycle that doesn't go through load or store, such
+    // as the second fetch of a pipeline refill
+    pub fn sequential_cycle(&mut self, address: u32) {
+        self.clock += self.access_time(address, true) as u64;
+    }
+
+    // Charge internal (I) cycles, during which the bus is idle
+    pub fn internal_cycles(&mut self, cycles: u32) {
+        self.clock += cycles as u64;
+    }
+
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    // When the next event is due. Until then nothing outside the CPU changes
+    // unless the CPU touches it.
+    pub fn next_event(&mut self) -> u64 {
+        self.scheduler.next_due().unwrap_or(u64::MAX)
+    }
+
+    // Bring everything whose event has fallen due up to date
+    pub fn run_events(&mut self) {
+        while let Some(source) = self.scheduler.pop_due(self.clock) {
+            self.sync(source);
+            self.update_inputs(source);
+            self.reschedule(source);
+        }
+    }
+
+    // Advance an event source by the cycles since it was last brought up to
+    // date
+    fn sync(&mut self, source: Source) {
+        let index = source.index();
+        let cycles = (self.clock - self.synced[index]) as u32;
+        self.synced[index] = self.clock;
+        if cycles == 0 {
+            return;
+        }
+
+        match source {
+            Source::Memc => {},
+            Source::Vidc => {
+                self.vidc.tick(cycles, &mut self.memc, &self.ram);
+                // The CPU waits out DMA
+                self.clock += self.memc.take_dma_cycles() as u64;
+            },
+            Source::Ioc => self.ioc.tick(cycles),
+            Source::Cmos => self.cmos.tick(cycles),
+            Source::Device(index) => self.devices[index].tick(cycles),
+        }
+    }
+
+    fn reschedule(&mut self, source: Source) {
+        let next_event = match source {
+            Source::Memc => None,
+            Source::Vidc => self.vidc.next_event(),
+            Source::Ioc => self.ioc.next_event(),
+            Source::Cmos => self.cmos.next_event(),
+            Source::Device(index) => self.devices[index].next_event(),
+        };
+        let cycles = next_event.unwrap_or(MAX_EVENT_INTERVAL).clamp(1, MAX_EVENT_INTERVAL);
+        self.scheduler.schedule(source, self.clock + cycles as u64);
+    }
+
+    // Pass a source's interrupt outputs on to IOC along with everyone else's.
+    // Pulses on IOC's latched inputs only go once.
+    fn update_inputs(&mut self, source: Source) {
+        // Sound DMA is driven by VIDC's FIFO, but it's MEMC that raises the
+        // interrupt when it swaps buffers
+        if source == Source::Vidc {
+            self.inputs[Source::Memc.index()] = self.memc.interrupts();
+        }
+
+        self.inputs[source.index()] = match source {
+            Source::Memc => self.memc.interrupts(),
+            Source::Vidc => self.vidc.interrupts(),
+            Source::Ioc => self.ioc.interrupts(),
+            Source::Cmos => self.cmos.interrupts(),
+            Source::Device(index) => self.devices[index].interrupts(),
+        };
+
+        let mut inputs = Interrupts::NONE;
+        for input in self.inputs.iter_mut() {
+            inputs |= *input;
+            input.irq_a &= !IRQ_A_LATCHED;
+        }
+        self.ioc.set_inputs(inputs);
+    }
+
+    // Access a source, bringing it up to date first and then finding out what
+    // the access did to its interrupts and its next event
+    fn access<T, F: FnOnce(&mut Memory) -> T>(&mut self, source: Source, f: F) -> T {
+        self.sync(source);
+        let result = f(self);
+        self.update_inputs(source);
+        self.reschedule(source);
+        self.rescheduled = true;
+        result
+    }
+
+    // Whether an access has rescheduled an event since this was last asked
+    pub fn take_rescheduled(&mut self) -> bool {
+        mem::replace(&mut self.rescheduled, false)
+    }
+
+    pub fn user_mode(&self) -> bool {
+        self.user_mode
+    }
+
+    pub fn set_user_mode(&mut self, user_mode: bool) {
+        self.user_mode = user_mode;
+    }
+
+    // Whether an access has been aborted since this was last asked
+    pub fn take_abort(&mut self) -> bool {
+        mem::replace(&mut self.aborted, false)
+    }
+
+    fn abort(&mut self) -> u32 {
+        self.aborted = true;
+        0
+    }
+
+    // The physical address of RAM or ROM that an instruction fetch reads,
+    // which is what the instruction cache goes by. Code anywhere else isn't
+    // cached, and nor is code in logical pages that aren't mapped.
+    pub fn code_address(&self, masked_address: u32) -> Option<u32> {
+        if masked_address < 0x02000000 {
+            if self.rom_mapped {
+                Some(0x03800000 + masked_address % (self.rom.len() * 4) as u32)
+            } else {
+                self.memc.translate(masked_address).map(|(physical, _)| 0x02000000 + physical % (self.ram.len() * 4) as u32)
+            }
+        } else if masked_address < 0x03000000 {
+            Some(0x02000000 + (masked_address - 0x02000000) % (self.ram.len() * 4) as u32)
+        } else if masked_address >= 0x03800000 {
+            // The ROM repeats through the top 8MB
+            Some(0x03800000 + (masked_address - 0x03800000) % (self.rom.len() * 4) as u32)
+        } else {
+            None
+        }
+    }
+
+    // Whether code can be read from an address in the current mode without
+    // the access being aborted
+    pub fn readable(&self, masked_address: u32) -> bool {
+        if masked_address < 0x02000000 {
+            self.rom_mapped || self.logical(masked_address, false).is_some()
+        } else {
+            !self.user_mode || masked_address >= 0x03400000
+        }
+    }
+
+    // Fetch an instruction, decoded, unless the fetch is aborted
+    pub fn fetch(&mut self, address: u32) -> Option<Decoded> {
+        let masked_address = address & 0x03fffffc;
+        let code_address = match self.code_address(masked_address) {
+            Some(code_address) if !self.user_mode || self.readable(masked_address) => code_address,
+            _ => return self.fetch_uncached(masked_address)
+        };
+
+        match self.icache.get(code_address) {
+            Some(decoded) => {
+                self.fetch_cycle(masked_address);
+                Some(decoded)
+            },
+            None => {
+                let decoded = self.fetch_uncached(masked_address)?;
+                self.icache.insert(code_address, decoded);
+                Some(decoded)
+            }
+        }
+    }
+
+    fn fetch_uncached(&mut self, masked_address: u32) -> Option<Decoded> {
+        let word = self.read(masked_address);
+        if self.take_abort() { None } else { Some(instructions::decode(word)) }
+    }
+
+    // The bus cycle of an instruction fetch from RAM or ROM, without reading
+    // the word
+    pub fn fetch_cycle(&mut self, address: u32) {
+        let masked_address = address & 0x03fffffc;
+        self.bus_cycle(masked_address);
+        if masked_address >= 0x03800000 {
+            self.rom_mapped = false;
+        }
+    }
+
+    // The word at a code address, without a bus cycle
+    pub fn peek_code(&self, code_address: u32) -> u32 {
+        if code_address >= 0x03800000 {
+            self.rom[((code_address - 0x03800000) / 4) as usize]
+        } else {
+            self.ram[((code_address - 0x02000000) / 4) as usize]
+        }
+    }
+
+    // The word at an address in RAM or ROM, without a bus cycle or anything
+    // else an access would do
+    pub fn peek(&self, address: u32) -> Option<u32> {
+        self.code_address(address & 0x03fffffc).map(|code_address| self.peek_code(code_address))
+    }
+
+    // Change a byte of RAM without a bus cycle, for debuggers, returning
+    // whether the address was in RAM
+    pub fn poke_byte(&mut self, address: u32, value: u8) -> bool {
+        let code_address = match self.code_address(address & 0x03fffffc) {
+            Some(code_address) if code_address < 0x03800000 => code_address,
+            _ => return false
+        };
+        let index = ((code_address - 0x02000000) / 4) as usize;
+        self.write_ram_byte(index, address, value);
+        true
+    }
+
+    pub fn ram_size(&self) -> usize {
+        self.ram.len() * 4
+    }
+
+    // Writes so far to the page a code address is in
+    pub fn code_generation(&self, code_address: u32) -> u32 {
+        self.icache.generation(code_address)
+    }
+
+    // RAM, the chips and the attached devices, and where each is up to. The
+    // ROM isn't saved, only enough to tell whether it's the same one.
+    pub fn save(&self, state: &mut StateWriter) {
+        state.u32(self.rom_checksum());
+        state.words(&self.ram);
+        state.bool(self.rom_mapped);
+
+        self.memc.save(state);
+        self.vidc.save(state);
+        self.ioc.save(state);
+        self.cmos.save(state);
+        state.usize(self.devices.len());
+        for device in &self.devices {
+            device.save(state);
+        }
+
+        state.u32(self.next_sequential);
+        state.u64(self.clock);
+        self.scheduler.save(state);
+        for &synced in &self.synced {
+            state.u64(synced);
+        }
+        for inputs in &self.inputs {
+            state.u8(inputs.irq_a);
+            state.u8(inputs.irq_b);
+            state.u8(inputs.fiq);
+        }
+    }
+
+    pub fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        if state.u32()? != self.rom_checksum() {
+            return Err(snapshot::invalid("Snapshot was made with a different ROM"));
+        }
+        state.words_into(&mut self.ram)?;
+        self.rom_mapped = state.bool()?;
+
+        self.memc.restore(state)?;
+        self.vidc.restore(state)?;
+        self.ioc.restore(state)?;
+        self.cmos.restore(state)?;
+        if state.usize()? != self.devices.len() {
+            return Err(snapshot::invalid("Snapshot has different devices attached"));
+        }
+        for device in self.devices.iter_mut() {
+            device.restore(state)?;
+        }
+
+        self.next_sequential = state.u32()?;
+        self.clock = state.u64()?;
+        self.scheduler.restore(state)?;
+        for synced in self.synced.iter_mut() {
+            *synced = state.u64()?;
+        }
+        for inputs in self.inputs.iter_mut() {
+            *inputs = Interrupts { irq_a: state.u8()?, irq_b: state.u8()?, fiq: state.u8()? };
+        }
+
+        // Everything decoded from RAM is out of date
+        for page in 0..(self.ram.len() * 4) >> PAGE_SHIFT {
+            self.icache.invalidate_page(0x02000000 + (page << PAGE_SHIFT) as u32);
+        }
+        Ok(())
+    }
+
+    fn rom_checksum(&self) -> u32 {
+        // FNV-1a, a word at a time
+        self.rom.iter().fold(0x811c9dc5, |hash: u32, &word| (hash ^ word).wrapping_mul(0x01000193))
     }
 
     pub fn load(&mut self, address: u32) -> u32 {
+        let value = self.read(address);
+        self.record(AccessKind::Load, address, value);
+        value
+    }
+
+    pub fn store(&mut self, address: u32, data: u32) {
+        self.record(AccessKind::Store, address, data);
+        self.write(address, data);
+    }
+
+    fn record(&mut self, kind: AccessKind, address: u32, value: u32) {
+        if let Some(ref mut accesses) = self.accesses {
+            accesses.push(Access { kind, address, value });
+        }
+    }
+
+    fn read(&mut self, address: u32) -> u32 {
         let masked_address = address & 0x03fffffc;
+        self.bus_cycle(masked_address);
 
         // Logically mapped RAM unless ROM is mapped low
         if masked_address < 0x02000000 {
             if self.rom_mapped {
                 // console.debug("Fetching from ROM mapped low");
-                self.rom[(masked_address / 4) as usize]
+                self.rom[(masked_address / 4) as usize % self.rom.len()]
             } else {
-                // This should be mapped logical-to-physical
-                // console.debug("Fetching from logical RAM");
-                self.ram[(masked_address / 4) as usize]
+                match self.logical(masked_address, false) {
+                    Some(index) => self.ram[index],
+                    None => self.abort()
+                }
             }
+        } else if self.user_mode && masked_address < 0x03400000 {
+            // Physical RAM and I/O are only there for the supervisor
+            self.abort()
         } else if masked_address < 0x03000000 {
             // Physically mapped RAM
             // console.debug("Fetching from physical RAM");
-            self.ram[((masked_address - 0x02000000) / 4) as usize]
+            self.ram[((masked_address - 0x02000000) / 4) as usize % self.ram.len()]
         } else if masked_address < 0x03400000 {
             // console.debug("Fetching from I/O controllers");
-            unimplemented!("Reading from I/O controllers")
+            self.load_io(masked_address)
         } else if masked_address < 0x03800000 {
             // console.debug("Fetching from low ROM");
             self.rom_mapped = false;
@@ -44,42 +543,158 @@ impl Memory {
         } else {
             // High ROM
             self.rom_mapped = false;
-            self.rom[((masked_address - 0x03800000) / 4) as usize]
+            self.rom[((masked_address - 0x03800000) / 4) as usize % self.rom.len()]
         }
     }
 
-    pub fn store(&mut self, address: u32, _data: u32) {
+    fn write(&mut self, address: u32, data: u32) {
         let masked_address = address & 0x03fffffc;
-        println!("Store address: {:08X}", masked_address);
+        self.bus_cycle(masked_address);
 
-        // Logically mapped RAM unless ROM is mapped low
+        // Logically mapped RAM, even with ROM mapped low for reads
         if masked_address < 0x02000000 {
-            unimplemented!("Writing to logically mapped RAM");
+            match self.logical(masked_address, true) {
+                Some(index) => self.write_ram(index, data),
+                None => self.aborted = true
+            }
+        } else if self.user_mode {
+            // User mode can only write to logical RAM
+            self.aborted = true;
         } else if masked_address < 0x03000000 {
-            unimplemented!("Writing to physically mapped RAM");
+            let index = ((masked_address - 0x02000000) / 4) as usize % self.ram.len();
+            self.write_ram(index, data);
         } else if masked_address < 0x03400000 {
-            // console.debug("Fetching from I/O controllers");
-            unimplemented!("Writing to I/O controllers")
+            self.store_io(masked_address, data);
         } else if masked_address < 0x03600000 {
-            unimplemented!("Writing to VIDC");
+            self.access(Source::Vidc, |memory| memory.vidc.store(masked_address, data));
         } else if masked_address < 0x03800000 {
             self.rom_mapped = false;
-            unimplemented!("Writing to DMA/MEMC");
+            // MEMC's control register turns VIDC's DMA on and off, so VIDC
+            // needs to be up to date with the old setting first
+            self.sync(Source::Vidc);
+            self.access(Source::Memc, |memory| memory.memc.store(masked_address, data));
+            self.vidc.sound.set_dma_enabled(self.memc.sound_dma_enabled());
+            self.reschedule(Source::Vidc);
         } else {
             self.rom_mapped = false;
-            unimplemented!("Writing to L2P address translator");
+            // The bottom bits of the address are part of the physical page
+            // number
+            self.memc.write_cam(address & 0x03ffffff);
+        }
+    }
+
+    // The word of RAM that MEMC maps a logical address to, if there's a page
+    // there that allows the access
+    fn logical(&self, masked_address: u32, write: bool) -> Option<usize> {
+        let (physical, protection) = self.memc.translate(masked_address)?;
+        if self.memc.permits(protection, self.user_mode, write) {
+            Some((physical / 4) as usize % self.ram.len())
+        } else {
+            None
+        }
+    }
+
+    fn write_ram(&mut self, index: usize, data: u32) {
+        self.ram[index] = data;
+        self.icache.invalidate(0x02000000 + (index * 4) as u32);
+    }
+
+    fn write_ram_byte(&mut self, index: usize, address: u32, data: u8) {
+        let shift = (address & 3) * 8;
+        let word = (self.ram[index] & !0xffu32.shl(shift)) | (data as u32).shl(shift);
+        self.write_ram(index, word);
+    }
+
+    // I/O space: IOC is selected by address bit 21, with bits 16-18 choosing
+    // between its internal registers (bank 0) and external peripherals
+    fn load_io(&mut self, address: u32) -> u32 {
+        if address & 0x00200000 != 0 && (address >> 16) & 7 == BANK_IOC {
+            return self.access(Source::Ioc, |memory| memory.ioc.load(address & 0xffff));
+        }
+
+        match self.decode_io(address) {
+            Some((index, offset)) => self.access(Source::Device(index), |memory| memory.devices[index].load(offset)),
+            None => 0
+        }
+    }
+
+    fn store_io(&mut self, address: u32, data: u32) {
+        if address & 0x00200000 != 0 && (address >> 16) & 7 == BANK_IOC {
+            self.access(Source::Ioc, |memory| memory.ioc.store(address & 0xffff, data));
+            self.update_i2c();
+            return;
+        }
+
+        if let Some((index, offset)) = self.decode_io(address) {
+            self.access(Source::Device(index), |memory| memory.devices[index].store(offset, data));
+            if (*self.devices[index]).as_any().is::<Latches>() {
+                self.update_floppy_lines();
+            }
+        }
+    }
+
+    fn decode_io(&self, address: u32) -> Option<(usize, u32)> {
+        self.selects.iter().find_map(|(select, index)| select.decode(address).map(|offset| (*index, offset)))
+    }
+
+    // The CMOS clock chip hangs off IOC control register bits 0 and 1
+    fn update_i2c(&mut self) {
+        self.sync(Source::Cmos);
+        let control = self.ioc.control();
+        self.cmos.clock(control & CONTROL_SCL != 0, control & CONTROL_SDA != 0);
+
+        let sda = if self.cmos.sda_output() { 0xff } else { !CONTROL_SDA };
+        self.ioc.set_control_inputs(sda);
+    }
+
+    // Latches A and B drive the floppy drive select, side, density and reset
+    fn update_floppy_lines(&mut self) {
+        let (drives, side, double_density, reset) = match self.device::<Latches>() {
+            Some(latches) => {
+                let (drives, side) = latches.floppy_select();
+                (drives, side, latches.double_density(), latches.take_fdc_reset())
+            },
+            None => return
+        };
+
+        if let Some(fdc) = self.device::<Fdc>() {
+            if reset {
+                fdc.reset();
+            }
+            fdc.select(drives, side);
+            fdc.set_double_density(double_density);
         }
     }
 
     pub fn load_byte(&mut self, address: u32) -> u8 {
-        let word = self.load(address);
+        let word = self.read(address);
         let field = address & 0x00000003;
-        (word.shr(field * 8) & 0xff) as u8
+        let byte = (word.shr(field * 8) & 0xff) as u8;
+        self.record(AccessKind::LoadByte, address, byte as u32);
+        byte
     }
 
     pub fn store_byte(&mut self, address: u32, data: u8) {
-        let field = (address & 0x00000003) as u8;
-        let word = (data & 0xff).shl(field * 8);
-        self.store(address, word as u32);
+        let masked_address = address & 0x03ffffff;
+        self.record(AccessKind::StoreByte, address, data as u32);
+
+        // RAM only takes the byte addressed
+        let index = if masked_address < 0x02000000 {
+            self.logical(masked_address, true)
+        } else if masked_address < 0x03000000 && !self.user_mode {
+            Some(((masked_address - 0x02000000) / 4) as usize % self.ram.len())
+        } else {
+            None
+        };
+
+        match index {
+            Some(index) => {
+                self.bus_cycle(masked_address);
+                self.write_ram_byte(index, masked_address, data);
+            },
+            // ARM2 drives the byte onto all four lanes of the data bus, and
+            // aborts are left to write
+            None => self.write(address, (data as u32) * 0x01010101)
+        }
     }
 }
@@ -3,3 +3,37 @@ pub mod cpu;
 pub mod registers;
 pub mod memory;
 pub mod instructions;
+pub mod memc;
+pub mod ioc;
+pub mod vidc;
+pub mod sound;
+pub mod floppy;
+pub mod fdc;
+pub mod adf;
+pub mod hfe;
+pub mod pcf8583;
+pub mod cmos;
+pub mod harddisc;
+pub mod hdc;
+pub mod ide;
+pub mod podule;
+pub mod hostfs;
+pub mod net;
+pub mod ether3;
+pub mod serial;
+pub mod acia;
+pub mod uart;
+pub mod printer;
+pub mod device;
+pub mod latches;
+pub mod machine;
+pub mod scheduler;
+pub mod icache;
+pub mod trace;
+pub mod swis;
+pub mod disassembler;
+pub mod debugger;
+pub mod gdb;
+pub mod snapshot;
+#[cfg(feature = "jit")]
+pub mod jit;
@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Where an emulated network card's Ethernet frames go on the host. Frames are
+// whole Ethernet frames without the preamble or FCS.
+
+pub trait NetworkBackend {
+    fn send(&mut self, frame: &[u8]);
+
+    // Next frame from the network, if one has arrived
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+// Open a backend from a description: "loopback", "pcap:<out>[,<in>]" (either
+// path can be left empty) or "udp:<local>,<peer>" (both address:port)
+pub fn open(spec: &str) -> io::Result<Box<dyn NetworkBackend>> {
+    let mut parts = spec.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("loopback"), None) => Ok(Box::new(Loopback::new())),
+        (Some("pcap"), Some(paths)) => {
+            let mut paths = paths.splitn(2, ',').map(|path| Some(path).filter(|path| !path.is_empty()));
+            let output = paths.next().and_then(|path| path);
+            let input = paths.next().and_then(|path| path);
+            Ok(Box::new(PcapBackend::new(output, input)?))
+        },
+        (Some("udp"), Some(addresses)) => {
+            let bad = || io::Error::new(io::ErrorKind::InvalidInput, "Bad UDP address");
+            let mut addresses = addresses.splitn(2, ',');
+            let local = addresses.next().and_then(|address| address.parse().ok()).ok_or_else(bad)?;
+            let peer = addresses.next().and_then(|address| address.parse().ok()).ok_or_else(bad)?;
+            Ok(Box::new(UdpBackend::new(local, peer)?))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown network backend {}", spec)))
+    }
+}
+
+// Every frame sent comes straight back, so a stack can talk to itself
+pub struct Loopback {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Loopback { frames: VecDeque::new() }
+    }
+}
+
+impl Default for Loopback {
+    fn default() -> Loopback {
+        Loopback::new()
+    }
+}
+
+impl NetworkBackend for Loopback {
+    fn send(&mut self, frame: &[u8]) {
+        self.frames.push_back(frame.to_vec());
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+}
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+// Records sent frames to a pcap file for Wireshark and friends, and plays the
+// frames in another capture back as if they had arrived from the network
+pub struct PcapBackend {
+    output: Option<File>,
+    input: VecDeque<Vec<u8>>,
+}
+
+impl PcapBackend {
+    pub fn new<P: AsRef<Path>>(output: Option<P>, input: Option<P>) -> io::Result<PcapBackend> {
+        let output = match output {
+            Some(path) => {
+                let mut file = File::create(path)?;
+                let mut header = Vec::new();
+                // Version 2.4 is two halfwords, major first
+                for word in &[PCAP_MAGIC, 0x00040002, 0, 0, PCAP_SNAPLEN, LINKTYPE_ETHERNET] {
+                    header.extend_from_slice(&word.to_le_bytes());
+                }
+                file.write_all(&header)?;
+                Some(file)
+            },
+            None => None
+        };
+
+        let input = match input {
+            Some(path) => read_pcap(path)?,
+            None => VecDeque::new()
+        };
+
+        Ok(PcapBackend { output, input })
+    }
+}
+
+impl NetworkBackend for PcapBackend {
+    fn send(&mut self, frame: &[u8]) {
+        if let Some(ref mut file) = self.output {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let mut record = Vec::new();
+            for word in &[now.as_secs() as u32, now.subsec_micros(), frame.len() as u32, frame.len() as u32] {
+                record.extend_from_slice(&word.to_le_bytes());
+            }
+            record.extend_from_slice(frame);
+
+            if let Err(error) = file.write_all(&record) {
+                println!("Couldn't write capture: {}", error);
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.input.pop_front()
+    }
+}
+
+fn read_pcap<P: AsRef<Path>>(path: P) -> io::Result<VecDeque<Vec<u8>>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Not an Ethernet pcap file");
+    if data.len() < 24 {
+        return Err(invalid());
+    }
+
+    // Captures are in the byte order of the machine that wrote them
+    let magic = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    let big_endian = match magic {
+        PCAP_MAGIC => false,
+        _ if magic.swap_bytes() == PCAP_MAGIC => true,
+        _ => return Err(invalid())
+    };
+    let word = |offset: usize| {
+        let bytes = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
+        if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+    };
+
+    if word(20) != LINKTYPE_ETHERNET {
+        return Err(invalid());
+    }
+
+    let mut frames = VecDeque::new();
+    let mut offset = 24;
+    while offset + 16 <= data.len() {
+        let length = word(offset + 8) as usize;
+        let start = offset + 16;
+        if start + length > data.len() {
+            break;
+        }
+        frames.push_back(data[start..start + length].to_vec());
+        offset = start + length;
+    }
+    Ok(frames)
+}
+
+// Tunnels frames as UDP datagrams to a peer, such as another emulator
+pub struct UdpBackend {
+    socket: UdpSocket,
+    peer: SocketAddr,
+}
+
+impl UdpBackend {
+    pub fn new(local: SocketAddr, peer: SocketAddr) -> io::Result<UdpBackend> {
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpBackend { socket, peer })
+    }
+}
+
+impl NetworkBackend for UdpBackend {
+    fn send(&mut self, frame: &[u8]) {
+        if let Err(error) = self.socket.send_to(frame, self.peer) {
+            println!("Couldn't send frame: {}", error);
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let mut buffer = [0; 2048];
+        match self.socket.recv_from(&mut buffer) {
+            Ok((length, _)) => Some(buffer[..length].to_vec()),
+            Err(_) => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    fn frame(length: usize, seed: u8) -> Vec<u8> {
+        (0..length).map(|index| (index as u8).wrapping_mul(7).wrapping_add(seed)).collect()
+    }
+
+    fn word(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+    }
+
+    #[test]
+    fn loopback() {
+        let mut backend = Loopback::new();
+        assert_eq!(backend.receive(), None);
+        backend.send(&frame(60, 1));
+        backend.send(&frame(1514, 2));
+        assert_eq!(backend.receive(), Some(frame(60, 1)));
+        assert_eq!(backend.receive(), Some(frame(1514, 2)));
+        assert_eq!(backend.receive(), None);
+    }
+
+    #[test]
+    fn pcap_layout() {
+        let path = env::temp_dir().join(format!("webarc-pcap-{}", std::process::id()));
+        {
+            let mut backend = PcapBackend::new(Some(&path), None).unwrap();
+            backend.send(&frame(60, 1));
+            backend.send(&frame(100, 2));
+            assert_eq!(backend.receive(), None);
+        }
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 24 + 16 + 60 + 16 + 100);
+        assert_eq!(&data[0..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&data[4..8], &[2, 0, 4, 0]);
+        assert_eq!(word(&data, 8), 0);
+        assert_eq!(word(&data, 12), 0);
+        assert_eq!(word(&data, 16), 65535);
+        assert_eq!(word(&data, 20), 1);
+
+        // Each record has a timestamp, then the captured and original lengths
+        assert!(word(&data, 24) > 0);
+        assert!(word(&data, 28) < 1_000_000);
+        assert_eq!(word(&data, 32), 60);
+        assert_eq!(word(&data, 36), 60);
+        assert_eq!(&data[40..100], &frame(60, 1)[..]);
+        assert_eq!(word(&data, 108), 100);
+        assert_eq!(word(&data, 112), 100);
+        assert_eq!(&data[116..], &frame(100, 2)[..]);
+
+        // The capture plays back in order
+        let mut backend = PcapBackend::new(None, Some(&path)).unwrap();
+        assert_eq!(backend.receive(), Some(frame(60, 1)));
+        assert_eq!(backend.receive(), Some(frame(100, 2)));
+        assert_eq!(backend.receive(), None);
+
+        // Big-endian captures too, stopping at a truncated record
+        let mut swapped = Vec::new();
+        for offset in (0..24).step_by(4) {
+            swapped.extend_from_slice(&word(&data, offset).to_be_bytes());
+        }
+        for offset in (24..40).step_by(4) {
+            swapped.extend_from_slice(&word(&data, offset).to_be_bytes());
+        }
+        swapped.extend_from_slice(&data[40..110]);
+        fs::write(&path, &swapped).unwrap();
+        let mut backend = PcapBackend::new(None, Some(&path)).unwrap();
+        assert_eq!(backend.receive(), Some(frame(60, 1)));
+        assert_eq!(backend.receive(), None);
+
+        fs::write(&path, &data[..20]).unwrap();
+        assert!(PcapBackend::new(None, Some(&path)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn udp() {
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (first_address, second_address) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+        drop((first, second));
+
+        let mut first = UdpBackend::new(first_address, second_address).unwrap();
+        let mut second = UdpBackend::new(second_address, first_address).unwrap();
+        assert_eq!(first.receive(), None);
+
+        first.send(&frame(60, 1));
+        second.send(&frame(1514, 2));
+
+        let wait = |backend: &mut UdpBackend| {
+            for _ in 0..100 {
+                if let Some(frame) = backend.receive() {
+                    return frame;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("No frame arrived");
+        };
+        assert_eq!(wait(&mut second), frame(60, 1));
+        assert_eq!(wait(&mut first), frame(1514, 2));
+    }
+
+    #[test]
+    fn specs() {
+        let path = env::temp_dir().join(format!("webarc-pcap-spec-{}", std::process::id()));
+        let mut backend = open("loopback").unwrap();
+        backend.send(&frame(60, 1));
+        assert_eq!(backend.receive(), Some(frame(60, 1)));
+
+        let mut backend = open(&format!("pcap:{}", path.display())).unwrap();
+        backend.send(&frame(60, 1));
+        drop(backend);
+        let mut backend = open(&format!("pcap:,{}", path.display())).unwrap();
+        assert_eq!(backend.receive(), Some(frame(60, 1)));
+        fs::remove_file(&path).unwrap();
+
+        assert!(open("udp:127.0.0.1:0,127.0.0.1:9").is_ok());
+        assert!(open("udp:127.0.0.1:0").is_err());
+        assert!(open("udp:localhost,127.0.0.1:9").is_err());
+        assert!(open("loopback:").is_err());
+        assert!(open("tap").is_err());
+    }
+}
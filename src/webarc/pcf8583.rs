@@ -0,0 +1,630 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use webarc::device::Device;
+use webarc::snapshot::{self, StateReader, StateWriter};
+use webarc::sound::CYCLES_PER_MICROSECOND;
+
+// PCF8583 clock and CMOS RAM, on the I2C bus bit-banged through IOC control
+// register bits 0 (SDA) and 1 (SCL). Addresses 0x00-0x0F hold the clock and
+// alarm registers, and 0x10-0xFF are the 240 bytes of RAM RISC OS keeps its
+// configuration in.
+
+// Slave address with A0 tied low
+const DEVICE_ADDRESS: u8 = 0xa0;
+
+const CONTROL: u8 = 0x00;
+const HUNDREDTHS: u8 = 0x01;
+const SECONDS: u8 = 0x02;
+const MINUTES: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const YEAR_DATE: u8 = 0x05;
+const WEEKDAY_MONTH: u8 = 0x06;
+
+const CONTROL_STOP_COUNTING: u8 = 1 << 7;
+
+pub const RAM_START: usize = 0x10;
+
+const CYCLES_PER_HUNDREDTH: u32 = 10000 * CYCLES_PER_MICROSECOND;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    Address,
+    WordAddress,
+    Write,
+    Read,
+}
+
+const PHASES: [Phase; 5] = [Phase::Idle, Phase::Address, Phase::WordAddress, Phase::Write, Phase::Read];
+
+pub struct Pcf8583 {
+    // Whole address space; the clock registers are kept separately
+    memory: [u8; 256],
+
+    hundredths: u8,
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    date: u8,
+    month: u8,
+    year: u8,
+    weekday: u8,
+    cycles: u32,
+
+    scl: bool,
+    sda: bool,
+    sda_out: bool,
+    phase: Phase,
+    bit: u8,
+    shift: u8,
+    acked: bool,
+    pointer: u8,
+
+    ram_file: Option<PathBuf>,
+    ram_dirty: bool,
+}
+
+impl Pcf8583 {
+    pub fn new() -> Pcf8583 {
+        let mut cmos = Pcf8583 {
+            memory: [0; 256],
+            hundredths: 0,
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            date: 1,
+            month: 1,
+            year: 0,
+            weekday: 0,
+            cycles: 0,
+            scl: true,
+            sda: true,
+            sda_out: true,
+            phase: Phase::Idle,
+            bit: 0,
+            shift: 0,
+            acked: false,
+            pointer: 0,
+            ram_file: None,
+            ram_dirty: false,
+        };
+        cmos.set_time_from_host();
+        cmos
+    }
+
+    pub fn set_time_from_host(&mut self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seconds = now.as_secs();
+        let days = (seconds / 86400) as i64;
+        let (year, month, date) = civil_from_days(days);
+
+        self.hundredths = (now.subsec_millis() / 10) as u8;
+        self.seconds = (seconds % 60) as u8;
+        self.minutes = (seconds / 60 % 60) as u8;
+        self.hours = (seconds / 3600 % 24) as u8;
+        self.date = date;
+        self.month = month;
+        self.year = (year % 4) as u8;
+
+        // 1st January 1970 was a Thursday
+        self.weekday = ((days + 4) % 7) as u8;
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.memory[RAM_START..]
+    }
+
+    pub fn set_ram(&mut self, ram: &[u8]) {
+        let length = ram.len().min(256 - RAM_START);
+        self.memory[RAM_START..RAM_START + length].copy_from_slice(&ram[..length]);
+    }
+
+    // Accepts either the 240 bytes of RAM or an image of the whole address
+    // space as saved by other emulators
+    pub fn load_ram<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+
+        match data.len() {
+            240 => self.set_ram(&data),
+            256 => self.set_ram(&data[RAM_START..]),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "CMOS RAM image must be 240 or 256 bytes"))
+        }
+        Ok(())
+    }
+
+    pub fn save_ram<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut image = self.memory;
+        for address in 0..RAM_START as u8 {
+            image[address as usize] = self.read_register(address);
+        }
+        File::create(path)?.write_all(&image)
+    }
+
+    // Load RAM from a file if it exists, and save it back whenever RISC OS
+    // changes it
+    pub fn set_ram_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        if path.as_ref().exists() {
+            self.load_ram(&path)?;
+        }
+        self.ram_file = Some(path.as_ref().to_path_buf());
+        Ok(())
+    }
+
+    // Level this device leaves on SDA (it can only pull it low)
+    pub fn sda_output(&self) -> bool {
+        self.sda_out
+    }
+
+    pub fn clock(&mut self, scl: bool, sda: bool) {
+        let (previous_scl, previous_sda) = (self.scl, self.sda);
+        self.scl = scl;
+        self.sda = sda;
+
+        if scl && previous_scl && sda != previous_sda {
+            if sda {
+                self.stop();
+            } else {
+                self.start();
+            }
+        } else if scl && !previous_scl {
+            self.rising_edge();
+        } else if !scl && previous_scl {
+            self.falling_edge();
+        }
+    }
+
+    fn start(&mut self) {
+        self.phase = Phase::Address;
+        self.bit = 0;
+        self.shift = 0;
+        self.sda_out = true;
+    }
+
+    fn stop(&mut self) {
+        self.phase = Phase::Idle;
+        self.sda_out = true;
+
+        if self.ram_dirty {
+            self.ram_dirty = false;
+            if let Some(ref path) = self.ram_file {
+                if let Err(error) = self.save_ram(path) {
+                    println!("Couldn't save CMOS RAM: {}", error);
+                }
+            }
+        }
+    }
+
+    // SDA is sampled while SCL is high
+    fn rising_edge(&mut self) {
+        match self.phase {
+            Phase::Idle => {},
+            Phase::Read => {
+                if self.bit == 8 {
+                    self.acked = !self.sda;
+                }
+                self.bit += 1;
+            },
+            _ => {
+                if self.bit < 8 {
+                    self.shift = (self.shift << 1) | self.sda as u8;
+                }
+                self.bit += 1;
+            }
+        }
+    }
+
+    // SDA changes while SCL is low
+    fn falling_edge(&mut self) {
+        match self.phase {
+            Phase::Idle => {},
+            Phase::Read => {
+                if self.bit < 8 {
+                    self.present_bit();
+                } else if self.bit == 8 {
+                    // Let the master acknowledge
+                    self.sda_out = true;
+                } else if self.bit == 9 {
+                    if self.acked {
+                        self.pointer = self.pointer.wrapping_add(1);
+                        self.bit = 0;
+                        self.present_bit();
+                    } else {
+                        self.phase = Phase::Idle;
+                        self.sda_out = true;
+                    }
+                } else {
+                    // End of the address acknowledge, so start sending
+                    self.bit = 0;
+                    self.present_bit();
+                }
+            },
+            _ => {
+                if self.bit == 8 {
+                    let byte = self.shift;
+                    self.sda_out = !self.receive(byte);
+                } else if self.bit == 9 {
+                    self.sda_out = true;
+                    self.bit = 0;
+                    self.shift = 0;
+                }
+            }
+        }
+    }
+
+    fn present_bit(&mut self) {
+        let byte = self.read_register(self.pointer);
+        self.sda_out = (byte << self.bit) & 0x80 != 0;
+    }
+
+    // Handle a byte written by the master, returning whether to acknowledge it
+    fn receive(&mut self, byte: u8) -> bool {
+        match self.phase {
+            Phase::Address => {
+                if byte & 0xfe != DEVICE_ADDRESS {
+                    self.phase = Phase::Idle;
+                    return false;
+                }
+                if byte & 1 != 0 {
+                    // Count the acknowledge clock past the end of a byte
+                    self.phase = Phase::Read;
+                    self.bit = 9;
+                } else {
+                    self.phase = Phase::WordAddress;
+                }
+            },
+            Phase::WordAddress => {
+                self.pointer = byte;
+                self.phase = Phase::Write;
+            },
+            Phase::Write => {
+                let pointer = self.pointer;
+                self.write_register(pointer, byte);
+                self.pointer = pointer.wrapping_add(1);
+            },
+            _ => {}
+        }
+        true
+    }
+
+    fn read_register(&self, address: u8) -> u8 {
+        match address {
+            HUNDREDTHS => to_bcd(self.hundredths),
+            SECONDS => to_bcd(self.seconds),
+            MINUTES => to_bcd(self.minutes),
+            HOURS => to_bcd(self.hours),
+            YEAR_DATE => self.year << 6 | to_bcd(self.date),
+            WEEKDAY_MONTH => self.weekday << 5 | to_bcd(self.month),
+            _ => self.memory[address as usize]
+        }
+    }
+
+    fn write_register(&mut self, address: u8, value: u8) {
+        match address {
+            HUNDREDTHS => self.hundredths = from_bcd(value),
+            SECONDS => self.seconds = from_bcd(value & 0x7f),
+            MINUTES => self.minutes = from_bcd(value & 0x7f),
+            HOURS => self.hours = from_bcd(value & 0x3f),
+            YEAR_DATE => {
+                self.year = value >> 6;
+                self.date = from_bcd(value & 0x3f);
+            },
+            WEEKDAY_MONTH => {
+                self.weekday = value >> 5;
+                self.month = from_bcd(value & 0x1f);
+            },
+            _ => {
+                self.memory[address as usize] = value;
+                if address as usize >= RAM_START {
+                    self.ram_dirty = true;
+                }
+            }
+        }
+    }
+
+    fn count(&mut self) {
+        self.hundredths += 1;
+        if self.hundredths < 100 { return; }
+        self.hundredths = 0;
+
+        self.seconds += 1;
+        if self.seconds < 60 { return; }
+        self.seconds = 0;
+
+        self.minutes += 1;
+        if self.minutes < 60 { return; }
+        self.minutes = 0;
+
+        self.hours += 1;
+        if self.hours < 24 { return; }
+        self.hours = 0;
+
+        self.weekday = (self.weekday + 1) % 7;
+        self.date += 1;
+        if self.date <= self.days_in_month() { return; }
+        self.date = 1;
+
+        self.month += 1;
+        if self.month <= 12 { return; }
+        self.month = 1;
+
+        self.year = (self.year + 1) & 3;
+    }
+
+    // The chip only knows the year modulo 4, and treats year 0 as a leap year
+    fn days_in_month(&self) -> u8 {
+        match self.month {
+            2 => if self.year == 0 { 29 } else { 28 },
+            4 | 6 | 9 | 11 => 30,
+            _ => 31
+        }
+    }
+}
+
+impl Device for Pcf8583 {
+    fn tick(&mut self, cycles: u32) {
+        if self.memory[CONTROL as usize] & CONTROL_STOP_COUNTING != 0 {
+            return;
+        }
+
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_HUNDREDTH {
+            self.cycles -= CYCLES_PER_HUNDREDTH;
+            self.count();
+        }
+    }
+
+    // The RAM file stays as it is, and gets the restored RAM the next time
+    // it's written
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.memory);
+        for &register in &[self.hundredths, self.seconds, self.minutes, self.hours, self.date, self.month, self.year, self.weekday] {
+            state.u8(register);
+        }
+        state.u32(self.cycles);
+        state.bool(self.scl);
+        state.bool(self.sda);
+        state.bool(self.sda_out);
+        state.u8(self.phase as u8);
+        state.u8(self.bit);
+        state.u8(self.shift);
+        state.bool(self.acked);
+        state.u8(self.pointer);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.bytes_into(&mut self.memory)?;
+        for register in [
+            &mut self.hundredths, &mut self.seconds, &mut self.minutes, &mut self.hours,
+            &mut self.date, &mut self.month, &mut self.year, &mut self.weekday
+        ] {
+            *register = state.u8()?;
+        }
+        self.cycles = state.u32()?;
+        self.scl = state.bool()?;
+        self.sda = state.bool()?;
+        self.sda_out = state.bool()?;
+        self.phase = *PHASES.get(state.u8()? as usize).ok_or_else(|| snapshot::invalid("Bad CMOS state in snapshot"))?;
+        self.bit = state.u8()?;
+        self.shift = state.u8()?;
+        self.acked = state.bool()?;
+        self.pointer = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Default for Pcf8583 {
+    fn default() -> Pcf8583 {
+        Pcf8583::new()
+    }
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+// Gregorian date from days since 1st January 1970
+pub fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let date = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // The master's side of the bus. SDA only changes while SCL is low, apart
+    // from start and stop conditions.
+    fn start(cmos: &mut Pcf8583) {
+        cmos.clock(false, true);
+        cmos.clock(true, true);
+        cmos.clock(true, false);
+        cmos.clock(false, false);
+    }
+
+    fn stop(cmos: &mut Pcf8583) {
+        cmos.clock(false, false);
+        cmos.clock(true, false);
+        cmos.clock(true, true);
+    }
+
+    // Send a byte, returning whether it was acknowledged
+    fn write(cmos: &mut Pcf8583, byte: u8) -> bool {
+        for bit in (0..8).rev() {
+            let sda = (byte >> bit) & 1 != 0;
+            cmos.clock(false, sda);
+            cmos.clock(true, sda);
+            cmos.clock(false, sda);
+        }
+        cmos.clock(false, true);
+        cmos.clock(true, true);
+        let acked = !cmos.sda_output();
+        cmos.clock(false, true);
+        acked
+    }
+
+    // Receive a byte, acknowledging it if more are wanted
+    fn read(cmos: &mut Pcf8583, ack: bool) -> u8 {
+        let mut byte = 0;
+        for _ in 0..8 {
+            cmos.clock(false, true);
+            cmos.clock(true, true);
+            byte = byte << 1 | cmos.sda_output() as u8;
+        }
+        cmos.clock(false, !ack);
+        cmos.clock(true, !ack);
+        cmos.clock(false, !ack);
+        byte
+    }
+
+    fn write_bytes(cmos: &mut Pcf8583, address: u8, bytes: &[u8]) {
+        start(cmos);
+        assert!(write(cmos, DEVICE_ADDRESS));
+        assert!(write(cmos, address));
+        for &byte in bytes {
+            assert!(write(cmos, byte));
+        }
+        stop(cmos);
+    }
+
+    // Set the word address, then read from it after a repeated start
+    fn read_bytes(cmos: &mut Pcf8583, address: u8, count: usize) -> Vec<u8> {
+        start(cmos);
+        assert!(write(cmos, DEVICE_ADDRESS));
+        assert!(write(cmos, address));
+        start(cmos);
+        assert!(write(cmos, DEVICE_ADDRESS | 1));
+        let bytes = (0..count).map(|i| read(cmos, i + 1 < count)).collect();
+        stop(cmos);
+        bytes
+    }
+
+    #[test]
+    fn transfers() {
+        let mut cmos = Pcf8583::new();
+        write_bytes(&mut cmos, 0x40, &[0x12, 0x34, 0xff, 0x56]);
+        assert_eq!(&cmos.ram()[0x30..0x34], &[0x12, 0x34, 0xff, 0x56]);
+        assert_eq!(read_bytes(&mut cmos, 0x40, 4), [0x12, 0x34, 0xff, 0x56]);
+        // The bus is left released
+        assert!(cmos.sda_output());
+
+        // Another device's address isn't acknowledged, and nothing is written
+        start(&mut cmos);
+        assert!(!write(&mut cmos, 0xa2));
+        assert!(!write(&mut cmos, 0x40));
+        assert!(!write(&mut cmos, 0x99));
+        stop(&mut cmos);
+        assert_eq!(cmos.ram()[0x30], 0x12);
+
+        // A read without a word address carries on from the last byte read,
+        // and one that isn't acknowledged ends the transfer
+        start(&mut cmos);
+        assert!(write(&mut cmos, DEVICE_ADDRESS | 1));
+        assert_eq!(read(&mut cmos, false), 0x56);
+        assert!(cmos.sda_output());
+        assert_eq!(read(&mut cmos, false), 0xff);
+        stop(&mut cmos);
+    }
+
+    #[test]
+    fn word_address_wraps() {
+        let mut cmos = Pcf8583::new();
+        write_bytes(&mut cmos, 0xfe, &[0xaa, 0xbb, CONTROL_STOP_COUNTING, 0x45]);
+        assert_eq!(&cmos.ram()[0xee..], &[0xaa, 0xbb]);
+        assert_eq!(cmos.read_register(CONTROL), CONTROL_STOP_COUNTING);
+        assert_eq!(cmos.read_register(HUNDREDTHS), 0x45);
+        assert_eq!(read_bytes(&mut cmos, 0xfe, 4), [0xaa, 0xbb, CONTROL_STOP_COUNTING, 0x45]);
+    }
+
+    // Set the clock to a time, with the year modulo 4, and run it on by a
+    // hundredth of a second
+    fn next(year: u8, month: u8, date: u8, hours: u8, minutes: u8, seconds: u8) -> [u8; 6] {
+        let mut cmos = Pcf8583::new();
+        write_bytes(&mut cmos, CONTROL, &[
+            0,
+            0x99,
+            to_bcd(seconds),
+            to_bcd(minutes),
+            to_bcd(hours),
+            year << 6 | to_bcd(date),
+            to_bcd(month),
+        ]);
+        cmos.tick(CYCLES_PER_HUNDREDTH);
+        let registers = read_bytes(&mut cmos, HUNDREDTHS, 6);
+        [registers[0], registers[1], registers[2], registers[3], registers[4], registers[5] & 0x1f]
+    }
+
+    #[test]
+    fn rollover() {
+        assert_eq!(next(1, 3, 15, 10, 20, 30), [0x00, 0x31, 0x20, 0x10, 1 << 6 | 0x15, 0x03]);
+        assert_eq!(next(1, 3, 15, 10, 20, 59), [0x00, 0x00, 0x21, 0x10, 1 << 6 | 0x15, 0x03]);
+        assert_eq!(next(1, 3, 15, 10, 59, 59), [0x00, 0x00, 0x00, 0x11, 1 << 6 | 0x15, 0x03]);
+        assert_eq!(next(1, 3, 15, 23, 59, 59), [0x00, 0x00, 0x00, 0x00, 1 << 6 | 0x16, 0x03]);
+        assert_eq!(next(1, 4, 30, 23, 59, 59), [0x00, 0x00, 0x00, 0x00, 1 << 6 | 0x01, 0x05]);
+        assert_eq!(next(1, 5, 30, 23, 59, 59), [0x00, 0x00, 0x00, 0x00, 1 << 6 | 0x31, 0x05]);
+        assert_eq!(next(2, 12, 31, 23, 59, 59), [0x00, 0x00, 0x00, 0x00, 3 << 6 | 0x01, 0x01]);
+        assert_eq!(next(3, 12, 31, 23, 59, 59), [0x00, 0x00, 0x00, 0x00, 0x01, 0x01]);
+
+        // February has 29 days in year 0 and 28 in the others
+        assert_eq!(next(0, 2, 28, 23, 59, 59), [0x00, 0x00, 0x00, 0x00, 0x29, 0x02]);
+        assert_eq!(next(0, 2, 29, 23, 59, 59), [0x00, 0x00, 0x00, 0x00, 0x01, 0x03]);
+        assert_eq!(next(1, 2, 28, 23, 59, 59), [0x00, 0x00, 0x00, 0x00, 1 << 6 | 0x01, 0x03]);
+    }
+
+    #[test]
+    fn stopped() {
+        let mut cmos = Pcf8583::new();
+        write_bytes(&mut cmos, CONTROL, &[CONTROL_STOP_COUNTING, 0x50]);
+        cmos.tick(10 * CYCLES_PER_HUNDREDTH);
+        assert_eq!(read_bytes(&mut cmos, HUNDREDTHS, 1), [0x50]);
+    }
+
+    #[test]
+    fn ram_files() {
+        let path = env::temp_dir().join(format!("webarc-cmos-{}", std::process::id()));
+        let ram: Vec<u8> = (0..240).map(|i| i as u8 ^ 0x5a).collect();
+
+        let mut cmos = Pcf8583::new();
+        fs::write(&path, &ram).unwrap();
+        cmos.load_ram(&path).unwrap();
+        assert_eq!(cmos.ram(), &ram[..]);
+
+        // A whole address space image, whose clock registers are ignored
+        let mut image = vec![0xff; RAM_START];
+        image.extend(ram.iter().map(|byte| !byte));
+        fs::write(&path, &image).unwrap();
+        let mut cmos = Pcf8583::new();
+        cmos.load_ram(&path).unwrap();
+        assert_eq!(cmos.ram(), &image[RAM_START..]);
+        assert_eq!(cmos.read_register(CONTROL), 0);
+
+        // Saving writes the whole address space
+        cmos.save_ram(&path).unwrap();
+        let saved = fs::read(&path).unwrap();
+        assert_eq!(saved.len(), 256);
+        assert_eq!(&saved[RAM_START..], &image[RAM_START..]);
+
+        fs::write(&path, &ram[..100]).unwrap();
+        let error = cmos.load_ram(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(cmos.ram(), &image[RAM_START..]);
+    }
+}
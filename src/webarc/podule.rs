@@ -0,0 +1,342 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use webarc::device::{Device, Interrupts};
+use webarc::ioc::{FIQ_PODULE, IRQ_B_PODULE};
+use webarc::snapshot::{self, StateReader, StateWriter};
+
+// Expansion cards in IOC bank 4. Each of the four slots gets 16KB of address
+// space in each of the slow (0x3240000), medium (0x32C0000), fast (0x3340000)
+// and sync (0x33C0000) cycle types, so the slot is address bits 14-15. Podules
+// sit on data bus bits 0-15, and the byte at the bottom of the slow space is
+// the ID that RISC OS probes for at boot. Podule IRQs and FIQs are wired
+// together onto IOC IRQ B and FIQ.
+
+pub const SLOTS: usize = 4;
+const SLOT_SIZE: u32 = 0x4000;
+
+// An empty slot's data lines float high
+pub const NO_CARD: u32 = 0xffffffff;
+
+// Simple ID byte: interrupt status in bits 0 and 2, the ID in bits 3-6, and
+// bit 7 clear for Acorn conformant cards. An ID of 0 means an extended ID
+// follows.
+const ID_IRQ: u8 = 1 << 0;
+const ID_FIQ: u8 = 1 << 2;
+
+// Extended ID flags in byte 1
+const FLAGS_CHUNK_DIRECTORIES: u8 = 1 << 0;
+
+// Bytes 8-15 are the interrupt status pointers, then the chunk directory
+const CHUNK_DIRECTORY: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Slow,
+    Medium,
+    Fast,
+    Sync,
+}
+
+impl Access {
+    // Cycle type from address bits 19-20
+    pub fn from_address(address: u32) -> Access {
+        match (address >> 19) & 3 {
+            0 => Access::Slow,
+            1 => Access::Medium,
+            2 => Access::Fast,
+            _ => Access::Sync
+        }
+    }
+}
+
+pub trait Podule {
+    // Offsets are bytes within the slot's 16KB, and data is in bits 0-15
+    fn load(&mut self, access: Access, offset: u32) -> u32;
+
+    fn store(&mut self, access: Access, offset: u32, data: u32);
+
+    fn irq(&self) -> bool {
+        false
+    }
+
+    fn fiq(&self) -> bool {
+        false
+    }
+
+    fn tick(&mut self, _cycles: u32) {}
+
+    // Cycles until the card next needs ticking, as for Device
+    fn next_event(&self) -> Option<u32> {
+        None
+    }
+
+    // The card's state in a snapshot, as for Device. Its ROM isn't included.
+    fn save(&self, _state: &mut StateWriter) {}
+
+    fn restore(&mut self, _state: &mut StateReader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Cards that only need to identify themselves with a simple ID
+pub fn simple_id(id: u8, irq: bool, fiq: bool) -> u8 {
+    ((id & 0xf) << 3) | if irq { ID_IRQ } else { 0 } | if fiq { ID_FIQ } else { 0 }
+}
+
+pub struct Chunk {
+    // Operating system identity byte, which says what the chunk is
+    pub os_identity: u8,
+    pub data: Vec<u8>,
+}
+
+pub struct ExtendedId {
+    pub product: u16,
+    pub manufacturer: u16,
+    pub country: u8,
+    pub chunks: Vec<Chunk>,
+}
+
+impl ExtendedId {
+    // Everything a card shows in its ID space: the header, then a chunk
+    // directory of 8 byte entries ending in a zero byte, then the chunks
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; CHUNK_DIRECTORY];
+        bytes[1] = if self.chunks.is_empty() { 0 } else { FLAGS_CHUNK_DIRECTORIES };
+        bytes[3] = self.product as u8;
+        bytes[4] = (self.product >> 8) as u8;
+        bytes[5] = self.manufacturer as u8;
+        bytes[6] = (self.manufacturer >> 8) as u8;
+        bytes[7] = self.country;
+
+        let mut start = CHUNK_DIRECTORY + self.chunks.len() * 8 + 4;
+        for chunk in &self.chunks {
+            let size = chunk.data.len();
+            bytes.extend_from_slice(&[chunk.os_identity, size as u8, (size >> 8) as u8, (size >> 16) as u8]);
+            bytes.extend_from_slice(&(start as u32).to_le_bytes());
+            start += size;
+        }
+        bytes.extend_from_slice(&[0; 4]);
+
+        for chunk in &self.chunks {
+            bytes.extend_from_slice(&chunk.data);
+        }
+        bytes
+    }
+}
+
+// A card that is nothing but a ROM, such as a podule's ROM dumped to a file.
+// Each byte of the ROM is a word apart in the slow space, so the slot only
+// shows 4KB at a time, and a write anywhere in the slot selects which 4KB
+// page that is.
+pub struct RomPodule {
+    rom: Vec<u8>,
+    page: usize,
+}
+
+impl RomPodule {
+    pub fn new(rom: Vec<u8>) -> RomPodule {
+        RomPodule { rom, page: 0 }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<RomPodule> {
+        let mut rom = Vec::new();
+        File::open(path)?.read_to_end(&mut rom)?;
+        Ok(RomPodule::new(rom))
+    }
+}
+
+impl Podule for RomPodule {
+    fn load(&mut self, _access: Access, offset: u32) -> u32 {
+        let index = self.page * (SLOT_SIZE as usize / 4) + (offset as usize >> 2);
+        self.rom.get(index).map_or(0xff, |byte| *byte as u32)
+    }
+
+    fn store(&mut self, _access: Access, _offset: u32, data: u32) {
+        self.page = (data & 0xff) as usize;
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.usize(self.page);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.page = state.usize()?;
+        Ok(())
+    }
+}
+
+pub struct Podules {
+    pub slots: [Option<Box<dyn Podule>>; SLOTS],
+}
+
+impl Podules {
+    pub fn new() -> Podules {
+        Podules {
+            slots: [None, None, None, None],
+        }
+    }
+
+    pub fn insert(&mut self, slot: usize, podule: Box<dyn Podule>) {
+        self.slots[slot] = Some(podule);
+    }
+
+    pub fn remove(&mut self, slot: usize) -> Option<Box<dyn Podule>> {
+        self.slots[slot].take()
+    }
+
+    pub fn irq(&self) -> bool {
+        self.slots.iter().flatten().any(|podule| podule.irq())
+    }
+
+    pub fn fiq(&self) -> bool {
+        self.slots.iter().flatten().any(|podule| podule.fiq())
+    }
+}
+
+impl Device for Podules {
+    // Addresses are anywhere in IOC bank 4, with the cycle type in bits 19-20
+    fn load(&mut self, address: u32) -> u32 {
+        let (slot, offset) = slot_offset(address);
+        match self.slots[slot] {
+            Some(ref mut podule) => podule.load(Access::from_address(address), offset),
+            None => NO_CARD
+        }
+    }
+
+    fn store(&mut self, address: u32, data: u32) {
+        let (slot, offset) = slot_offset(address);
+        if let Some(ref mut podule) = self.slots[slot] {
+            podule.store(Access::from_address(address), offset, data);
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        for podule in self.slots.iter_mut().flatten() {
+            podule.tick(cycles);
+        }
+    }
+
+    fn next_event(&self) -> Option<u32> {
+        self.slots.iter().flatten().filter_map(|podule| podule.next_event()).min()
+    }
+
+    fn interrupts(&mut self) -> Interrupts {
+        let mut interrupts = Interrupts::irq_b(IRQ_B_PODULE, self.irq());
+        interrupts |= Interrupts::fiq(FIQ_PODULE, self.fiq());
+        interrupts
+    }
+
+    // Each slot's card, if there is one, with its state in a block of its
+    // own so a card that doesn't match what was saved can be told
+    fn save(&self, state: &mut StateWriter) {
+        for slot in &self.slots {
+            state.bool(slot.is_some());
+            if let Some(ref podule) = *slot {
+                let mut card = StateWriter::new();
+                podule.save(&mut card);
+                state.bytes(&card.into_bytes());
+            }
+        }
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if state.bool()? != slot.is_some() {
+                return Err(snapshot::invalid(&format!("Snapshot has different podules fitted in slot {}", index)));
+            }
+            if let Some(ref mut podule) = *slot {
+                let data = state.bytes()?;
+                let mut card = StateReader::new(&data);
+                podule.restore(&mut card)?;
+                if !card.finished() {
+                    return Err(snapshot::invalid(&format!("Snapshot has a different podule in slot {}", index)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Podules {
+    fn default() -> Podules {
+        Podules::new()
+    }
+}
+
+fn slot_offset(address: u32) -> (usize, u32) {
+    (((address >> 14) & 3) as usize, address & (SLOT_SIZE - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_id() {
+        let id = ExtendedId {
+            product: 0x1234,
+            manufacturer: 0x5678,
+            country: 0,
+            chunks: vec![
+                Chunk { os_identity: 0x81, data: vec![1; 3] },
+                Chunk { os_identity: 0xf5, data: vec![2; 0x10005] },
+            ],
+        };
+        let bytes = id.bytes();
+        assert_eq!(&bytes[..8], &[0, FLAGS_CHUNK_DIRECTORIES, 0, 0x34, 0x12, 0x78, 0x56, 0]);
+
+        // Two entries with 24 bit sizes and the offsets of the chunks, which
+        // come straight after the terminator
+        let data = CHUNK_DIRECTORY as u32 + 2 * 8 + 4;
+        assert_eq!(&bytes[16..24], &[0x81, 3, 0, 0, data as u8, 0, 0, 0]);
+        assert_eq!(&bytes[24..32], &[0xf5, 0x05, 0x00, 0x01, data as u8 + 3, 0, 0, 0]);
+        assert_eq!(&bytes[32..36], &[0; 4]);
+        assert_eq!(&bytes[36..39], &[1; 3]);
+        assert_eq!(bytes.len(), 39 + 0x10005);
+        assert!(bytes[39..].iter().all(|&byte| byte == 2));
+
+        // No chunks, and no directory flag
+        let bytes = ExtendedId { product: 1, manufacturer: 2, country: 3, chunks: Vec::new() }.bytes();
+        assert_eq!(bytes, [0, 0, 0, 1, 0, 2, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rom_pages() {
+        let rom: Vec<u8> = (0..0x2800).map(|i| (i / 0x1000 * 0x10 + i % 7) as u8).collect();
+        let mut podule = RomPodule::new(rom);
+
+        // A byte a word, 4KB to a page
+        assert_eq!(podule.load(Access::Slow, 0), 0x00);
+        assert_eq!(podule.load(Access::Slow, 4), 0x01);
+        assert_eq!(podule.load(Access::Slow, 0x3ffc), 0xfff % 7);
+        podule.store(Access::Slow, 0x1234, 2);
+        assert_eq!(podule.load(Access::Slow, 0), 0x20 + 0x2000 % 7);
+        assert_eq!(podule.load(Access::Slow, 0x1ffc), 0x20 + 0x27ff % 7);
+
+        // Past the end of the ROM reads as nothing there
+        assert_eq!(podule.load(Access::Slow, 0x2000), 0xff);
+        podule.store(Access::Slow, 0, 0x103);
+        assert_eq!(podule.load(Access::Slow, 0), 0xff);
+    }
+
+    #[test]
+    fn slots() {
+        let mut podules = Podules::new();
+        podules.insert(2, Box::new(RomPodule::new(vec![0x42; 16])));
+
+        // Slot 2 in every cycle type, and nothing in the others
+        for &space in &[0x03240000, 0x032c0000, 0x03340000, 0x033c0000] {
+            assert_eq!(podules.load(space + 2 * SLOT_SIZE), 0x42);
+            for &slot in &[0, 1, 3] {
+                assert_eq!(podules.load(space + slot * SLOT_SIZE), NO_CARD);
+            }
+        }
+        assert_eq!(Access::from_address(0x033c0000), Access::Sync);
+
+        assert!(podules.remove(2).is_some());
+        assert_eq!(podules.load(0x03240000 + 2 * SLOT_SIZE), NO_CARD);
+        podules.store(0x03240000 + 2 * SLOT_SIZE, 1);
+    }
+}
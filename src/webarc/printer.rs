@@ -0,0 +1,317 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use webarc::device::{Device, Interrupts};
+use webarc::ioc::IRQ_A_PRINTER_ACK;
+use webarc::snapshot::{StateReader, StateWriter};
+use webarc::sound::CYCLES_PER_MICROSECOND;
+
+// Centronics parallel printer port. On IOC machines the data comes from a
+// latch in bank 5 and the strobe from latch B, with BUSY and ACK arriving as
+// IOC IRQ A events. The A5000 uses the 82C711's PC-style port instead. Either
+// way the printer at the other end is a file capturing everything sent.
+
+// How long the printer stays busy with each byte before acknowledging it
+const BYTE_TIME: u32 = 10 * CYCLES_PER_MICROSECOND;
+
+pub struct Printer {
+    output: Option<File>,
+    busy_countdown: u32,
+    acknowledged: bool,
+}
+
+impl Printer {
+    pub fn new() -> Printer {
+        Printer {
+            output: None,
+            busy_countdown: 0,
+            acknowledged: false,
+        }
+    }
+
+    // Append everything printed to a file
+    pub fn capture_to<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.output = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        Ok(())
+    }
+
+    pub fn strobe(&mut self, byte: u8) {
+        if self.busy() {
+            return;
+        }
+
+        if let Some(ref mut file) = self.output {
+            if let Err(error) = file.write_all(&[byte]) {
+                println!("Couldn't write printer output: {}", error);
+            }
+        }
+        self.busy_countdown = BYTE_TIME;
+    }
+
+    // With nothing to print to, the printer is offline and stays busy
+    pub fn busy(&self) -> bool {
+        self.output.is_none() || self.busy_countdown > 0
+    }
+
+    // Whether the printer has pulsed ACK since this was last asked
+    pub fn take_acknowledge(&mut self) -> bool {
+        let acknowledged = self.acknowledged;
+        self.acknowledged = false;
+        acknowledged
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if self.busy_countdown > 0 {
+            self.busy_countdown = self.busy_countdown.saturating_sub(cycles);
+            if self.busy_countdown == 0 {
+                self.acknowledged = true;
+            }
+        }
+    }
+
+    // Acknowledging the byte being printed
+    pub fn next_event(&self) -> Option<u32> {
+        if self.busy_countdown > 0 { Some(self.busy_countdown) } else { None }
+    }
+
+    // Where the printer is with the current byte. The capture file isn't
+    // part of the machine.
+    pub fn save(&self, state: &mut StateWriter) {
+        state.u32(self.busy_countdown);
+        state.bool(self.acknowledged);
+    }
+
+    pub fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.busy_countdown = state.u32()?;
+        self.acknowledged = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Printer {
+        Printer::new()
+    }
+}
+
+// The 82C711's parallel port at PC I/O ports 0x278-0x27A
+pub const PORT_DATA: u16 = 0x278;
+const PORT_STATUS: u16 = 0x279;
+pub const PORT_CONTROL: u16 = 0x27a;
+
+// Status lines, several of them inverted on the way in
+const STATUS_NOT_ERROR: u8 = 1 << 3;
+const STATUS_SELECT: u8 = 1 << 4;
+const STATUS_NOT_ACK: u8 = 1 << 6;
+const STATUS_NOT_BUSY: u8 = 1 << 7;
+
+const CONTROL_STROBE: u8 = 1 << 0;
+const CONTROL_IRQ_ENABLE: u8 = 1 << 4;
+
+pub struct PcParallel {
+    pub printer: Printer,
+    data: u8,
+    control: u8,
+    // ACK has pulsed since the status was last read
+    acknowledge: bool,
+    interrupt: bool,
+}
+
+impl PcParallel {
+    pub fn new() -> PcParallel {
+        PcParallel {
+            printer: Printer::new(),
+            data: 0,
+            control: 0,
+            acknowledge: false,
+            interrupt: false,
+        }
+    }
+
+    // Whether an ACK pulse has interrupted since this was last asked, which
+    // is an edge for IOC to latch
+    pub fn take_irq(&mut self) -> bool {
+        let interrupt = self.interrupt;
+        self.interrupt = false;
+        interrupt
+    }
+}
+
+impl Device for PcParallel {
+    fn load(&mut self, port: u32) -> u32 {
+        let port = port as u16;
+        let value = match port {
+            PORT_DATA => self.data,
+            PORT_STATUS => {
+                let mut status = STATUS_NOT_ERROR | STATUS_NOT_ACK;
+                if !self.printer.busy() {
+                    status |= STATUS_NOT_BUSY | STATUS_SELECT;
+                }
+                if self.acknowledge {
+                    status &= !STATUS_NOT_ACK;
+                    self.acknowledge = false;
+                }
+                status
+            },
+            PORT_CONTROL => self.control,
+            _ => 0xff
+        };
+        value as u32
+    }
+
+    fn store(&mut self, port: u32, data: u32) {
+        let port = port as u16;
+        let value = data as u8;
+
+        match port {
+            PORT_DATA => self.data = value,
+            PORT_CONTROL => {
+                // The printer takes the data as the strobe is asserted
+                if value & CONTROL_STROBE != 0 && self.control & CONTROL_STROBE == 0 {
+                    self.printer.strobe(self.data);
+                }
+                self.control = value & 0x3f;
+            },
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.printer.tick(cycles);
+        if self.printer.take_acknowledge() {
+            self.acknowledge = true;
+            self.interrupt = self.control & CONTROL_IRQ_ENABLE != 0;
+        }
+    }
+
+    fn next_event(&self) -> Option<u32> {
+        self.printer.next_event()
+    }
+
+    fn interrupts(&mut self) -> Interrupts {
+        Interrupts::irq_a(IRQ_A_PRINTER_ACK, self.take_irq())
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        self.printer.save(state);
+        state.u8(self.data);
+        state.u8(self.control);
+        state.bool(self.acknowledge);
+        state.bool(self.interrupt);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.printer.restore(state)?;
+        self.data = state.u8()?;
+        self.control = state.u8()?;
+        self.acknowledge = state.bool()?;
+        self.interrupt = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Default for PcParallel {
+    fn default() -> PcParallel {
+        PcParallel::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use webarc::ioc::IRQ_A_PRINTER_BUSY;
+    use webarc::latches::Latches;
+
+    const READY: u8 = STATUS_NOT_ERROR | STATUS_SELECT | STATUS_NOT_ACK | STATUS_NOT_BUSY;
+
+    fn capture(name: &str) -> std::path::PathBuf {
+        let path = env::temp_dir().join(format!("webarc-printer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn outb(port: &mut PcParallel, register: u16, value: u8) {
+        port.store(register as u32, value as u32);
+    }
+
+    fn status(port: &mut PcParallel) -> u8 {
+        port.load(PORT_STATUS as u32) as u8
+    }
+
+    fn print(port: &mut PcParallel, byte: u8, control: u8) {
+        outb(port, PORT_DATA, byte);
+        outb(port, PORT_CONTROL, control | CONTROL_STROBE);
+        outb(port, PORT_CONTROL, control);
+    }
+
+    #[test]
+    fn pc_handshake() {
+        let mut port = PcParallel::new();
+
+        // Offline with nowhere to print to
+        assert_eq!(status(&mut port) & STATUS_NOT_BUSY, 0);
+        print(&mut port, b'X', 0);
+        assert_eq!(port.next_event(), None);
+
+        let path = capture("pc");
+        port.printer.capture_to(&path).unwrap();
+        assert_eq!(status(&mut port), READY);
+
+        // Busy from the strobe until ACK, which shows once in the status.
+        // Strobes while it's busy are lost.
+        print(&mut port, b'H', 0);
+        assert_eq!(status(&mut port), STATUS_NOT_ERROR | STATUS_NOT_ACK);
+        print(&mut port, b'X', 0);
+        port.tick(BYTE_TIME - 1);
+        assert_eq!(status(&mut port), STATUS_NOT_ERROR | STATUS_NOT_ACK);
+        port.tick(1);
+        assert_eq!(status(&mut port), READY & !STATUS_NOT_ACK);
+        assert_eq!(status(&mut port), READY);
+        assert_eq!(port.interrupts(), Interrupts::NONE);
+
+        // ACK interrupts once when enabled
+        print(&mut port, b'i', CONTROL_IRQ_ENABLE);
+        port.tick(BYTE_TIME);
+        assert_eq!(port.interrupts(), Interrupts::irq_a(IRQ_A_PRINTER_ACK, true));
+        assert_eq!(port.interrupts(), Interrupts::NONE);
+
+        drop(port);
+        assert_eq!(fs::read(&path).unwrap(), b"Hi");
+        fs::remove_file(&path).unwrap();
+    }
+
+    // The IOC machines' port: the data latch, the strobe in latch B, and BUSY
+    // and ACK on IRQ A
+    #[test]
+    fn latch_handshake() {
+        const PRINTER_DATA: u32 = 0x10;
+        const LATCH_B: u32 = 0x18;
+        const STROBE: u32 = 1 << 4;
+        let busy = Interrupts::irq_a(IRQ_A_PRINTER_BUSY, true);
+        let ack = Interrupts::irq_a(IRQ_A_PRINTER_ACK, true);
+
+        let mut latches = Latches::new();
+        let path = capture("latches");
+        latches.printer.capture_to(&path).unwrap();
+        assert_eq!(latches.interrupts(), Interrupts::NONE);
+
+        // The latches come up all ones, strobe included
+        latches.store(LATCH_B, 0);
+        for &byte in b"OK" {
+            latches.store(PRINTER_DATA, (byte as u32) << 16);
+            latches.store(LATCH_B, STROBE << 16);
+            latches.store(LATCH_B, 0);
+            assert_eq!(latches.interrupts(), busy);
+            latches.tick(latches.next_event().unwrap());
+            assert_eq!(latches.interrupts(), ack);
+            assert_eq!(latches.interrupts(), Interrupts::NONE);
+        }
+
+        drop(latches);
+        assert_eq!(fs::read(&path).unwrap(), b"OK");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,3 +1,7 @@
+use std::io;
+use webarc::snapshot::{StateReader, StateWriter};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Mode {
     User = 0,
     Firq = 1,
@@ -5,6 +9,18 @@ pub enum Mode {
     Svc = 3
 }
 
+impl Mode {
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name.to_lowercase().as_str() {
+            "usr" | "user" => Some(Mode::User),
+            "fiq" | "firq" => Some(Mode::Firq),
+            "irq" => Some(Mode::Irq),
+            "svc" => Some(Mode::Svc),
+            _ => None
+        }
+    }
+}
+
 pub struct RegisterFile {
     /* Register layout:
         0  R0
@@ -47,10 +63,15 @@ impl RegisterFile {
     }
 
     fn index(&self, reg: u32) -> usize {
+        RegisterFile::slot(self.mode(), reg)
+    }
+
+    // Where a register is kept for a mode
+    pub fn slot(mode: Mode, reg: u32) -> usize {
         (if reg == 15 {
             15
         } else {
-            match self.mode() {
+            match mode {
                 Mode::User => reg,
                 Mode::Firq => if reg >= 8 { reg + 8 } else { reg },
                 Mode::Irq => if reg >= 13 { reg + 10 } else { reg },
@@ -59,6 +80,12 @@ impl RegisterFile {
         }) as usize
     }
 
+    // Every register of every mode, by slot, for code that goes to them
+    // directly
+    pub fn slots(&mut self) -> &mut [u32; 27] {
+        &mut self.registers
+    }
+
     // Get register for current mode
     pub fn reg(&self, reg: u32) -> u32 {
         self.registers[self.index(reg)]
@@ -87,6 +114,15 @@ impl RegisterFile {
         }
     }
 
+    // Every bank, by slot
+    pub fn save(&self, state: &mut StateWriter) {
+        state.words(&self.registers);
+    }
+
+    pub fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.words_into(&mut self.registers)
+    }
+
     pub fn mode(&self) -> Mode {
         match self.registers[15] & 3 {
             0 => Mode::User,
@@ -97,3 +133,9 @@ impl RegisterFile {
         }
     }
 }
+
+impl Default for RegisterFile {
+    fn default() -> RegisterFile {
+        RegisterFile::new()
+    }
+}
@@ -0,0 +1,128 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use webarc::snapshot::{self, StateReader, StateWriter};
+
+// Cycle-stamped events in the order they fall due. Each source of events has
+// at most one outstanding: scheduling it again replaces the earlier event,
+// whose heap entry is left to be skipped over when it comes to the top.
+
+// What an event is for: one of the chips every machine has, or the attached
+// device at an index
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Source {
+    Memc,
+    Vidc,
+    Ioc,
+    Cmos,
+    Device(usize),
+}
+
+impl Source {
+    pub fn index(&self) -> usize {
+        match *self {
+            Source::Memc => 0,
+            Source::Vidc => 1,
+            Source::Ioc => 2,
+            Source::Cmos => 3,
+            Source::Device(index) => 4 + index,
+        }
+    }
+}
+
+pub struct Scheduler {
+    events: BinaryHeap<Reverse<(u64, Source)>>,
+    // When each source's event is due, by source index
+    due: Vec<Option<u64>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            events: BinaryHeap::new(),
+            due: Vec::new(),
+        }
+    }
+
+    pub fn schedule(&mut self, source: Source, time: u64) {
+        let index = source.index();
+        if index >= self.due.len() {
+            self.due.resize(index + 1, None);
+        }
+
+        if self.due[index] != Some(time) {
+            self.due[index] = Some(time);
+            self.events.push(Reverse((time, source)));
+        }
+    }
+
+    // When the next event is due, if anything is waiting for one
+    pub fn next_due(&mut self) -> Option<u64> {
+        self.discard_stale();
+        self.events.peek().map(|&Reverse((time, _))| time)
+    }
+
+    // The next event due by a time, taking it off the queue
+    pub fn pop_due(&mut self, now: u64) -> Option<Source> {
+        self.discard_stale();
+        match self.events.peek() {
+            Some(&Reverse((time, source))) if time <= now => {
+                self.events.pop();
+                self.due[source.index()] = None;
+                Some(source)
+            },
+            _ => None
+        }
+    }
+
+    // Only the events still to come are saved, and the queue is made again
+    // from them
+    pub fn save(&self, state: &mut StateWriter) {
+        state.usize(self.due.len());
+        for due in &self.due {
+            state.bool(due.is_some());
+            state.u64(due.unwrap_or(0));
+        }
+    }
+
+    pub fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        let sources = state.usize()?;
+        if sources < Source::Device(0).index() {
+            return Err(snapshot::invalid("Bad event queue in snapshot"));
+        }
+
+        self.events.clear();
+        self.due = Vec::new();
+        for index in 0..sources {
+            let pending = state.bool()?;
+            let time = state.u64()?;
+            let source = match index {
+                0 => Source::Memc,
+                1 => Source::Vidc,
+                2 => Source::Ioc,
+                3 => Source::Cmos,
+                _ => Source::Device(index - Source::Device(0).index())
+            };
+            if pending {
+                self.schedule(source, time);
+            }
+        }
+        self.due.resize(sources, None);
+        Ok(())
+    }
+
+    fn discard_stale(&mut self) {
+        while let Some(&Reverse((time, source))) = self.events.peek() {
+            if self.due[source.index()] == Some(time) {
+                return;
+            }
+            self.events.pop();
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}
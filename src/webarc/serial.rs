@@ -0,0 +1,329 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+// Where the bytes from an emulated serial port go on the host, and where the
+// bytes it receives come from. The modem control lines aren't carried, so
+// the far end always looks ready.
+
+pub trait SerialBackend {
+    fn write(&mut self, byte: u8);
+
+    // Next byte from the far end, if one has arrived
+    fn read(&mut self) -> Option<u8>;
+}
+
+// Open a backend from a description: "stdio", "pty", "loopback",
+// "file:<out>[,<in>]" (either path can be left empty, and the input is played
+// back as if typed) or "tcp:<port>" (which listens on localhost)
+pub fn open(spec: &str) -> io::Result<Box<dyn SerialBackend>> {
+    let mut parts = spec.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("stdio"), None) => Ok(Box::new(StdioSerial::new())),
+        (Some("loopback"), None) => Ok(Box::new(Loopback::new())),
+        #[cfg(unix)]
+        (Some("pty"), None) => Ok(Box::new(PtySerial::new()?)),
+        (Some("file"), Some(paths)) => {
+            let mut paths = paths.splitn(2, ',').map(|path| Some(path).filter(|path| !path.is_empty()));
+            let output = paths.next().and_then(|path| path);
+            let input = paths.next().and_then(|path| path);
+            Ok(Box::new(FileSerial::new(output, input)?))
+        },
+        (Some("tcp"), Some(port)) => {
+            let port = port.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Bad TCP port"))?;
+            Ok(Box::new(TcpSerial::listen(port)?))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown serial backend {}", spec)))
+    }
+}
+
+// Reads from a blocking stream on another thread, so the port can poll it.
+// Errors are retried, because a pty reports them until its other side is
+// opened.
+fn spawn_reader<R: Read + Send + 'static>(mut reader: R) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = [0; 256];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(length) => if buffer[..length].iter().any(|byte| sender.send(*byte).is_err()) {
+                    break;
+                },
+                Err(_) => thread::sleep(Duration::from_millis(100))
+            }
+        }
+    });
+    receiver
+}
+
+// Writes to a stream that might block on another thread, so the emulator
+// doesn't stall
+fn spawn_writer<W: Write + Send + 'static>(mut writer: W) -> Sender<u8> {
+    let (sender, receiver) = mpsc::channel::<u8>();
+    thread::spawn(move || {
+        for byte in receiver {
+            if writer.write_all(&[byte]).and_then(|_| writer.flush()).is_err() {
+                break;
+            }
+        }
+    });
+    sender
+}
+
+// The emulator's own terminal
+// Every byte sent comes straight back, as with a loopback plug in the port
+pub struct Loopback {
+    bytes: VecDeque<u8>,
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Loopback { bytes: VecDeque::new() }
+    }
+}
+
+impl Default for Loopback {
+    fn default() -> Loopback {
+        Loopback::new()
+    }
+}
+
+impl SerialBackend for Loopback {
+    fn write(&mut self, byte: u8) {
+        self.bytes.push_back(byte);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.bytes.pop_front()
+    }
+}
+
+pub struct StdioSerial {
+    input: Receiver<u8>,
+}
+
+impl StdioSerial {
+    pub fn new() -> StdioSerial {
+        StdioSerial { input: spawn_reader(io::stdin()) }
+    }
+}
+
+impl Default for StdioSerial {
+    fn default() -> StdioSerial {
+        StdioSerial::new()
+    }
+}
+
+impl SerialBackend for StdioSerial {
+    fn write(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        if let Err(error) = stdout.write_all(&[byte]).and_then(|_| stdout.flush()) {
+            println!("Couldn't write serial output: {}", error);
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+// Records output to a file, and plays the contents of another file back as
+// input
+pub struct FileSerial {
+    output: Option<File>,
+    input: VecDeque<u8>,
+}
+
+impl FileSerial {
+    pub fn new<P: AsRef<Path>>(output: Option<P>, input: Option<P>) -> io::Result<FileSerial> {
+        let output = match output {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None
+        };
+
+        let mut data = Vec::new();
+        if let Some(path) = input {
+            File::open(path)?.read_to_end(&mut data)?;
+        }
+
+        Ok(FileSerial { output, input: data.into() })
+    }
+}
+
+impl SerialBackend for FileSerial {
+    fn write(&mut self, byte: u8) {
+        if let Some(ref mut file) = self.output {
+            if let Err(error) = file.write_all(&[byte]) {
+                println!("Couldn't write serial output: {}", error);
+            }
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+}
+
+// Accepts one connection at a time on a localhost port, so a terminal
+// program can be pointed at it with telnet or netcat. Output with nobody
+// connected is dropped, as if the cable were unplugged.
+pub struct TcpSerial {
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+}
+
+impl TcpSerial {
+    pub fn listen(port: u16) -> io::Result<TcpSerial> {
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpSerial { listener, stream: None })
+    }
+
+    fn connection(&mut self) -> Option<&mut TcpStream> {
+        if self.stream.is_none() {
+            if let Ok((stream, _)) = self.listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    self.stream = Some(stream);
+                }
+            }
+        }
+        self.stream.as_mut()
+    }
+}
+
+impl SerialBackend for TcpSerial {
+    fn write(&mut self, byte: u8) {
+        let failed = match self.connection() {
+            Some(stream) => stream.write_all(&[byte]).is_err(),
+            None => false
+        };
+        if failed {
+            self.stream = None;
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let mut buffer = [0];
+        let result = self.connection()?.read(&mut buffer);
+        match result {
+            Ok(1) => Some(buffer[0]),
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => None,
+            // The other end hung up
+            _ => {
+                self.stream = None;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod pty {
+    use std::os::raw::{c_char, c_int};
+
+    extern "C" {
+        pub fn grantpt(fd: c_int) -> c_int;
+        pub fn unlockpt(fd: c_int) -> c_int;
+        pub fn ptsname(fd: c_int) -> *mut c_char;
+    }
+}
+
+// A pseudo-terminal whose other side can be opened by terminal software on
+// the host. Its path is printed when it's created.
+#[cfg(unix)]
+pub struct PtySerial {
+    output: Sender<u8>,
+    input: Receiver<u8>,
+    pub path: String,
+}
+
+#[cfg(unix)]
+impl PtySerial {
+    pub fn new() -> io::Result<PtySerial> {
+        use std::ffi::CStr;
+        use std::os::unix::io::AsRawFd;
+
+        let master = OpenOptions::new().read(true).write(true).open("/dev/ptmx")?;
+        let fd = master.as_raw_fd();
+
+        let path = unsafe {
+            if pty::grantpt(fd) != 0 || pty::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = pty::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            CStr::from_ptr(name).to_string_lossy().into_owned()
+        };
+        println!("Serial port is on {}", path);
+
+        let input = spawn_reader(master.try_clone()?);
+        Ok(PtySerial { output: spawn_writer(master), input, path })
+    }
+}
+
+#[cfg(unix)]
+impl SerialBackend for PtySerial {
+    fn write(&mut self, byte: u8) {
+        let _ = self.output.send(byte);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn file_specs() {
+        let output = env::temp_dir().join(format!("webarc-serial-out-{}", std::process::id()));
+        let input = env::temp_dir().join(format!("webarc-serial-in-{}", std::process::id()));
+        fs::write(&input, b"OK").unwrap();
+
+        let mut backend = open(&format!("file:{},{}", output.display(), input.display())).unwrap();
+        backend.write(b'A');
+        backend.write(b'T');
+        assert_eq!(backend.read(), Some(b'O'));
+        assert_eq!(backend.read(), Some(b'K'));
+        assert_eq!(backend.read(), None);
+        drop(backend);
+        assert_eq!(fs::read(&output).unwrap(), b"AT");
+
+        // Input only, with nowhere for output to go
+        let mut backend = open(&format!("file:,{}", input.display())).unwrap();
+        backend.write(b'A');
+        assert_eq!(backend.read(), Some(b'O'));
+
+        // Output only, appending
+        let mut backend = open(&format!("file:{}", output.display())).unwrap();
+        backend.write(b'Z');
+        assert_eq!(backend.read(), None);
+        drop(backend);
+        assert_eq!(fs::read(&output).unwrap(), b"ATZ");
+
+        fs::remove_file(&output).unwrap();
+        fs::remove_file(&input).unwrap();
+        assert!(open(&format!("file:,{}", input.display())).is_err());
+        assert!(open("serial").is_err());
+
+        let mut backend = open("loopback").unwrap();
+        backend.write(b'A');
+        backend.write(b'T');
+        assert_eq!(backend.read(), Some(b'A'));
+        assert_eq!(backend.read(), Some(b'T'));
+        assert_eq!(backend.read(), None);
+    }
+}
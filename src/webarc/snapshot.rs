@@ -0,0 +1,213 @@
+use std::io;
+
+// Save states: the whole machine written out as a binary snapshot and read
+// back, so emulation can carry on exactly where it left off. Each part of the
+// machine writes its own state and reads it back in the same order, with
+// numbers in little endian. A snapshot starts with a magic number and the
+// format version, and one from any other version is refused rather than half
+// understood.
+//
+// Only the machine itself is saved. What it's connected to on the host (disc
+// images, serial and network backends, printer capture files and HostFS)
+// stays as it is when a snapshot is restored, as do the tracer and the
+// debuggers. The same podules have to be fitted, and their state goes with
+// the machine's. Instructions decoded or translated from RAM are thrown away.
+
+pub const MAGIC: &[u8; 8] = b"WEBARCSS";
+pub const VERSION: u32 = 1;
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter {
+            data: Vec::new(),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    // What every snapshot starts with
+    pub fn header(&mut self) {
+        self.data.extend_from_slice(MAGIC);
+        self.u32(VERSION);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.u32(value as u32);
+    }
+
+    pub fn i64(&mut self, value: i64) {
+        self.u64(value as u64);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    // Sizes and indices are always 32 bits, whatever the host
+    pub fn usize(&mut self, value: usize) {
+        self.u32(value as u32);
+    }
+
+    // Bytes or words with their length first
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.usize(bytes.len());
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn words(&mut self, words: &[u32]) {
+        self.usize(words.len());
+        for &word in words {
+            self.u32(word);
+        }
+    }
+
+    pub fn option_u8(&mut self, value: Option<u8>) {
+        self.bool(value.is_some());
+        self.u8(value.unwrap_or(0));
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> StateWriter {
+        StateWriter::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            data,
+            position: 0,
+        }
+    }
+
+    // Whether everything has been read, which is how a snapshot that doesn't
+    // match what restored it shows up
+    pub fn finished(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    pub fn header(&mut self) -> io::Result<()> {
+        if self.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(invalid("Not a snapshot"));
+        }
+        match self.u32()? {
+            VERSION => Ok(()),
+            version => Err(invalid(&format!("Snapshot is version {}, not {}", version, VERSION)))
+        }
+    }
+
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.position < length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Snapshot is truncated"));
+        }
+        let taken = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+
+    pub fn i32(&mut self) -> io::Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    pub fn i64(&mut self) -> io::Result<i64> {
+        Ok(self.u64()? as i64)
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("Bad flag in snapshot"))
+        }
+    }
+
+    pub fn usize(&mut self) -> io::Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    pub fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.usize()?;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    pub fn words(&mut self) -> io::Result<Vec<u32>> {
+        let length = self.usize()?;
+        (0..length).map(|_| self.u32()).collect()
+    }
+
+    // Fill something of a fixed size, which the snapshot has to match
+    pub fn bytes_into(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+        let saved = self.bytes()?;
+        if saved.len() != bytes.len() {
+            return Err(invalid("Snapshot doesn't match the machine"));
+        }
+        bytes.copy_from_slice(&saved);
+        Ok(())
+    }
+
+    pub fn words_into(&mut self, words: &mut [u32]) -> io::Result<()> {
+        if self.usize()? != words.len() {
+            return Err(invalid("Snapshot doesn't match the machine"));
+        }
+        for word in words.iter_mut() {
+            *word = self.u32()?;
+        }
+        Ok(())
+    }
+
+    pub fn option_u8(&mut self) -> io::Result<Option<u8>> {
+        let present = self.bool()?;
+        let value = self.u8()?;
+        Ok(if present { Some(value) } else { None })
+    }
+}
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,368 @@
+use std::cmp::min;
+use std::io;
+use webarc::memc::Memc;
+use webarc::snapshot::{self, StateReader, StateWriter};
+
+// VIDC sound system. Bytes are fetched from RAM by MEMC sound DMA sixteen at
+// a time and played one per sample period, cycling through the eight stereo
+// image registers. The DAC output is a sample-and-hold waveform, which is
+// integrated over each host sample period to produce PCM at the host rate.
+
+pub const CYCLES_PER_MICROSECOND: u32 = 8;
+const CLOCK_HZ: u64 = 8_000_000;
+
+const DEFAULT_OUTPUT_RATE: u32 = 48000;
+
+// Stereo frames held for the host before the oldest are discarded
+const RING_FRAMES: usize = 8192;
+
+pub struct Sound {
+    // Sound frequency register: one byte is played every (SFR + 2)us
+    frequency: u32,
+    stereo_images: [u8; 8],
+    log_table: [i16; 256],
+
+    fifo: [u32; 4],
+    fifo_bytes: usize,
+    channel: usize,
+    countdown: u32,
+    level: (i32, i32),
+    // MEMC's sound DMA enable, as last passed on
+    dma_enabled: bool,
+
+    output_rate: u32,
+    phase: u64,
+    accumulator: (i64, i64),
+    ring: SoundRing,
+}
+
+impl Sound {
+    pub fn new() -> Sound {
+        let mut log_table = [0; 256];
+        for (byte, sample) in log_table.iter_mut().enumerate() {
+            *sample = decode_log_sample(byte as u8);
+        }
+
+        Sound {
+            frequency: 0,
+            stereo_images: [4; 8],
+            log_table,
+            fifo: [0; 4],
+            fifo_bytes: 0,
+            channel: 0,
+            countdown: 2 * CYCLES_PER_MICROSECOND,
+            level: (0, 0),
+            dma_enabled: false,
+            output_rate: DEFAULT_OUTPUT_RATE,
+            phase: 0,
+            accumulator: (0, 0),
+            ring: SoundRing::new(RING_FRAMES),
+        }
+    }
+
+    pub fn set_frequency(&mut self, value: u32) {
+        self.frequency = value & 0xff;
+    }
+
+    pub fn set_stereo_image(&mut self, channel: usize, image: u32) {
+        self.stereo_images[channel & 7] = (image & 7) as u8;
+    }
+
+    // Sample rate the host wants to pull PCM at (typically 44100 or 48000)
+    pub fn set_output_rate(&mut self, rate: u32) {
+        self.output_rate = rate;
+        self.phase = 0;
+        self.accumulator = (0, 0);
+        self.ring.clear();
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    // Number of stereo frames waiting for the host
+    pub fn available(&self) -> usize {
+        self.ring.len()
+    }
+
+    // Copy interleaved left/right samples into `out`, returning the number of
+    // samples written
+    pub fn read_samples(&mut self, out: &mut [i16]) -> usize {
+        self.ring.read(out)
+    }
+
+    pub fn tick(&mut self, cycles: u32, memc: &mut Memc, ram: &[u32]) {
+        let mut remaining = cycles;
+
+        while remaining > 0 {
+            let step = min(remaining, self.countdown);
+            self.integrate(step);
+            self.countdown -= step;
+            remaining -= step;
+
+            if self.countdown == 0 {
+                self.next_byte(memc, ram);
+                self.countdown = (self.frequency + 2) * CYCLES_PER_MICROSECOND;
+            }
+        }
+    }
+
+    pub fn set_dma_enabled(&mut self, enabled: bool) {
+        self.dma_enabled = enabled;
+    }
+
+    // Cycles until the FIFO next needs filling by DMA, which is all that
+    // matters outside VIDC. The bytes in between can be played whenever.
+    pub fn next_event(&self) -> Option<u32> {
+        if !self.dma_enabled {
+            return None;
+        }
+        let period = (self.frequency + 2) * CYCLES_PER_MICROSECOND;
+        Some(self.countdown + self.fifo_bytes as u32 * period)
+    }
+
+    fn next_byte(&mut self, memc: &mut Memc, ram: &[u32]) {
+        if !memc.sound_dma_enabled() {
+            self.fifo_bytes = 0;
+            self.level = (0, 0);
+            return;
+        }
+
+        if self.fifo_bytes == 0 {
+            self.fifo = memc.sound_dma(ram);
+            self.fifo_bytes = 16;
+        }
+
+        let index = 16 - self.fifo_bytes;
+        let byte = (self.fifo[index / 4] >> ((index % 4) * 8)) as u8;
+        self.fifo_bytes -= 1;
+
+        let sample = self.log_table[byte as usize] as i32;
+        let image = match self.stereo_images[self.channel] {
+            0 => 4,
+            image => image as i32
+        };
+        self.channel = (self.channel + 1) & 7;
+
+        // Image 1 is fully left, 4 is centre and 7 is fully right
+        self.level = (sample * (7 - image) / 6, sample * (image - 1) / 6);
+    }
+
+    // The sound system's part of VIDC's state. Output waiting for the host
+    // isn't part of the machine, so it's dropped on restore, and the host's
+    // sample rate stays as it is.
+    pub fn save(&self, state: &mut StateWriter) {
+        state.u32(self.frequency);
+        state.bytes(&self.stereo_images);
+        state.words(&self.fifo);
+        state.usize(self.fifo_bytes);
+        state.usize(self.channel);
+        state.u32(self.countdown);
+        state.i32(self.level.0);
+        state.i32(self.level.1);
+        state.bool(self.dma_enabled);
+        state.u32(self.output_rate);
+        state.u64(self.phase);
+        state.i64(self.accumulator.0);
+        state.i64(self.accumulator.1);
+    }
+
+    pub fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.frequency = state.u32()?;
+        state.bytes_into(&mut self.stereo_images)?;
+        state.words_into(&mut self.fifo)?;
+        self.fifo_bytes = state.usize()?;
+        self.channel = state.usize()?;
+        if self.fifo_bytes > 16 || self.channel > 7 {
+            return Err(snapshot::invalid("Bad sound state in snapshot"));
+        }
+        self.countdown = state.u32()?;
+        self.level = (state.i32()?, state.i32()?);
+        self.dma_enabled = state.bool()?;
+
+        // The phase only carries over at the rate it was measured at
+        let output_rate = state.u32()?;
+        let phase = state.u64()?;
+        let accumulator = (state.i64()?, state.i64()?);
+        if output_rate == self.output_rate {
+            self.phase = phase;
+            self.accumulator = accumulator;
+        } else {
+            self.phase = 0;
+            self.accumulator = (0, 0);
+        }
+        self.ring.clear();
+        Ok(())
+    }
+
+    fn integrate(&mut self, cycles: u32) {
+        // Time is measured in units of 1 / (CLOCK_HZ * output_rate) seconds,
+        // so a cycle is output_rate units and a host sample is CLOCK_HZ units
+        let mut units = cycles as u64 * self.output_rate as u64;
+
+        while units > 0 {
+            let used = min(units, CLOCK_HZ - self.phase);
+            self.accumulator.0 += self.level.0 as i64 * used as i64;
+            self.accumulator.1 += self.level.1 as i64 * used as i64;
+            self.phase += used;
+            units -= used;
+
+            if self.phase == CLOCK_HZ {
+                let left = (self.accumulator.0 / CLOCK_HZ as i64) as i16;
+                let right = (self.accumulator.1 / CLOCK_HZ as i64) as i16;
+                self.ring.push(left, right);
+                self.accumulator = (0, 0);
+                self.phase = 0;
+            }
+        }
+    }
+}
+
+impl Default for Sound {
+    fn default() -> Sound {
+        Sound::new()
+    }
+}
+
+// VIDC samples are 8-bit logarithmic: bit 0 is the sign and bits 1-7 hold a
+// 3-bit chord and 4-bit step, decoded the same way as u-law
+fn decode_log_sample(byte: u8) -> i16 {
+    let magnitude = byte >> 1;
+    let chord = (magnitude >> 4) as u32;
+    let step = (magnitude & 0xf) as i32;
+    let linear = (((step * 2) + 33) << chord) - 33;
+
+    // Scale 0..8031 up to most of the i16 range
+    let scaled = (linear * 4) as i16;
+    if byte & 1 != 0 { -scaled } else { scaled }
+}
+
+struct SoundRing {
+    samples: Box<[i16]>,
+    read: usize,
+    len: usize,
+}
+
+impl SoundRing {
+    fn new(frames: usize) -> SoundRing {
+        SoundRing {
+            samples: vec![0; frames * 2].into_boxed_slice(),
+            read: 0,
+            len: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len / 2
+    }
+
+    fn clear(&mut self) {
+        self.read = 0;
+        self.len = 0;
+    }
+
+    fn push(&mut self, left: i16, right: i16) {
+        let capacity = self.samples.len();
+
+        // Drop the oldest frame rather than stalling emulation
+        if self.len == capacity {
+            self.read = (self.read + 2) % capacity;
+            self.len -= 2;
+        }
+
+        let write = (self.read + self.len) % capacity;
+        self.samples[write] = left;
+        self.samples[write + 1] = right;
+        self.len += 2;
+    }
+
+    fn read(&mut self, out: &mut [i16]) -> usize {
+        let capacity = self.samples.len();
+        let count = min(out.len() & !1, self.len);
+
+        for sample in out.iter_mut().take(count) {
+            *sample = self.samples[self.read];
+            self.read = (self.read + 1) % capacity;
+        }
+
+        self.len -= count;
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOUND_DMA: u32 = 1 << 11;
+
+    #[test]
+    fn log_samples() {
+        assert_eq!(decode_log_sample(0x00), 0);
+        assert_eq!(decode_log_sample(0x01), 0);
+        // Largest chord and step: (30 + 33) << 7 - 33 = 8031, scaled by 4
+        assert_eq!(decode_log_sample(0xfe), 32124);
+        assert_eq!(decode_log_sample(0xff), -32124);
+        // The start of each chord
+        for chord in 0..8 {
+            let byte = (chord << 5) as u8;
+            assert_eq!(decode_log_sample(byte), (((33 << chord) - 33) * 4) as i16);
+        }
+
+        // Louder with every step, and the sign bit only changes the sign
+        for magnitude in 1..128u8 {
+            assert!(decode_log_sample(magnitude << 1) > decode_log_sample((magnitude - 1) << 1));
+            assert_eq!(decode_log_sample(magnitude << 1 | 1), -decode_log_sample(magnitude << 1));
+        }
+    }
+
+    // The level after playing one byte through a channel with a stereo image
+    fn play(image: u32) -> (i32, i32) {
+        let mut sound = Sound::new();
+        sound.set_stereo_image(0, image);
+        let mut memc = Memc::new();
+        memc.control = SOUND_DMA;
+        sound.next_byte(&mut memc, &[0xfefefefe; 4]);
+        sound.level
+    }
+
+    #[test]
+    fn stereo_images() {
+        let sample = decode_log_sample(0xfe) as i32;
+        assert_eq!(play(1), (sample, 0));
+        assert_eq!(play(4), (sample / 2, sample / 2));
+        assert_eq!(play(7), (0, sample));
+        // Image 0 isn't valid, and plays in the centre
+        assert_eq!(play(0), play(4));
+    }
+
+    fn resample(rate: u32, seconds: u32) -> Vec<i16> {
+        let mut sound = Sound::new();
+        sound.set_output_rate(rate);
+        sound.level = (1000, -1000);
+
+        let mut samples = Vec::new();
+        let mut buffer = [0; 1024];
+        // A hundredth of a second at a time, so the ring never fills
+        for _ in 0..seconds * 100 {
+            sound.integrate(CLOCK_HZ as u32 / 100);
+            loop {
+                let count = sound.read_samples(&mut buffer);
+                if count == 0 {
+                    break;
+                }
+                samples.extend_from_slice(&buffer[..count]);
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn output_rates() {
+        for &rate in &[8000, 22050, 44100, 48000] {
+            let samples = resample(rate, 2);
+            assert_eq!(samples.len(), rate as usize * 2 * 2, "at {}Hz", rate);
+            assert!(samples.chunks(2).all(|frame| frame == [1000, -1000]));
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use webarc::hostfs;
+use webarc::memory::Memory;
+use webarc::registers::{Mode, RegisterFile};
+
+// RISC OS SWI names, for showing SWI instructions the way RISC OS would, and
+// a log of the SWIs called while running. Bit 17 of a SWI number is the X
+// bit, which asks for errors to be returned with V set rather than raised,
+// and is shown as an X before the name.
+
+pub const X_BIT: u32 = 0x20000;
+
+const V_BIT: u32 = 0x10000000;
+
+const OS_WRITE_I: u32 = 0x100;
+
+// Each chunk of SWIs: its first SWI, the prefix of its names, and the rest of
+// the names in order. These are the kernel's and those of the modules in the
+// RISC OS 3.11 ROM that programs call most, with ARM3's cache module and the
+// emulator's HostFS.
+const CHUNKS: [(u32, &str, &[&str]); 25] = [
+    (0x00, "OS", &[
+        "WriteC", "WriteS", "Write0", "NewLine", "ReadC", "CLI", "Byte", "Word",
+        "File", "Args", "BGet", "BPut", "GBPB", "Find", "ReadLine", "Control",
+        "GetEnv", "Exit", "SetEnv", "IntOn", "IntOff", "CallBack", "EnterOS", "BreakPt",
+        "BreakCtrl", "UnusedSWI", "UpdateMEMC", "SetCallBack", "Mouse", "Heap", "Module", "Claim",
+        "Release", "ReadUnsigned", "GenerateEvent", "ReadVarVal", "SetVarVal", "GSInit", "GSRead", "GSTrans",
+        "BinaryToDecimal", "FSControl", "ChangeDynamicArea", "GenerateError", "ReadEscapeState", "EvaluateExpression", "SpriteOp", "ReadPalette",
+        "ServiceCall", "ReadVduVariables", "ReadPoint", "UpCall", "CallAVector", "ReadModeVariable", "RemoveCursors", "RestoreCursors",
+        "SWINumberToString", "SWINumberFromString", "ValidateAddress", "CallAfter", "CallEvery", "RemoveTickerEvent", "InstallKeyHandler", "CheckModeValid",
+        "ChangeEnvironment", "ClaimScreenMemory", "ReadMonotonicTime", "SubstituteArgs", "PrettyPrint", "Plot", "WriteN", "AddToVector",
+        "WriteEnv", "ReadArgs", "ReadRAMFsLimits", "ClaimDeviceVector", "ReleaseDeviceVector", "DelinkApplication", "RelinkApplication", "HeapSort",
+        "ExitAndDie", "ReadMemMapInfo", "ReadMemMapEntries", "SetMemMapEntries", "AddCallBack", "ReadDefaultHandler", "SetECFOrigin", "SerialOp",
+        "ReadSysInfo", "Confirm", "ChangedBox", "CRC", "ReadDynamicArea", "PrintChar", "ChangeRedirection", "RemoveCallBack",
+        "FindMemMapEntries", "SetColour",
+    ]),
+    (0xc0, "OS", &["ConvertStandardDateAndTime", "ConvertDateAndTime"]),
+    (0xd0, "OS", &[
+        "ConvertHex1", "ConvertHex2", "ConvertHex4", "ConvertHex6", "ConvertHex8",
+        "ConvertCardinal1", "ConvertCardinal2", "ConvertCardinal3", "ConvertCardinal4",
+        "ConvertInteger1", "ConvertInteger2", "ConvertInteger3", "ConvertInteger4",
+        "ConvertBinary1", "ConvertBinary2", "ConvertBinary3", "ConvertBinary4",
+        "ConvertSpacedCardinal1", "ConvertSpacedCardinal2", "ConvertSpacedCardinal3", "ConvertSpacedCardinal4",
+        "ConvertSpacedInteger1", "ConvertSpacedInteger2", "ConvertSpacedInteger3", "ConvertSpacedInteger4",
+        "ConvertFixedNetStation", "ConvertNetStation", "ConvertFixedFileSize", "ConvertFileSize",
+    ]),
+    (0x280, "Cache", &["Control", "Cacheable", "Updateable", "Disruptive", "Flush"]),
+    (0x40040, "NetFS", &[
+        "ReadFSNumber", "SetFSNumber", "ReadFSName", "SetFSName", "ReadCurrentContext", "SetCurrentContext",
+        "ReadFSTimeouts", "SetFSTimeouts", "DoFSOp", "EnumerateFSList", "EnumerateFS", "ConvertDate",
+        "DoFSOpToGivenFS", "UpdateFSList", "EnumerateFSContexts", "ReadUserId", "GetObjectUID", "EnableCache",
+    ]),
+    (0x40080, "Font", &[
+        "CacheAddr", "FindFont", "LoseFont", "ReadDefn", "ReadInfo", "StringWidth", "Paint", "Caret",
+        "ConverttoOS", "Converttopoints", "SetFont", "CurrentFont", "FutureFont", "FindCaret", "CharBBox", "ReadScaleFactor",
+        "SetScaleFactor", "ListFonts", "SetFontColours", "SetPalette", "ReadThresholds", "SetThresholds", "FindCaretJ", "StringBBox",
+        "ReadColourTable", "MakeBitmap", "UnCacheFile", "SetFontMax", "ReadFontMax", "ReadFontPrefix", "SwitchOutputToBuffer", "ReadFontMetrics",
+        "DecodeMenu", "ScanString", "SetColourTable", "CurrentRGB", "FutureRGB", "ReadEncodingFilename", "FindField", "ApplyFields",
+        "LookupFont",
+    ]),
+    (0x400c0, "Wimp", &[
+        "Initialise", "CreateWindow", "CreateIcon", "DeleteWindow", "DeleteIcon", "OpenWindow", "CloseWindow", "Poll",
+        "RedrawWindow", "UpdateWindow", "GetRectangle", "GetWindowState", "GetWindowInfo", "SetIconState", "GetIconState", "GetPointerInfo",
+        "DragBox", "ForceRedraw", "SetCaretPosition", "GetCaretPosition", "CreateMenu", "DecodeMenu", "WhichIcon", "SetExtent",
+        "SetPointerShape", "OpenTemplate", "CloseTemplate", "LoadTemplate", "ProcessKey", "CloseDown", "StartTask", "ReportError",
+        "GetWindowOutline", "PollIdle", "PlotIcon", "SetMode", "SetPalette", "ReadPalette", "SetColour", "SendMessage",
+        "CreateSubMenu", "SpriteOp", "BaseOfSprites", "BlockCopy", "SlotSize", "ReadPixTrans", "ClaimFreeMemory", "CommandWindow",
+        "TextColour", "TransferBlock", "ReadSysInfo", "SetFontColours", "GetMenuState", "RegisterFilter", "AddMessages", "RemoveMessages",
+        "SetColourMapping", "TextOp", "SetWatchdogState", "Extend", "ResizeIcon",
+    ]),
+    (0x40140, "Sound", &["Configure", "Enable", "Stereo", "Speaker"]),
+    (0x40180, "Sound", &[
+        "Volume", "SoundLog", "LogScale", "InstallVoice", "RemoveVoice", "AttachVoice", "ControlPacked", "Tuning",
+        "Pitch", "Control", "AttachNamedVoice", "ReadControlBlock", "WriteControlBlock",
+    ]),
+    (0x401c0, "Sound", &["QInit", "QSchedule", "QRemove", "QFree", "QSDispatch", "QTempo", "QBeat", "QInterface"]),
+    (0x40240, "ADFS", &[
+        "DiscOp", "HDC", "Drives", "FreeSpace", "Retries", "DescribeDisc", "VetFormat", "FlpProcessDCB",
+        "ControllerType", "PowerControl", "SetIDEController", "IDEUserOp", "MiscOp",
+    ]),
+    (0x40280, "Podule", &[
+        "ReadID", "ReadHeader", "EnumerateChunks", "ReadChunk", "ReadBytes", "WriteBytes", "CallLoader", "RawRead",
+        "RawWrite", "HardwareAddress",
+    ]),
+    (0x40380, "Debugger", &["Disassemble"]),
+    (0x40480, "FPEmulator", &["Version"]),
+    (0x40540, "FileCore", &[
+        "DiscOp", "Create", "Drives", "FreeSpace", "FloppyStructure", "DescribeDisc", "DiscardReadSectorsCache", "DiscFormat",
+        "LayoutStructure", "MiscOp",
+    ]),
+    (0x405c0, "Shell", &["Create", "Destroy"]),
+    (0x406c0, "Hourglass", &["On", "Off", "Smash", "Start", "Percentage", "LEDs", "Colours"]),
+    (0x40700, "Draw", &[
+        "ProcessPath", "ProcessPathFP", "Fill", "FillFP", "Stroke", "StrokeFP", "StrokePath", "StrokePathFP",
+        "FlattenPath", "FlattenPathFP", "TransformPath", "TransformPathFP",
+    ]),
+    (0x40740, "ColourTrans", &[
+        "SelectTable", "SelectGCOLTable", "ReturnGCOL", "SetGCOL", "ReturnColourNumber", "ReturnGCOLForMode", "ReturnColourNumberForMode", "ReturnOppGCOL",
+        "SetOppGCOL", "ReturnOppColourNumber", "ReturnOppGCOLForMode", "ReturnOppColourNumberForMode", "GCOLToColourNumber", "ColourNumberToGCOL", "ReturnFontColours", "SetFontColours",
+        "InvalidateCache", "SetCalibration", "ReadCalibration", "ConvertDeviceColour", "ConvertDevicePalette", "ConvertRGBToCIE", "ConvertCIEToRGB", "WriteCalibrationToFile",
+        "ConvertRGBToHSV", "ConvertHSVToRGB", "ConvertRGBToCMYK", "ConvertCMYKToRGB", "ReadPalette", "WritePalette", "SetColour", "MiscOp",
+        "WriteLoadingsToFile", "SetTextColour", "SetOppTextColour", "GenerateTable",
+    ]),
+    (0x41500, "MessageTrans", &["FileInfo", "OpenFile", "Lookup", "MakeMenus", "CloseFile", "EnumerateTokens", "ErrorLookup", "GSLookup", "CopyError"]),
+    (0x42400, "DragASprite", &["Start", "Stop"]),
+    (0x42640, "Filter", &["RegisterPreFilter", "RegisterPostFilter", "DeRegisterPreFilter", "DeRegisterPostFilter"]),
+    (0x42680, "TaskManager", &["TaskNameFromHandle", "EnumerateTasks", "Shutdown"]),
+    (0x43040, "Territory", &[
+        "Number", "Register", "Deregister", "NumberToName", "Exists", "AlphabetNumberToName", "SelectAlphabet", "SetTime",
+        "ReadCurrentTimeZone", "ConvertTimeToUTCOrdinals", "ReadTimeZones", "ConvertDateAndTime", "ConvertStandardDateAndTime", "ConvertStandardDate", "ConvertStandardTime", "ConvertTimeToOrdinals",
+        "ConvertTimeStringToOrdinals", "ConvertOrdinalsToTime", "Alphabet", "AlphabetIdentifier", "SelectKeyboardHandler", "WriteDirection", "CharacterPropertyTable", "LowerCaseTable",
+        "UpperCaseTable", "ControlTable", "PlainTable", "ValueTable", "RepresentationTable", "Collate", "ReadSymbols", "ReadCalendarInformation",
+        "NameToNumber", "TransformString",
+    ]),
+    (hostfs::SWI_BASE, "HostFS", &["Open", "GetBytes", "PutBytes", "Args", "Close", "File", "Func"]),
+];
+
+// The name of a SWI, if it's one in the table
+pub fn name(number: u32) -> Option<String> {
+    let prefix = if number & X_BIT != 0 { "X" } else { "" };
+    let number = number & !X_BIT & 0x00ffffff;
+
+    if (OS_WRITE_I..OS_WRITE_I + 0x100).contains(&number) {
+        return Some(format!("{}OS_WriteI+{}", prefix, number - OS_WRITE_I));
+    }
+    CHUNKS.iter()
+        .find(|&&(base, _, names)| (base..base + names.len() as u32).contains(&number))
+        .map(|&(base, chunk, names)| format!("{}{}_{}", prefix, chunk, names[(number - base) as usize]))
+}
+
+// The number of a named SWI, the other way round from name
+pub fn number(name: &str) -> Option<u32> {
+    let (x_bit, name) = match name.strip_prefix('X') {
+        Some(name) => (X_BIT, name),
+        None => (0, name)
+    };
+
+    if let Some(offset) = name.strip_prefix("OS_WriteI+") {
+        let offset = offset.parse::<u32>().ok().filter(|&offset| offset < 0x100)?;
+        return Some((OS_WRITE_I + offset) | x_bit);
+    }
+
+    let mut parts = name.splitn(2, '_');
+    let (chunk, swi) = (parts.next()?, parts.next()?);
+    CHUNKS.iter()
+        .filter(|&&(_, prefix, _)| prefix == chunk)
+        .find_map(|&(base, _, names)| names.iter().position(|&name| name == swi).map(|index| base + index as u32))
+        .map(|number| number | x_bit)
+}
+
+fn name_or_number(number: u32) -> String {
+    name(number).unwrap_or_else(|| format!("&{:X}", number))
+}
+
+// A SWI that's been called and hasn't returned yet
+struct Call {
+    number: u32,
+    return_address: u32,
+    mode: Mode,
+}
+
+// Some SWIs never return to their callers, and errors can unwind through
+// several, so calls still outstanding beyond this are forgotten
+const MAX_OUTSTANDING_CALLS: usize = 64;
+
+// Logs each SWI with R0-R7 as it's called, and again with R0-R7 and any
+// error when it returns, which is when execution gets back to the
+// instruction after it in the same mode
+pub struct SwiLogger {
+    output: Box<dyn Write>,
+    calls: Vec<Call>,
+}
+
+impl SwiLogger {
+    pub fn new(output: Box<dyn Write>) -> SwiLogger {
+        SwiLogger {
+            output,
+            calls: Vec::new(),
+        }
+    }
+
+    // To a file, or stdout without one
+    pub fn open(path: Option<&str>) -> io::Result<SwiLogger> {
+        let output: Box<dyn Write> = match path {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout())
+        };
+        Ok(SwiLogger::new(output))
+    }
+
+    pub fn call(&mut self, address: u32, number: u32, registers: &RegisterFile) {
+        let line = format!("{:08X}  SWI {}{}", address, name_or_number(number), arguments(registers));
+        self.write(&line);
+
+        if self.calls.len() >= MAX_OUTSTANDING_CALLS {
+            self.calls.remove(0);
+        }
+        self.calls.push(Call { number, return_address: address.wrapping_add(4), mode: registers.mode() });
+    }
+
+    // Called with each instruction about to be executed
+    pub fn check_return(&mut self, address: u32, registers: &RegisterFile, memory: &Memory) {
+        let mode = registers.mode();
+        let index = match self.calls.iter().rposition(|call| call.return_address == address && call.mode == mode) {
+            Some(index) => index,
+            None => return
+        };
+        let number = self.calls[index].number;
+        self.calls.truncate(index);
+
+        let line = if registers.reg(15) & V_BIT != 0 {
+            let block = registers.reg(0);
+            let error = memory.peek(block).unwrap_or(0);
+            format!("          {} returned error &{:X}: {}", name_or_number(number), error, error_message(memory, block + 4))
+        } else {
+            format!("          {} returned{}", name_or_number(number), arguments(registers))
+        };
+        self.write(&line);
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(error) = self.output.flush() {
+            println!("Couldn't write SWI log: {}", error);
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        if let Err(error) = writeln!(self.output, "{}", line) {
+            println!("Couldn't write SWI log: {}", error);
+        }
+    }
+}
+
+fn arguments(registers: &RegisterFile) -> String {
+    (0..8).map(|reg| format!("  R{}={:08X}", reg, registers.reg(reg))).collect()
+}
+
+// The zero-terminated message of an error block, as far as it can be read
+fn error_message(memory: &Memory, address: u32) -> String {
+    let mut message = String::new();
+    for offset in 0..252 {
+        let address = address + offset;
+        let byte = match memory.peek(address) {
+            Some(word) => (word >> ((address & 3) * 8)) as u8,
+            None => break
+        };
+        if byte == 0 {
+            break;
+        }
+        message.push(byte as char);
+    }
+    message
+}
@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use webarc::registers::Mode;
+
+// Instruction tracing. A record is made for each instruction the filter lets
+// through, with what it did to the registers and memory, and goes to a sink:
+// nowhere, stdout, a file, or a ring buffer of the most recent records for
+// looking back over after something's gone wrong. With no sink, the CPU
+// doesn't make records at all.
+
+const REGISTER_NAMES: [&str; 16] = ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7", "R8", "R9", "R10", "R11", "R12", "R13", "R14", "PC"];
+
+pub enum Sink {
+    None,
+    Stdout,
+    File(BufWriter<File>),
+    Ring { records: VecDeque<Record>, capacity: usize },
+}
+
+// none, stdout, file:<path> or ring:<records>
+pub fn open(spec: &str) -> io::Result<Sink> {
+    let mut parts = spec.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("none"), None) => Ok(Sink::None),
+        (Some("stdout"), None) => Ok(Sink::Stdout),
+        (Some("file"), Some(path)) => Ok(Sink::File(BufWriter::new(File::create(path)?))),
+        (Some("ring"), Some(capacity)) => {
+            let capacity = capacity.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Bad ring buffer size"))?;
+            Ok(Sink::Ring { records: VecDeque::with_capacity(capacity), capacity })
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown trace sink {}", spec)))
+    }
+}
+
+// Broad kinds of instruction, by encoding
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Class {
+    DataProcessing,
+    Multiply,
+    Swap,
+    SingleTransfer,
+    BlockTransfer,
+    Branch,
+    Coprocessor,
+    Swi,
+}
+
+impl Class {
+    pub fn of(word: u32) -> Class {
+        match (word >> 25) & 7 {
+            0b000 if word & 0xf0 == 0x90 => if word & (1 << 24) != 0 { Class::Swap } else { Class::Multiply },
+            0b000 | 0b001 => Class::DataProcessing,
+            0b010 | 0b011 => Class::SingleTransfer,
+            0b100 => Class::BlockTransfer,
+            0b101 => Class::Branch,
+            0b110 => Class::Coprocessor,
+            _ => if word & (1 << 24) != 0 { Class::Swi } else { Class::Coprocessor }
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Class> {
+        match name.to_lowercase().as_str() {
+            "alu" | "dp" => Some(Class::DataProcessing),
+            "mul" | "multiply" => Some(Class::Multiply),
+            "swp" | "swap" => Some(Class::Swap),
+            "ldr" | "str" | "transfer" => Some(Class::SingleTransfer),
+            "ldm" | "stm" | "block" => Some(Class::BlockTransfer),
+            "b" | "branch" => Some(Class::Branch),
+            "cp" | "coprocessor" => Some(Class::Coprocessor),
+            "swi" => Some(Class::Swi),
+            _ => None
+        }
+    }
+}
+
+// Which instructions get records. Empty lists let everything through.
+#[derive(Default)]
+pub struct Filter {
+    pub addresses: Option<Range<u32>>,
+    pub modes: Vec<Mode>,
+    pub classes: Vec<Class>,
+}
+
+impl Filter {
+    pub fn matches(&self, address: u32, mode: Mode, word: u32) -> bool {
+        self.addresses.as_ref().is_none_or(|range| range.contains(&address))
+            && (self.modes.is_empty() || self.modes.contains(&mode))
+            && (self.classes.is_empty() || self.classes.contains(&Class::of(word)))
+    }
+}
+
+// <start>-<end>, in hex, end exclusive
+pub fn parse_range(spec: &str) -> Option<Range<u32>> {
+    let mut parts = spec.splitn(2, '-');
+    let start = u32::from_str_radix(parts.next()?.trim_start_matches("0x"), 16).ok()?;
+    let end = u32::from_str_radix(parts.next()?.trim_start_matches("0x"), 16).ok()?;
+    Some(start..end)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AccessKind {
+    Load,
+    Store,
+    LoadByte,
+    StoreByte,
+}
+
+// A data access made by an instruction. Instruction fetches aren't included.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub address: u32,
+    pub value: u32,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            AccessKind::Load => write!(f, "[{:08X}] -> {:08X}", self.address, self.value),
+            AccessKind::Store => write!(f, "[{:08X}] <- {:08X}", self.address, self.value),
+            AccessKind::LoadByte => write!(f, "[{:08X}] -> {:02X}", self.address, self.value),
+            AccessKind::StoreByte => write!(f, "[{:08X}] <- {:02X}", self.address, self.value),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub pc: u32,
+    pub word: u32,
+    pub mode: Mode,
+    // Whether the condition passed
+    pub executed: bool,
+    pub disassembly: String,
+    // Registers of the mode the instruction started in that it changed, with
+    // their new values. PC is only included when it didn't just move on to
+    // the next instruction.
+    pub registers: Vec<(u32, u32)>,
+    pub accesses: Vec<Access>,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08X}  {:08X}  {:<32}", self.pc, self.word, self.disassembly)?;
+        if !self.executed {
+            write!(f, "  (skipped)")?;
+        }
+        for &(reg, value) in &self.registers {
+            write!(f, "  {}={:08X}", REGISTER_NAMES[reg as usize], value)?;
+        }
+        for access in &self.accesses {
+            write!(f, "  {}", access)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Tracer {
+    sink: Sink,
+    pub filter: Filter,
+}
+
+impl Tracer {
+    pub fn new() -> Tracer {
+        Tracer {
+            sink: Sink::None,
+            filter: Filter::default(),
+        }
+    }
+
+    pub fn set_sink(&mut self, sink: Sink) {
+        self.flush();
+        self.sink = sink;
+    }
+
+    pub fn enabled(&self) -> bool {
+        !matches!(self.sink, Sink::None)
+    }
+
+    pub fn wants(&self, address: u32, mode: Mode, word: u32) -> bool {
+        self.enabled() && self.filter.matches(address, mode, word)
+    }
+
+    pub fn record(&mut self, record: Record) {
+        match self.sink {
+            Sink::None => {},
+            Sink::Stdout => println!("{}", record),
+            Sink::File(ref mut file) => if let Err(error) = writeln!(file, "{}", record) {
+                println!("Couldn't write trace: {}", error);
+                self.sink = Sink::None;
+            },
+            Sink::Ring { ref mut records, capacity } => {
+                if records.len() >= capacity {
+                    records.pop_front();
+                }
+                if capacity > 0 {
+                    records.push_back(record);
+                }
+            }
+        }
+    }
+
+    // What the ring buffer holds, oldest first
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        match self.sink {
+            Sink::Ring { ref records, .. } => Some(records.iter()),
+            _ => None
+        }.into_iter().flatten()
+    }
+
+    pub fn flush(&mut self) {
+        if let Sink::File(ref mut file) = self.sink {
+            if let Err(error) = file.flush() {
+                println!("Couldn't write trace: {}", error);
+            }
+        }
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Tracer {
+        Tracer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(pc: u32) -> Record {
+        Record {
+            pc,
+            word: 0xe2800001,
+            mode: Mode::Svc,
+            executed: true,
+            disassembly: "ADD R0, R0, #1".to_owned(),
+            registers: vec![(0, pc)],
+            accesses: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ring() {
+        let mut tracer = Tracer::new();
+        tracer.set_sink(open("ring:3").unwrap());
+        assert!(tracer.enabled());
+        assert_eq!(tracer.records().count(), 0);
+
+        // Only the most recent records are kept, oldest first
+        for pc in 0..5 {
+            tracer.record(record(pc * 4));
+        }
+        let pcs: Vec<u32> = tracer.records().map(|record| record.pc).collect();
+        assert_eq!(pcs, [8, 12, 16]);
+
+        tracer.set_sink(open("ring:0").unwrap());
+        tracer.record(record(0));
+        assert_eq!(tracer.records().count(), 0);
+
+        // Other sinks have nothing to look back over
+        tracer.set_sink(Sink::None);
+        assert_eq!(tracer.records().count(), 0);
+        assert!(open("ring:lots").is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use std::io;
+use webarc::device::{Device, Interrupts};
+use webarc::ioc::IRQ_B_SERIAL;
+use webarc::serial::SerialBackend;
+use webarc::snapshot::{StateReader, StateWriter};
+use webarc::sound::CYCLES_PER_MICROSECOND;
+
+// The 82C711's 16450-compatible UART, which is the serial port on the A5000,
+// at PC I/O ports 0x3F8-0x3FF. OUT2 in the modem control register gates its
+// interrupt onto IOC IRQ B, as on a PC.
+
+pub const PORT_BASE: u16 = 0x3f8;
+pub const PORT_END: u16 = 0x3ff;
+
+const RECEIVE_TRANSMIT: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const INTERRUPT_IDENTIFICATION: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+const SCRATCH: u16 = 7;
+
+const ENABLE_RECEIVE: u8 = 1 << 0;
+const ENABLE_TRANSMIT: u8 = 1 << 1;
+
+// Interrupt identification values, in priority order after the line status
+const NO_INTERRUPT: u8 = 0x01;
+const RECEIVE_INTERRUPT: u8 = 0x04;
+const TRANSMIT_INTERRUPT: u8 = 0x02;
+
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+
+const MODEM_CONTROL_OUT2: u8 = 1 << 3;
+const MODEM_CONTROL_LOOP: u8 = 1 << 4;
+
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+const LINE_STATUS_IDLE: u8 = 1 << 6;
+
+// CTS, DSR and DCD, which are always asserted
+const MODEM_STATUS_READY: u8 = 0xb0;
+
+const CLOCK: u32 = 115200;
+const BITS_PER_CHARACTER: u32 = 10;
+
+pub struct Uart {
+    backend: Option<Box<dyn SerialBackend>>,
+
+    receive: u8,
+    transmit: Option<u8>,
+    interrupt_enable: u8,
+    line_control: u8,
+    modem_control: u8,
+    line_status: u8,
+    scratch: u8,
+    divisor: u16,
+    // The transmit interrupt is cleared by reading it from the identification
+    // register, until the holding register empties again
+    transmit_interrupt: bool,
+    transmit_countdown: u32,
+    receive_countdown: u32,
+}
+
+impl Uart {
+    pub fn new() -> Uart {
+        Uart {
+            backend: None,
+            receive: 0,
+            transmit: None,
+            interrupt_enable: 0,
+            line_control: 0,
+            modem_control: 0,
+            line_status: LINE_STATUS_TRANSMIT_EMPTY | LINE_STATUS_IDLE,
+            scratch: 0,
+            divisor: 12,
+            transmit_interrupt: false,
+            transmit_countdown: 0,
+            receive_countdown: 0,
+        }
+    }
+
+    pub fn attach(&mut self, backend: Box<dyn SerialBackend>) {
+        self.backend = Some(backend);
+    }
+
+    pub fn detach(&mut self) -> Option<Box<dyn SerialBackend>> {
+        self.backend.take()
+    }
+
+    fn identification(&self) -> u8 {
+        if self.interrupt_enable & ENABLE_RECEIVE != 0 && self.line_status & LINE_STATUS_DATA_READY != 0 {
+            RECEIVE_INTERRUPT
+        } else if self.interrupt_enable & ENABLE_TRANSMIT != 0 && self.transmit_interrupt {
+            TRANSMIT_INTERRUPT
+        } else {
+            NO_INTERRUPT
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.modem_control & MODEM_CONTROL_OUT2 != 0 && self.identification() != NO_INTERRUPT
+    }
+
+    fn character_time(&self) -> u32 {
+        let baud = CLOCK / (self.divisor.max(1) as u32);
+        1_000_000 * CYCLES_PER_MICROSECOND / baud * BITS_PER_CHARACTER
+    }
+}
+
+impl Device for Uart {
+    fn load(&mut self, port: u32) -> u32 {
+        let port = port as u16;
+        let dlab = self.line_control & LINE_CONTROL_DLAB != 0;
+
+        let value = match port - PORT_BASE {
+            RECEIVE_TRANSMIT if dlab => self.divisor as u8,
+            RECEIVE_TRANSMIT => {
+                self.line_status &= !LINE_STATUS_DATA_READY;
+                self.receive
+            },
+            INTERRUPT_ENABLE if dlab => (self.divisor >> 8) as u8,
+            INTERRUPT_ENABLE => self.interrupt_enable,
+            INTERRUPT_IDENTIFICATION => {
+                let identification = self.identification();
+                if identification == TRANSMIT_INTERRUPT {
+                    self.transmit_interrupt = false;
+                }
+                identification
+            },
+            LINE_CONTROL => self.line_control,
+            MODEM_CONTROL => self.modem_control,
+            LINE_STATUS => self.line_status,
+            MODEM_STATUS => MODEM_STATUS_READY,
+            SCRATCH => self.scratch,
+            _ => unreachable!()
+        };
+        value as u32
+    }
+
+    fn store(&mut self, port: u32, data: u32) {
+        let port = port as u16;
+        let value = data as u8;
+        let dlab = self.line_control & LINE_CONTROL_DLAB != 0;
+
+        match port - PORT_BASE {
+            RECEIVE_TRANSMIT if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            RECEIVE_TRANSMIT => {
+                self.transmit = Some(value);
+                self.transmit_interrupt = false;
+                self.line_status &= !(LINE_STATUS_TRANSMIT_EMPTY | LINE_STATUS_IDLE);
+            },
+            INTERRUPT_ENABLE if dlab => self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8,
+            INTERRUPT_ENABLE => {
+                // Enabling the transmit interrupt with the holding register
+                // already empty raises it straight away
+                if value & ENABLE_TRANSMIT != 0 && self.interrupt_enable & ENABLE_TRANSMIT == 0 {
+                    self.transmit_interrupt = self.line_status & LINE_STATUS_TRANSMIT_EMPTY != 0;
+                }
+                self.interrupt_enable = value & 0x0f;
+            },
+            LINE_CONTROL => self.line_control = value,
+            MODEM_CONTROL => self.modem_control = value & 0x1f,
+            SCRATCH => self.scratch = value,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let loopback = self.modem_control & MODEM_CONTROL_LOOP != 0;
+
+        if self.transmit_countdown > cycles {
+            self.transmit_countdown -= cycles;
+        } else if let Some(byte) = self.transmit.take() {
+            // Loopback mode wires the transmitter back to the receiver
+            if loopback {
+                self.receive = byte;
+                self.line_status |= LINE_STATUS_DATA_READY;
+            } else if let Some(ref mut backend) = self.backend {
+                backend.write(byte);
+            }
+            self.transmit_countdown = self.character_time();
+            self.line_status |= LINE_STATUS_TRANSMIT_EMPTY | LINE_STATUS_IDLE;
+            self.transmit_interrupt = true;
+        } else {
+            self.transmit_countdown = 0;
+        }
+
+        if self.receive_countdown > cycles {
+            self.receive_countdown -= cycles;
+            return;
+        }
+        self.receive_countdown = self.character_time();
+
+        if loopback || self.line_status & LINE_STATUS_DATA_READY != 0 {
+            return;
+        }
+        if let Some(byte) = self.backend.as_mut().and_then(|backend| backend.read()) {
+            self.receive = byte;
+            self.line_status |= LINE_STATUS_DATA_READY;
+        }
+    }
+
+    // Finishing the character going out, and checking the host for the
+    // next one coming in
+    fn next_event(&self) -> Option<u32> {
+        let transmit = if self.transmit.is_some() { Some(self.transmit_countdown) } else { None };
+        let receive = if self.backend.is_some() { Some(self.receive_countdown) } else { None };
+        transmit.into_iter().chain(receive).min()
+    }
+
+    fn interrupts(&mut self) -> Interrupts {
+        Interrupts::irq_b(IRQ_B_SERIAL, self.irq())
+    }
+
+    // Whatever the port is connected to stays connected
+    fn save(&self, state: &mut StateWriter) {
+        state.u8(self.receive);
+        state.option_u8(self.transmit);
+        for &register in &[self.interrupt_enable, self.line_control, self.modem_control, self.line_status, self.scratch] {
+            state.u8(register);
+        }
+        state.u16(self.divisor);
+        state.bool(self.transmit_interrupt);
+        state.u32(self.transmit_countdown);
+        state.u32(self.receive_countdown);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.receive = state.u8()?;
+        self.transmit = state.option_u8()?;
+        for register in [&mut self.interrupt_enable, &mut self.line_control, &mut self.modem_control, &mut self.line_status, &mut self.scratch] {
+            *register = state.u8()?;
+        }
+        self.divisor = state.u16()?;
+        self.transmit_interrupt = state.bool()?;
+        self.transmit_countdown = state.u32()?;
+        self.receive_countdown = state.u32()?;
+        Ok(())
+    }
+}
+
+impl Default for Uart {
+    fn default() -> Uart {
+        Uart::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webarc::serial::Loopback;
+
+    fn outb(uart: &mut Uart, register: u16, value: u8) {
+        uart.store((PORT_BASE + register) as u32, value as u32);
+    }
+
+    fn inb(uart: &mut Uart, register: u16) -> u8 {
+        uart.load((PORT_BASE + register) as u32) as u8
+    }
+
+    fn run(uart: &mut Uart, cycles: u32) {
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let step = uart.next_event().unwrap_or(remaining).clamp(1, remaining);
+            uart.tick(step);
+            remaining -= step;
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        let mut uart = Uart::new();
+        uart.attach(Box::new(Loopback::new()));
+        assert_eq!(inb(&mut uart, LINE_STATUS), LINE_STATUS_TRANSMIT_EMPTY | LINE_STATUS_IDLE);
+        assert_eq!(inb(&mut uart, INTERRUPT_IDENTIFICATION), NO_INTERRUPT);
+        assert_eq!(inb(&mut uart, MODEM_STATUS), MODEM_STATUS_READY);
+
+        // 115200 baud through the divisor latch
+        outb(&mut uart, LINE_CONTROL, LINE_CONTROL_DLAB | 3);
+        outb(&mut uart, RECEIVE_TRANSMIT, 1);
+        outb(&mut uart, INTERRUPT_ENABLE, 0);
+        assert_eq!((inb(&mut uart, RECEIVE_TRANSMIT), inb(&mut uart, INTERRUPT_ENABLE)), (1, 0));
+        outb(&mut uart, LINE_CONTROL, 3);
+        let character_time = uart.character_time();
+        assert_eq!(character_time, 69 * BITS_PER_CHARACTER);
+
+        // The transmit interrupt comes up as soon as it's enabled, but only
+        // reaches IOC through OUT2, and reading it clears it
+        outb(&mut uart, INTERRUPT_ENABLE, ENABLE_RECEIVE | ENABLE_TRANSMIT);
+        assert!(!uart.irq());
+        outb(&mut uart, MODEM_CONTROL, MODEM_CONTROL_OUT2);
+        assert_eq!(uart.interrupts(), Interrupts::irq_b(IRQ_B_SERIAL, true));
+        assert_eq!(inb(&mut uart, INTERRUPT_IDENTIFICATION), TRANSMIT_INTERRUPT);
+        assert_eq!(inb(&mut uart, INTERRUPT_IDENTIFICATION), NO_INTERRUPT);
+        assert!(!uart.irq());
+
+        // Out and straight back in, with the received byte interrupting first
+        outb(&mut uart, RECEIVE_TRANSMIT, b'A');
+        assert_eq!(inb(&mut uart, LINE_STATUS), 0);
+        run(&mut uart, 1);
+        assert_eq!(inb(&mut uart, LINE_STATUS), LINE_STATUS_DATA_READY | LINE_STATUS_TRANSMIT_EMPTY | LINE_STATUS_IDLE);
+        assert_eq!(inb(&mut uart, INTERRUPT_IDENTIFICATION), RECEIVE_INTERRUPT);
+        assert_eq!(inb(&mut uart, RECEIVE_TRANSMIT), b'A');
+        assert_eq!(inb(&mut uart, INTERRUPT_IDENTIFICATION), TRANSMIT_INTERRUPT);
+        assert_eq!(inb(&mut uart, INTERRUPT_IDENTIFICATION), NO_INTERRUPT);
+
+        // Loopback mode keeps the byte inside the chip
+        outb(&mut uart, MODEM_CONTROL, MODEM_CONTROL_OUT2 | MODEM_CONTROL_LOOP);
+        outb(&mut uart, RECEIVE_TRANSMIT, b'B');
+        run(&mut uart, character_time);
+        assert_eq!(inb(&mut uart, RECEIVE_TRANSMIT), b'B');
+        outb(&mut uart, MODEM_CONTROL, MODEM_CONTROL_OUT2);
+        run(&mut uart, 2 * character_time);
+        assert_eq!(inb(&mut uart, LINE_STATUS) & LINE_STATUS_DATA_READY, 0);
+
+        outb(&mut uart, SCRATCH, 0x5a);
+        assert_eq!(inb(&mut uart, SCRATCH), 0x5a);
+    }
+}
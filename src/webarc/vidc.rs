@@ -0,0 +1,190 @@
+use std::io;
+use webarc::device::{Device, Interrupts};
+use webarc::ioc::IRQ_A_VSYNC;
+use webarc::memc::Memc;
+use webarc::snapshot::{StateReader, StateWriter};
+use webarc::sound::{Sound, CYCLES_PER_MICROSECOND};
+
+// VIDC is write-only. Each word written to 0x3400000-0x35FFFFF carries the
+// register address in bits 24-31 and the value in bits 0-23.
+
+const HORIZONTAL_CYCLE: u32 = 0x80;
+const HORIZONTAL_DISPLAY_START: u32 = 0x8c;
+const HORIZONTAL_DISPLAY_END: u32 = 0x90;
+const VERTICAL_CYCLE: u32 = 0xa0;
+const VERTICAL_DISPLAY_START: u32 = 0xac;
+const VERTICAL_DISPLAY_END: u32 = 0xb0;
+const VERTICAL_CURSOR_START: u32 = 0xb8;
+const VERTICAL_CURSOR_END: u32 = 0xbc;
+const STEREO_IMAGE_FIRST: u32 = 0x60;
+const STEREO_IMAGE_LAST: u32 = 0x7c;
+const SOUND_FREQUENCY: u32 = 0xc0;
+const CONTROL: u32 = 0xe0;
+
+// Pixel rates for the control register's clock select, in MHz
+const PIXEL_RATES: [u32; 4] = [8, 12, 16, 24];
+const CONTROL_BITS_PER_PIXEL_SHIFT: u32 = 2;
+
+// The cursor is 32 pixels of 2 bits, and takes a quadword DMA on each line
+const CURSOR_QUADWORDS: u32 = 1;
+
+// Timing before the display registers are programmed: 64us lines and 312 of
+// them to a frame, as for a 50Hz TV
+const DEFAULT_LINE_CYCLES: u32 = 64 * CYCLES_PER_MICROSECOND;
+const DEFAULT_LINES: u32 = 312;
+
+pub struct Vidc {
+    registers: [u32; 64],
+    pub sound: Sound,
+
+    // Raster position: the line being scanned, from the start of vertical
+    // sync, and how long until the next
+    line: u32,
+    line_countdown: u32,
+    // Vertical flyback has started since IOC was last told
+    vsync: bool,
+}
+
+impl Vidc {
+    pub fn new() -> Vidc {
+        Vidc {
+            registers: [0; 64],
+            sound: Sound::new(),
+            line: 0,
+            line_countdown: DEFAULT_LINE_CYCLES,
+            vsync: false,
+        }
+    }
+
+    pub fn write(&mut self, data: u32) {
+        let register = (data >> 24) & 0xfc;
+        let value = data & 0x00ffffff;
+
+        match register {
+            STEREO_IMAGE_FIRST..=STEREO_IMAGE_LAST => {
+                // 0x60 holds the image for channel 1 and 0x7C for channel 0
+                let channel = ((register - STEREO_IMAGE_FIRST) / 4 + 1) & 7;
+                self.sound.set_stereo_image(channel as usize, value);
+            },
+            SOUND_FREQUENCY => self.sound.set_frequency(value),
+            _ => {}
+        }
+
+        self.registers[(register / 4) as usize] = value;
+    }
+
+    pub fn register(&self, register: u32) -> u32 {
+        self.registers[((register & 0xfc) / 4) as usize]
+    }
+
+    // Timing registers hold their values in bits 14-23
+    fn timing(&self, register: u32) -> u32 {
+        (self.register(register) >> 14) & 0x3ff
+    }
+
+    // A line is twice the horizontal cycle register plus two pixels long
+    pub fn line_cycles(&self) -> u32 {
+        match self.timing(HORIZONTAL_CYCLE) {
+            0 => DEFAULT_LINE_CYCLES,
+            cycle => {
+                let rate = PIXEL_RATES[(self.register(CONTROL) & 3) as usize];
+                (cycle * 2 + 2) * CYCLES_PER_MICROSECOND / rate
+            }
+        }
+    }
+
+    // Quadwords of screen memory fetched for each displayed line. The display
+    // registers count in units of two pixels.
+    fn line_quadwords(&self) -> u32 {
+        let start = self.timing(HORIZONTAL_DISPLAY_START);
+        let end = self.timing(HORIZONTAL_DISPLAY_END);
+        let pixels = end.saturating_sub(start) * 2;
+        let bits_per_pixel = 1 << ((self.register(CONTROL) >> CONTROL_BITS_PER_PIXEL_SHIFT) & 3);
+        (pixels * bits_per_pixel).div_ceil(128)
+    }
+
+    // Ask MEMC for the DMA the line needs, for the display and the cursor
+    fn line_dma(&self, memc: &mut Memc) {
+        let line = self.line;
+        let mut quadwords = 0;
+
+        if line >= self.timing(VERTICAL_DISPLAY_START) && line < self.timing(VERTICAL_DISPLAY_END) {
+            quadwords += self.line_quadwords();
+        }
+        if line >= self.timing(VERTICAL_CURSOR_START) && line < self.timing(VERTICAL_CURSOR_END) {
+            quadwords += CURSOR_QUADWORDS;
+        }
+
+        if quadwords > 0 {
+            memc.video_dma(quadwords);
+        }
+    }
+
+    pub fn frame_lines(&self) -> u32 {
+        match self.timing(VERTICAL_CYCLE) {
+            0 => DEFAULT_LINES,
+            lines => lines + 1
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32, memc: &mut Memc, ram: &[u32]) {
+        self.sound.tick(cycles, memc, ram);
+
+        let mut remaining = cycles;
+        while remaining >= self.line_countdown {
+            remaining -= self.line_countdown;
+            self.line_countdown = self.line_cycles();
+
+            self.line = (self.line + 1) % self.frame_lines();
+            // Flyback starts as the display ends
+            if self.line == self.timing(VERTICAL_DISPLAY_END) {
+                self.vsync = true;
+            }
+            self.line_dma(memc);
+        }
+        self.line_countdown -= remaining;
+    }
+}
+
+// VIDC's own tick takes MEMC and RAM for its DMA, so the memory system calls
+// that rather than the device one
+impl Device for Vidc {
+    fn store(&mut self, _address: u32, data: u32) {
+        self.write(data);
+    }
+
+    // The next line, or the next sound DMA
+    fn next_event(&self) -> Option<u32> {
+        let sound = self.sound.next_event().unwrap_or(self.line_countdown);
+        Some(self.line_countdown.min(sound))
+    }
+
+    fn interrupts(&mut self) -> Interrupts {
+        let vsync = self.vsync;
+        self.vsync = false;
+        Interrupts::irq_a(IRQ_A_VSYNC, vsync)
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.words(&self.registers);
+        self.sound.save(state);
+        state.u32(self.line);
+        state.u32(self.line_countdown);
+        state.bool(self.vsync);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.words_into(&mut self.registers)?;
+        self.sound.restore(state)?;
+        self.line = state.u32()?;
+        self.line_countdown = state.u32()?;
+        self.vsync = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Vidc {
+    fn default() -> Vidc {
+        Vidc::new()
+    }
+}
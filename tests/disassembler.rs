@@ -0,0 +1,401 @@
+extern crate webarc;
+
+use webarc::disassembler::disassemble;
+use webarc::swis;
+
+// Round trips through the disassembler: every word a generator comes up with
+// is disassembled and put back together by the small assembler here, and has
+// to come out the same. Only the encodings an assembler would choose are
+// generated, since the others can't come back.
+
+const ADDRESS: u32 = 0x02001000;
+
+const CONDITIONS: [&str; 16] = ["EQ", "NE", "CS", "CC", "MI", "PL", "VS", "VC", "HI", "LS", "GE", "LT", "GT", "LE", "AL", "NV"];
+const REGISTERS: [&str; 16] = ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7", "R8", "R9", "R10", "R11", "R12", "SP", "LR", "PC"];
+const ALU_OPS: [&str; 16] = ["AND", "EOR", "SUB", "RSB", "ADD", "ADC", "SBC", "RSC", "TST", "TEQ", "CMP", "CMN", "ORR", "MOV", "BIC", "MVN"];
+const SHIFTS: [&str; 4] = ["LSL", "LSR", "ASR", "ROR"];
+
+// Longest first where one starts another
+const MNEMONICS: [&str; 32] = [
+    "ADR", "SWP", "SWI", "MUL", "MLA", "LDM", "STM", "LDR", "STR", "LDC", "STC", "CDP", "MCR", "MRC", "DCD",
+    "AND", "EOR", "SUB", "RSB", "ADD", "ADC", "SBC", "RSC", "TST", "TEQ", "CMP", "CMN", "ORR", "MOV", "BIC", "MVN", "BL",
+];
+
+// xorshift, so runs can be repeated from a seed
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u32
+    }
+
+    fn bits(&mut self, count: u32) -> u32 {
+        self.next() & ((1 << count) - 1)
+    }
+
+    fn flag(&mut self) -> bool {
+        self.bits(1) != 0
+    }
+}
+
+fn encode_immediate(value: u32) -> Option<u32> {
+    (0..16).map(|rotate| (rotate, value.rotate_left(rotate * 2)))
+        .find(|&(_, rotated)| rotated < 0x100)
+        .map(|(rotate, rotated)| rotate << 8 | rotated)
+}
+
+fn parse_number(text: &str) -> u32 {
+    match text.strip_prefix('&') {
+        Some(hex) => u32::from_str_radix(hex, 16).unwrap(),
+        None => text.parse().unwrap()
+    }
+}
+
+fn parse_register(text: &str) -> u32 {
+    REGISTERS.iter().position(|&name| name == text)
+        .or_else(|| text.strip_prefix('R').and_then(|number| number.parse().ok()))
+        .unwrap_or_else(|| panic!("Bad register {}", text)) as u32
+}
+
+fn parse_prefixed(text: &str, prefix: &str) -> u32 {
+    text.strip_prefix(prefix).unwrap_or_else(|| panic!("Expected {} in {}", prefix, text)).parse().unwrap()
+}
+
+// Up and the value, from #n or #-n
+fn parse_offset(text: &str) -> (bool, u32) {
+    let text = text.strip_prefix('#').unwrap();
+    match text.strip_prefix('-') {
+        Some(value) => (false, parse_number(value)),
+        None => (true, parse_number(text))
+    }
+}
+
+// Operands split at commas that aren't inside brackets or braces
+fn split_operands(text: &str) -> Vec<String> {
+    let mut operands = vec![String::new()];
+    let mut depth = 0;
+    for c in text.chars() {
+        match c {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(String::new());
+                continue;
+            },
+            _ => {}
+        }
+        operands.last_mut().unwrap().push(c);
+    }
+    operands
+}
+
+// A register with an optional shift, as operand 2 or a transfer's offset
+fn encode_shifted(operands: &[String]) -> u32 {
+    let rm = parse_register(&operands[0]);
+    let shift = match operands.get(1) {
+        Some(shift) => shift,
+        None => return rm
+    };
+    if shift == "RRX" {
+        return rm | 3 << 5;
+    }
+
+    let (name, amount) = shift.split_at(3);
+    let shift_type = SHIFTS.iter().position(|&s| s == name).unwrap() as u32;
+    let amount = amount.trim();
+    if let Some(amount) = amount.strip_prefix('#') {
+        rm | shift_type << 5 | (parse_number(amount) & 0x1f) << 7
+    } else {
+        rm | shift_type << 5 | 1 << 4 | parse_register(amount) << 8
+    }
+}
+
+fn encode_operand2(operands: &[String]) -> u32 {
+    match operands[0].strip_prefix('#') {
+        Some(value) => 1 << 25 | encode_immediate(parse_number(value)).unwrap(),
+        None => encode_shifted(operands)
+    }
+}
+
+// Pre-indexing, up, write back, base and offset for [Rn], [Rn,<offset>]{!},
+// [Rn],<offset> and PC-relative addresses
+fn encode_location<F: Fn(&[String]) -> (bool, u32)>(address: u32, operands: &[String], offset: F) -> u32 {
+    let first = &operands[0];
+    if let Some(target) = first.strip_prefix('&') {
+        let difference = u32::from_str_radix(target, 16).unwrap().wrapping_sub(address.wrapping_add(8)) as i32;
+        let (up, value) = offset(&[format!("#{}", difference)]);
+        return 1 << 24 | (up as u32) << 23 | 15 << 16 | value;
+    }
+
+    let write_back = first.ends_with('!');
+    let inner = split_operands(first.trim_end_matches('!').trim_start_matches('[').trim_end_matches(']'));
+    let rn = parse_register(&inner[0]);
+
+    let (pre_indexed, write_back, (up, value)) = if operands.len() > 1 {
+        (false, false, offset(&operands[1..]))
+    } else if inner.len() > 1 {
+        (true, write_back, offset(&inner[1..]))
+    } else {
+        (true, write_back, (true, 0))
+    };
+    (pre_indexed as u32) << 24 | (up as u32) << 23 | (write_back as u32) << 21 | rn << 16 | value
+}
+
+fn encode_register_list(text: &str) -> u32 {
+    let text = text.trim_start_matches('{').trim_end_matches('^').trim_end_matches('}');
+    if text.is_empty() {
+        return 0;
+    }
+    text.split(',').map(|item| {
+        let mut ends = item.splitn(2, '-');
+        let first = parse_register(ends.next().unwrap());
+        let last = ends.next().map(parse_register).unwrap_or(first);
+        (first..=last).fold(0, |list, reg| list | 1 << reg)
+    }).fold(0, |list, registers| list | registers)
+}
+
+fn encode_coprocessor(operands: &[String], first: usize) -> u32 {
+    let info = operands.get(first).map(|info| parse_number(info)).unwrap_or(0);
+    info << 5 | parse_prefixed(&operands[first - 1], "CR")
+}
+
+fn assemble(address: u32, text: &str) -> u32 {
+    let (mnemonic, operands) = match text.find(' ') {
+        Some(space) => (&text[..space], text[space..].trim()),
+        None => (text, "")
+    };
+    let operands = split_operands(operands);
+
+    // B and BL are told apart by what's left being a condition
+    let (base, rest) = MNEMONICS.iter()
+        .find(|&&base| mnemonic.starts_with(base) && (base != "BL" || CONDITIONS.iter().any(|&cond| mnemonic == format!("BL{}", cond) || mnemonic == "BL")))
+        .map(|&base| (base, &mnemonic[base.len()..]))
+        .unwrap_or(("B", &mnemonic[1..]));
+    let (cond, suffix) = match CONDITIONS.iter().position(|&cond| rest.starts_with(cond)) {
+        Some(cond) => (cond as u32, &rest[2..]),
+        None => (0xe, rest)
+    };
+    let word = cond << 28;
+    let register = |index: usize, shift: u32| parse_register(&operands[index]) << shift;
+
+    match base {
+        "DCD" => parse_number(&operands[0]),
+        "ADR" => {
+            let pc = address.wrapping_add(8);
+            let target = parse_number(&operands[1]);
+            let (opcode, value) = if target >= pc { (4, target - pc) } else { (2, pc - target) };
+            word | 1 << 25 | opcode << 21 | 15 << 16 | register(0, 12) | encode_immediate(value).unwrap()
+        },
+        "MUL" | "MLA" => {
+            let accumulate = base == "MLA";
+            let rn = if accumulate { register(3, 12) } else { 0 };
+            word | (accumulate as u32) << 21 | ((suffix == "S") as u32) << 20 | register(0, 16) | rn | register(2, 8) | 0x90 | register(1, 0)
+        },
+        "SWP" => {
+            let rn = parse_register(operands[2].trim_start_matches('[').trim_end_matches(']'));
+            word | 1 << 24 | ((suffix == "B") as u32) << 22 | rn << 16 | register(0, 12) | 0x90 | register(1, 0)
+        },
+        "LDR" | "STR" => {
+            let load = base == "LDR";
+            let location = encode_location(address, &operands[1..], |offset| {
+                if offset[0].starts_with('#') {
+                    parse_offset(&offset[0])
+                } else {
+                    let up = !offset[0].starts_with('-');
+                    let mut shifted = offset.to_vec();
+                    shifted[0] = shifted[0].trim_start_matches('-').to_owned();
+                    (up, 1 << 25 | encode_shifted(&shifted))
+                }
+            });
+            // T is write back on a post-indexed transfer
+            let translated = suffix.ends_with('T') as u32;
+            word | 1 << 26 | location | (suffix.starts_with('B') as u32) << 22 | translated << 21 | (load as u32) << 20 | register(0, 12)
+        },
+        "LDM" | "STM" => {
+            let load = base == "LDM";
+            let (pre_indexed, up) = match (suffix, load) {
+                ("IA", _) | ("FD", true) | ("EA", false) => (false, true),
+                ("IB", _) | ("ED", true) | ("FA", false) => (true, true),
+                ("DA", _) | ("FA", true) | ("ED", false) => (false, false),
+                _ => (true, false)
+            };
+            let write_back = operands[0].ends_with('!');
+            let rn = parse_register(operands[0].trim_end_matches('!'));
+            let user = operands[1].ends_with('^');
+            word | 0b100 << 25 | (pre_indexed as u32) << 24 | (up as u32) << 23 | (user as u32) << 22 | (write_back as u32) << 21
+                | (load as u32) << 20 | rn << 16 | encode_register_list(&operands[1])
+        },
+        "B" | "BL" => {
+            let offset = parse_number(&operands[0]).wrapping_sub(address.wrapping_add(8)) >> 2;
+            word | 0b101 << 25 | ((base == "BL") as u32) << 24 | (offset & 0x00ffffff)
+        },
+        "SWI" => {
+            let comment = if operands[0].starts_with('&') { parse_number(&operands[0]) } else { swis::number(&operands[0]).unwrap() };
+            word | 0xf << 24 | comment
+        },
+        "LDC" | "STC" => {
+            let location = encode_location(address, &operands[2..], |offset| {
+                let (up, value) = parse_offset(&offset[0]);
+                (up, value / 4)
+            });
+            // Post-indexing always writes back
+            let location = if location & (1 << 24) == 0 { location | 1 << 21 } else { location };
+            word | 0b110 << 25 | location | ((suffix == "L") as u32) << 22 | ((base == "LDC") as u32) << 20
+                | parse_prefixed(&operands[1], "CR") << 12 | parse_prefixed(&operands[0], "CP") << 8
+        },
+        "CDP" => {
+            word | 0xe << 24 | parse_number(&operands[1]) << 20 | parse_prefixed(&operands[3], "CR") << 16
+                | parse_prefixed(&operands[2], "CR") << 12 | parse_prefixed(&operands[0], "CP") << 8 | encode_coprocessor(&operands, 5)
+        },
+        "MCR" | "MRC" => {
+            word | 0xe << 24 | parse_number(&operands[1]) << 21 | ((base == "MRC") as u32) << 20 | parse_prefixed(&operands[3], "CR") << 16
+                | register(2, 12) | parse_prefixed(&operands[0], "CP") << 8 | 1 << 4 | encode_coprocessor(&operands, 5)
+        },
+        _ => {
+            let opcode = ALU_OPS.iter().position(|&op| op == base).unwrap() as u32;
+            let set_flags = suffix == "S" || (8..12).contains(&opcode);
+            let (rd, rn, operand2) = match opcode {
+                8..=11 => (if suffix == "P" { 15 << 12 } else { 0 }, register(0, 16), &operands[1..]),
+                13 | 15 => (register(0, 12), 0, &operands[1..]),
+                _ => (register(0, 12), register(1, 16), &operands[2..])
+            };
+            word | opcode << 21 | (set_flags as u32) << 20 | rn | rd | encode_operand2(operand2)
+        }
+    }
+}
+
+fn round_trip(word: u32) {
+    let text = disassemble(ADDRESS, word);
+    assert_eq!(assemble(ADDRESS, &text), word, "{:08X} came out as {}", word, text);
+}
+
+// Operand 2 as an immediate an assembler would pick, or a shifted register
+fn random_operand2(random: &mut Random) -> u32 {
+    if random.flag() {
+        1 << 25 | encode_immediate(random.bits(8).rotate_right(random.bits(4) * 2)).unwrap()
+    } else if random.flag() {
+        random.bits(4) << 8 | random.bits(2) << 5 | 1 << 4 | random.bits(4)
+    } else {
+        random.bits(5) << 7 | random.bits(2) << 5 | random.bits(4)
+    }
+}
+
+#[test]
+fn data_processing() {
+    let mut random = Random(0x0123_4567_89ab_cdef);
+    for _ in 0..20000 {
+        let opcode = random.bits(4);
+        let (set_flags, rd, rn) = match opcode {
+            8..=11 => (1, if random.flag() { 15 } else { 0 }, random.bits(4)),
+            13 | 15 => (random.bits(1), random.bits(4), 0),
+            _ => (random.bits(1), random.bits(4), random.bits(4))
+        };
+        let mut word = random.bits(4) << 28 | opcode << 21 | set_flags << 20 | rn << 16 | rd << 12 | random_operand2(&mut random);
+
+        // Addresses made from PC come back as ADD when they could be either
+        if rn == 15 && set_flags == 0 && (opcode == 2 || opcode == 4) && word & (1 << 25) != 0 {
+            word = word & !0xfff | encode_immediate((random.bits(8) + 1) << (random.bits(3) * 2)).unwrap();
+        }
+        round_trip(word);
+    }
+}
+
+#[test]
+fn transfers() {
+    let mut random = Random(0x1357_9bdf_0246_8ace);
+    for _ in 0..20000 {
+        let pre_indexed = random.bits(1);
+        let offset = if random.flag() {
+            1 << 25 | random.bits(5) << 7 | random.bits(2) << 5 | random.bits(4)
+        } else {
+            random.bits(12)
+        };
+        let word = random.bits(4) << 28 | 1 << 26 | pre_indexed << 24 | random.bits(1) << 23 | random.bits(1) << 22
+            | random.bits(1) << 21 | random.bits(1) << 20 | random.bits(4) << 16 | random.bits(4) << 12 | offset;
+        round_trip(word);
+
+        let block = random.bits(4) << 28 | 0b100 << 25 | random.bits(5) << 20 | random.bits(4) << 16 | random.bits(16);
+        round_trip(block);
+
+        let swap = random.bits(4) << 28 | 1 << 24 | random.bits(1) << 22 | random.bits(4) << 16 | random.bits(4) << 12 | 0x90 | random.bits(4);
+        round_trip(swap);
+    }
+}
+
+#[test]
+fn branches_and_multiplies() {
+    let mut random = Random(0xfedc_ba98_7654_3210);
+    for _ in 0..20000 {
+        round_trip(random.bits(4) << 28 | 0b101 << 25 | random.bits(25));
+
+        let accumulate = random.bits(1);
+        let rn = if accumulate != 0 { random.bits(4) } else { 0 };
+        round_trip(random.bits(4) << 28 | accumulate << 21 | random.bits(1) << 20 | random.bits(4) << 16 | rn << 12 | random.bits(4) << 8 | 0x90 | random.bits(4));
+    }
+}
+
+#[test]
+fn coprocessor_and_swis() {
+    let mut random = Random(0x0f1e_2d3c_4b5a_6978);
+    for _ in 0..20000 {
+        // Post-indexing without write back isn't an addressing mode yet
+        let pre_indexed = random.bits(1);
+        let write_back = if pre_indexed != 0 { random.bits(1) } else { 1 };
+        round_trip(random.bits(4) << 28 | 0b110 << 25 | pre_indexed << 24 | random.bits(2) << 22 | write_back << 21 | random.bits(21));
+        round_trip(random.bits(4) << 28 | 0xe << 24 | random.bits(20) << 4 & !0x10);
+        round_trip(random.bits(4) << 28 | 0xe << 24 | random.bits(20) << 4 | 0x10 | random.bits(4));
+        round_trip(random.bits(4) << 28 | 0xf << 24 | random.bits(24));
+        round_trip(random.bits(4) << 28 | 0xf << 24 | random.bits(1) << 17 | random.bits(6));
+    }
+}
+
+#[test]
+fn acorn_syntax() {
+    let cases = [
+        (0xe2800001, "ADD     R0,R0,#1"),
+        (0xe3a0e0ff, "MOV     LR,#&FF"),
+        (0xe1b0f00e, "MOVS    PC,LR"),
+        (0xe33ff003, "TEQP    PC,#3"),
+        (0x11520003, "CMPNE   R2,R3"),
+        (0xe0810102, "ADD     R0,R1,R2,LSL #2"),
+        (0xe1a00061, "MOV     R0,R1,RRX"),
+        (0xe1a00021, "MOV     R0,R1,LSR #32"),
+        (0xe1a00251, "MOV     R0,R1,ASR R2"),
+        (0xe28f0010, "ADR     R0,&02001018"),
+        (0xe59f0004, "LDR     R0,&0200100C"),
+        (0xe5910000, "LDR     R0,[R1]"),
+        (0xe5d1000c, "LDRB    R0,[R1,#&C]"),
+        (0xe5310004, "LDR     R0,[R1,#-4]!"),
+        (0xe4b10004, "LDRT    R0,[R1],#4"),
+        (0xe7910102, "LDR     R0,[R1,R2,LSL #2]"),
+        (0xe6110002, "LDR     R0,[R1],-R2"),
+        (0xe92d400f, "STMFD   SP!,{R0-R3,LR}"),
+        (0xe8bd800f, "LDMFD   SP!,{R0-R3,PC}"),
+        (0xe8d10003, "LDMIA   R1,{R0,R1}^"),
+        (0xeafffffe, "B       &02001000"),
+        (0x0b000000, "BLEQ    &02001008"),
+        (0xef000000, "SWI     OS_WriteC"),
+        (0xef020006, "SWI     XOS_Byte"),
+        (0xef000141, "SWI     OS_WriteI+65"),
+        (0xef0c0000, "SWI     &C0000"),
+        (0xef0400c7, "SWI     Wimp_Poll"),
+        (0xef0606c0, "SWI     XHourglass_On"),
+        (0xef000058, "SWI     OS_ReadSysInfo"),
+        (0xe0010392, "MUL     R1,R2,R3"),
+        (0xe0314392, "MLAS    R1,R2,R3,R4"),
+        (0xe1420091, "SWPB    R0,R1,[R2]"),
+        (0xee070f10, "MCR     CP15,0,R0,CR7,CR0"),
+        (0xed912104, "LDC     CP1,CR2,[R1,#&10]"),
+        (0xee012002, "CDP     CP0,0,CR2,CR1,CR2"),
+        (0xe6000010, "DCD     &E6000010"),
+        (0xe1100000, "TST     R0,R0"),
+        (0xe1000000, "DCD     &E1000000"),
+    ];
+    for &(word, text) in cases.iter() {
+        assert_eq!(disassemble(ADDRESS, word), text, "{:08X}", word);
+        assert_eq!(assemble(ADDRESS, text), word, "{}", text);
+    }
+}
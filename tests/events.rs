@@ -0,0 +1,93 @@
+extern crate webarc;
+
+#[cfg(feature = "jit")]
+use webarc::jit::Jit;
+use webarc::machine::{Machine, Model};
+
+// Events that code brings forward have to happen when they're due, not at the
+// end of the batch of instructions that was running when they were moved
+
+const CODE: u32 = 0x02001000;
+const IOC: u32 = 0x03200000;
+// SVC mode with interrupts on
+const R15_FLAGS: u32 = 0x00000003;
+
+const AL: u32 = 0xe;
+
+// Timer 0 reloads every 100 ticks of IOC's 2MHz clock
+const TIMER_LATCH: u32 = 100;
+const CYCLES_PER_TIMER_TICK: u32 = 4;
+
+fn add_immediate(rd: u32, rn: u32, immediate: u32) -> u32 {
+    AL << 28 | 1 << 25 | 4 << 21 | rn << 16 | rd << 12 | immediate
+}
+
+// Store a word, pre-indexed from R12
+fn store(rd: u32, offset: u32) -> u32 {
+    AL << 28 | 1 << 26 | 1 << 24 | 1 << 23 | 12 << 16 | rd << 12 | offset
+}
+
+fn branch(from: u32, to: u32) -> u32 {
+    let offset = (to as i32 - from as i32 - 8) >> 2;
+    AL << 28 | 0b101 << 25 | (offset as u32 & 0x00ffffff)
+}
+
+// Start timer 0 with its interrupt unmasked, then count in R0 until the IRQ
+// vector, which loops on itself, is reached
+fn machine() -> Machine {
+    let mut rom = vec![0; 0x80000];
+    rom[0x18 / 4] = branch(0x18, 0x18);
+    let mut machine = Machine::new(Model::A3000, rom.into_boxed_slice());
+
+    // IOC takes the byte stored from bits 16-23. The timer has already gone
+    // off since reset, so its interrupt is cleared before it's unmasked.
+    let program = [
+        store(1, 0x40),
+        store(2, 0x44),
+        store(2, 0x48),
+        store(3, 0x14),
+        store(3, 0x18),
+        add_immediate(0, 0, 1),
+        branch(CODE + 24, CODE + 20),
+    ];
+    for (i, &word) in program.iter().enumerate() {
+        machine.memory().store(CODE + i as u32 * 4, word);
+    }
+    for (reg, value) in [(0, 0), (1, TIMER_LATCH << 16), (2, 0), (3, 1 << 21), (12, IOC)] {
+        machine.cpu.registers.set_reg(reg, value);
+    }
+    machine.cpu.registers.set_reg(15, R15_FLAGS | (CODE + 8));
+    machine
+}
+
+fn pc(machine: &Machine) -> u32 {
+    machine.cpu.registers.reg_no_flags(15) - 8
+}
+
+// Stepping runs events after every instruction, so it sees the IRQ as soon as
+// it's due. Running in batches has to take it at the same point.
+fn check(mut machine: Machine) {
+    let mut stepped = self::machine();
+    while pc(&stepped) != 0x18 {
+        stepped.step();
+    }
+    let loops = stepped.cpu.registers.reg(0);
+    assert!(loops > 0 && loops < TIMER_LATCH * CYCLES_PER_TIMER_TICK);
+
+    machine.cpu.run_for(100_000);
+    assert_eq!(pc(&machine), 0x18);
+    assert_eq!(machine.cpu.registers.reg(0), loops);
+}
+
+#[test]
+fn timer_started_during_a_batch() {
+    check(machine());
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn timer_started_during_a_translated_batch() {
+    let mut machine = machine();
+    machine.cpu.jit = Some(Jit::new().expect("Couldn't start the JIT"));
+    check(machine);
+}
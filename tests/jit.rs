@@ -0,0 +1,187 @@
+#![cfg(feature = "jit")]
+
+extern crate webarc;
+
+use webarc::jit::Jit;
+use webarc::machine::{Machine, Model};
+
+// Differential tests for the JIT. Each program runs once on the interpreter and
+// once with the JIT, and the two machines have to agree on the registers, the
+// clock and memory wherever the JIT comes out of a block.
+
+const CODE: u32 = 0x02001000;
+const DATA: u32 = 0x02000000;
+const DATA_WORDS: u32 = 64;
+// SVC mode with interrupts off
+const R15_FLAGS: u32 = 0x0c000003;
+
+const AL: u32 = 0xe;
+
+// xorshift, so runs can be repeated from a seed
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u32
+    }
+
+    fn below(&mut self, limit: u32) -> u32 {
+        self.next() % limit
+    }
+
+    fn condition(&mut self) -> u32 {
+        if self.below(3) == 0 { self.below(16) } else { AL }
+    }
+}
+
+fn add_immediate(cond: u32, rd: u32, rn: u32, immediate: u32, rotate: u32) -> u32 {
+    cond << 28 | 1 << 25 | 4 << 21 | rn << 16 | rd << 12 | rotate << 8 | immediate
+}
+
+fn add_register(cond: u32, rd: u32, rn: u32, rm: u32, shift_type: u32, amount: u32) -> u32 {
+    cond << 28 | 4 << 21 | rn << 16 | rd << 12 | amount << 7 | shift_type << 5 | rm
+}
+
+// Pre-indexed from R12, without write back
+fn transfer(cond: u32, load: bool, byte: bool, rd: u32, offset: u32) -> u32 {
+    cond << 28 | 1 << 26 | 1 << 24 | 1 << 23 | (byte as u32) << 22 | (load as u32) << 20 | 12 << 16 | rd << 12 | offset
+}
+
+fn branch(cond: u32, link: bool, from: u32, to: u32) -> u32 {
+    let offset = (to as i32 - from as i32 - 8) >> 2;
+    cond << 28 | 0b101 << 25 | (link as u32) << 24 | (offset as u32 & 0x00ffffff)
+}
+
+fn random_program(random: &mut Random) -> Vec<u32> {
+    let length = 8 + random.below(40);
+    let mut program = Vec::new();
+
+    for index in 0..length {
+        let cond = random.condition();
+        let rd = random.below(12);
+        let rn = random.below(12);
+        let instruction = match random.below(10) {
+            0..=3 => add_immediate(cond, rd, rn, random.below(256), random.below(16)),
+            4..=5 => add_register(cond, rd, rn, random.below(12), random.below(4), random.below(32)),
+            6 => transfer(cond, true, random.below(2) == 0, rd, random.below(DATA_WORDS) * 4),
+            7 => transfer(cond, false, random.below(2) == 0, rd, random.below(DATA_WORDS) * 4),
+            _ => {
+                // Forwards, so every program gets to its end
+                let target = index + 1 + random.below(length - index);
+                branch(cond, random.below(4) == 0, CODE + index * 4, CODE + target * 4)
+            }
+        };
+        program.push(instruction);
+    }
+
+    // Round again
+    program.push(branch(AL, false, CODE + length * 4, CODE));
+    program
+}
+
+fn machine(program: &[u32], registers: &[u32], jit: bool) -> Machine {
+    let mut machine = Machine::new(Model::A3000, vec![0; 0x80000].into_boxed_slice());
+    for (i, &word) in program.iter().enumerate() {
+        machine.memory().store(CODE + i as u32 * 4, word);
+    }
+    for (reg, &value) in registers.iter().enumerate() {
+        machine.cpu.registers.set_reg(reg as u32, value);
+    }
+    machine.cpu.registers.set_reg(12, DATA);
+    machine.cpu.registers.set_reg(15, R15_FLAGS | (CODE + 8));
+
+    if jit {
+        machine.cpu.jit = Some(Jit::new().expect("Couldn't start the JIT"));
+    }
+    machine
+}
+
+fn state(machine: &mut Machine) -> (Vec<u32>, u64, Vec<u32>) {
+    let registers = machine.cpu.registers.slots().to_vec();
+    let clock = machine.memory().clock();
+    let data = (0..DATA_WORDS).map(|i| machine.memory().load(DATA + i * 4)).collect();
+    (registers, clock, data)
+}
+
+// Run the JIT a slice at a time, bringing the interpreter up to the same point
+// after each one
+fn compare(program: &[u32], registers: &[u32], slices: u32, cycles: u64) {
+    let mut interpreted = machine(program, registers, false);
+    let mut translated = machine(program, registers, true);
+
+    for slice in 0..slices {
+        translated.cpu.run_for(cycles);
+        let clock = translated.memory().clock();
+        while interpreted.memory().clock() < clock {
+            interpreted.step();
+        }
+
+        let expected = state(&mut interpreted);
+        let actual = state(&mut translated);
+        assert_eq!(expected, actual, "slice {} of {:08X?}", slice, program);
+    }
+}
+
+#[test]
+fn random_programs() {
+    let mut random = Random(0x5eed_1234_abcd_ef01);
+    for _ in 0..200 {
+        let program = random_program(&mut random);
+        let registers: Vec<u32> = (0..12).map(|_| random.next()).collect();
+        compare(&program, &registers, 8, 2000);
+    }
+}
+
+#[test]
+fn every_condition() {
+    for flags in 0..16 {
+        let program: Vec<u32> = (0..16).map(|cond| add_immediate(cond, cond % 12, cond % 12, 1 << (cond % 8), 0))
+            .chain(Some(branch(AL, false, CODE + 16 * 4, CODE)))
+            .collect();
+        let mut interpreted = machine(&program, &[], false);
+        let mut translated = machine(&program, &[], true);
+        for machine in [&mut interpreted, &mut translated] {
+            machine.cpu.registers.set_reg(15, flags << 28 | R15_FLAGS | (CODE + 8));
+        }
+
+        translated.cpu.run_for(1000);
+        let clock = translated.memory().clock();
+        while interpreted.memory().clock() < clock {
+            interpreted.step();
+        }
+        assert_eq!(state(&mut interpreted), state(&mut translated), "flags {:X}", flags);
+    }
+}
+
+// A loop that rewrites one of its own instructions has to see the new one
+#[test]
+fn self_modifying_code() {
+    let program = vec![
+        add_immediate(AL, 0, 0, 1, 0),
+        // Overwritten with ADD R2, R2, #1 by the store below
+        add_immediate(AL, 1, 1, 1, 0),
+        transfer(AL, false, false, 3, 4),
+        branch(AL, false, CODE + 12, CODE),
+    ];
+    let registers = [0, 0, 0, add_immediate(AL, 2, 2, 1, 0)];
+
+    // R12 points at the code rather than the data
+    let mut interpreted = machine(&program, &registers, false);
+    let mut translated = machine(&program, &registers, true);
+    for machine in [&mut interpreted, &mut translated] {
+        machine.cpu.registers.set_reg(12, CODE);
+    }
+
+    translated.cpu.run_for(1000);
+    let clock = translated.memory().clock();
+    while interpreted.memory().clock() < clock {
+        interpreted.step();
+    }
+
+    assert!(translated.cpu.registers.reg(2) > 0);
+    assert_eq!(translated.cpu.registers.reg(1), 1);
+    assert_eq!(state(&mut interpreted), state(&mut translated));
+}
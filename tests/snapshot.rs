@@ -0,0 +1,164 @@
+extern crate webarc;
+
+use webarc::ether3::Ether3;
+use webarc::hostfs;
+use webarc::machine::{Machine, Model};
+use webarc::net::Loopback;
+use webarc::podule::{Access, Podule, Podules};
+
+// A machine restored from a snapshot has to be the machine that was saved:
+// saving it again gives the same bytes, and it carries on exactly as the
+// original does. Snapshots from anything else are refused and leave the
+// machine as it was.
+
+const CODE: u32 = 0x02001000;
+const DATA: u32 = 0x02000000;
+// SVC mode with interrupts off
+const R15_FLAGS: u32 = 0x0c000003;
+
+// A logical page, and the CAM entry mapping physical page 5 there with 4KB
+// pages
+const LOGICAL: u32 = 0x8000;
+const CAM_ENTRY: u32 = 0x03800000 | LOGICAL | 5;
+
+// The Ether3's registers in slot 1's fast space
+const ETHER3: u32 = 0x03340000 + 0x4000;
+
+const AL: u32 = 0xe;
+
+const STATION_ADDRESS: [u8; 6] = [0x00, 0x00, 0xa4, 0x00, 0x00, 0x01];
+
+fn add_immediate(rd: u32, rn: u32, immediate: u32) -> u32 {
+    AL << 28 | 1 << 25 | 4 << 21 | rn << 16 | rd << 12 | immediate
+}
+
+fn add_register(rd: u32, rn: u32, rm: u32) -> u32 {
+    AL << 28 | 4 << 21 | rn << 16 | rd << 12 | rm
+}
+
+// Store a word, pre-indexed from R12
+fn store(rd: u32, offset: u32) -> u32 {
+    AL << 28 | 1 << 26 | 1 << 24 | 1 << 23 | 12 << 16 | rd << 12 | offset
+}
+
+fn branch(from: u32, to: u32) -> u32 {
+    let offset = (to as i32 - from as i32 - 8) >> 2;
+    AL << 28 | 0b101 << 25 | (offset as u32 & 0x00ffffff)
+}
+
+fn rom(seed: u32) -> Box<[u32]> {
+    (0..0x80000u32).map(|i| i.wrapping_mul(0x9e3779b9) ^ seed).collect()
+}
+
+// A loop keeping a running total in RAM, with HostFS and a network card
+// fitted, and something in the card's packet buffer
+fn machine(model: Model, rom: Box<[u32]>, ether3: bool) -> Machine {
+    let mut machine = Machine::new(model, rom);
+    if let Some(podules) = machine.memory().device::<Podules>() {
+        podules.insert(0, Box::new(hostfs::podule()));
+        if ether3 {
+            podules.insert(1, Box::new(Ether3::new(Vec::new(), STATION_ADDRESS, Box::new(Loopback::new()))));
+        }
+    }
+
+    let program = [
+        add_immediate(0, 0, 1),
+        add_register(1, 1, 0),
+        store(0, 0),
+        store(1, 4),
+        branch(CODE + 16, CODE),
+    ];
+    for (i, &word) in program.iter().enumerate() {
+        machine.memory().store(CODE + i as u32 * 4, word);
+    }
+    machine.cpu.registers.set_reg(12, DATA);
+    machine.cpu.registers.set_reg(15, R15_FLAGS | (CODE + 8));
+    machine
+}
+
+fn started() -> Machine {
+    let mut machine = machine(Model::A3000, rom(0), true);
+
+    // Select the next ROM page on the HostFS card, and write a few bytes of
+    // the Ether3's buffer through its window
+    machine.memory().store(0x03240000, 1);
+    machine.memory().store(ETHER3 + 4, 8);
+    machine.memory().store(ETHER3 + 0x1c, 0x100);
+    for value in [0x1234, 0x5678, 0x9abc] {
+        machine.memory().store(ETHER3 + 0x10, value);
+    }
+    machine.memory().store(CAM_ENTRY, 0);
+    machine.memory().store(LOGICAL, 0xcafe);
+
+    machine.cpu.run_for(200_000);
+    machine
+}
+
+#[test]
+fn round_trip() {
+    let mut original = started();
+    let saved = original.snapshot();
+
+    let mut restored = machine(Model::A3000, rom(0), true);
+    assert!(restored.snapshot() != saved);
+    restored.restore(&saved).unwrap();
+    assert!(restored.snapshot() == saved, "Restored machine saves differently");
+
+    // Both carry on the same way
+    for _ in 0..4 {
+        original.cpu.run_for(100_000);
+        restored.cpu.run_for(100_000);
+        assert!(restored.snapshot() == original.snapshot(), "Restored machine went its own way");
+    }
+    assert!(original.memory().peek(DATA).unwrap() > 0);
+    assert_eq!(original.memory().peek(DATA), restored.memory().peek(DATA));
+
+    // MEMC still maps the page
+    for machine in [&mut original, &mut restored] {
+        assert_eq!(machine.memory().peek(LOGICAL), Some(0xcafe));
+        assert_eq!(machine.memory().peek(0x02005000), Some(0xcafe));
+    }
+
+    // The cards were put back too: the same ROM page, and the same bytes in
+    // the packet buffer
+    let mut card = hostfs::podule();
+    let first_page = card.load(Access::Slow, 0);
+    card.store(Access::Slow, 0, 1);
+    let second_page = card.load(Access::Slow, 0);
+    assert_ne!(first_page, second_page);
+    for machine in [&mut original, &mut restored] {
+        assert_eq!(machine.memory().load(0x03240000) & 0xff, second_page);
+        machine.memory().store(ETHER3 + 0x1c, 0x100);
+        let buffer: Vec<u32> = (0..3).map(|_| machine.memory().load(ETHER3 + 0x10) & 0xffff).collect();
+        assert_eq!(buffer, [0x1234, 0x5678, 0x9abc]);
+    }
+}
+
+// Each of these has to fail, and leave the machine just as it was
+fn refused(machine: &mut Machine, data: &[u8]) {
+    let before = machine.snapshot();
+    assert!(machine.restore(data).is_err());
+    assert!(machine.snapshot() == before, "Refused snapshot changed the machine");
+}
+
+#[test]
+fn refusals() {
+    let saved = started().snapshot();
+
+    let mut newer = saved.clone();
+    newer[8] += 1;
+    refused(&mut machine(Model::A3000, rom(0), true), &newer);
+
+    let mut unlabelled = saved.clone();
+    unlabelled[0] = b'X';
+    refused(&mut machine(Model::A3000, rom(0), true), &unlabelled);
+
+    refused(&mut machine(Model::A3000, rom(1), true), &saved);
+    refused(&mut machine(Model::A310, rom(0), true), &saved);
+    refused(&mut machine(Model::A3000, rom(0), false), &saved);
+
+    let mut longer = saved.clone();
+    longer.push(0);
+    refused(&mut machine(Model::A3000, rom(0), true), &longer);
+    refused(&mut machine(Model::A3000, rom(0), true), &saved[..saved.len() - 1]);
+}
@@ -0,0 +1,91 @@
+extern crate webarc;
+
+use webarc::machine::{Machine, Model};
+
+// Sound DMA runs through a buffer and then swaps to the next one, raising
+// MEMC's sound interrupt on IOC as it does so the OS can say where the one
+// after that is. Writing Sstart is the OS doing just that.
+
+const CODE: u32 = 0x02001000;
+const IOC_IRQ_B_STATUS: u32 = 0x03200000 + 0x20;
+const VIDC_SOUND_FREQUENCY: u32 = 0xc0;
+// SVC mode with interrupts off
+const R15_FLAGS: u32 = 0x0c000003;
+
+const AL: u32 = 0xe;
+
+const IRQ_B_SOUND: u32 = 1 << 1;
+
+// MEMC registers
+const SSTART: u32 = 4;
+const SENDN: u32 = 5;
+const SPTR: u32 = 6;
+const CONTROL: u32 = 7;
+const CONTROL_SOUND_DMA: u32 = 1 << 11;
+
+// A byte every 2us, so a quadword every 256 cycles, and four quadwords to a
+// buffer
+const QUADWORD_CYCLES: u64 = 16 * 16;
+const BUFFER: u32 = 0x1000;
+const BUFFER_QUADWORDS: u32 = 4;
+
+fn branch(from: u32, to: u32) -> u32 {
+    let offset = (to as i32 - from as i32 - 8) >> 2;
+    AL << 28 | 0b101 << 25 | (offset as u32 & 0x00ffffff)
+}
+
+// MEMC takes its registers' values from the address written to
+fn memc(machine: &mut Machine, register: u32, value: u32) {
+    machine.memory().store(0x03600000 | register << 17 | (value >> 4) << 2, 0);
+}
+
+fn sound_irq(machine: &mut Machine) -> bool {
+    machine.memory().load(IOC_IRQ_B_STATUS) & IRQ_B_SOUND != 0
+}
+
+// Run until the sound interrupt goes up, and say how long it took
+fn until_sound_irq(machine: &mut Machine) -> u64 {
+    let start = machine.memory().clock();
+    while !sound_irq(machine) {
+        assert!(machine.memory().clock() - start < 100 * QUADWORD_CYCLES, "No sound interrupt");
+        machine.cpu.run_for(8);
+    }
+    machine.memory().clock() - start
+}
+
+#[test]
+fn buffer_swap() {
+    let mut machine = Machine::new(Model::A3000, vec![0; 0x80000].into_boxed_slice());
+    machine.memory().store(CODE, branch(CODE, CODE));
+    machine.cpu.registers.set_reg(15, R15_FLAGS | (CODE + 8));
+
+    machine.memory().store(0x03400000, VIDC_SOUND_FREQUENCY << 24);
+    let buffer_end = BUFFER + (BUFFER_QUADWORDS - 1) * 16;
+    memc(&mut machine, SSTART, BUFFER);
+    memc(&mut machine, SENDN, buffer_end);
+    memc(&mut machine, SPTR, 0);
+    assert!(!sound_irq(&mut machine));
+
+    // Nothing happens until DMA is turned on
+    machine.cpu.run_for(10 * QUADWORD_CYCLES);
+    assert!(!sound_irq(&mut machine));
+    machine.memory().store(0x03600000 | CONTROL << 17 | CONTROL_SOUND_DMA, 0);
+
+    // The first buffer plays through, and the interrupt stays up until the
+    // next Sstart is written
+    let first = until_sound_irq(&mut machine);
+    assert!(first > (BUFFER_QUADWORDS as u64 - 2) * QUADWORD_CYCLES, "{} cycles", first);
+    assert!(first <= BUFFER_QUADWORDS as u64 * QUADWORD_CYCLES, "{} cycles", first);
+    machine.cpu.run_for(2 * QUADWORD_CYCLES);
+    assert!(sound_irq(&mut machine));
+    memc(&mut machine, SSTART, BUFFER);
+    assert!(!sound_irq(&mut machine));
+
+    // The second buffer runs for exactly a buffer's worth of DMA from the
+    // swap, which was a little before the last write
+    let second = until_sound_irq(&mut machine) + 2 * QUADWORD_CYCLES;
+    let expected = BUFFER_QUADWORDS as u64 * QUADWORD_CYCLES;
+    assert!(second >= expected - 16 && second <= expected + 16, "{} cycles", second);
+    memc(&mut machine, SSTART, BUFFER);
+    assert!(!sound_irq(&mut machine));
+}
@@ -0,0 +1,53 @@
+extern crate webarc;
+
+use std::time::Instant;
+use webarc::machine::{Machine, Model};
+
+// How fast the interpreter gets through code from the instruction cache. It
+// only means anything in an optimised build, so it's run on request:
+//
+//     cargo test --release --test throughput -- --ignored --nocapture
+//
+// On a single shared core that has measured between 34 and 46 MIPS, so the
+// floor leaves room for a busy machine.
+
+const CODE: u32 = 0x02001000;
+// SVC mode with interrupts off
+const R15_FLAGS: u32 = 0x0c000003;
+
+const AL: u32 = 0xe;
+
+const MINIMUM_MIPS: f64 = 30.0;
+
+fn add_immediate(rd: u32, rn: u32, immediate: u32) -> u32 {
+    AL << 28 | 1 << 25 | 4 << 21 | rn << 16 | rd << 12 | immediate
+}
+
+fn branch(from: u32, to: u32) -> u32 {
+    let offset = (to as i32 - from as i32 - 8) >> 2;
+    AL << 28 | 0b101 << 25 | (offset as u32 & 0x00ffffff)
+}
+
+#[test]
+#[ignore]
+fn interpreter_mips() {
+    let mut machine = Machine::new(Model::A3000, vec![0; 0x80000].into_boxed_slice());
+
+    // R0 counts the times round a loop of eight instructions
+    let mut program: Vec<u32> = (0..7).map(|reg| add_immediate(reg, reg, 1)).collect();
+    program.push(branch(CODE + 7 * 4, CODE));
+    for (i, &word) in program.iter().enumerate() {
+        machine.memory().store(CODE + i as u32 * 4, word);
+    }
+    machine.cpu.registers.set_reg(15, R15_FLAGS | (CODE + 8));
+
+    // Ten seconds of the real machine
+    let start = Instant::now();
+    machine.cpu.run_for(80_000_000);
+    let seconds = start.elapsed().as_secs_f64();
+
+    let instructions = machine.cpu.registers.reg(0) as f64 * program.len() as f64;
+    let mips = instructions / seconds / 1_000_000.0;
+    println!("{} instructions in {:.3}s: {:.1} MIPS", instructions, seconds, mips);
+    assert!(mips >= MINIMUM_MIPS, "{:.1} MIPS", mips);
+}
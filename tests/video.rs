@@ -0,0 +1,138 @@
+extern crate webarc;
+
+use webarc::machine::{Machine, Model};
+
+// Video, cursor and sound DMA take the bus from the CPU, a quadword at a time,
+// so the more bandwidth a screen mode needs, the less work the CPU gets done
+// in a frame
+
+const CODE: u32 = 0x02001000;
+const VIDC: u32 = 0x03400000;
+// SVC mode with interrupts off
+const R15_FLAGS: u32 = 0x0c000003;
+
+const AL: u32 = 0xe;
+
+// MEMC's control register takes its value from the address
+const MEMC_CONTROL: u32 = 0x03600000 | 7 << 17;
+const CONTROL_VIDEO_DMA: u32 = 1 << 10;
+const CONTROL_SOUND_DMA: u32 = 1 << 11;
+
+// VIDC registers
+const HORIZONTAL_CYCLE: u32 = 0x80;
+const HORIZONTAL_DISPLAY_START: u32 = 0x8c;
+const HORIZONTAL_DISPLAY_END: u32 = 0x90;
+const VERTICAL_CYCLE: u32 = 0xa0;
+const VERTICAL_DISPLAY_START: u32 = 0xac;
+const VERTICAL_DISPLAY_END: u32 = 0xb0;
+const VERTICAL_CURSOR_START: u32 = 0xb8;
+const VERTICAL_CURSOR_END: u32 = 0xbc;
+const SOUND_FREQUENCY: u32 = 0xc0;
+const CONTROL: u32 = 0xe0;
+
+// 640x256 at a 16MHz pixel clock: 1024 pixel (64us) lines, 312 to a frame
+const PIXEL_CLOCK_16MHZ: u32 = 2;
+const LINE_CYCLES: u64 = 512;
+const FRAME_LINES: u64 = 312;
+const FRAME_CYCLES: u64 = LINE_CYCLES * FRAME_LINES;
+const DISPLAY_LINES: u64 = 256;
+const CURSOR_LINES: u64 = 32;
+
+// A quadword of DMA is an N cycle and three S cycles of RAM
+const QUADWORD_CYCLES: u64 = 2 + 3;
+
+// A sound byte every 8us, so a quadword every 1024 cycles
+const SOUND_PERIOD: u32 = 6;
+const SOUND_QUADWORD_CYCLES: u64 = 16 * 8 * 8;
+
+const FRAMES: u64 = 4;
+
+fn add_immediate(rd: u32, rn: u32, immediate: u32) -> u32 {
+    AL << 28 | 1 << 25 | 4 << 21 | rn << 16 | rd << 12 | immediate
+}
+
+fn branch(from: u32, to: u32) -> u32 {
+    let offset = (to as i32 - from as i32 - 8) >> 2;
+    AL << 28 | 0b101 << 25 | (offset as u32 & 0x00ffffff)
+}
+
+fn vidc(machine: &mut Machine, register: u32, value: u32) {
+    machine.memory().store(VIDC, register << 24 | value);
+}
+
+// Timing registers hold their values in bits 14-23
+fn timing(machine: &mut Machine, register: u32, value: u32) {
+    vidc(machine, register, value << 14);
+}
+
+// A machine counting in R0, with the display set up for a mode with the given
+// bits per pixel, the cursor on if asked for, and DMA turned on in MEMC as
+// given
+fn machine(bits_per_pixel: u32, cursor: bool, control: u32) -> Machine {
+    let mut machine = Machine::new(Model::A3000, vec![0; 0x80000].into_boxed_slice());
+    machine.memory().store(CODE, add_immediate(0, 0, 1));
+    machine.memory().store(CODE + 4, branch(CODE + 4, CODE));
+    machine.cpu.registers.set_reg(0, 0);
+    machine.cpu.registers.set_reg(15, R15_FLAGS | (CODE + 8));
+
+    // The display registers count two pixels at a time
+    timing(&mut machine, HORIZONTAL_CYCLE, 511);
+    timing(&mut machine, HORIZONTAL_DISPLAY_START, 100);
+    timing(&mut machine, HORIZONTAL_DISPLAY_END, 420);
+    timing(&mut machine, VERTICAL_CYCLE, FRAME_LINES as u32 - 1);
+    timing(&mut machine, VERTICAL_DISPLAY_START, 20);
+    timing(&mut machine, VERTICAL_DISPLAY_END, 20 + DISPLAY_LINES as u32);
+    if cursor {
+        timing(&mut machine, VERTICAL_CURSOR_START, 100);
+        timing(&mut machine, VERTICAL_CURSOR_END, 100 + CURSOR_LINES as u32);
+    }
+    let depth = bits_per_pixel.trailing_zeros();
+    vidc(&mut machine, CONTROL, depth << 2 | PIXEL_CLOCK_16MHZ);
+    vidc(&mut machine, SOUND_FREQUENCY, SOUND_PERIOD);
+    machine.memory().store(MEMC_CONTROL | control, 0);
+    machine
+}
+
+// Times round the loop over a few frames, after one to settle in
+fn iterations(machine: &mut Machine) -> u64 {
+    machine.cpu.run_for(FRAME_CYCLES);
+    let start = machine.cpu.registers.reg(0);
+    machine.cpu.run_for(FRAMES * FRAME_CYCLES);
+    (machine.cpu.registers.reg(0) - start) as u64
+}
+
+// Cycles a frame that the CPU didn't get, against a run with no DMA at all
+fn stolen_per_frame(bits_per_pixel: u32, cursor: bool, control: u32) -> u64 {
+    let idle = iterations(&mut machine(bits_per_pixel, cursor, 0));
+    let busy = iterations(&mut machine(bits_per_pixel, cursor, control));
+    let frames = FRAMES * FRAME_CYCLES;
+    (frames - busy * frames / idle) / FRAMES
+}
+
+// Within a line's worth of DMA either way, as that much can fall either side
+// of the frames measured
+fn assert_near(stolen: u64, expected: u64, line: u64) {
+    let slack = line + 16;
+    assert!(stolen + slack >= expected && stolen <= expected + slack, "{} cycles stolen, expected {}", stolen, expected);
+}
+
+#[test]
+fn bandwidth() {
+    // 640 pixels of 1 bit is 5 quadwords a line, and of 8 bits is 40
+    let low = stolen_per_frame(1, false, CONTROL_VIDEO_DMA);
+    assert_near(low, DISPLAY_LINES * 5 * QUADWORD_CYCLES, 5 * QUADWORD_CYCLES);
+    let high = stolen_per_frame(8, false, CONTROL_VIDEO_DMA);
+    assert_near(high, DISPLAY_LINES * 40 * QUADWORD_CYCLES, 40 * QUADWORD_CYCLES);
+    assert!(high > 7 * low);
+
+    // The cursor takes a quadword on each of its lines
+    let cursor = stolen_per_frame(1, true, CONTROL_VIDEO_DMA);
+    assert_near(cursor, (DISPLAY_LINES * 5 + CURSOR_LINES) * QUADWORD_CYCLES, 6 * QUADWORD_CYCLES);
+    assert!(cursor > low);
+
+    // Sound DMA on its own, then on top of the display
+    let sound = FRAME_CYCLES / SOUND_QUADWORD_CYCLES * QUADWORD_CYCLES;
+    assert_near(stolen_per_frame(1, false, CONTROL_SOUND_DMA), sound, QUADWORD_CYCLES);
+    let both = stolen_per_frame(8, true, CONTROL_VIDEO_DMA | CONTROL_SOUND_DMA);
+    assert_near(both, (DISPLAY_LINES * 40 + CURSOR_LINES) * QUADWORD_CYCLES + sound, 41 * QUADWORD_CYCLES);
+}